@@ -0,0 +1,79 @@
+//! Measures sending UDP datagrams through a `Forwarder`, both datagrams fitting in the MTU and
+//! datagrams fragmented into several frames.
+//!
+//! Run with `cargo bench --bench forwarder`.
+
+use pnet::datalink::{DataLinkSender, NetworkInterface};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::time::{Duration, Instant};
+
+use pcap2socks::pcap::HardwareAddr;
+use pcap2socks::Forwarder;
+
+/// Represents the number of datagrams sent in each run.
+const DATAGRAMS: usize = 100_000;
+/// Represents the number of runs of each case, of which the fastest is reported.
+const RUNS: usize = 5;
+
+/// Represents a sender discarding all frames. The buffer is kept across frames like the sender of
+/// an interface, so only the allocations of the forwarder are measured.
+struct Discarder {
+    buffer: Vec<u8>,
+}
+
+impl DataLinkSender for Discarder {
+    fn build_and_send(
+        &mut self,
+        num_packets: usize,
+        packet_size: usize,
+        func: &mut dyn FnMut(&mut [u8]),
+    ) -> Option<io::Result<()>> {
+        self.buffer.resize(packet_size, 0);
+        for _ in 0..num_packets {
+            func(&mut self.buffer);
+        }
+
+        Some(Ok(()))
+    }
+
+    fn send_to(&mut self, _: &[u8], _: Option<NetworkInterface>) -> Option<io::Result<()>> {
+        Some(Ok(()))
+    }
+}
+
+fn send(size: usize) -> Duration {
+    let src = SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 2), 10000);
+    let dst = SocketAddrV4::new(Ipv4Addr::new(1, 1, 1, 1), 53);
+
+    let mut tx = Forwarder::new(
+        Box::new(Discarder { buffer: Vec::new() }),
+        1500,
+        HardwareAddr::new(0x22, 0x22, 0x22, 0x22, 0x22, 0x22),
+        Ipv4Addr::new(192, 168, 1, 1),
+    );
+    tx.set_src_hardware_addr(
+        *src.ip(),
+        HardwareAddr::new(0x11, 0x11, 0x11, 0x11, 0x11, 0x11),
+    );
+    let payload = vec![0u8; size];
+
+    let start = Instant::now();
+    for _ in 0..DATAGRAMS {
+        tx.send_udp(dst, src, &payload).unwrap();
+    }
+
+    start.elapsed()
+}
+
+fn main() {
+    for &size in &[512, 8000, 60000] {
+        let elapsed = (0..RUNS).map(|_| send(size)).min().unwrap();
+        println!(
+            "{} Bytes: {:?} ({:.0} datagrams/s)",
+            size,
+            elapsed,
+            DATAGRAMS as f64 / elapsed.as_secs_f64()
+        );
+    }
+}
@@ -0,0 +1,186 @@
+//! Compares sharing one capture among redirectors with a `CaptureHub` against opening an
+//! independent capture for each redirector.
+//!
+//! Run with `cargo bench --bench hub`.
+
+use ipnetwork::Ipv4Network;
+use pnet::datalink::{DataLinkReceiver, DataLinkSender, NetworkInterface};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use pcap2socks::filter::{Action, Filter};
+use pcap2socks::hub::CaptureHub;
+use pcap2socks::packet::layer::ethernet::Ethernet;
+use pcap2socks::packet::layer::ipv4::Ipv4;
+use pcap2socks::packet::layer::udp::Udp;
+use pcap2socks::packet::layer::{LayerKinds, Layers};
+use pcap2socks::packet::Indicator;
+use pcap2socks::pcap::{HardwareAddr, Receiver, Sender};
+use pcap2socks::{Forwarder, ProxyConfig, Redirector};
+
+/// Represents the number of sources, each of which is handled by a redirector.
+const SOURCES: usize = 8;
+/// Represents the number of captured frames.
+const FRAMES: usize = 200_000;
+/// Represents the size of the payload of each frame.
+const PAYLOAD_SIZE: usize = 64;
+
+/// Represents a receiver replaying frames, as if all of them are captured on the interface.
+struct Replayer {
+    frames: Arc<Vec<Vec<u8>>>,
+    next: usize,
+}
+
+impl DataLinkReceiver for Replayer {
+    fn next(&mut self) -> io::Result<&[u8]> {
+        let frame = self
+            .frames
+            .get(self.next)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+        self.next += 1;
+
+        Ok(frame)
+    }
+}
+
+/// Represents a sender discarding all frames.
+struct Discarder;
+
+impl DataLinkSender for Discarder {
+    fn build_and_send(
+        &mut self,
+        num_packets: usize,
+        packet_size: usize,
+        func: &mut dyn FnMut(&mut [u8]),
+    ) -> Option<io::Result<()>> {
+        let mut buffer = vec![0u8; packet_size];
+        for _ in 0..num_packets {
+            func(&mut buffer);
+        }
+
+        Some(Ok(()))
+    }
+
+    fn send_to(&mut self, _: &[u8], _: Option<NetworkInterface>) -> Option<io::Result<()>> {
+        Some(Ok(()))
+    }
+}
+
+fn src(i: usize) -> Ipv4Addr {
+    Ipv4Addr::new(192, 168, 1, 2 + i as u8)
+}
+
+fn frames() -> Vec<Vec<u8>> {
+    let dst = SocketAddrV4::new(Ipv4Addr::new(1, 1, 1, 1), 53);
+    let payload = vec![0u8; PAYLOAD_SIZE];
+
+    (0..FRAMES)
+        .map(|i| {
+            let src = SocketAddrV4::new(src(i % SOURCES), 10000);
+            let ethernet = Ethernet::new(
+                LayerKinds::Ipv4,
+                HardwareAddr::new(0x11, 0x11, 0x11, 0x11, 0x11, i as u8 % SOURCES as u8),
+                HardwareAddr::new(0x22, 0x22, 0x22, 0x22, 0x22, 0x22),
+            )
+            .unwrap();
+            let ipv4 = Ipv4::new(i as u16, LayerKinds::Udp, *src.ip(), *dst.ip()).unwrap();
+            let mut udp = Udp::new(src.port(), dst.port());
+            udp.set_ipv4_layer(&ipv4);
+
+            let indicator = Indicator::new(
+                Layers::Ethernet(ethernet),
+                Some(Layers::Ipv4(ipv4)),
+                Some(Layers::Udp(udp)),
+            );
+            let mut buffer = vec![0u8; indicator.len() + payload.len()];
+            indicator
+                .serialize_with_payload(&mut buffer, &payload)
+                .unwrap();
+
+            buffer
+        })
+        .collect()
+}
+
+fn redirector(tx: Sender, i: usize) -> Redirector {
+    let tx = Forwarder::new(
+        tx,
+        1500,
+        HardwareAddr::new(0x22, 0x22, 0x22, 0x22, 0x22, 0x22),
+        Ipv4Addr::new(192, 168, 1, 1),
+    );
+    let mut redirector = Redirector::new(
+        Arc::new(Mutex::new(tx)),
+        Ipv4Network::new(src(i), 32).unwrap(),
+        Ipv4Addr::new(192, 168, 1, 1),
+        None,
+        ProxyConfig::new_socks(
+            SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1080),
+            false,
+            false,
+            None,
+        ),
+        None,
+    );
+    // Datagrams are dropped after being dispatched, so only the capture is measured
+    redirector.set_filter(Filter::new(Action::Deny));
+
+    redirector
+}
+
+fn receiver(frames: &Arc<Vec<Vec<u8>>>) -> Receiver {
+    Box::new(Replayer {
+        frames: Arc::clone(frames),
+        next: 0,
+    })
+}
+
+/// Each redirector opens its own capture, and receives all the frames on the interface.
+async fn independent(frames: &Arc<Vec<Vec<u8>>>) -> Duration {
+    let mut redirectors = (0..SOURCES)
+        .map(|i| redirector(Box::new(Discarder), i))
+        .collect::<Vec<_>>();
+
+    let start = Instant::now();
+    for redirector in redirectors.iter_mut() {
+        let _ = redirector.open(&mut receiver(frames)).await;
+    }
+
+    start.elapsed()
+}
+
+/// All the redirectors share one capture, and receive the frames of their sources only.
+async fn hub(frames: &Arc<Vec<Vec<u8>>>) -> Duration {
+    let mut hub = CaptureHub::new(Box::new(Discarder), receiver(frames));
+    for i in 0..SOURCES {
+        hub.add_redirector(redirector(hub.sender(), i));
+    }
+
+    let start = Instant::now();
+    let _ = hub.open().await;
+
+    start.elapsed()
+}
+
+#[tokio::main]
+async fn main() {
+    let frames = Arc::new(frames());
+
+    let independent = independent(&frames).await;
+    println!(
+        "{} independent captures: {:?} ({:.0} frames/s)",
+        SOURCES,
+        independent,
+        FRAMES as f64 / independent.as_secs_f64()
+    );
+
+    let hub = hub(&frames).await;
+    println!(
+        "1 hub of {} redirectors: {:?} ({:.0} frames/s)",
+        SOURCES,
+        hub,
+        FRAMES as f64 / hub.as_secs_f64()
+    );
+}
@@ -0,0 +1,115 @@
+//! Compares connecting through a SOCKS5 proxy with the pool of warm connections against
+//! connecting on demand, in bursts of connections like a page loading its resources. The proxy
+//! is served on the loopback with a simulated round trip in establishing each connection.
+//!
+//! Run with `cargo bench --bench pool`.
+
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time;
+
+use pcap2socks::proxy::{ConnectionPool, SocksUpstream, UpstreamConnector};
+use pcap2socks::ProxyConfig;
+
+/// Represents the number of connections in a burst, which is also the size of the pool.
+const BURST: usize = 32;
+/// Represents the number of bursts.
+const BURSTS: usize = 50;
+/// Represents the interval between 2 bursts, in which the pool is refilled.
+const INTERVAL: u64 = 20;
+/// Represents the simulated round trip time to the proxy, in milliseconds.
+const RTT: u64 = 5;
+
+/// Serves the SOCKS5 handshake of a connection without authentication, and replies succeeded to
+/// the CONNECT request of an IPv4 destination. The handshake starts one round trip after the
+/// connection is accepted, like the TCP handshake to a remote proxy takes a round trip before
+/// the connection can be used.
+async fn serve(mut stream: TcpStream) -> tokio::io::Result<()> {
+    time::sleep(Duration::from_millis(RTT)).await;
+
+    // Methods
+    let mut buffer = [0u8; 3];
+    stream.read_exact(&mut buffer).await?;
+    stream.write_all(&[5, 0]).await?;
+
+    // CONNECT
+    let mut buffer = [0u8; 10];
+    stream.read_exact(&mut buffer).await?;
+    stream.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0]).await?;
+
+    // Hold the connection until it is closed
+    let _ = stream.read(&mut buffer).await;
+
+    Ok(())
+}
+
+async fn proxy() -> SocketAddrV4 {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let remote = match listener.local_addr().unwrap() {
+        SocketAddr::V4(addr) => addr,
+        SocketAddr::V6(_) => unreachable!(),
+    };
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(serve(stream));
+        }
+    });
+
+    remote
+}
+
+/// Connects bursts of connections, and returns the latency of each connection. Connections in a
+/// burst are connected one after another, so the latency is not skewed by the scheduling of the
+/// proxy.
+async fn bursts(upstream: &dyn UpstreamConnector) -> Vec<Duration> {
+    let dst = SocketAddrV4::new(Ipv4Addr::new(1, 1, 1, 1), 80);
+
+    let mut latencies = Vec::with_capacity(BURST * BURSTS);
+    let mut streams = Vec::with_capacity(BURST);
+    for _ in 0..BURSTS {
+        time::sleep(Duration::from_millis(INTERVAL)).await;
+
+        for _ in 0..BURST {
+            let start = Instant::now();
+            streams.push(upstream.connect(dst, None).await.unwrap());
+            latencies.push(start.elapsed());
+        }
+        streams.clear();
+    }
+
+    latencies
+}
+
+fn summarize(name: &str, mut latencies: Vec<Duration>) {
+    latencies.sort();
+    let mean = latencies.iter().sum::<Duration>() / latencies.len() as u32;
+    let p50 = latencies[latencies.len() / 2];
+    let p99 = latencies[latencies.len() * 99 / 100];
+
+    println!(
+        "{}: mean {:?}, p50 {:?}, p99 {:?} ({} connections)",
+        name,
+        mean,
+        p50,
+        p99,
+        latencies.len()
+    );
+}
+
+#[tokio::main]
+async fn main() {
+    let remote = proxy().await;
+    let proxy = ProxyConfig::new_socks(remote, false, false, None);
+
+    let upstream = SocksUpstream::new(proxy.clone());
+    summarize("on demand", bursts(&upstream).await);
+
+    let pool = Arc::new(ConnectionPool::new(remote, BURST));
+    pool.fill();
+    let upstream = SocksUpstream::new_with_pool(proxy, Some(Arc::clone(&pool)));
+    summarize("pooled", bursts(&upstream).await);
+    println!("pool hits {}, misses {}", pool.hits(), pool.misses());
+}
@@ -0,0 +1,47 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use pcap2socks::packet::{self, ChecksumMode, Defraggler, Indicator};
+
+fuzz_target!(|frame: &[u8]| {
+    packet::verify_checksums(frame, ChecksumMode::Full);
+
+    let indicator = match Indicator::from(frame) {
+        Some(indicator) => indicator,
+        None => return,
+    };
+    indicator.brief();
+    let content_len = indicator.content_len();
+    let mut buffer = vec![0u8; indicator.len()];
+    let _ = indicator.serialize(&mut buffer);
+
+    if let Some(arp) = indicator.arp() {
+        arp.to_string();
+    }
+    if let Some(icmpv4) = indicator.icmpv4() {
+        icmpv4.identifier();
+        icmpv4.sequence_number();
+        icmpv4.next_hop_mtu();
+        icmpv4.next_level_layer_kind();
+        icmpv4.src();
+        icmpv4.dst();
+    }
+    if let Some(tcp) = indicator.tcp() {
+        tcp.mss();
+        tcp.wscale();
+        tcp.sack();
+        tcp.ts();
+        tcp.md5();
+        tcp.md5_digest(frame.get(indicator.len()..).unwrap_or_default(), b"key");
+    }
+
+    // Fragments
+    if let Some(ipv4) = indicator.ipv4() {
+        if ipv4.is_fragment() {
+            let content = &frame[..content_len.min(frame.len())];
+            let mut defraggler = Defraggler::new();
+            if let Some(frag) = defraggler.add(&indicator, content) {
+                frag.concatenate();
+            }
+        }
+    }
+});
@@ -0,0 +1,143 @@
+//! Support for matching IPv4 addresses against networks in the CIDR notation.
+
+use ipnetwork::Ipv4Network;
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+
+/// Represents an error parsing a network in the CIDR notation.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CidrError {
+    input: String,
+    reason: String,
+}
+
+impl CidrError {
+    /// Returns the input which cannot be parsed.
+    pub fn input(&self) -> &str {
+        &self.input
+    }
+}
+
+impl Display for CidrError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "invalid network \"{}\": {}", self.input, self.reason)
+    }
+}
+
+impl Error for CidrError {}
+
+/// Parses an IPv4 network in the CIDR notation, like `192.168.1.0/24`. An address without a
+/// prefix length represents the single address.
+pub fn parse_network(s: &str) -> Result<Ipv4Network, CidrError> {
+    let s = s.trim();
+
+    s.parse::<Ipv4Network>().map_err(|e| CidrError {
+        input: s.to_string(),
+        reason: e.to_string(),
+    })
+}
+
+/// Represents a set of IPv4 networks, which is the common representation of address ranges in
+/// the configuration, like the bypassed destinations and the proxy ARP networks.
+#[derive(Clone, Debug, Default, Eq, Hash, PartialEq)]
+pub struct Ipv4NetworkSet {
+    networks: Vec<Ipv4Network>,
+}
+
+impl Ipv4NetworkSet {
+    /// Creates an empty `Ipv4NetworkSet`.
+    pub fn new() -> Ipv4NetworkSet {
+        Ipv4NetworkSet::default()
+    }
+
+    /// Adds a network to the set. Returns `false` if the network is already in the set.
+    pub fn insert(&mut self, network: Ipv4Network) -> bool {
+        if self.networks.contains(&network) {
+            return false;
+        }
+        self.networks.push(network);
+
+        true
+    }
+
+    /// Removes all the networks.
+    pub fn clear(&mut self) {
+        self.networks.clear();
+    }
+
+    /// Returns if the address falls in any of the networks.
+    pub fn contains(&self, ip_addr: Ipv4Addr) -> bool {
+        self.networks
+            .iter()
+            .any(|network| network.contains(ip_addr))
+    }
+
+    /// Returns the networks in the order they were added.
+    pub fn networks(&self) -> &[Ipv4Network] {
+        &self.networks
+    }
+
+    /// Returns if the set is empty.
+    pub fn is_empty(&self) -> bool {
+        self.networks.is_empty()
+    }
+}
+
+impl From<Vec<Ipv4Network>> for Ipv4NetworkSet {
+    fn from(networks: Vec<Ipv4Network>) -> Self {
+        let mut set = Ipv4NetworkSet::new();
+        for network in networks {
+            set.insert(network);
+        }
+
+        set
+    }
+}
+
+impl From<Ipv4Network> for Ipv4NetworkSet {
+    fn from(network: Ipv4Network) -> Self {
+        Ipv4NetworkSet::from(vec![network])
+    }
+}
+
+impl FromStr for Ipv4NetworkSet {
+    type Err = CidrError;
+
+    /// Parses networks separated by commas, like `10.0.0.0/8,192.168.1.1`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut set = Ipv4NetworkSet::new();
+        for network in s.split(',').filter(|network| !network.trim().is_empty()) {
+            set.insert(parse_network(network)?);
+        }
+
+        Ok(set)
+    }
+}
+
+#[test]
+fn ipv4_network_set_parse() {
+    let set = "10.0.0.0/8, 192.168.1.1,10.0.0.0/8"
+        .parse::<Ipv4NetworkSet>()
+        .unwrap();
+    assert_eq!(set.networks().len(), 2);
+    assert!(set.contains(Ipv4Addr::new(10, 1, 2, 3)));
+    assert!(set.contains(Ipv4Addr::new(192, 168, 1, 1)));
+    assert!(!set.contains(Ipv4Addr::new(192, 168, 1, 2)));
+    assert!("".parse::<Ipv4NetworkSet>().unwrap().is_empty());
+
+    // Malformed input is reported with the network
+    let e = "10.0.0.0/8,10.0.0/8"
+        .parse::<Ipv4NetworkSet>()
+        .err()
+        .unwrap();
+    assert_eq!(e.input(), "10.0.0/8");
+    assert_eq!(
+        e.to_string(),
+        "invalid network \"10.0.0/8\": invalid address: 10.0.0"
+    );
+    assert!(parse_network("10.0.0.0/33").is_err());
+    assert!(parse_network("10.0.0.0/").is_err());
+    assert!(parse_network("10.0.0.0/8/8").is_err());
+}
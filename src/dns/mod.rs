@@ -0,0 +1,201 @@
+//! Support for snooping DNS responses.
+
+use lru::LruCache;
+use std::net::Ipv4Addr;
+
+/// Represents the port of DNS.
+pub const DNS_PORT: u16 = 53;
+
+const HEADER_SIZE: usize = 12;
+const FLAG_RESPONSE: u8 = 0x80;
+const RCODE_MASK: u8 = 0x0f;
+const TYPE_A: u16 = 1;
+const CLASS_IN: u16 = 1;
+const POINTER_MASK: u8 = 0xc0;
+const MAX_POINTERS: usize = 16;
+const MAX_NAME_SIZE: usize = 255;
+
+/// Represents a cache mapping IPv4 addresses to the names they were resolved from.
+#[derive(Debug)]
+pub struct DnsCache {
+    cache: LruCache<Ipv4Addr, String>,
+}
+
+impl DnsCache {
+    /// Creates a new `DnsCache` holding at most `capacity` addresses.
+    pub fn new(capacity: usize) -> DnsCache {
+        DnsCache {
+            cache: LruCache::new(capacity),
+        }
+    }
+
+    /// Inserts a name of the address.
+    pub fn insert(&mut self, ip_addr: Ipv4Addr, name: String) {
+        self.cache.put(ip_addr, name);
+    }
+
+    /// Snoops a DNS response and records the names of the addresses in the answers. Returns the
+    /// number of the recorded addresses.
+    pub fn snoop(&mut self, payload: &[u8]) -> usize {
+        match parse_response(payload) {
+            Some(answers) => {
+                let size = answers.len();
+                for (ip_addr, name) in answers {
+                    self.insert(ip_addr, name);
+                }
+
+                size
+            }
+            None => 0,
+        }
+    }
+
+    /// Returns the name of the address.
+    pub fn get(&mut self, ip_addr: Ipv4Addr) -> Option<String> {
+        self.cache.get(&ip_addr).cloned()
+    }
+
+    /// Returns the number of the addresses in the cache.
+    pub fn len(&self) -> usize {
+        self.cache.len()
+    }
+
+    /// Returns if the cache is empty.
+    pub fn is_empty(&self) -> bool {
+        self.cache.is_empty()
+    }
+}
+
+fn read_u16(payload: &[u8], offset: usize) -> Option<u16> {
+    let bytes = payload.get(offset..offset.checked_add(2)?)?;
+
+    Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+/// Reads a name at the offset. Returns the name and the offset after the name.
+fn read_name(payload: &[u8], offset: usize) -> Option<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut size = 0;
+    let mut pos = offset;
+    let mut next = None;
+    let mut pointers = 0;
+
+    loop {
+        let len = *payload.get(pos)?;
+        if len & POINTER_MASK == POINTER_MASK {
+            let pointer = (read_u16(payload, pos)? & 0x3fff) as usize;
+            if next.is_none() {
+                next = Some(pos + 2);
+            }
+            pointers += 1;
+            if pointers > MAX_POINTERS {
+                return None;
+            }
+            pos = pointer;
+        } else if len & POINTER_MASK != 0 {
+            return None;
+        } else if len == 0 {
+            if next.is_none() {
+                next = Some(pos + 1);
+            }
+            break;
+        } else {
+            let label = payload.get(pos + 1..pos + 1 + len as usize)?;
+            size += len as usize + 1;
+            if size > MAX_NAME_SIZE {
+                return None;
+            }
+            labels.push(String::from_utf8_lossy(label).to_ascii_lowercase());
+            pos += 1 + len as usize;
+        }
+    }
+
+    Some((labels.join("."), next.unwrap()))
+}
+
+/// Parses a DNS response. Returns the IPv4 addresses in the answers with the name in the question.
+/// Answers of CNAME records are followed, so all the addresses are mapped to the queried name.
+pub fn parse_response(payload: &[u8]) -> Option<Vec<(Ipv4Addr, String)>> {
+    if payload.len() < HEADER_SIZE {
+        return None;
+    }
+    // Only successful responses are parsed
+    if payload[2] & FLAG_RESPONSE == 0 || payload[3] & RCODE_MASK != 0 {
+        return None;
+    }
+    let qdcount = read_u16(payload, 4)?;
+    let ancount = read_u16(payload, 6)?;
+    // Multiple questions are not supported by most servers
+    if qdcount != 1 {
+        return None;
+    }
+
+    // Question
+    let (name, mut offset) = read_name(payload, HEADER_SIZE)?;
+    offset += 4;
+    if offset > payload.len() || name.is_empty() {
+        return None;
+    }
+
+    // Answers
+    let mut answers = Vec::new();
+    for _ in 0..ancount {
+        let (_, next) = read_name(payload, offset)?;
+        let rtype = read_u16(payload, next)?;
+        let class = read_u16(payload, next + 2)?;
+        let rdlength = read_u16(payload, next + 8)? as usize;
+        let rdata = payload.get(next + 10..next + 10 + rdlength)?;
+        if rtype == TYPE_A && class == CLASS_IN && rdata.len() == 4 {
+            answers.push((
+                Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3]),
+                name.clone(),
+            ));
+        }
+        offset = next + 10 + rdlength;
+    }
+
+    Some(answers)
+}
+
+#[test]
+fn dns_parse_response() {
+    let header = [
+        0x12, 0x34, 0x81, 0x80, 0x00, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00,
+    ];
+    // www.Example.com IN A
+    let question = [
+        0x03, b'w', b'w', b'w', 0x07, b'E', b'x', b'a', b'm', b'p', b'l', b'e', 0x03, b'c', b'o',
+        b'm', 0x00, 0x00, 0x01, 0x00, 0x01,
+    ];
+    // CNAME example.com
+    let cname = [
+        0xc0, 0x0c, 0x00, 0x05, 0x00, 0x01, 0x00, 0x00, 0x00, 0x3c, 0x00, 0x02, 0xc0, 0x10,
+    ];
+    // A 93.184.216.34
+    let a = [
+        0xc0, 0x10, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x3c, 0x00, 0x04, 93, 184, 216, 34,
+    ];
+    let payload = [&header[..], &question[..], &cname[..], &a[..]].concat();
+
+    let answers = parse_response(&payload).unwrap();
+    assert_eq!(
+        answers,
+        vec![(
+            Ipv4Addr::new(93, 184, 216, 34),
+            String::from("www.example.com")
+        )]
+    );
+
+    let mut cache = DnsCache::new(1);
+    assert_eq!(cache.snoop(&payload), 1);
+    assert_eq!(
+        cache.get(Ipv4Addr::new(93, 184, 216, 34)),
+        Some(String::from("www.example.com"))
+    );
+
+    // Queries and truncated responses are ignored
+    let mut query = payload.clone();
+    query[2] = 0x01;
+    assert!(parse_response(&query).is_none());
+    assert!(parse_response(&payload[..payload.len() - 1]).is_none());
+}
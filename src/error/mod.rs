@@ -0,0 +1,202 @@
+//! Support for the errors of the crate.
+
+use super::cidr::CidrError;
+use super::pcap::InterfaceError;
+use super::proxy::SocksReply;
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::io;
+
+/// Represents an error about the state of the redirection, like a full cache or no UDP port left.
+/// The error is carried in the `io::Error` returned internally, and can be taken out by
+/// `StateError::from_io_error`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum StateError {
+    /// Represents the send queue of a TCP connection is full.
+    QueueFull,
+    /// Represents the receive window of a TCP connection is full.
+    WindowFull,
+    /// Represents all the UDP ports are bound and none of them is idle.
+    PortsExhausted,
+    /// Represents the connection is not tracked, like it is closed or never opened.
+    UnknownConnection,
+}
+
+impl StateError {
+    /// Returns the state error carried in the error.
+    pub fn from_io_error(e: &io::Error) -> Option<StateError> {
+        e.get_ref()
+            .and_then(|e| e.downcast_ref::<StateError>())
+            .copied()
+    }
+
+    /// Returns the kind of the error as an `io::Error`.
+    pub fn kind(&self) -> io::ErrorKind {
+        match self {
+            StateError::UnknownConnection => io::ErrorKind::NotFound,
+            _ => io::ErrorKind::Other,
+        }
+    }
+}
+
+impl Display for StateError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            StateError::QueueFull => write!(f, "queue is full"),
+            StateError::WindowFull => write!(f, "window is full"),
+            StateError::PortsExhausted => write!(f, "cannot bind UDP port"),
+            StateError::UnknownConnection => write!(f, "unknown connection"),
+        }
+    }
+}
+
+impl Error for StateError {}
+
+impl From<StateError> for io::Error {
+    fn from(e: StateError) -> io::Error {
+        io::Error::new(e.kind(), e)
+    }
+}
+
+/// Represents an error of the crate.
+#[derive(Debug)]
+pub enum PcapSocksError {
+    /// Represents an I/O error, like of the capture or of a socket.
+    Io(io::Error),
+    /// Represents a reply of a SOCKS5 server other than succeeded.
+    Socks(SocksReply),
+    /// Represents an error parsing a network in the CIDR notation.
+    Parse(CidrError),
+    /// Represents an error selecting an interface.
+    Interface(InterfaceError),
+    /// Represents an error about the state of the redirection.
+    State(StateError),
+}
+
+impl PcapSocksError {
+    /// Returns the kind of the error as an `io::Error`.
+    pub fn kind(&self) -> io::ErrorKind {
+        match self {
+            PcapSocksError::Io(e) => e.kind(),
+            PcapSocksError::Socks(_) => io::ErrorKind::Other,
+            PcapSocksError::State(e) => e.kind(),
+            PcapSocksError::Parse(_) => io::ErrorKind::InvalidInput,
+            PcapSocksError::Interface(_) => io::ErrorKind::NotFound,
+        }
+    }
+}
+
+impl Display for PcapSocksError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            PcapSocksError::Io(e) => write!(f, "{}", e),
+            PcapSocksError::Socks(reply) => write!(f, "{}", reply),
+            PcapSocksError::Parse(e) => write!(f, "{}", e),
+            PcapSocksError::Interface(e) => write!(f, "{}", e),
+            PcapSocksError::State(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for PcapSocksError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            PcapSocksError::Io(e) => Some(e),
+            PcapSocksError::Socks(reply) => Some(reply),
+            PcapSocksError::Parse(e) => Some(e),
+            PcapSocksError::Interface(e) => Some(e),
+            PcapSocksError::State(e) => Some(e),
+        }
+    }
+}
+
+impl From<io::Error> for PcapSocksError {
+    /// Converts an `io::Error`, taking out the typed error carried in it if any.
+    fn from(e: io::Error) -> PcapSocksError {
+        if let Some(reply) = SocksReply::from_io_error(&e) {
+            return PcapSocksError::Socks(reply);
+        }
+        if let Some(state) = StateError::from_io_error(&e) {
+            return PcapSocksError::State(state);
+        }
+
+        PcapSocksError::Io(e)
+    }
+}
+
+impl From<SocksReply> for PcapSocksError {
+    fn from(reply: SocksReply) -> PcapSocksError {
+        PcapSocksError::Socks(reply)
+    }
+}
+
+impl From<CidrError> for PcapSocksError {
+    fn from(e: CidrError) -> PcapSocksError {
+        PcapSocksError::Parse(e)
+    }
+}
+
+impl From<InterfaceError> for PcapSocksError {
+    fn from(e: InterfaceError) -> PcapSocksError {
+        PcapSocksError::Interface(e)
+    }
+}
+
+impl From<StateError> for PcapSocksError {
+    fn from(e: StateError) -> PcapSocksError {
+        PcapSocksError::State(e)
+    }
+}
+
+impl From<PcapSocksError> for io::Error {
+    /// Converts a `PcapSocksError` back at the boundaries which require an `io::Error`. Typed
+    /// errors are carried in the `io::Error`.
+    fn from(e: PcapSocksError) -> io::Error {
+        let kind = e.kind();
+        match e {
+            PcapSocksError::Io(e) => e,
+            PcapSocksError::Socks(reply) => reply.into(),
+            PcapSocksError::State(e) => e.into(),
+            PcapSocksError::Parse(e) => io::Error::new(kind, e),
+            PcapSocksError::Interface(e) => io::Error::new(kind, e),
+        }
+    }
+}
+
+/// Represents a result of the crate.
+pub type Result<T> = std::result::Result<T, PcapSocksError>;
+
+#[test]
+fn pcap_socks_error_from_io_error() {
+    // Typed errors carried in an `io::Error` are taken out
+    let e = PcapSocksError::from(io::Error::from(StateError::QueueFull));
+    assert!(matches!(e, PcapSocksError::State(StateError::QueueFull)));
+    assert_eq!(e.to_string(), "queue is full");
+    let e = PcapSocksError::from(io::Error::from(SocksReply::HostUnreachable));
+    assert!(matches!(
+        e,
+        PcapSocksError::Socks(SocksReply::HostUnreachable)
+    ));
+    assert!(e.source().is_some());
+
+    // Other errors are kept as they are
+    let e = PcapSocksError::from(io::Error::from(io::ErrorKind::TimedOut));
+    assert!(matches!(e, PcapSocksError::Io(_)));
+    assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+
+    // Typed errors survive the round trip through an `io::Error`
+    let e = io::Error::from(PcapSocksError::State(StateError::PortsExhausted));
+    assert_eq!(e.kind(), io::ErrorKind::Other);
+    assert_eq!(
+        StateError::from_io_error(&e),
+        Some(StateError::PortsExhausted)
+    );
+    let e = io::Error::from(PcapSocksError::Interface(InterfaceError::NoAddress));
+    assert_eq!(e.kind(), io::ErrorKind::NotFound);
+    let e = io::Error::from(PcapSocksError::State(StateError::UnknownConnection));
+    assert_eq!(e.kind(), io::ErrorKind::NotFound);
+    assert_eq!(
+        StateError::from_io_error(&e),
+        Some(StateError::UnknownConnection)
+    );
+}
@@ -0,0 +1,185 @@
+//! Support for filtering destinations.
+
+use crate::cidr::Ipv4NetworkSet;
+use std::cmp::{max, min};
+use std::net::SocketAddrV4;
+
+/// Represents the action of a filter.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Action {
+    Allow,
+    Deny,
+}
+
+/// Represents a rule matching destinations by networks and port range.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct Rule {
+    networks: Ipv4NetworkSet,
+    ports: Option<(u16, u16)>,
+}
+
+impl Rule {
+    /// Creates a `Rule` matching all ports in the networks.
+    pub fn new(networks: Ipv4NetworkSet) -> Rule {
+        Rule {
+            networks,
+            ports: None,
+        }
+    }
+
+    /// Creates a `Rule` matching ports in the inclusive range in the networks.
+    pub fn new_with_ports(networks: Ipv4NetworkSet, first: u16, last: u16) -> Rule {
+        Rule {
+            networks,
+            ports: Some((min(first, last), max(first, last))),
+        }
+    }
+
+    /// Returns if the rule matches the given destination.
+    pub fn is_match(&self, dst: SocketAddrV4) -> bool {
+        if !self.networks.contains(*dst.ip()) {
+            return false;
+        }
+
+        match self.ports {
+            Some((first, last)) => first <= dst.port() && dst.port() <= last,
+            None => true,
+        }
+    }
+
+    /// Returns the networks of the rule.
+    pub fn networks(&self) -> &Ipv4NetworkSet {
+        &self.networks
+    }
+
+    /// Returns the inclusive port range of the rule.
+    pub fn ports(&self) -> Option<(u16, u16)> {
+        self.ports
+    }
+}
+
+/// Represents a filter deciding which destinations are allowed to be redirected.
+///
+/// The default action decides the precedence of rules. If the default action is `Allow`, a
+/// destination is allowed unless it matches a deny rule, and an allow rule overrides a deny rule.
+/// If the default action is `Deny`, a destination is denied unless it matches an allow rule, and a
+/// deny rule overrides an allow rule.
+#[derive(Clone, Debug)]
+pub struct Filter {
+    default: Action,
+    allows: Vec<Rule>,
+    denies: Vec<Rule>,
+}
+
+impl Filter {
+    /// Creates a new `Filter`.
+    pub fn new(default: Action) -> Filter {
+        Filter {
+            default,
+            allows: Vec::new(),
+            denies: Vec::new(),
+        }
+    }
+
+    /// Sets the default action of the filter.
+    pub fn set_default(&mut self, default: Action) {
+        self.default = default;
+    }
+
+    /// Appends an allow rule.
+    pub fn allow(&mut self, rule: Rule) {
+        self.allows.push(rule);
+    }
+
+    /// Appends a deny rule.
+    pub fn deny(&mut self, rule: Rule) {
+        self.denies.push(rule);
+    }
+
+    /// Removes all the rules.
+    pub fn clear(&mut self) {
+        self.allows.clear();
+        self.denies.clear();
+    }
+
+    /// Returns the default action of the filter.
+    pub fn default_action(&self) -> Action {
+        self.default
+    }
+
+    /// Returns the action of the given destination.
+    pub fn action(&self, dst: SocketAddrV4) -> Action {
+        let is_allowed = self.allows.iter().any(|rule| rule.is_match(dst));
+        let is_denied = self.denies.iter().any(|rule| rule.is_match(dst));
+
+        match self.default {
+            Action::Allow => match is_denied && !is_allowed {
+                true => Action::Deny,
+                false => Action::Allow,
+            },
+            Action::Deny => match is_allowed && !is_denied {
+                true => Action::Allow,
+                false => Action::Deny,
+            },
+        }
+    }
+
+    /// Returns if the given destination is allowed.
+    pub fn is_allowed(&self, dst: SocketAddrV4) -> bool {
+        self.action(dst) == Action::Allow
+    }
+}
+
+impl Default for Filter {
+    fn default() -> Self {
+        Filter::new(Action::Allow)
+    }
+}
+
+#[test]
+fn filter_allow_by_default() {
+    use std::net::Ipv4Addr;
+
+    let mut filter = Filter::new(Action::Allow);
+    filter.deny(Rule::new("10.0.0.0/8,172.16.0.0/12".parse().unwrap()));
+    filter.allow(Rule::new_with_ports(
+        "10.1.0.0/16".parse().unwrap(),
+        80,
+        443,
+    ));
+
+    assert!(filter.is_allowed(SocketAddrV4::new(Ipv4Addr::new(8, 8, 8, 8), 53)));
+    assert!(!filter.is_allowed(SocketAddrV4::new(Ipv4Addr::new(10, 2, 0, 1), 80)));
+    assert!(!filter.is_allowed(SocketAddrV4::new(Ipv4Addr::new(172, 16, 0, 1), 80)));
+    assert!(filter.is_allowed(SocketAddrV4::new(Ipv4Addr::new(10, 1, 0, 1), 80)));
+    assert!(filter.is_allowed(SocketAddrV4::new(Ipv4Addr::new(10, 1, 0, 1), 443)));
+    assert!(!filter.is_allowed(SocketAddrV4::new(Ipv4Addr::new(10, 1, 0, 1), 22)));
+}
+
+#[test]
+fn filter_deny_by_default() {
+    use std::net::Ipv4Addr;
+
+    let mut filter = Filter::new(Action::Deny);
+    filter.allow(Rule::new("192.168.0.0/16".parse().unwrap()));
+    filter.deny(Rule::new_with_ports(
+        "192.168.1.0/24".parse().unwrap(),
+        22,
+        22,
+    ));
+
+    assert!(!filter.is_allowed(SocketAddrV4::new(Ipv4Addr::new(8, 8, 8, 8), 53)));
+    assert!(filter.is_allowed(SocketAddrV4::new(Ipv4Addr::new(192, 168, 2, 1), 22)));
+    assert!(filter.is_allowed(SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 1), 80)));
+    assert!(!filter.is_allowed(SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 1), 22)));
+}
+
+#[test]
+fn filter_rule_ports_reversed() {
+    use std::net::Ipv4Addr;
+
+    let rule = Rule::new_with_ports("0.0.0.0/0".parse().unwrap(), 1000, 100);
+    assert_eq!(rule.ports(), Some((100, 1000)));
+    assert!(rule.is_match(SocketAddrV4::new(Ipv4Addr::new(1, 1, 1, 1), 500)));
+    assert!(!rule.is_match(SocketAddrV4::new(Ipv4Addr::new(1, 1, 1, 1), 1001)));
+}
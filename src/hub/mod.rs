@@ -0,0 +1,245 @@
+//! Support for sharing a capture among redirectors.
+
+use log::trace;
+use pnet::datalink::{DataLinkSender, NetworkInterface};
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io;
+
+use super::error;
+use super::packet::layer::Layers;
+use super::packet::Indicator;
+use super::pcap::{Receiver, Sender};
+use super::{wait_timedout, Redirector, TIMEDOUT_WAIT};
+
+/// Represents a sender shared by forwarders of a `CaptureHub`.
+struct SharedSender(Arc<Mutex<Sender>>);
+
+impl DataLinkSender for SharedSender {
+    fn build_and_send(
+        &mut self,
+        num_packets: usize,
+        packet_size: usize,
+        func: &mut dyn FnMut(&mut [u8]),
+    ) -> Option<io::Result<()>> {
+        self.0
+            .lock()
+            .unwrap()
+            .build_and_send(num_packets, packet_size, func)
+    }
+
+    fn send_to(&mut self, packet: &[u8], dst: Option<NetworkInterface>) -> Option<io::Result<()>> {
+        self.0.lock().unwrap().send_to(packet, dst)
+    }
+}
+
+/// Represents a hub sharing a capture of an interface among redirectors. Frames are read and
+/// parsed once, and dispatched to the redirector of the source, so redirectors of different
+/// sources on the same interface do not open their own captures.
+pub struct CaptureHub {
+    tx: Arc<Mutex<Sender>>,
+    rx: Receiver,
+    redirectors: Vec<Redirector>,
+    timedout_wait: Duration,
+}
+
+impl CaptureHub {
+    /// Creates a new `CaptureHub` with the channel of an interface.
+    pub fn new(tx: Sender, rx: Receiver) -> CaptureHub {
+        CaptureHub {
+            tx: Arc::new(Mutex::new(tx)),
+            rx,
+            redirectors: Vec::new(),
+            timedout_wait: Duration::from_millis(TIMEDOUT_WAIT),
+        }
+    }
+
+    /// Returns a sender of the shared channel, which should be used in creating the `Forwarder`
+    /// of each redirector.
+    pub fn sender(&self) -> Sender {
+        Box::new(SharedSender(Arc::clone(&self.tx)))
+    }
+
+    /// Adds a redirector to the hub. Frames from a source will be dispatched to the first
+    /// redirector whose source networks contain the source.
+    pub fn add_redirector(&mut self, redirector: Redirector) {
+        self.redirectors.push(redirector);
+    }
+
+    /// Sets the minimum time between 2 reads from the shared capture if no frame arrives. See
+    /// `Redirector::set_timedout_wait` for the semantics.
+    pub fn set_timedout_wait(&mut self, wait: Duration) {
+        self.timedout_wait = wait;
+    }
+
+    /// Returns the redirectors in the hub.
+    pub fn redirectors(&self) -> &[Redirector] {
+        &self.redirectors
+    }
+
+    /// Opens the shared capture for redirection. Returns when all the redirectors are shut down.
+    pub async fn open(&mut self) -> error::Result<()> {
+        self.open_monitored(None).await
+    }
+
+    /// Opens the shared capture for redirection and monitoring.
+    pub async fn open_monitored(
+        &mut self,
+        is_running: Option<Arc<AtomicBool>>,
+    ) -> error::Result<()> {
+        for redirector in self.redirectors.iter_mut() {
+            redirector.start()?;
+        }
+
+        loop {
+            // Monitor
+            if let Some(is_running) = &is_running {
+                if !is_running.load(Ordering::Relaxed) {
+                    return Ok(());
+                }
+            }
+            // Remove the redirectors which are shut down
+            let mut i = 0;
+            while i < self.redirectors.len() {
+                if self.redirectors[i].handle_timed() {
+                    trace!("remove redirector {} from hub", i);
+                    self.redirectors.remove(i);
+                } else {
+                    i += 1;
+                }
+            }
+            if self.redirectors.is_empty() {
+                return Ok(());
+            }
+            for redirector in self.redirectors.iter_mut() {
+                redirector.retry_connects().await;
+            }
+            let instant = Instant::now();
+            match self.rx.next() {
+                Ok(frame) => {
+                    if let Some(ref indicator) = Indicator::from(frame) {
+                        let src = match src(indicator) {
+                            Some(src) => src,
+                            None => continue,
+                        };
+                        if let Some(redirector) = self
+                            .redirectors
+                            .iter_mut()
+                            .find(|redirector| redirector.is_src(src))
+                        {
+                            redirector.handle_indicator(indicator, frame).await;
+                        }
+                    };
+                }
+                Err(e) => {
+                    if e.kind() == io::ErrorKind::TimedOut {
+                        wait_timedout(instant, self.timedout_wait);
+                        continue;
+                    }
+                    return Err(e.into());
+                }
+            };
+        }
+    }
+}
+
+fn src(indicator: &Indicator) -> Option<Ipv4Addr> {
+    match indicator.network() {
+        Some(Layers::Arp(arp)) => Some(arp.src()),
+        Some(Layers::Ipv4(ipv4)) => Some(ipv4.src()),
+        _ => None,
+    }
+}
+
+#[tokio::test]
+async fn capture_hub_dispatch() {
+    use super::pcap::HardwareAddr;
+    use super::testing::{Frames, Recorder, Replayer};
+    use super::{Forwarder, ProxyConfig};
+    use ipnetwork::Ipv4Network;
+    use pnet::packet::arp::{ArpHardwareTypes, ArpOperations, ArpPacket, MutableArpPacket};
+    use pnet::packet::ethernet::{EtherTypes, EthernetPacket, MutableEthernetPacket};
+    use pnet::packet::{MutablePacket, Packet};
+    use std::net::SocketAddrV4;
+
+    let arp_request = |src: Ipv4Addr, hardware_addr: HardwareAddr, dst: Ipv4Addr| {
+        let mut buffer = vec![0u8; 42];
+        let mut ethernet = MutableEthernetPacket::new(&mut buffer).unwrap();
+        ethernet.set_destination(HardwareAddr::broadcast());
+        ethernet.set_source(hardware_addr);
+        ethernet.set_ethertype(EtherTypes::Arp);
+        let mut arp = MutableArpPacket::new(ethernet.payload_mut()).unwrap();
+        arp.set_hardware_type(ArpHardwareTypes::Ethernet);
+        arp.set_protocol_type(EtherTypes::Ipv4);
+        arp.set_hw_addr_len(6);
+        arp.set_proto_addr_len(4);
+        arp.set_operation(ArpOperations::Request);
+        arp.set_sender_hw_addr(hardware_addr);
+        arp.set_sender_proto_addr(src);
+        arp.set_target_hw_addr(HardwareAddr::zero());
+        arp.set_target_proto_addr(dst);
+
+        buffer
+    };
+
+    let gw = Ipv4Addr::new(192, 168, 1, 1);
+    let srcs = [Ipv4Addr::new(192, 168, 1, 2), Ipv4Addr::new(192, 168, 1, 3)];
+    let frames = Frames::default();
+    let mut hub = CaptureHub::new(
+        Box::new(Recorder(Arc::clone(&frames))),
+        Box::new(Replayer::new(vec![
+            arp_request(srcs[1], "11:11:11:11:11:13".parse().unwrap(), gw),
+            arp_request(srcs[0], "11:11:11:11:11:12".parse().unwrap(), gw),
+        ])),
+    );
+    for (i, src) in srcs.iter().enumerate() {
+        let tx = Forwarder::new(
+            hub.sender(),
+            1500,
+            HardwareAddr::new(0, 0, 0, 0, 0, i as u8 + 1),
+            Ipv4Addr::new(192, 168, 1, 100),
+        );
+        hub.add_redirector(Redirector::new(
+            Arc::new(Mutex::new(tx)),
+            Ipv4Network::new(*src, 32).unwrap(),
+            Ipv4Addr::new(192, 168, 1, 100),
+            Some(gw),
+            ProxyConfig::new_socks(
+                SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1080),
+                false,
+                false,
+                None,
+            ),
+            None,
+        ));
+    }
+
+    let e = hub.open().await.unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+
+    // Each source is replied by its own redirector
+    let frames = frames.lock().unwrap();
+    let replies = frames
+        .iter()
+        .filter_map(|frame| {
+            let ethernet = EthernetPacket::new(frame).unwrap();
+            let arp = ArpPacket::new(ethernet.payload()).unwrap();
+            match arp.get_operation() == ArpOperations::Reply
+                && arp.get_target_proto_addr() != Ipv4Addr::BROADCAST
+                && arp.get_target_proto_addr() != gw
+            {
+                true => Some((arp.get_target_proto_addr(), arp.get_sender_hw_addr())),
+                false => None,
+            }
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        replies,
+        vec![
+            (srcs[1], HardwareAddr::new(0, 0, 0, 0, 0, 2)),
+            (srcs[0], HardwareAddr::new(0, 0, 0, 0, 0, 1)),
+        ]
+    );
+}
@@ -5,61 +5,180 @@
 use ipnetwork::Ipv4Network;
 use log::{debug, info, trace, warn};
 use lru::LruCache;
-use rand::{self, Rng};
-use stat::Traffic;
+use pnet::datalink::DataLinkReceiver;
+use stat::{Stats, Traffic};
 use std::cmp::{max, min};
 use std::collections::{HashMap, HashSet};
 use std::net::{Ipv4Addr, Shutdown, SocketAddrV4};
 use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, PoisonError, Weak};
 use std::thread;
 use std::time::Duration;
 use tokio::io;
-
+use tokio::runtime::Handle;
+use tokio::sync::mpsc;
+use tokio::task::{self, JoinHandle};
+use tokio::time::{self, Instant};
+use tracing::{debug_span, Instrument, Span};
+
+pub mod cidr;
+pub mod dns;
+pub mod error;
+pub mod filter;
+pub mod hub;
+pub mod limit;
+pub mod observer;
 pub mod packet;
 pub mod pcap;
 pub mod proxy;
+pub mod rewrite;
 pub mod stat;
 pub mod tcp;
+#[cfg(test)]
+mod testing;
 
+pub use self::error::{PcapSocksError, StateError};
 pub use self::proxy::ProxyConfig;
-use self::proxy::{DatagramWorker, ForwardDatagram, ForwardStream, StreamWorker};
+use self::proxy::{
+    ConnectionPool, DatagramWorker, ForwardDatagram, ForwardStream, SocksReply, SocksUpstream,
+    StreamWorker, UpstreamConnector,
+};
+use cidr::Ipv4NetworkSet;
+use dns::{DnsCache, DNS_PORT};
+use filter::Filter;
+use limit::TokenBucket;
+use observer::{ConnectionInfo, Event, EventReceiver, EventSender, Observer};
 use packet::layer::arp::Arp;
 use packet::layer::ethernet::Ethernet;
 use packet::layer::icmpv4::Icmpv4;
 use packet::layer::ipv4::Ipv4;
 use packet::layer::tcp::Tcp;
 use packet::layer::udp::Udp;
-use packet::layer::{Layer, LayerKinds, Layers};
-use packet::{Defraggler, Indicator};
-use pcap::Interface;
-use pcap::{HardwareAddr, Receiver, Sender};
-use tcp::{TcpRxState, TcpTxState};
+use packet::layer::{Layer, LayerKind, LayerKinds, Layers};
+use packet::{ChecksumMode, Defraggler, Indicator};
+use pcap::{Batch, BatchSender, HardwareAddr, Receiver, Sender};
+use pcap::{CaptureConfig, Interface, InterfaceError};
+use rewrite::Rewriter;
+use tcp::{IsnStrategy, TcpRxState, TcpState, TcpTxState};
+#[cfg(test)]
+use testing::{
+    dst, forwarder, frame, receive, redirector, segment, segment_with_options, sent, sizes, src,
+    src_hardware_addr, tcp_forwarder, CountingUpstream, DuplexUpstream, Frames, Recorder,
+    RejectingUpstream, Replayer,
+};
 
 /// Gets a list of available network interfaces for the current machine.
 pub fn interfaces() -> Vec<Interface> {
+    interfaces_with_loopback(false)
+}
+
+/// Gets a list of available network interfaces for the current machine, including loopback
+/// interfaces, which carry the traffic of applications on the same host bound to loopback.
+pub fn interfaces_including_loopback() -> Vec<Interface> {
+    interfaces_with_loopback(true)
+}
+
+fn interfaces_with_loopback(is_loopback_included: bool) -> Vec<Interface> {
     pcap::interfaces()
         .into_iter()
-        .filter(|inter| inter.is_up() && !inter.is_loopback())
+        .filter(|inter| inter.is_up() && (is_loopback_included || !inter.is_loopback()))
         .collect()
 }
 
-/// Gets an available network interface.
+/// Gets an available network interface. The name can be the name, the GUID, the alias like the
+/// friendly name on Windows, or the description of the interface.
 pub fn interface(name: Option<String>) -> Option<Interface> {
-    let mut inters = match name {
+    try_interface(name).ok()
+}
+
+/// Gets an available network interface like `interface`, but returns an error describing why the
+/// interface cannot be determined.
+pub fn try_interface(name: Option<String>) -> Result<Interface, InterfaceError> {
+    try_interface_with_loopback(name, false)
+}
+
+/// Gets an available network interface like `try_interface`, where loopback interfaces are also
+/// candidates if they are included.
+pub fn try_interface_with_loopback(
+    name: Option<String>,
+    is_loopback_included: bool,
+) -> Result<Interface, InterfaceError> {
+    match name {
         Some(ref name) => {
-            let mut inters = interfaces();
-            inters.retain(|inter| inter.name() == name);
+            let inters =
+                pcap::find_interfaces(interfaces_with_loopback(is_loopback_included), name);
+            if inters.is_empty() {
+                // Tell interfaces without IPv4 addresses from missing ones
+                let inters = pcap::all_interfaces()
+                    .into_iter()
+                    .filter(|inter| inter.is_up() && (is_loopback_included || !inter.is_loopback()))
+                    .collect();
+                if !pcap::find_interfaces(inters, name).is_empty() {
+                    return Err(InterfaceError::NoAddress);
+                }
+            }
 
-            inters
+            select_interface(inters)
         }
-        None => interfaces(),
-    };
+        None => select_interface(interfaces_with_loopback(is_loopback_included)),
+    }
+}
 
-    if inters.len() != 1 {
-        None
-    } else {
-        Some(inters.pop().unwrap())
+/// Gets an available network interface owning the IPv4 address.
+pub fn interface_by_ip(ip_addr: Ipv4Addr) -> Option<Interface> {
+    interface_by_network(Ipv4Network::from(ip_addr))
+}
+
+/// Gets an available network interface owning an IPv4 address in the network. Returns `None` if
+/// no or multiple interfaces own addresses in the network.
+pub fn interface_by_network(network: Ipv4Network) -> Option<Interface> {
+    match try_interface_by_network(network) {
+        Ok(inter) => Some(inter),
+        Err(InterfaceError::Ambiguous(names)) => {
+            warn!("multiple interfaces own {}: {}", network, names.join(", "));
+
+            None
+        }
+        Err(_) => {
+            warn!("no interface owns {}", network);
+
+            None
+        }
+    }
+}
+
+/// Gets an available network interface owning an IPv4 address in the network like
+/// `interface_by_network`, but returns an error describing why the interface cannot be
+/// determined.
+pub fn try_interface_by_network(network: Ipv4Network) -> Result<Interface, InterfaceError> {
+    try_interface_by_network_with_loopback(network, false)
+}
+
+/// Gets an available network interface owning an IPv4 address in the network like
+/// `try_interface_by_network`, where loopback interfaces are also candidates if they are included.
+pub fn try_interface_by_network_with_loopback(
+    network: Ipv4Network,
+    is_loopback_included: bool,
+) -> Result<Interface, InterfaceError> {
+    let mut inters = interfaces_with_loopback(is_loopback_included);
+    inters.retain(|inter| {
+        inter
+            .ip_addrs()
+            .iter()
+            .any(|ip_addr| network.contains(*ip_addr))
+    });
+
+    select_interface(inters)
+}
+
+/// Selects the only interface in the candidates.
+fn select_interface(mut inters: Vec<Interface>) -> Result<Interface, InterfaceError> {
+    match inters.len() {
+        0 => Err(InterfaceError::NotFound),
+        1 => Ok(inters.pop().unwrap()),
+        _ => Err(InterfaceError::Ambiguous(
+            inters.iter().map(|inter| inter.name().clone()).collect(),
+        )),
     }
 }
 
@@ -69,6 +188,9 @@ const MAX_U32_WINDOW_SIZE: usize = 16 * 1024 * 1024;
 /// Represents the wait time after a `TimedOut` `IoError`.
 const TIMEDOUT_WAIT: u64 = 20;
 
+/// Represents the capacity of the channel of frames read in a blocking thread.
+const CAPTURE_CHANNEL_SIZE: usize = 1024;
+
 /// Represents if the receive-side silly window syndrome avoidance, Clark's algorithm, is enabled.
 const ENABLE_RECV_SWS_AVOID: bool = true;
 /// Represents if the send-side silly window syndrome avoidance, Clark's algorithm, is enabled.
@@ -77,29 +199,86 @@ const ENABLE_SEND_SWS_AVOID: bool = true;
 /// Represents if the delayed ACK is enabled.
 const ENABLE_DELAYED_ACK: bool = true;
 
+/// Represents if the TCP PSH flag is set in the last segment of sent data.
+const ENABLE_PSH: bool = true;
+
 /// Represents if the TCP MSS option is enabled.
 const ENABLE_MSS: bool = true;
 
+/// Represents the MSS assumed by the source if no TCP MSS option is advertised.
+const DEFAULT_MSS: usize = 536;
+
 /// Represents the minimum frame size.
 /// Because all traffic is in Ethernet, and the 802.3 specifies the minimum is 64 Bytes.
 /// Exclude the 4 bytes used in FCS, the minimum frame size in pcap2socks is 60 Bytes.
 const MINIMUM_FRAME_SIZE: usize = 60;
 
-/// Represents a channel forward traffic to the source in pcap.
+/// Represents the max limit of UDP token buckets for rate limiting.
+const MAX_UDP_BUCKET: usize = 1024;
+
+/// Represents the max limit of addresses in the DNS cache.
+const MAX_DNS_CACHE: usize = 4096;
+
+/// Represents the max limit of pairs of addresses with the mirrored DSCP.
+const MAX_MIRRORED_DSCP: usize = 4096;
+
+/// Represents the max limit of flows recorded in a dry run.
+const MAX_DRY_RUN_FLOWS: usize = 4096;
+
+/// Represents the count of retransmissions of full-sized segments without progress before a
+/// possible MTU black hole is reported.
+const BLACK_HOLE_RETRANS: usize = 2;
+
+/// Represents the MTU which a source is clamped to once a possible MTU black hole is detected.
+const BLACK_HOLE_MTU: usize = 576;
+
+/// Represents the minimum MTU, below which the next-hop MTU in an ICMPv4 fragmentation required
+/// is ignored.
+const MIN_MTU: usize = 68;
+
+/// Represents a channel forward traffic to the source in pcap. The forwarder is shared by the
+/// redirector, the workers and the ticker in a `Mutex`, which is only locked in short synchronous
+/// sections and never held across an `.await`.
 pub struct Forwarder {
     tx: Sender,
     src_mtu_map: HashMap<Ipv4Addr, usize>,
     local_mtu: usize,
     src_hardware_addr_map: HashMap<Ipv4Addr, HardwareAddr>,
+    src_vlan_map: HashMap<HardwareAddr, u16>,
+    vlan: Option<u16>,
     local_hardware_addr: HardwareAddr,
     local_ip_addr: Ipv4Addr,
     ipv4_identification_map: HashMap<(Ipv4Addr, Ipv4Addr), u16>,
+    dscp: u8,
+    dscp_map: LruCache<(Ipv4Addr, Ipv4Addr), u8>,
+    ttl: Option<u8>,
     states: HashMap<(SocketAddrV4, SocketAddrV4), TcpTxState>,
+    rate_limit: Option<usize>,
+    rate_limit_map: HashMap<(SocketAddrV4, SocketAddrV4), Option<usize>>,
+    tcp_buckets: HashMap<(SocketAddrV4, SocketAddrV4), TokenBucket>,
+    /// Represents the count of segments sent and retransmitted to the source of TCP connections.
+    segment_counts: HashMap<(SocketAddrV4, SocketAddrV4), (usize, usize)>,
+    /// Represents the SACK blocks most recently received from the source of TCP connections.
+    src_sacks: HashMap<(SocketAddrV4, SocketAddrV4), Vec<(u32, u32)>>,
+    udp_buckets: LruCache<(SocketAddrV4, SocketAddrV4), TokenBucket>,
+    dns_cache: Option<DnsCache>,
+    /// Represents the scratch buffer for serializing the transport layer in fragmentation.
+    fragment_buffer: Vec<u8>,
+    /// Represents the frames of segments or fragments queued to be sent together.
+    batch: Batch,
+    is_batching: bool,
+    min_frame_size: Option<usize>,
+    md5_keys: HashMap<(SocketAddrV4, Option<u16>), Arc<[u8]>>,
+    window_overrides: HashMap<(SocketAddrV4, Option<u16>), u16>,
+    is_black_hole_clamped: bool,
+    /// Represents the sources whose MTU is clamped because of possible MTU black holes.
+    black_holes: HashSet<Ipv4Addr>,
+    stats: Stats,
+    events: Option<EventSender>,
     traffic_size: Option<Arc<AtomicUsize>>,
     traffic_count: Option<Arc<AtomicUsize>>,
 }
 
-#[allow(clippy::unnecessary_lazy_evaluations)]
 impl Forwarder {
     /// Creates a new `Forwarder`.
     pub fn new(
@@ -126,21 +305,48 @@ impl Forwarder {
             src_mtu_map: HashMap::new(),
             local_mtu: mtu,
             src_hardware_addr_map: HashMap::new(),
+            src_vlan_map: HashMap::new(),
+            vlan: None,
             local_hardware_addr,
             local_ip_addr,
             ipv4_identification_map: HashMap::new(),
+            dscp: 0,
+            dscp_map: LruCache::new(MAX_MIRRORED_DSCP),
+            ttl: None,
             states: HashMap::new(),
+            rate_limit: None,
+            rate_limit_map: HashMap::new(),
+            tcp_buckets: HashMap::new(),
+            segment_counts: HashMap::new(),
+            src_sacks: HashMap::new(),
+            udp_buckets: LruCache::new(MAX_UDP_BUCKET),
+            dns_cache: None,
+            fragment_buffer: Vec::new(),
+            batch: Batch::new(),
+            is_batching: false,
+            min_frame_size: Some(MINIMUM_FRAME_SIZE),
+            md5_keys: HashMap::new(),
+            window_overrides: HashMap::new(),
+            is_black_hole_clamped: false,
+            black_holes: HashSet::new(),
+            stats: Stats::new(),
+            events: None,
             traffic_size: size,
             traffic_count: count,
         }
     }
 
-    /// Sets the source MTU.
+    /// Sets the source MTU. The MTU of a source clamped because of a possible MTU black hole will
+    /// not be raised above `BLACK_HOLE_MTU`.
     pub fn set_src_mtu(&mut self, src_ip_addr: Ipv4Addr, mtu: usize) -> bool {
         let prev_mtu = *self
             .src_mtu_map
             .get(&src_ip_addr)
             .unwrap_or(&self.local_mtu);
+        let mtu = match self.black_holes.contains(&src_ip_addr) {
+            true => min(mtu, BLACK_HOLE_MTU),
+            false => mtu,
+        };
 
         self.src_mtu_map
             .insert(src_ip_addr, min(self.local_mtu, mtu));
@@ -164,17 +370,189 @@ impl Forwarder {
         );
     }
 
+    /// Sets the DSCP of the sent IPv4 packets. The DSCP should be less than 64.
+    pub fn set_dscp(&mut self, dscp: u8) -> io::Result<()> {
+        if dscp >= 64 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("DSCP should be less than 64, but got {}", dscp),
+            ));
+        }
+        self.dscp = dscp;
+        trace!("set DSCP to {}", dscp);
+
+        Ok(())
+    }
+
+    /// Sets the DSCP of the IPv4 packets sent from the destination to the source, which overrides
+    /// the default DSCP. This is used in mirroring the DSCP set by the source. The least recently
+    /// set pair of addresses will be dropped if the limit is reached.
+    pub fn set_mirrored_dscp(&mut self, dst_ip_addr: Ipv4Addr, src_ip_addr: Ipv4Addr, dscp: u8) {
+        self.dscp_map.put((src_ip_addr, dst_ip_addr), dscp);
+    }
+
+    /// Sets the TTL of the sent IPv4 packets.
+    pub fn set_ttl(&mut self, ttl: u8) {
+        self.ttl = Some(ttl);
+        trace!("set TTL to {}", ttl);
+    }
+
+    /// Sets the VLAN identifier of the source hardware address. Frames to the source will carry
+    /// an 802.1Q VLAN tag if the VLAN identifier is set.
+    pub fn set_src_vlan(&mut self, hardware_addr: HardwareAddr, vlan: Option<u16>) {
+        match vlan {
+            Some(vlan) => {
+                self.src_vlan_map.insert(hardware_addr, vlan);
+            }
+            None => {
+                self.src_vlan_map.remove(&hardware_addr);
+            }
+        };
+        trace!("set VLAN of {} to {:?}", hardware_addr, vlan);
+    }
+
+    /// Sets the default VLAN identifier of frames to sources whose VLAN is unknown, including
+    /// broadcasts.
+    pub fn set_vlan(&mut self, vlan: Option<u16>) {
+        self.vlan = vlan;
+        trace!("set default VLAN to {:?}", vlan);
+    }
+
+    /// Sets if the MTU of a source is clamped to `BLACK_HOLE_MTU` once a possible MTU black hole is
+    /// detected, which is a TCP connection making no progress after retransmissions of full-sized
+    /// segments while smaller segments like the handshake get through.
+    pub fn set_black_hole_clamping(&mut self, is_black_hole_clamped: bool) {
+        self.is_black_hole_clamped = is_black_hole_clamped;
+        trace!("set MTU black hole clamping to {}", is_black_hole_clamped);
+    }
+
+    /// Sets the local hardware address presented to sources, and announces it with a gratuitous
+    /// ARP if it changes.
+    pub fn set_local_hardware_addr(&mut self, hardware_addr: HardwareAddr) -> error::Result<()> {
+        if self.local_hardware_addr == hardware_addr {
+            return Ok(());
+        }
+        self.local_hardware_addr = hardware_addr;
+        trace!("set local hardware address to {}", hardware_addr);
+
+        Ok(self.send_gratuitous_arp()?)
+    }
+
+    /// Sets the send half of the interface, like the interface is reopened.
+    pub fn set_sender(&mut self, tx: Sender) {
+        self.tx = tx;
+        trace!("set sender");
+    }
+
     /// Sets the local IP address.
     pub fn set_local_ip_addr(&mut self, ip_addr: Ipv4Addr) {
         self.local_ip_addr = ip_addr;
         trace!("set local IP address to {}", ip_addr);
     }
 
-    fn increase_ipv4_identification(&mut self, dst_ip_addr: Ipv4Addr, src_ip_addr: Ipv4Addr) {
+    /// Sets the minimum size of sent frames. Frames smaller than the size will be padded with
+    /// zeros, which is required in 802.3 but unnecessary on some virtual interfaces. `None`
+    /// represents no padding.
+    pub fn set_min_frame_size(&mut self, min_frame_size: Option<usize>) {
+        self.min_frame_size = min_frame_size;
+        trace!("set minimum frame size to {:?}", min_frame_size);
+    }
+
+    /// Sets the key of the TCP MD5 signature (RFC 2385) of connections from the source port to the
+    /// destination. `None` source port represents all the source ports, and `None` key removes
+    /// the key.
+    pub fn set_tcp_md5(&mut self, dst: SocketAddrV4, src_port: Option<u16>, key: Option<Vec<u8>>) {
+        match key {
+            Some(key) => {
+                self.md5_keys.insert((dst, src_port), Arc::from(key));
+                trace!("set TCP MD5 key of {:?} -> {}", src_port, dst);
+            }
+            None => {
+                self.md5_keys.remove(&(dst, src_port));
+                trace!("remove TCP MD5 key of {:?} -> {}", src_port, dst);
+            }
+        }
+    }
+
+    /// Returns the key of the TCP MD5 signature of the connection.
+    pub fn get_tcp_md5(&self, dst: SocketAddrV4, src: SocketAddrV4) -> Option<&[u8]> {
+        self.get_tcp_md5_key(dst, src).map(|key| key.as_ref())
+    }
+
+    fn get_tcp_md5_key(&self, dst: SocketAddrV4, src: SocketAddrV4) -> Option<&Arc<[u8]>> {
+        self.md5_keys
+            .get(&(dst, Some(src.port())))
+            .or_else(|| self.md5_keys.get(&(dst, None)))
+    }
+
+    /// Sets the override of the TCP receive window advertised to connections from the source port
+    /// to the destination, in the unit of the window field. The advertised window will not exceed
+    /// the override regardless of the buffer, so `Some(0)` stops the sources from sending, and
+    /// `None` removes the override and resumes the window computed from the buffer. `None` source
+    /// port represents all the source ports. A window update is sent to the connections whose
+    /// window is reopened.
+    pub fn set_tcp_window_override(
+        &mut self,
+        dst: SocketAddrV4,
+        src_port: Option<u16>,
+        window: Option<u16>,
+    ) -> io::Result<()> {
+        let keys = self
+            .states
+            .keys()
+            .filter(|(src, state_dst)| {
+                *state_dst == dst && (src_port.is_none() || src_port == Some(src.port()))
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        let prev_windows = keys
+            .iter()
+            .map(|&(src, dst)| self.get_tcp_window(dst, src))
+            .collect::<Vec<_>>();
+
+        match window {
+            Some(window) => {
+                self.window_overrides.insert((dst, src_port), window);
+                trace!(
+                    "set TCP window override of {:?} -> {} to {}",
+                    src_port,
+                    dst,
+                    window
+                );
+            }
+            None => {
+                self.window_overrides.remove(&(dst, src_port));
+                trace!("remove TCP window override of {:?} -> {}", src_port, dst);
+            }
+        }
+
+        // Send a window update if the window is reopened
+        for (&(src, dst), prev_window) in keys.iter().zip(prev_windows) {
+            if prev_window == 0 && self.get_tcp_window(dst, src) > 0 {
+                trace!("update TCP window of {} -> {}", dst, src);
+
+                self.send_tcp_ack_0(dst, src)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the override of the TCP receive window of the connection.
+    pub fn get_tcp_window_override(&self, dst: SocketAddrV4, src: SocketAddrV4) -> Option<u16> {
+        self.window_overrides
+            .get(&(dst, Some(src.port())))
+            .or_else(|| self.window_overrides.get(&(dst, None)))
+            .copied()
+    }
+
+    /// Reserves an IPv4 identification for a datagram, which is shared by all of its fragments.
+    fn reserve_ipv4_identification(&mut self, dst_ip_addr: Ipv4Addr, src_ip_addr: Ipv4Addr) -> u16 {
         let entry = self
             .ipv4_identification_map
             .entry((src_ip_addr, dst_ip_addr))
             .or_insert(0);
+        let identification = *entry;
         *entry = entry.checked_add(1).unwrap_or(0);
         trace!(
             "increase IPv4 identification of {} -> {} to {}",
@@ -182,6 +560,18 @@ impl Forwarder {
             src_ip_addr,
             entry
         );
+
+        identification
+    }
+
+    /// Sets if DNS responses should be snooped. The names in the responses will be used in
+    /// connecting to the destinations through the proxy.
+    pub fn set_dns_snooping(&mut self, is_enabled: bool) {
+        if !is_enabled {
+            self.dns_cache = None;
+        } else if self.dns_cache.is_none() {
+            self.dns_cache = Some(DnsCache::new(MAX_DNS_CACHE));
+        }
     }
 
     /// Sets the state of a TCP connection.
@@ -191,11 +581,38 @@ impl Forwarder {
         self.states.insert(key, state);
     }
 
+    /// Sets the default rate limit in bytes per second of all connections. `None` represents no
+    /// limit.
+    pub fn set_default_rate_limit(&mut self, rate: Option<usize>) {
+        self.rate_limit = rate;
+        trace!("set default rate limit to {:?}", rate);
+    }
+
+    /// Sets the rate limit in bytes per second of a connection, which overrides the default rate
+    /// limit. `None` represents no limit.
+    pub fn set_rate_limit(&mut self, dst: SocketAddrV4, src: SocketAddrV4, rate: Option<usize>) {
+        let key = (src, dst);
+
+        self.rate_limit_map.insert(key, rate);
+        trace!("set rate limit of {} -> {} to {:?}", dst, src, rate);
+    }
+
+    /// Removes the rate limit of a connection, which makes the connection follow the default rate
+    /// limit.
+    pub fn remove_rate_limit(&mut self, dst: SocketAddrV4, src: SocketAddrV4) {
+        let key = (src, dst);
+
+        self.rate_limit_map.remove(&key);
+    }
+
     /// Removes all information related to a TCP connection.
     pub fn clean_up(&mut self, dst: SocketAddrV4, src: SocketAddrV4) {
         let key = (src, dst);
 
         self.states.remove(&key);
+        self.tcp_buckets.remove(&key);
+        self.segment_counts.remove(&key);
+        self.src_sacks.remove(&key);
     }
 
     /// Returns the source MTU.
@@ -206,6 +623,21 @@ impl Forwarder {
             .unwrap_or(&self.local_mtu)
     }
 
+    fn get_dscp(&self, dst_ip_addr: Ipv4Addr, src_ip_addr: Ipv4Addr) -> u8 {
+        *self
+            .dscp_map
+            .peek(&(src_ip_addr, dst_ip_addr))
+            .unwrap_or(&self.dscp)
+    }
+
+    /// Returns the name of the address snooped from DNS responses.
+    pub fn get_dns_name(&mut self, ip_addr: Ipv4Addr) -> Option<String> {
+        match self.dns_cache {
+            Some(ref mut cache) => cache.get(ip_addr),
+            None => None,
+        }
+    }
+
     /// Returns the state of a TCP connection.
     pub fn get_state(&self, dst: SocketAddrV4, src: SocketAddrV4) -> Option<&TcpTxState> {
         let key = (src, dst);
@@ -213,6 +645,68 @@ impl Forwarder {
         self.states.get(&key)
     }
 
+    /// Sets the SACK blocks most recently received from the source of a TCP connection, which
+    /// are excluded in retransmitting timed out data. Empty SACK blocks clear the previous ones.
+    pub fn set_src_sacks(&mut self, dst: SocketAddrV4, src: SocketAddrV4, sacks: Vec<(u32, u32)>) {
+        let key = (src, dst);
+
+        if sacks.is_empty() {
+            self.src_sacks.remove(&key);
+        } else {
+            self.src_sacks.insert(key, sacks);
+        }
+
+        let sacked = self.get_src_sacked(dst, src);
+        if let Some(state) = self.get_state_mut(dst, src) {
+            state.set_sacked(sacked);
+        }
+    }
+
+    /// Returns the SACK blocks most recently received from the source of a TCP connection.
+    pub fn get_src_sacks(&self, dst: SocketAddrV4, src: SocketAddrV4) -> &[(u32, u32)] {
+        let key = (src, dst);
+
+        self.src_sacks
+            .get(&key)
+            .map_or(&[], |sacks| sacks.as_slice())
+    }
+
+    fn get_src_sacked(&self, dst: SocketAddrV4, src: SocketAddrV4) -> usize {
+        match self.get_state(dst, src) {
+            Some(state) => {
+                let sequence = state.cache().sequence();
+                let recv_next = state.cache().recv_next();
+                let sacks = self.get_src_sacks(dst, src);
+                let unsacked = disjoint_u32_ranges((sequence, recv_next), sacks)
+                    .iter()
+                    .map(|range| range.1.wrapping_sub(range.0) as usize)
+                    .sum::<usize>();
+
+                state.cache().len().saturating_sub(unsacked)
+            }
+            None => 0,
+        }
+    }
+
+    /// Returns the count of data and FIN segments sent to the source of a TCP connection, and
+    /// the count of those retransmitted.
+    pub fn get_segment_counts(&self, dst: SocketAddrV4, src: SocketAddrV4) -> (usize, usize) {
+        let key = (src, dst);
+
+        *self.segment_counts.get(&key).unwrap_or(&(0, 0))
+    }
+
+    fn increase_segment_counts(&mut self, dst: SocketAddrV4, src: SocketAddrV4, is_retrans: bool) {
+        let key = (src, dst);
+
+        let counts = self.segment_counts.entry(key).or_insert((0, 0));
+        counts.0 += 1;
+        if is_retrans {
+            counts.1 += 1;
+        }
+        self.stats.increase_segments(is_retrans);
+    }
+
     /// Returns the mutable state of a TCP connection.
     pub fn get_state_mut(
         &mut self,
@@ -228,23 +722,91 @@ impl Forwarder {
         let key = (src, dst);
 
         let state = self.states.get(&key).unwrap();
+        let window = match self.get_tcp_window_override(dst, src) {
+            Some(window_override) => min(state.available_window(), window_override),
+            None => state.available_window(),
+        };
 
-        // Avoid SWS
+        // Avoid SWS, which is compared in bytes since the window is scaled by the window scale
+        // advertised to the source
         if ENABLE_RECV_SWS_AVOID {
-            let thresh = min(state.half_max_window() as usize, self.local_mtu);
+            let wscale = state.src_wscale().unwrap_or(0);
+            let mss = state.recv_mss().map_or(DEFAULT_MSS, |mss| mss as usize);
+            let thresh = min((state.half_max_window() as usize) << wscale, mss);
 
-            if (state.window() as usize) < thresh {
+            if ((window as usize) << wscale) < thresh {
                 0
             } else {
-                state.window()
+                window
             }
         } else {
-            state.window()
+            window
+        }
+    }
+
+    /// Returns the rate limit in bytes per second of a connection.
+    pub fn get_rate_limit(&self, dst: SocketAddrV4, src: SocketAddrV4) -> Option<usize> {
+        let key = (src, dst);
+
+        match self.rate_limit_map.get(&key) {
+            Some(rate) => *rate,
+            None => self.rate_limit,
+        }
+    }
+
+    fn get_tcp_rate_remaining(&mut self, dst: SocketAddrV4, src: SocketAddrV4) -> usize {
+        let key = (src, dst);
+
+        let rate = match self.get_rate_limit(dst, src) {
+            Some(rate) => rate,
+            None => return usize::MAX,
+        };
+        // Allow at least a segment to be sent at once
+        let mss = self.get_src_mtu(*src.ip()) - (Ipv4::minimum_len() + Tcp::minimum_len());
+        let capacity = max(rate, mss);
+
+        let bucket = self
+            .tcp_buckets
+            .entry(key)
+            .or_insert_with(|| TokenBucket::new(rate, capacity));
+        if bucket.rate() != rate || bucket.capacity() != capacity {
+            bucket.set_rate(rate, capacity);
+        }
+
+        bucket.available()
+    }
+
+    /// Ticks all the TCP connections, which retransmits timed out data and sends data held back by
+    /// the rate limit even if the connection is silent in both directions.
+    pub fn tick_all(&mut self) {
+        let keys = self.states.keys().cloned().collect::<Vec<_>>();
+        for (src, dst) in keys {
+            if let Err(ref e) = self.tick(dst, src) {
+                if StateError::from_io_error(e) != Some(StateError::UnknownConnection) {
+                    warn!("handle timeout: {}: {} -> {}: {}", "TCP", dst, src, e);
+                }
+            }
         }
     }
 
+    /// Returns the total size of the caches and the queues of all the TCP connections.
+    pub fn get_total_cache_size(&self) -> usize {
+        self.states
+            .values()
+            .map(|state| state.cache().len() + state.queue().len())
+            .sum()
+    }
+
+    /// Returns the total capacity of the caches of all the TCP connections. Queues are not
+    /// included since they are not bounded.
+    pub fn get_total_cache_capacity(&self) -> usize {
+        self.states.values().fold(0, |capacity, state| {
+            capacity.saturating_add(state.cache().capacity())
+        })
+    }
+
     /// Returns the size of the cache and the queue of a TCP connection.
-    pub fn get_cache_size(&mut self, dst: SocketAddrV4, src: SocketAddrV4) -> usize {
+    pub fn get_cache_size(&self, dst: SocketAddrV4, src: SocketAddrV4) -> usize {
         let key = (src, dst);
 
         let state = self.states.get(&key).unwrap();
@@ -254,10 +816,20 @@ impl Forwarder {
 
     /// Sends an ARP reply packet.
     pub fn send_arp_reply(&mut self, src_ip_addr: Ipv4Addr) -> io::Result<()> {
+        self.send_proxy_arp_reply(self.local_ip_addr, src_ip_addr)
+    }
+
+    /// Sends an ARP reply packet claiming the destination IP address as owned by the local
+    /// hardware address.
+    pub fn send_proxy_arp_reply(
+        &mut self,
+        dst_ip_addr: Ipv4Addr,
+        src_ip_addr: Ipv4Addr,
+    ) -> io::Result<()> {
         // ARP
         let arp = Arp::new_reply(
             self.local_hardware_addr,
-            self.local_ip_addr,
+            dst_ip_addr,
             *self
                 .src_hardware_addr_map
                 .get(&src_ip_addr)
@@ -318,18 +890,46 @@ impl Forwarder {
         self.send_ipv4(dst_ip_addr, src_ip_addr, Layers::Icmpv4(icmpv4), None)
     }
 
-    /// Appends TCP payload to the queue.
+    /// Sends an ICMPv4 fragmentation required, and DF flag set packet carrying the next-hop MTU.
+    pub fn send_icmpv4_fragmentation_required(
+        &mut self,
+        dst_ip_addr: Ipv4Addr,
+        src_ip_addr: Ipv4Addr,
+        next_hop_mtu: u16,
+        payload: &[u8],
+    ) -> io::Result<()> {
+        // ICMPv4
+        let icmpv4 = Icmpv4::new_fragmentation_required_and_df_flag_set(next_hop_mtu, payload);
+
+        self.send_ipv4(dst_ip_addr, src_ip_addr, Layers::Icmpv4(icmpv4), None)
+    }
+
+    /// Sends an ICMPv4 time to live exceeded in transit packet.
+    pub fn send_icmpv4_time_exceeded(
+        &mut self,
+        dst_ip_addr: Ipv4Addr,
+        src_ip_addr: Ipv4Addr,
+        payload: &[u8],
+    ) -> io::Result<()> {
+        // ICMPv4
+        let icmpv4 = Icmpv4::new_time_exceeded(payload);
+
+        self.send_ipv4(dst_ip_addr, src_ip_addr, Layers::Icmpv4(icmpv4), None)
+    }
+
+    /// Appends TCP payload to the queue. Returns an error if the TCP FIN is already appended.
     pub fn queue_tcp(
         &mut self,
         dst: SocketAddrV4,
         src: SocketAddrV4,
         payload: &[u8],
-    ) -> io::Result<()> {
+    ) -> error::Result<()> {
         // Append to queue
         let state = self
             .get_state_mut(dst, src)
-            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
-        state.append_queue(payload);
+            .ok_or(StateError::UnknownConnection)?;
+        state.append_queue(payload)?;
+        self.stats.set_tx_buffered(self.get_total_cache_size());
 
         self.send_tcp(dst, src)
     }
@@ -340,46 +940,36 @@ impl Forwarder {
         dst: SocketAddrV4,
         src: SocketAddrV4,
         sacks: Option<Vec<(u32, u32)>>,
-    ) -> io::Result<()> {
+    ) -> error::Result<()> {
+        let mss = self.get_tcp_mss(dst, src);
+        let sacked = self.get_src_sacked(dst, src);
         let state = self
             .get_state_mut(dst, src)
-            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+            .ok_or(StateError::UnknownConnection)?;
         let sequence = state.cache().sequence();
         let recv_next = state.cache().recv_next();
 
-        // Congestion control
-        if let Some(cc) = &mut state.cc_mut() {
-            cc.fast_retransmission();
+        // Congestion control, which is reduced only once in a recovery
+        if !state.is_in_recovery() {
+            if let Some(cc) = &mut state.cc_mut() {
+                cc.fast_retransmission();
+            }
+            state.enter_recovery(sacked, mss);
         }
 
         // Find all disjointed ranges
-        let mut ranges = vec![(sequence, recv_next), ];
-        if let Some(sacks) = sacks {
-            for sack in sacks {
-                let mut temp_ranges = Vec::new();
-
-                for range in ranges {
-                    for temp_range in disjoint_u32_range(range, sack) {
-                        temp_ranges.push(temp_range);
-                    }
-                }
-
-                ranges = temp_ranges;
-            }
-        }
-        let ranges = ranges;
+        let ranges = disjoint_u32_ranges((sequence, recv_next), sacks.as_deref().unwrap_or(&[]));
 
         // Retransmit
+        let mut is_retransmitted = false;
         for range in &ranges {
-            let size = range
-                .1
-                .checked_sub(range.0)
-                .unwrap_or_else(|| range.1 + (u32::MAX - range.0)) as usize;
+            let size = range.1.wrapping_sub(range.0) as usize;
             let state = self
                 .get_state(dst, src)
-                .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+                .ok_or(StateError::UnknownConnection)?;
             let payload = state.cache().get(range.0, size)?;
             if !payload.is_empty() {
+                is_retransmitted = true;
                 if range.1 == recv_next && state.cache_fin().is_some() {
                     // ACK/FIN
                     trace!(
@@ -391,7 +981,7 @@ impl Forwarder {
                     );
 
                     // Send
-                    self.send_tcp_ack(dst, src, range.0, payload.as_slice(), true)?;
+                    self.send_tcp_ack(dst, src, range.0, payload.as_slice(), true, true)?;
                 } else {
                     // ACK
                     trace!(
@@ -403,7 +993,7 @@ impl Forwarder {
                     );
 
                     // Send
-                    self.send_tcp_ack(dst, src, range.0, payload.as_slice(), false)?;
+                    self.send_tcp_ack(dst, src, range.0, payload.as_slice(), false, true)?;
                 }
             }
         }
@@ -411,13 +1001,23 @@ impl Forwarder {
         // Pure FIN
         let state = self
             .get_state(dst, src)
-            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+            .ok_or(StateError::UnknownConnection)?;
         if ranges.is_empty() && state.cache_fin().is_some() {
             // FIN
             trace!("retransmit TCP FIN {} -> {}", dst, src);
 
             // Send
-            self.send_tcp_fin(dst, src)?;
+            self.send_tcp_fin(dst, src, true)?;
+            is_retransmitted = true;
+        }
+
+        // Report only if anything is retransmitted
+        if is_retransmitted {
+            self.send_event(Event::Retransmit {
+                src,
+                dst,
+                is_timedout: false,
+            });
         }
 
         Ok(())
@@ -429,13 +1029,36 @@ impl Forwarder {
         &mut self,
         dst: SocketAddrV4,
         src: SocketAddrV4,
-    ) -> io::Result<()> {
+    ) -> error::Result<()> {
+        let mss = self.get_tcp_mss(dst, src);
         let state = self
             .get_state_mut(dst, src)
-            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+            .ok_or(StateError::UnknownConnection)?;
         let next_rto = state.next_rto();
         let payload = state.cache_mut().get_timed_out_and_update(next_rto);
+
+        // MTU black hole, where the send MSS is lowered until segments get through
+        if payload.len() >= state.send_mss(mss) {
+            let timedout_retrans = state.increase_timedout_retrans();
+            if timedout_retrans >= BLACK_HOLE_RETRANS {
+                state.lower_send_mss(mss);
+            }
+            if timedout_retrans == BLACK_HOLE_RETRANS {
+                state.suspect_black_hole();
+            }
+        }
+
+        // Exclude the ranges SACKed by the source
+        let state = self
+            .get_state(dst, src)
+            .ok_or(StateError::UnknownConnection)?;
         let sequence = state.cache().sequence();
+        let end = sequence.wrapping_add(payload.len() as u32);
+        let ranges = disjoint_u32_ranges((sequence, end), self.get_src_sacks(dst, src));
+
+        let state = self
+            .get_state_mut(dst, src)
+            .ok_or(StateError::UnknownConnection)?;
         let size = state.cache().len();
 
         if size > 0 {
@@ -447,33 +1070,60 @@ impl Forwarder {
                 if let Some(cc) = &mut state.cc_mut() {
                     cc.timedout();
                 }
+                state.exit_recovery();
 
                 // If all the cache is get, the FIN should also be sent
-                if size == payload.len() && state.cache_fin().is_some() {
-                    // ACK/FIN
+                let is_fin = size == payload.len() && state.cache_fin().is_some();
+                if is_fin {
                     state.update_fin_timer();
-                    trace!(
-                        "retransmit TCP ACK/FIN ({} Bytes) and FIN {} -> {} from {} due to timeout",
-                        payload.len(),
-                        dst,
-                        src,
-                        sequence
-                    );
+                }
+                self.send_event(Event::Retransmit {
+                    src,
+                    dst,
+                    is_timedout: true,
+                });
 
-                    // Send
-                    self.send_tcp_ack(dst, src, sequence, payload.as_slice(), true)?;
-                } else {
-                    // ACK
-                    trace!(
-                        "retransmit TCP ACK ({} Bytes) {} -> {} from {} due to timeout",
-                        payload.len(),
-                        dst,
-                        src,
-                        sequence
-                    );
+                for range in &ranges {
+                    let offset = range.0.wrapping_sub(sequence) as usize;
+                    let payload = &payload[offset..offset + range.1.wrapping_sub(range.0) as usize];
+                    if payload.is_empty() {
+                        continue;
+                    }
+
+                    if is_fin && range.1 == end {
+                        // ACK/FIN
+                        trace!(
+                            "retransmit TCP ACK/FIN ({} Bytes) and FIN {} -> {} from {} due to timeout",
+                            payload.len(),
+                            dst,
+                            src,
+                            range.0
+                        );
+
+                        // Send
+                        self.send_tcp_ack(dst, src, range.0, payload, true, true)?;
+                    } else {
+                        // ACK
+                        trace!(
+                            "retransmit TCP ACK ({} Bytes) {} -> {} from {} due to timeout",
+                            payload.len(),
+                            dst,
+                            src,
+                            range.0
+                        );
+
+                        // Send
+                        self.send_tcp_ack(dst, src, range.0, payload, false, true)?;
+                    }
+                }
+
+                // Pure FIN if the end of the data is SACKed
+                if is_fin && ranges.last().map(|range| range.1) != Some(end) {
+                    // FIN
+                    trace!("retransmit TCP FIN {} -> {} due to timeout", dst, src);
 
                     // Send
-                    self.send_tcp_ack(dst, src, sequence, payload.as_slice(), false)?;
+                    self.send_tcp_fin(dst, src, true)?;
                 }
             }
         } else {
@@ -483,10 +1133,15 @@ impl Forwarder {
                     // Double RTO
                     state.double_rto();
                     state.update_fin_timer();
+                    self.send_event(Event::Retransmit {
+                        src,
+                        dst,
+                        is_timedout: true,
+                    });
                     trace!("retransmit TCP FIN {} -> {} due to timeout", dst, src);
 
                     // Send
-                    self.send_tcp_fin(dst, src)?;
+                    self.send_tcp_fin(dst, src, true)?;
                 }
             }
         }
@@ -494,7 +1149,7 @@ impl Forwarder {
         // Delayed ACK0
         let state = self
             .get_state(dst, src)
-            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+            .ok_or(StateError::UnknownConnection)?;
         if state.delayed_ack() {
             self.send_tcp_ack_0(dst, src)?;
         }
@@ -503,11 +1158,11 @@ impl Forwarder {
     }
 
     /// Sends TCP packets from the queue.
-    pub fn send_tcp(&mut self, dst: SocketAddrV4, src: SocketAddrV4) -> io::Result<()> {
+    pub fn send_tcp(&mut self, dst: SocketAddrV4, src: SocketAddrV4) -> error::Result<()> {
         // Retransmit unhandled SYN
         let state = self
             .get_state(dst, src)
-            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+            .ok_or(StateError::UnknownConnection)?;
         if state.cache_syn().is_some() {
             return self.send_tcp_ack_syn(dst, src);
         }
@@ -515,24 +1170,38 @@ impl Forwarder {
         if state.src_window() > 0 {
             // TCP sequence
             let sent_size = state.cache().len();
-            let remain_size = state.send_window().saturating_sub(sent_size);
+            let remain_size = match state.recovery_window() {
+                // Proportional rate reduction in recovery
+                Some(window) => min(state.src_window().saturating_sub(sent_size), window),
+                None => state.send_window().saturating_sub(sent_size),
+            };
             let remain_size = min(remain_size, u16::MAX as usize) as u16;
 
             let mut size = min(remain_size as usize, state.queue().len());
+            let is_cache_empty = state.cache().is_empty();
+            // Limit rate
+            size = min(size, self.get_tcp_rate_remaining(dst, src));
             // Avoid SWS
             if ENABLE_SEND_SWS_AVOID {
                 let mtu = *self.src_mtu_map.get(src.ip()).unwrap_or(&self.local_mtu);
                 let mss = mtu - (Ipv4::minimum_len() + Tcp::minimum_len());
+                let mss = self
+                    .get_state(dst, src)
+                    .map_or(mss, |state| state.send_mss(mss));
 
-                if size < mss && !state.cache().is_empty() {
+                if size < mss && !is_cache_empty {
                     size = 0;
                 }
             }
             let size = size;
             if size > 0 {
+                if let Some(bucket) = self.tcp_buckets.get_mut(&(src, dst)) {
+                    bucket.consume(size);
+                }
+
                 let state = self
                     .get_state_mut(dst, src)
-                    .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+                    .ok_or(StateError::UnknownConnection)?;
                 let payload = state.append_cache(size)?;
 
                 // If the queue is empty and a FIN is in the queue, pop it
@@ -543,16 +1212,16 @@ impl Forwarder {
                     // Send
                     let state = self
                         .get_state(dst, src)
-                        .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+                        .ok_or(StateError::UnknownConnection)?;
                     let sequence = state.sequence();
-                    self.send_tcp_ack(dst, src, sequence, &payload, true)?;
+                    self.send_tcp_ack(dst, src, sequence, &payload, true, false)?;
                 } else {
                     // ACK
                     let state = self
                         .get_state(dst, src)
-                        .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+                        .ok_or(StateError::UnknownConnection)?;
                     let sequence = state.sequence();
-                    self.send_tcp_ack(dst, src, sequence, &payload, false)?;
+                    self.send_tcp_ack(dst, src, sequence, &payload, false, false)?;
                 }
             }
         }
@@ -561,7 +1230,7 @@ impl Forwarder {
         // FIN
         let state = self
             .get_state_mut(dst, src)
-            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+            .ok_or(StateError::UnknownConnection)?;
         #[allow(clippy::collapsible_if)]
         if state.queue_fin() {
             if state.cache().is_empty() {
@@ -569,7 +1238,7 @@ impl Forwarder {
                 state.append_cache_fin();
 
                 // Send
-                self.send_tcp_fin(dst, src)?;
+                self.send_tcp_fin(dst, src, false)?;
             }
         }
 
@@ -583,27 +1252,47 @@ impl Forwarder {
         sequence: u32,
         payload: &[u8],
         is_fin: bool,
+        is_retrans: bool,
     ) -> io::Result<()> {
-        // Segmentation
-        let mss = *self.src_mtu_map.get(src.ip()).unwrap_or(&self.local_mtu)
-            - (Ipv4::minimum_len() + Tcp::minimum_len());
+        // Segments are sent in a batch
+        let is_batch = self.begin_batch();
+        let result = self.send_tcp_ack_segments(dst, src, sequence, payload, is_fin, is_retrans);
+
+        self.end_batch(is_batch, result)
+    }
+
+    fn send_tcp_ack_segments(
+        &mut self,
+        dst: SocketAddrV4,
+        src: SocketAddrV4,
+        sequence: u32,
+        payload: &[u8],
+        is_fin: bool,
+        is_retrans: bool,
+    ) -> io::Result<()> {
+        // Segmentation, where the send MSS is only probed up in sending new data
+        let mss = self.get_tcp_mss(dst, src);
+        let state = self
+            .get_state_mut(dst, src)
+            .ok_or_else(|| io::Error::from(StateError::UnknownConnection))?;
+        let mss = match is_retrans {
+            true => state.send_mss(mss),
+            false => state.probe_send_mss(mss),
+        };
         let mut i = 0;
         while mss * i < payload.len() {
             let state = self
                 .get_state(dst, src)
-                .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+                .ok_or_else(|| io::Error::from(StateError::UnknownConnection))?;
             let size = min(mss, payload.len() - i * mss);
+            let is_last = mss * (i + 1) >= payload.len();
             let payload = &payload[i * mss..i * mss + size];
-            let sequence = sequence
-                .checked_add((i * mss) as u32)
-                .unwrap_or_else(|| (i * mss) as u32 - (u32::MAX - sequence));
-            let mut recv_next = sequence
-                .checked_add(size as u32)
-                .unwrap_or_else(|| size as u32 - (u32::MAX - sequence));
+            let sequence = sequence.wrapping_add((i * mss) as u32);
+            let mut recv_next = sequence.wrapping_add(size as u32);
 
             // TCP
             let tcp;
-            if is_fin && mss * (i + 1) >= payload.len() {
+            if is_fin && is_last {
                 // ACK/FIN
                 tcp = Tcp::new_ack_fin(
                     dst.port(),
@@ -613,7 +1302,17 @@ impl Forwarder {
                     self.get_tcp_window(dst, src),
                     None,
                 );
-                recv_next = recv_next.checked_add(1).unwrap_or(0);
+                recv_next = recv_next.wrapping_add(1);
+            } else if ENABLE_PSH && is_last {
+                // ACK/PSH
+                tcp = Tcp::new_ack_psh(
+                    dst.port(),
+                    src.port(),
+                    sequence,
+                    state.acknowledgement(),
+                    self.get_tcp_window(dst, src),
+                    None,
+                );
             } else {
                 // ACK
                 tcp = Tcp::new_ack(
@@ -634,18 +1333,18 @@ impl Forwarder {
                 Layers::Tcp(tcp),
                 Some(payload),
             )?;
+            self.increase_segment_counts(dst, src, is_retrans);
 
             // Clear TCP delayed ACK
             let state = self
                 .get_state_mut(dst, src)
-                .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+                .ok_or_else(|| io::Error::from(StateError::UnknownConnection))?;
             state.clear_delayed_ack();
+            state.add_recovery_out(size);
 
             // Update TCP sequence
             let record_sequence = state.sequence();
-            let sub_sequence = recv_next
-                .checked_sub(record_sequence)
-                .unwrap_or_else(|| recv_next + (u32::MAX - record_sequence));
+            let sub_sequence = recv_next.wrapping_sub(record_sequence);
             if (sub_sequence as usize) <= MAX_U32_WINDOW_SIZE {
                 state.add_sequence(sub_sequence);
             }
@@ -656,12 +1355,45 @@ impl Forwarder {
         Ok(())
     }
 
+    fn get_tcp_mss(&self, dst: SocketAddrV4, src: SocketAddrV4) -> usize {
+        let mut mss = *self.src_mtu_map.get(src.ip()).unwrap_or(&self.local_mtu)
+            - (Ipv4::minimum_len() + Tcp::minimum_len());
+        if self.get_tcp_md5(dst, src).is_some() {
+            mss -= Tcp::md5_len();
+        }
+
+        mss
+    }
+
+    fn send_event(&self, event: Event) {
+        if let Some(events) = &self.events {
+            events.send(event);
+        }
+    }
+
+    fn report_black_hole(&mut self, dst: SocketAddrV4, src: SocketAddrV4) {
+        warn!(
+            "TCP {} -> {} makes no progress after {} retransmissions of full-sized segments while smaller ones get through, possible MTU black hole",
+            dst, src, BLACK_HOLE_RETRANS
+        );
+        self.stats.increase_black_holes();
+
+        if self.is_black_hole_clamped && self.black_holes.insert(*src.ip()) {
+            self.set_src_mtu(*src.ip(), BLACK_HOLE_MTU);
+            warn!("clamp MTU of {} to {}", src.ip(), BLACK_HOLE_MTU);
+        }
+    }
+
     /// Sends an TCP delayed ACK packet without payload.
-    pub fn send_tcp_delay_ack_0(&mut self, dst: SocketAddrV4, src: SocketAddrV4) -> io::Result<()> {
+    pub fn send_tcp_delay_ack_0(
+        &mut self,
+        dst: SocketAddrV4,
+        src: SocketAddrV4,
+    ) -> error::Result<()> {
         if ENABLE_DELAYED_ACK {
             let state = self
                 .get_state_mut(dst, src)
-                .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+                .ok_or(StateError::UnknownConnection)?;
 
             if state.delayed_ack() {
                 self.send_tcp_ack_0(dst, src)?;
@@ -676,11 +1408,11 @@ impl Forwarder {
     }
 
     /// Sends an TCP ACK packet without payload.
-    pub fn send_tcp_ack_0(&mut self, dst: SocketAddrV4, src: SocketAddrV4) -> io::Result<()> {
+    pub fn send_tcp_ack_0(&mut self, dst: SocketAddrV4, src: SocketAddrV4) -> error::Result<()> {
         // TCP
         let state = self
             .get_state(dst, src)
-            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+            .ok_or(StateError::UnknownConnection)?;
         let tcp = Tcp::new_ack(
             dst.port(),
             src.port(),
@@ -697,13 +1429,14 @@ impl Forwarder {
         // Clear TCP delayed ACK
         let state = self
             .get_state_mut(dst, src)
-            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+            .ok_or(StateError::UnknownConnection)?;
         state.clear_delayed_ack();
 
         Ok(())
     }
 
-    fn send_tcp_ack_syn(&mut self, dst: SocketAddrV4, src: SocketAddrV4) -> io::Result<()> {
+    /// Sends an TCP ACK/SYN packet.
+    pub fn send_tcp_ack_syn(&mut self, dst: SocketAddrV4, src: SocketAddrV4) -> error::Result<()> {
         let mss = match ENABLE_MSS {
             true => {
                 let mss = self.local_mtu - (Ipv4::minimum_len() + Tcp::minimum_len());
@@ -717,11 +1450,17 @@ impl Forwarder {
             }
             false => None,
         };
+        if let Some(mss) = mss {
+            let state = self
+                .get_state_mut(dst, src)
+                .ok_or(StateError::UnknownConnection)?;
+            state.set_recv_mss(mss);
+        }
 
         // TCP
         let state = self
             .get_state(dst, src)
-            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+            .ok_or(StateError::UnknownConnection)?;
         let tcp = Tcp::new_ack_syn(
             dst.port(),
             src.port(),
@@ -740,18 +1479,18 @@ impl Forwarder {
         // Clear TCP delayed ACK
         let state = self
             .get_state_mut(dst, src)
-            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+            .ok_or(StateError::UnknownConnection)?;
         state.clear_delayed_ack();
 
         Ok(())
     }
 
     /// Sends an TCP ACK/RST packet.
-    pub fn send_tcp_ack_rst(&mut self, dst: SocketAddrV4, src: SocketAddrV4) -> io::Result<()> {
+    pub fn send_tcp_ack_rst(&mut self, dst: SocketAddrV4, src: SocketAddrV4) -> error::Result<()> {
         // TCP
         let state = self
             .get_state(dst, src)
-            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+            .ok_or(StateError::UnknownConnection)?;
         let tcp = Tcp::new_ack_rst(
             dst.port(),
             src.port(),
@@ -767,13 +1506,14 @@ impl Forwarder {
         // Clear TCP delayed ACK
         let state = self
             .get_state_mut(dst, src)
-            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+            .ok_or(StateError::UnknownConnection)?;
         state.clear_delayed_ack();
 
         Ok(())
     }
 
     /// Sends an TCP ACK/RST packet of an untracked connection.
+    #[deprecated = "use send_tcp_rst or send_tcp_ack_rst_acknowledging instead"]
     pub fn send_tcp_ack_rst_untracked(
         &mut self,
         dst: SocketAddrV4,
@@ -787,7 +1527,34 @@ impl Forwarder {
         self.send_ipv4(*dst.ip(), *src.ip(), Layers::Tcp(tcp), None)
     }
 
-    /// Sends an TCP RST packet.
+    /// Sends an TCP ACK/RST packet responding a TCP SYN packet, without any state.
+    #[deprecated = "use send_tcp_ack_rst_acknowledging instead"]
+    pub fn send_tcp_ack_rst_syn_untracked(
+        &mut self,
+        dst: SocketAddrV4,
+        src: SocketAddrV4,
+        acknowledgement: u32,
+    ) -> io::Result<()> {
+        self.send_tcp_ack_rst_acknowledging(dst, src, acknowledgement)
+    }
+
+    /// Sends an TCP ACK/RST packet of an untracked connection, responding a TCP packet without
+    /// ACK. The acknowledgement should cover the responded packet.
+    pub fn send_tcp_ack_rst_acknowledging(
+        &mut self,
+        dst: SocketAddrV4,
+        src: SocketAddrV4,
+        acknowledgement: u32,
+    ) -> io::Result<()> {
+        // TCP
+        let tcp = Tcp::new_ack_rst(dst.port(), src.port(), 0, acknowledgement, 0, None);
+
+        // Send
+        self.send_ipv4(*dst.ip(), *src.ip(), Layers::Tcp(tcp), None)
+    }
+
+    /// Sends an TCP RST packet. The sequence should be the acknowledgement of the responded packet
+    /// if the connection is untracked.
     pub fn send_tcp_rst(
         &mut self,
         dst: SocketAddrV4,
@@ -801,11 +1568,16 @@ impl Forwarder {
         self.send_ipv4(*dst.ip(), *src.ip(), Layers::Tcp(tcp), None)
     }
 
-    fn send_tcp_fin(&mut self, dst: SocketAddrV4, src: SocketAddrV4) -> io::Result<()> {
+    fn send_tcp_fin(
+        &mut self,
+        dst: SocketAddrV4,
+        src: SocketAddrV4,
+        is_retrans: bool,
+    ) -> io::Result<()> {
         // TCP
         let state = self
             .get_state(dst, src)
-            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+            .ok_or_else(|| io::Error::from(StateError::UnknownConnection))?;
         let tcp = Tcp::new_fin(
             dst.port(),
             src.port(),
@@ -816,7 +1588,10 @@ impl Forwarder {
         );
 
         // Send
-        self.send_ipv4(*dst.ip(), *src.ip(), Layers::Tcp(tcp), None)
+        self.send_ipv4(*dst.ip(), *src.ip(), Layers::Tcp(tcp), None)?;
+        self.increase_segment_counts(dst, src, is_retrans);
+
+        Ok(())
     }
 
     /// Sends UDP packets.
@@ -826,6 +1601,41 @@ impl Forwarder {
         src: SocketAddrV4,
         payload: &[u8],
     ) -> io::Result<()> {
+        // Limit rate
+        if let Some(rate) = self.get_rate_limit(dst, src) {
+            let key = (src, dst);
+            // Allow at least a maximum datagram to be sent at once
+            let capacity = max(rate, u16::MAX as usize);
+
+            if self.udp_buckets.get(&key).is_none() {
+                self.udp_buckets.put(key, TokenBucket::new(rate, capacity));
+            }
+            let bucket = self.udp_buckets.get_mut(&key).unwrap();
+            if bucket.rate() != rate || bucket.capacity() != capacity {
+                bucket.set_rate(rate, capacity);
+            }
+            if !bucket.try_consume(payload.len()) {
+                trace!(
+                    "drop UDP ({} Bytes) {} -> {} due to rate limit",
+                    payload.len(),
+                    dst,
+                    src
+                );
+
+                return Ok(());
+            }
+        }
+
+        // Snoop DNS
+        if dst.port() == DNS_PORT {
+            if let Some(ref mut cache) = self.dns_cache {
+                let size = cache.snoop(payload);
+                if size > 0 {
+                    trace!("snoop {} addresses from DNS {} -> {}", size, dst, src);
+                }
+            }
+        }
+
         // UDP
         let udp = Udp::new(dst.port(), src.port());
 
@@ -844,6 +1654,15 @@ impl Forwarder {
         mut transport: Layers,
         payload: Option<&[u8]>,
     ) -> io::Result<()> {
+        // Sign TCP
+        if let Layers::Tcp(ref mut tcp) = transport {
+            let dst = SocketAddrV4::new(dst_ip_addr, tcp.src());
+            let src = SocketAddrV4::new(src_ip_addr, tcp.dst());
+            if let Some(key) = self.get_tcp_md5_key(dst, src) {
+                tcp.set_md5_key(Arc::clone(key));
+            }
+        }
+
         // Fragmentation
         let size = transport.len()
             + match payload {
@@ -855,18 +1674,15 @@ impl Forwarder {
             .get(&src_ip_addr)
             .unwrap_or(&self.local_mtu)
             - Ipv4::minimum_len();
+        let identification = self.reserve_ipv4_identification(dst_ip_addr, src_ip_addr);
         if size <= mss {
             // IPv4
-            let ipv4 = Ipv4::new(
-                *self
-                    .ipv4_identification_map
-                    .get(&(src_ip_addr, dst_ip_addr))
-                    .unwrap_or(&0),
-                transport.kind(),
-                dst_ip_addr,
-                src_ip_addr,
-            )
-            .unwrap();
+            let mut ipv4 =
+                Ipv4::new(identification, transport.kind(), dst_ip_addr, src_ip_addr).unwrap();
+            ipv4.set_dscp(self.get_dscp(dst_ip_addr, src_ip_addr))?;
+            if let Some(ttl) = self.ttl {
+                ipv4.set_ttl(ttl);
+            }
 
             // Set IPv4 layer for checksum
             match transport {
@@ -896,77 +1712,102 @@ impl Forwarder {
                 _ => {}
             }
 
-            // Payload
-            let mut buffer = vec![0u8; size];
-            match payload {
-                Some(payload) => transport.serialize_with_payload(
-                    buffer.as_mut_slice(),
-                    payload,
-                    transport.len() + payload.len(),
-                )?,
-                None => transport.serialize(buffer.as_mut_slice(), transport.len())?,
-            };
+            // Payload, the scratch buffer is taken out and will be put back after sending, and
+            // fragments are sent in a batch
+            let mut buffer = std::mem::take(&mut self.fragment_buffer);
+            buffer.resize(size, 0);
+            let is_batch = self.begin_batch();
+            let result = self.send_ipv4_fragments(
+                dst_ip_addr,
+                src_ip_addr,
+                identification,
+                &transport,
+                payload,
+                mss,
+                &mut buffer,
+            );
+            self.fragment_buffer = buffer;
+            self.end_batch(is_batch, result)?;
+        }
 
-            let mut n = 0;
-            while n < size {
-                let mut length = min(size - n, mss);
-                let mut remain = size - n - length;
+        Ok(())
+    }
 
-                // Alignment
-                if remain > 0 {
-                    length = length / 8 * 8;
-                    remain = size - n - length;
-                }
+    #[allow(clippy::too_many_arguments)]
+    fn send_ipv4_fragments(
+        &mut self,
+        dst_ip_addr: Ipv4Addr,
+        src_ip_addr: Ipv4Addr,
+        identification: u16,
+        transport: &Layers,
+        payload: Option<&[u8]>,
+        mss: usize,
+        buffer: &mut [u8],
+    ) -> io::Result<()> {
+        let size = buffer.len();
+        match payload {
+            Some(payload) => transport.serialize_with_payload(
+                buffer,
+                payload,
+                transport.len() + payload.len(),
+            )?,
+            None => transport.serialize(buffer, transport.len())?,
+        };
 
-                // Leave at least 8 Bytes for last fragment
-                if remain > 0 && remain < 8 {
-                    length -= 8;
-                }
+        let mut n = 0;
+        while n < size {
+            let mut length = min(size - n, mss);
+            let mut remain = size - n - length;
 
-                // IPv4
-                let ipv4 = if remain > 0 {
-                    Ipv4::new_more_fragment(
-                        *self
-                            .ipv4_identification_map
-                            .get(&(src_ip_addr, dst_ip_addr))
-                            .unwrap_or(&0),
-                        transport.kind(),
-                        (n / 8) as u16,
-                        dst_ip_addr,
-                        src_ip_addr,
-                    )
-                    .unwrap()
-                } else {
-                    Ipv4::new_last_fragment(
-                        *self
-                            .ipv4_identification_map
-                            .get(&(src_ip_addr, dst_ip_addr))
-                            .unwrap_or(&0),
-                        transport.kind(),
-                        (n / 8) as u16,
-                        dst_ip_addr,
-                        src_ip_addr,
-                    )
-                    .unwrap()
-                };
+            // Alignment
+            if remain > 0 {
+                length = length / 8 * 8;
+                remain = size - n - length;
+            }
 
-                // Send
-                self.send_ethernet(
-                    *self
-                        .src_hardware_addr_map
-                        .get(&src_ip_addr)
-                        .unwrap_or(&pcap::HARDWARE_ADDR_UNSPECIFIED),
-                    Layers::Ipv4(ipv4),
-                    None,
-                    Some(&buffer[n..n + length]),
-                )?;
+            // Leave at least 8 Bytes for last fragment
+            if remain > 0 && remain < 8 {
+                length -= 8;
+            }
 
-                n += length;
+            // IPv4
+            let mut ipv4 = if remain > 0 {
+                Ipv4::new_more_fragment(
+                    identification,
+                    transport.kind(),
+                    (n / 8) as u16,
+                    dst_ip_addr,
+                    src_ip_addr,
+                )
+                .unwrap()
+            } else {
+                Ipv4::new_last_fragment(
+                    identification,
+                    transport.kind(),
+                    (n / 8) as u16,
+                    dst_ip_addr,
+                    src_ip_addr,
+                )
+                .unwrap()
+            };
+            ipv4.set_dscp(self.get_dscp(dst_ip_addr, src_ip_addr))?;
+            if let Some(ttl) = self.ttl {
+                ipv4.set_ttl(ttl);
             }
-        }
 
-        // Update IPv4 identification
-        self.increase_ipv4_identification(dst_ip_addr, src_ip_addr);
+            // Send
+            self.send_ethernet(
+                *self
+                    .src_hardware_addr_map
+                    .get(&src_ip_addr)
+                    .unwrap_or(&pcap::HARDWARE_ADDR_UNSPECIFIED),
+                Layers::Ipv4(ipv4),
+                None,
+                Some(&buffer[n..n + length]),
+            )?;
+
+            n += length;
+        }
 
         Ok(())
     }
@@ -979,8 +1820,14 @@ impl Forwarder {
         payload: Option<&[u8]>,
     ) -> io::Result<()> {
         // Ethernet
-        let ethernet =
+        let mut ethernet =
             Ethernet::new(network.kind(), self.local_hardware_addr, src_hardware_addr).unwrap();
+        ethernet.set_vlan(
+            self.src_vlan_map
+                .get(&src_hardware_addr)
+                .cloned()
+                .or(self.vlan),
+        );
 
         // Indicator
         let indicator = Indicator::new(Layers::Ethernet(ethernet), Some(network), transport);
@@ -995,13 +1842,15 @@ impl Forwarder {
     fn send(&mut self, indicator: &Indicator) -> io::Result<()> {
         // Serialize and send
         let size = indicator.len();
-        let buffer_size = max(size, MINIMUM_FRAME_SIZE);
+        let buffer_size = max(size, self.min_frame_size.unwrap_or(0));
         let mut result = None;
-        self.tx.build_and_send(1, buffer_size, &mut |buffer| {
+        self.send_frame(buffer_size, &mut |buffer| {
             if let Err(e) = indicator.serialize(&mut buffer[..size]) {
                 result = Some(e);
             }
-        });
+            // Padding
+            buffer[size..].iter_mut().for_each(|b| *b = 0);
+        })?;
         match result {
             Some(e) => return Err(e),
             None => debug!("send to pcap: {} ({} Bytes)", indicator.brief(), size),
@@ -1018,20 +1867,98 @@ impl Forwarder {
         Ok(())
     }
 
-    fn send_with_payload(&mut self, indicator: &Indicator, payload: &[u8]) -> io::Result<()> {
-        // Serialize and send
-        let size = indicator.len();
-        let buffer_size = max(size + payload.len(), MINIMUM_FRAME_SIZE);
-        let mut result = None;
+    /// Sends a raw frame to the interface, padded to the minimum frame size like other frames.
+    /// The frame is only checked to be no shorter than an Ethernet header and no longer than the
+    /// MTU plus an Ethernet header with a VLAN tag. The caller is responsible for the correctness
+    /// of the frame, including the hardware addresses, the checksums and the sequence numbers of
+    /// any connection it belongs to.
+    pub fn send_raw(&mut self, frame: &[u8]) -> io::Result<()> {
+        if frame.len() < Ethernet::minimum_len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "frame too short",
+            ));
+        }
+        if frame.len() > self.local_mtu + Ethernet::maximum_len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "frame too big"));
+        }
+
+        // Send
+        let size = frame.len();
+        let buffer_size = max(size, self.min_frame_size.unwrap_or(0));
         self.tx
             .build_and_send(1, buffer_size, &mut |buffer| {
-                if let Err(e) =
-                    indicator.serialize_with_payload(&mut buffer[..size + payload.len()], payload)
-                {
-                    result = Some(e);
-                }
+                buffer[..size].copy_from_slice(frame);
+                // Padding
+                buffer[size..].iter_mut().for_each(|b| *b = 0);
             })
             .unwrap_or(Ok(()))?;
+        debug!("send to pcap: raw frame ({} Bytes)", size);
+
+        // Monitor
+        if let Some(size) = &self.traffic_size {
+            size.fetch_add(buffer_size, Ordering::Relaxed);
+        }
+        if let Some(count) = &self.traffic_count {
+            count.fetch_add(1, Ordering::Relaxed);
+        }
+
+        Ok(())
+    }
+
+    /// Sends a frame of the size built by the function, or queues it if frames are sent in a
+    /// batch.
+    fn send_frame(&mut self, size: usize, func: &mut dyn FnMut(&mut [u8])) -> io::Result<()> {
+        match self.is_batching {
+            true => {
+                self.batch.push(size, func);
+
+                Ok(())
+            }
+            false => self.tx.build_and_send(1, size, func).unwrap_or(Ok(())),
+        }
+    }
+
+    /// Starts queueing frames in a batch. Returns `false` if a batch is already started, which is
+    /// left to its starter to flush.
+    fn begin_batch(&mut self) -> bool {
+        !std::mem::replace(&mut self.is_batching, true)
+    }
+
+    /// Flushes the frames queued in the batch started by `begin_batch` in one operation. Frames
+    /// queued before an error are still sent, and the error is returned.
+    fn end_batch(&mut self, is_batch: bool, result: io::Result<()>) -> io::Result<()> {
+        if !is_batch {
+            return result;
+        }
+        self.is_batching = false;
+        if self.batch.is_empty() {
+            return result;
+        }
+
+        let flushed = self.tx.send_batch(&self.batch);
+        trace!("flush {} frames to pcap", self.batch.len());
+        self.batch.clear();
+
+        result.and(flushed)
+    }
+
+    fn send_with_payload(&mut self, indicator: &Indicator, payload: &[u8]) -> io::Result<()> {
+        // Serialize and send
+        let size = indicator.len();
+        let buffer_size = max(size + payload.len(), self.min_frame_size.unwrap_or(0));
+        let mut result = None;
+        self.send_frame(buffer_size, &mut |buffer| {
+            if let Err(e) =
+                indicator.serialize_with_payload(&mut buffer[..size + payload.len()], payload)
+            {
+                result = Some(e);
+            }
+            // Padding
+            buffer[size + payload.len()..]
+                .iter_mut()
+                .for_each(|b| *b = 0);
+        })?;
         match result {
             Some(e) => return Err(e),
             None => debug!(
@@ -1060,25 +1987,30 @@ impl ForwardStream for Forwarder {
 
         let state = self
             .get_state_mut(dst, src)
-            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+            .ok_or_else(|| io::Error::from(StateError::UnknownConnection))?;
         state.update_syn_timer();
 
         Ok(())
     }
 
     fn forward(&mut self, dst: SocketAddrV4, src: SocketAddrV4, payload: &[u8]) -> io::Result<()> {
-        let state = self
-            .get_state(dst, src)
-            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
-        if state.cache_fin().is_some() || state.queue_fin() {
-            return Err(io::Error::from(io::ErrorKind::InvalidData));
-        }
-
-        self.queue_tcp(dst, src, payload)
+        Ok(self.queue_tcp(dst, src, payload)?)
     }
 
     fn tick(&mut self, dst: SocketAddrV4, src: SocketAddrV4) -> io::Result<()> {
-        self.retransmit_tcp_timedout(dst, src)
+        self.retransmit_tcp_timedout(dst, src)?;
+
+        // Send data in the queue which is held back by the rate limit
+        if self.get_rate_limit(dst, src).is_some() {
+            let state = self
+                .get_state(dst, src)
+                .ok_or_else(|| io::Error::from(StateError::UnknownConnection))?;
+            if state.cache_syn().is_none() && !state.queue().is_empty() {
+                self.send_tcp(dst, src)?;
+            }
+        }
+
+        Ok(())
     }
 
     fn close(&mut self, dst: SocketAddrV4, src: SocketAddrV4) -> io::Result<()> {
@@ -1088,13 +2020,31 @@ impl ForwardStream for Forwarder {
         };
         state.append_queue_fin();
 
-        self.send_tcp(dst, src)
+        Ok(self.send_tcp(dst, src)?)
+    }
+
+    fn drain(&mut self, dst: SocketAddrV4, src: SocketAddrV4, size: usize) -> io::Result<()> {
+        if self.get_state(dst, src).is_none() {
+            return Err(io::Error::from(StateError::UnknownConnection));
+        }
+
+        let prev_window = self.get_tcp_window(dst, src);
+        self.get_state_mut(dst, src).unwrap().drain_pending(size);
+
+        // Send a window update if the window is reopened
+        if prev_window == 0 && self.get_tcp_window(dst, src) > 0 {
+            trace!("update TCP window of {} -> {}", dst, src);
+
+            self.send_tcp_ack_0(dst, src)?;
+        }
+
+        Ok(())
     }
 
     fn check(&self, dst: SocketAddrV4, src: SocketAddrV4) -> io::Result<usize> {
         let state = self
             .get_state(dst, src)
-            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+            .ok_or_else(|| io::Error::from(StateError::UnknownConnection))?;
         Ok(state.queue_remaining())
     }
 }
@@ -1105,20 +2055,126 @@ impl ForwardDatagram for Forwarder {
     }
 }
 
-#[allow(clippy::unnecessary_lazy_evaluations)]
+/// Spawns a task ticking all the TCP connections of the forwarder periodically. The task stops
+/// once the forwarder or the redirector is dropped.
+fn spawn_ticker(tx: Weak<Mutex<Forwarder>>, tick_interval: Duration) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = time::interval(tick_interval);
+        interval.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            match tx.upgrade() {
+                Some(tx) => tx.lock().unwrap().tick_all(),
+                None => return,
+            }
+        }
+    })
+}
+
+/// Sleeps for the remaining time of the wait after a `TimedOut` read began at the instant. Reads
+/// which block until the read timeout are not delayed further.
+fn wait_timedout(instant: std::time::Instant, wait: Duration) {
+    let elapsed = instant.elapsed();
+    if elapsed < wait {
+        thread::sleep(wait - elapsed);
+    }
+}
+
+/// Spawns a blocking task reading frames from the receive half and sending them to the channel.
+/// The task waits for the channel if it is full, which leaves frames in the buffer of the
+/// interface, unless frames are dropped and counted in the statistics instead if `is_dropping` is
+/// set. The task stops once the read fails, or the capture is stopped or the channel is closed,
+/// and returns the receive half.
+fn spawn_capture(
+    mut rx: Receiver,
+    frames: mpsc::Sender<io::Result<Vec<u8>>>,
+    is_capturing: Arc<AtomicBool>,
+    is_dropping: bool,
+    timedout_wait: Duration,
+    stats: Stats,
+) -> JoinHandle<Receiver> {
+    task::spawn_blocking(move || {
+        while is_capturing.load(Ordering::Relaxed) {
+            let instant = std::time::Instant::now();
+            match rx.next() {
+                Ok(frame) => {
+                    let frame = Ok(frame.to_vec());
+                    if is_dropping {
+                        match frames.try_send(frame) {
+                            Ok(_) => {}
+                            Err(mpsc::error::TrySendError::Full(_)) => {
+                                trace!("drop frame: capture channel full");
+                                stats.increase_dropped_frames();
+                            }
+                            Err(mpsc::error::TrySendError::Closed(_)) => break,
+                        }
+                    } else if frames.blocking_send(frame).is_err() {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    if e.kind() == io::ErrorKind::TimedOut {
+                        wait_timedout(instant, timedout_wait);
+                        continue;
+                    }
+                    let _ = frames.blocking_send(Err(e));
+
+                    break;
+                }
+            };
+        }
+
+        rx
+    })
+}
+
+/// Represents a receive half standing in for the one moved to the capture task.
+struct DetachedReceiver;
+
+impl DataLinkReceiver for DetachedReceiver {
+    fn next(&mut self) -> io::Result<&[u8]> {
+        Err(io::Error::from(io::ErrorKind::NotConnected))
+    }
+}
+
+/// Returns if the error in connecting to the proxy is transient and worth a retry.
+fn is_transient(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+    )
+}
+
+/// Returns the port of the UDP datagram worker with the least recent traffic. The least recently
+/// used port is preferred among workers with the same activity.
+fn find_idle_udp_port<F>(lru: &LruCache<u16, SocketAddrV4>, activity: F) -> Option<u16>
+where
+    F: Fn(u16) -> Option<Instant>,
+{
+    lru.iter()
+        .rev()
+        .map(|(&port, _)| port)
+        .min_by_key(|&port| activity(port))
+}
+
+fn disjoint_u32_ranges(main: (u32, u32), subs: &[(u32, u32)]) -> Vec<(u32, u32)> {
+    let mut ranges = vec![main];
+    for &sub in subs {
+        ranges = ranges
+            .into_iter()
+            .flat_map(|range| disjoint_u32_range(range, sub))
+            .collect();
+    }
+
+    ranges
+}
+
 fn disjoint_u32_range(main: (u32, u32), sub: (u32, u32)) -> Vec<(u32, u32)> {
-    let size_main = main
-        .1
-        .checked_sub(main.0)
-        .unwrap_or_else(|| main.1 + (u32::MAX - main.0)) as usize;
-    let diff_first = sub
-        .0
-        .checked_sub(main.0)
-        .unwrap_or_else(|| sub.0 + (u32::MAX - main.0)) as usize;
-    let diff_second = sub
-        .1
-        .checked_sub(main.1)
-        .unwrap_or_else(|| sub.1 + (u32::MAX - main.1)) as usize;
+    let size_main = main.1.wrapping_sub(main.0) as usize;
+    let diff_first = sub.0.wrapping_sub(main.0) as usize;
+    let diff_second = sub.1.wrapping_sub(main.1) as usize;
     let mut vector = Vec::with_capacity(2);
 
     #[allow(clippy::collapsible_if)]
@@ -1141,10 +2197,7 @@ fn disjoint_u32_range(main: (u32, u32), sub: (u32, u32)) -> Vec<(u32, u32)> {
         #[allow(clippy::collapsible_if)]
         if diff_second > MAX_U32_WINDOW_SIZE {
             // The distance between the main's left edge and the sub's right edge
-            let diff = sub
-                .1
-                .checked_sub(main.0)
-                .unwrap_or_else(|| sub.1 + (u32::MAX - main.0)) as usize;
+            let diff = sub.1.wrapping_sub(main.0) as usize;
             if diff > MAX_U32_WINDOW_SIZE {
                 // sub is in the left of the main
                 vector.push((main.0, main.1));
@@ -1160,10 +2213,30 @@ fn disjoint_u32_range(main: (u32, u32), sub: (u32, u32)) -> Vec<(u32, u32)> {
     vector
 }
 
+/// Returns the cool down time between 2 fast retransmissions, which is the fixed cool down if any,
+/// or else one smoothed RTT in the bounds.
+fn retrans_cool_down(cool_down: Option<Duration>, srtt: Option<Duration>) -> Duration {
+    match cool_down {
+        Some(cool_down) => cool_down,
+        None => srtt
+            .map(|srtt| {
+                srtt.clamp(
+                    Duration::from_millis(MIN_RETRANS_COOL_DOWN),
+                    Duration::from_millis(MAX_RETRANS_COOL_DOWN),
+                )
+            })
+            .unwrap_or_else(|| Duration::from_millis(RETRANS_COOL_DOWN)),
+    }
+}
+
 /// Represents the threshold of TCP ACK duplicates before trigger a fast retransmission.
 const DUPLICATES_THRESHOLD: usize = 3;
-/// Represents the cool down time between 2 retransmissions.
-const RETRANS_COOL_DOWN: u128 = 200;
+/// Represents the cool down time between 2 retransmissions before the RTT is measured.
+const RETRANS_COOL_DOWN: u64 = 200;
+/// Represents the minimum cool down time between 2 retransmissions.
+const MIN_RETRANS_COOL_DOWN: u64 = 10;
+/// Represents the maximum cool down time between 2 retransmissions.
+const MAX_RETRANS_COOL_DOWN: u64 = 1000;
 
 /// Represents if the TCP selective acknowledgment option is enabled.
 const ENABLE_SACK: bool = true;
@@ -1172,57 +2245,284 @@ const ENABLE_SACK: bool = true;
 const ENABLE_WSCALE: bool = true;
 /// Represents the max window scale of the receive window.
 const MAX_RECV_WSCALE: u8 = 8;
+/// Represents the max number of gaps tracked in the receive window.
+const MAX_RECV_GAPS: usize = 256;
 
 /// Represents the max limit of UDP port for binding in local.
 const MAX_UDP_PORT: usize = 256;
 
-/// Represents a channel redirect traffic to the proxy or loopback to the source in pcap.
-pub struct Redirector {
-    tx: Arc<Mutex<Forwarder>>,
-    tx_src_hardware_addr_set_ip_addr_set: HashSet<Ipv4Addr>,
-    src_ip_addr: Ipv4Network,
-    local_ip_addr: Ipv4Addr,
-    gw_ip_addr: Option<Ipv4Addr>,
-    proxy: ProxyConfig,
-    streams: HashMap<(SocketAddrV4, SocketAddrV4), StreamWorker>,
-    states: HashMap<(SocketAddrV4, SocketAddrV4), TcpRxState>,
-    datagrams: HashMap<u16, DatagramWorker>,
-    /// Represents the map mapping a source port to a local port.
-    datagram_map: HashMap<SocketAddrV4, u16>,
-    /// Represents the LRU mapping a local port to a source port.
-    udp_lru: LruCache<u16, SocketAddrV4>,
-    defrag: Defraggler,
-    traffic_size: Option<Arc<AtomicUsize>>,
-    traffic_count: Option<Arc<AtomicUsize>>,
-}
+/// Represents the timeout of UDP associations.
+const ASSOCIATION_TIMEOUT: u64 = 60000;
 
-impl Redirector {
-    /// Creates a new `Redirector`.
-    pub fn new(
-        tx: Arc<Mutex<Forwarder>>,
-        src_ip_addr: Ipv4Network,
-        local_ip_addr: Ipv4Addr,
-        gw_ip_addr: Option<Ipv4Addr>,
+/// Represents the interval between 2 sweeps of idle connections.
+const REAP_INTERVAL: u64 = 1000;
+
+/// Represents the interval between 2 ticks of all the TCP connections.
+const TICK_INTERVAL: u64 = 200;
+
+/// Represents the minimum time of a zero window before a TCP connection is reported as full.
+const WINDOW_FULL_THRESHOLD: u64 = 1000;
+
+/// Represents the grace period of draining connections in shutting down.
+const SHUTDOWN_GRACE_PERIOD: u64 = 5000;
+
+/// Represents the minimum time between 2 changes of the hardware address of a source.
+const HARDWARE_ADDR_DEBOUNCE: u64 = 1000;
+
+/// Represents the initial wait time before retrying connecting to the proxy.
+const CONNECT_BACKOFF: u64 = 100;
+
+/// Represents the initial wait time before reopening the interface.
+const REOPEN_BACKOFF: u64 = 1000;
+
+/// Represents the maximum wait time before reopening the interface.
+const MAX_REOPEN_BACKOFF: u64 = 30000;
+
+/// Represents a handle which shuts down a `Redirector` gracefully.
+#[derive(Clone, Debug)]
+pub struct ShutdownHandle {
+    is_shutdown: Arc<AtomicBool>,
+}
+
+impl ShutdownHandle {
+    /// Triggers the shutdown of the `Redirector`. The `Redirector` will stop accepting new
+    /// connections, close active connections and return from `open` after all the connections are
+    /// closed, or the grace period is reached.
+    pub fn shutdown(&self) {
+        self.is_shutdown.store(true, Ordering::Relaxed);
+    }
+
+    /// Returns if the shutdown is triggered.
+    pub fn is_shutdown(&self) -> bool {
+        self.is_shutdown.load(Ordering::Relaxed)
+    }
+}
+
+/// Represents a handle which pauses and resumes a `Redirector`.
+#[derive(Clone, Debug)]
+pub struct PauseHandle {
+    is_paused: Arc<AtomicBool>,
+}
+
+impl PauseHandle {
+    /// Pauses the redirection. See `Redirector::pause` for the semantics.
+    pub fn pause(&self) {
+        self.is_paused.store(true, Ordering::Relaxed);
+    }
+
+    /// Resumes the redirection.
+    pub fn resume(&self) {
+        self.is_paused.store(false, Ordering::Relaxed);
+    }
+
+    /// Returns if the redirection is paused.
+    pub fn is_paused(&self) -> bool {
+        self.is_paused.load(Ordering::Relaxed)
+    }
+}
+
+/// Represents a TCP connection connecting to the proxy, which keeps the SYN for a retry.
+struct TcpConnect {
+    src: SocketAddrV4,
+    dst: SocketAddrV4,
+    state: TcpRxState,
+    target: SocketAddrV4,
+    name: Option<String>,
+    payload: Vec<u8>,
+    syn: Tcp,
+    quote: Vec<u8>,
+    retries: usize,
+    deadline: Instant,
+}
+
+/// Represents a channel redirect traffic to the proxy or loopback to the source in pcap.
+pub struct Redirector {
+    /// Represents the shared forwarder. The guard must be dropped before any `.await`.
+    tx: Arc<Mutex<Forwarder>>,
+    /// Represents the map mapping a source IP address to its hardware address, VLAN identifier
+    /// and the time it was set.
+    tx_src_hardware_addr_map: HashMap<Ipv4Addr, (HardwareAddr, Option<u16>, Instant)>,
+    src_ip_addrs: Ipv4NetworkSet,
+    local_ip_addr: Ipv4Addr,
+    gw_ip_addr: Option<Ipv4Addr>,
+    proxy_arp_networks: Ipv4NetworkSet,
+    arp_interval: Option<Duration>,
+    last_arp: Instant,
+    vlan: Option<u16>,
+    is_dscp_mirrored: bool,
+    is_ttl_decremented: bool,
+    path_mtu: Option<usize>,
+    checksum_mode: ChecksumMode,
+    isn_strategy: IsnStrategy,
+    isn_epoch: Instant,
+    /// Represents the proxy configuration if the upstream is the default SOCKS upstream.
+    proxy: Option<ProxyConfig>,
+    pool: Option<Arc<ConnectionPool>>,
+    upstream: Arc<dyn UpstreamConnector>,
+    /// Represents the upstreams which TCP connections to the destinations are mirrored to.
+    tees: HashMap<SocketAddrV4, Arc<dyn UpstreamConnector>>,
+    filter: Arc<Mutex<Filter>>,
+    rewriter: Arc<Mutex<Rewriter>>,
+    streams: HashMap<(SocketAddrV4, SocketAddrV4), StreamWorker>,
+    /// Represents the channel of streams dropped before the connections are torn down, tagged
+    /// with the identifiers of the connections.
+    teardown_tx: mpsc::UnboundedSender<(usize, SocketAddrV4, SocketAddrV4)>,
+    teardown_rx: mpsc::UnboundedReceiver<(usize, SocketAddrV4, SocketAddrV4)>,
+    /// Represents the map mapping a connection to its identifier.
+    stream_ids: HashMap<(SocketAddrV4, SocketAddrV4), usize>,
+    next_stream_id: usize,
+    states: HashMap<(SocketAddrV4, SocketAddrV4), TcpRxState>,
+    /// Represents the map mapping a connection to its tracing span.
+    spans: HashMap<(SocketAddrV4, SocketAddrV4), Span>,
+    /// Represents the connections which are reported with a full window.
+    full_windows: HashSet<(SocketAddrV4, SocketAddrV4)>,
+    window_full_threshold: Duration,
+    datagrams: HashMap<u16, DatagramWorker>,
+    /// Represents the map mapping a source port to a local port.
+    datagram_map: HashMap<SocketAddrV4, u16>,
+    /// Represents the LRU mapping a local port to a source port.
+    udp_lru: LruCache<u16, SocketAddrV4>,
+    association_timeout: Option<Duration>,
+    max_streams: Option<usize>,
+    max_recv_gaps: Option<usize>,
+    /// Represents the destinations which the window scale is disabled for.
+    wscale_disabled_dsts: HashSet<SocketAddrV4>,
+    is_wscale_fallback: bool,
+    retrans_cool_down: Option<Duration>,
+    connect_retries: usize,
+    connect_backoff: Duration,
+    /// Represents the TCP connections waiting for a retry of connecting to the proxy.
+    connects: HashMap<(SocketAddrV4, SocketAddrV4), TcpConnect>,
+    idle_timeout: Option<Duration>,
+    reap_interval: Duration,
+    last_reap: Instant,
+    is_shutdown: Arc<AtomicBool>,
+    shutdown_grace_period: Duration,
+    shutdown_deadline: Option<Instant>,
+    is_paused: Arc<AtomicBool>,
+    is_paused_reset: bool,
+    is_udp_strict: bool,
+    is_auto_reopened: bool,
+    is_capture_dropping: bool,
+    timedout_wait: Duration,
+    tick_interval: Duration,
+    ticker: Option<JoinHandle<()>>,
+    defrag: Defraggler,
+    stats: Stats,
+    observer: Option<Arc<dyn Observer>>,
+    events: Option<EventSender>,
+    traffic_size: Option<Arc<AtomicUsize>>,
+    traffic_count: Option<Arc<AtomicUsize>>,
+    is_dry_run: bool,
+    /// Represents the map mapping a flow seen in the dry run to its number of packets and size.
+    dry_run_flows: LruCache<(LayerKind, SocketAddrV4, SocketAddrV4), (usize, usize)>,
+}
+
+impl Redirector {
+    /// Creates a new `Redirector`.
+    pub fn new(
+        tx: Arc<Mutex<Forwarder>>,
+        src_ip_addr: Ipv4Network,
+        local_ip_addr: Ipv4Addr,
+        gw_ip_addr: Option<Ipv4Addr>,
         proxy: ProxyConfig,
         traffic: Option<Traffic>,
+    ) -> Redirector {
+        let upstream = Arc::new(SocksUpstream::new(proxy.clone()));
+        let mut redirector = Redirector::new_with_upstream(
+            tx,
+            src_ip_addr,
+            local_ip_addr,
+            gw_ip_addr,
+            upstream,
+            traffic,
+        );
+        redirector.proxy = Some(proxy);
+
+        redirector
+    }
+
+    /// Creates a new `Redirector` connecting to the destinations through the upstream instead of
+    /// the SOCKS proxy.
+    pub fn new_with_upstream(
+        tx: Arc<Mutex<Forwarder>>,
+        src_ip_addr: Ipv4Network,
+        local_ip_addr: Ipv4Addr,
+        gw_ip_addr: Option<Ipv4Addr>,
+        upstream: Arc<dyn UpstreamConnector>,
+        traffic: Option<Traffic>,
     ) -> Redirector {
         let size = traffic.as_ref().map(|traffic| traffic.size());
         let count = traffic.as_ref().map(|traffic| traffic.count());
+        let (teardown_tx, teardown_rx) = mpsc::unbounded_channel();
+
+        // Report the statistics of the forwarder together
+        let stats = tx.lock().unwrap().stats.clone();
         let redirector = Redirector {
             tx,
-            tx_src_hardware_addr_set_ip_addr_set: HashSet::new(),
-            src_ip_addr,
+            tx_src_hardware_addr_map: HashMap::new(),
+            src_ip_addrs: Ipv4NetworkSet::from(src_ip_addr),
             local_ip_addr,
             gw_ip_addr,
-            proxy,
+            proxy_arp_networks: Ipv4NetworkSet::new(),
+            arp_interval: None,
+            last_arp: Instant::now(),
+            vlan: None,
+            is_dscp_mirrored: false,
+            is_ttl_decremented: false,
+            path_mtu: None,
+            checksum_mode: ChecksumMode::Off,
+            isn_strategy: IsnStrategy::Random,
+            isn_epoch: Instant::now(),
+            proxy: None,
+            pool: None,
+            upstream,
+            tees: HashMap::new(),
+            filter: Arc::new(Mutex::new(Filter::default())),
+            rewriter: Arc::new(Mutex::new(Rewriter::new())),
             streams: HashMap::new(),
+            teardown_tx,
+            teardown_rx,
+            stream_ids: HashMap::new(),
+            next_stream_id: 0,
             states: HashMap::new(),
+            spans: HashMap::new(),
+            full_windows: HashSet::new(),
+            window_full_threshold: Duration::from_millis(WINDOW_FULL_THRESHOLD),
             datagrams: HashMap::new(),
             datagram_map: HashMap::new(),
             udp_lru: LruCache::new(MAX_UDP_PORT),
+            association_timeout: Some(Duration::from_millis(ASSOCIATION_TIMEOUT)),
+            max_streams: None,
+            max_recv_gaps: Some(MAX_RECV_GAPS),
+            wscale_disabled_dsts: HashSet::new(),
+            is_wscale_fallback: false,
+            retrans_cool_down: None,
+            connect_retries: 0,
+            connect_backoff: Duration::from_millis(CONNECT_BACKOFF),
+            connects: HashMap::new(),
+            idle_timeout: None,
+            reap_interval: Duration::from_millis(REAP_INTERVAL),
+            last_reap: Instant::now(),
+            is_shutdown: Arc::new(AtomicBool::new(false)),
+            shutdown_grace_period: Duration::from_millis(SHUTDOWN_GRACE_PERIOD),
+            shutdown_deadline: None,
+            is_paused: Arc::new(AtomicBool::new(false)),
+            is_paused_reset: false,
+            is_udp_strict: true,
+            is_auto_reopened: false,
+            is_capture_dropping: false,
+            timedout_wait: Duration::from_millis(TIMEDOUT_WAIT),
+            tick_interval: Duration::from_millis(TICK_INTERVAL),
+            ticker: None,
             defrag: Defraggler::new(),
+            stats,
+            observer: None,
+            events: None,
             traffic_size: size,
             traffic_count: count,
+            is_dry_run: false,
+            dry_run_flows: LruCache::new(MAX_DRY_RUN_FLOWS),
         };
         if let Some(gw_ip_addr) = gw_ip_addr {
             redirector.tx.lock().unwrap().set_local_ip_addr(gw_ip_addr);
@@ -1231,687 +2531,4588 @@ impl Redirector {
         redirector
     }
 
-    /// Opens an `Interface` for redirection.
-    pub async fn open(&mut self, rx: &mut Receiver) -> io::Result<()> {
-        self.open_monitored(rx, None).await
+    /// Adds an additional source network for redirection.
+    pub fn add_src_ip_addr(&mut self, src_ip_addr: Ipv4Network) {
+        self.src_ip_addrs.insert(src_ip_addr);
     }
 
-    /// Opens an `Interface` for redirection and monitoring.
-    pub async fn open_monitored(
-        &mut self,
-        rx: &mut Receiver,
-        is_running: Option<Arc<AtomicBool>>,
-    ) -> io::Result<()> {
-        // Send gratuitous ARP
-        if self.gw_ip_addr.is_some() {
-            self.tx.lock().unwrap().send_gratuitous_arp()?;
+    /// Sets the source network for redirection, which replaces all the source networks. `None`
+    /// represents the source is unspecified, and it will be learned from the first ARP request
+    /// for the gateway, then pinned until set again. This is useful if the address of the source
+    /// is unknown ahead of time, like assigned by DHCP.
+    pub fn set_src_ip_addr(&mut self, src_ip_addr: Option<Ipv4Network>) {
+        self.src_ip_addrs.clear();
+        if let Some(src_ip_addr) = src_ip_addr {
+            self.src_ip_addrs.insert(src_ip_addr);
         }
+        trace!("set source to {:?}", src_ip_addr);
+    }
 
-        loop {
-            // Monitor
-            if let Some(is_running) = &is_running {
-                if !is_running.load(Ordering::Relaxed) {
-                    return Ok(());
-                }
-            }
-            match rx.next() {
-                Ok(frame) => {
-                    if let Some(ref indicator) = Indicator::from(frame) {
-                        if let Some(t) = indicator.network_kind() {
-                            match t {
-                                LayerKinds::Arp => {
-                                    if let Err(ref e) = self.handle_arp(indicator) {
-                                        warn!("handle {}: {}", indicator.brief(), e);
-                                    }
-                                }
-                                LayerKinds::Ipv4 => {
-                                    if let Err(ref e) = self.handle_ipv4(indicator, frame).await {
-                                        warn!("handle {}: {}", indicator.brief(), e);
-                                    }
-                                }
-                                _ => unreachable!(),
-                            }
-                        }
-                    };
-                }
-                Err(e) => {
-                    if e.kind() == io::ErrorKind::TimedOut {
-                        thread::sleep(Duration::from_millis(TIMEDOUT_WAIT));
-                        continue;
-                    }
-                    return Err(e);
-                }
-            };
-        }
+    /// Returns the source networks for redirection.
+    pub fn get_src_ip_addrs(&self) -> &[Ipv4Network] {
+        self.src_ip_addrs.networks()
     }
 
-    fn handle_arp(&mut self, indicator: &Indicator) -> io::Result<()> {
-        if let Some(gw_ip_addr) = self.gw_ip_addr {
-            if let Some(arp) = indicator.arp() {
-                let src = arp.src();
-                if src != self.local_ip_addr
-                    && self.src_ip_addr.contains(src)
-                    && arp.dst() == gw_ip_addr
-                {
-                    let src = arp.src();
-                    debug!(
-                        "receive from pcap: {} ({} Bytes)",
-                        indicator.brief(),
-                        indicator.len()
-                    );
+    /// Sets the networks of addresses which ARP requests from the sources will be replied as owned
+    /// by the local hardware address, also called proxy ARP, so the sources will send traffic to
+    /// these addresses, like the default gateway and off-subnet hosts, through the redirector. ARP
+    /// requests for a source's own address are never replied. The networks can be parsed from
+    /// CIDR strings into an `Ipv4NetworkSet`.
+    pub fn set_proxy_arp<T: Into<Ipv4NetworkSet>>(&mut self, networks: T) {
+        let networks = networks.into();
+        trace!("set proxy ARP networks to {:?}", networks.networks());
+        self.proxy_arp_networks = networks;
+    }
 
-                    // Set forwarder's hardware address
-                    self.set_tx_hardware_addr(src, arp.src_hardware_addr());
+    /// Sets the interval of announcing the publishing address with gratuitous ARP and unsolicited
+    /// ARP replies to known sources. `None` represents announcing only on startup.
+    pub fn set_arp_interval(&mut self, arp_interval: Option<Duration>) {
+        self.arp_interval = arp_interval;
+        trace!("set ARP interval to {:?}", arp_interval);
+    }
 
-                    // Send
-                    self.tx.lock().unwrap().send_arp_reply(src)?;
+    /// Sets the VLAN identifier of the sources. If the VLAN identifier is set, only frames carrying
+    /// an 802.1Q VLAN tag of the VLAN identifier will be redirected.
+    pub fn set_vlan(&mut self, vlan: Option<u16>) {
+        self.vlan = vlan;
+        self.tx.lock().unwrap().set_vlan(vlan);
+    }
 
-                    // Monitor
-                    if let Some(size) = &self.traffic_size {
-                        size.fetch_add(indicator.content_len(), Ordering::Relaxed);
-                    }
-                    if let Some(count) = &self.traffic_count {
-                        count.fetch_add(1, Ordering::Relaxed);
-                    }
-                }
+    /// Sets the key of the TCP MD5 signature (RFC 2385) of connections from the source port to the
+    /// destination. `None` source port represents all the source ports, and `None` key removes
+    /// the key. Connections signed with MD5 signatures cannot be redirected without a key, and
+    /// will be reset.
+    pub fn set_tcp_md5(&mut self, dst: SocketAddrV4, src_port: Option<u16>, key: Option<Vec<u8>>) {
+        self.tx.lock().unwrap().set_tcp_md5(dst, src_port, key);
+    }
+
+    /// Sets the override of the TCP receive window advertised to connections from the source port
+    /// to the destination. See `Forwarder::set_tcp_window_override` for the semantics.
+    pub fn set_tcp_window_override(
+        &mut self,
+        dst: SocketAddrV4,
+        src_port: Option<u16>,
+        window: Option<u16>,
+    ) -> io::Result<()> {
+        self.tx
+            .lock()
+            .unwrap()
+            .set_tcp_window_override(dst, src_port, window)
+    }
+
+    /// Sets if the DSCP set by the sources should be mirrored in the IPv4 packets sent to the
+    /// sources.
+    pub fn set_dscp_mirroring(&mut self, is_mirrored: bool) {
+        self.is_dscp_mirrored = is_mirrored;
+    }
+
+    /// Sets if the TTL of the IPv4 packets from sources to destinations should be decremented as
+    /// a router does. Packets whose TTL reaches zero will be dropped, and an ICMPv4 time exceeded
+    /// will be sent to the source, which makes traceroute work through the redirector.
+    pub fn set_ttl_decrement(&mut self, is_decremented: bool) {
+        self.is_ttl_decremented = is_decremented;
+    }
+
+    /// Sets the MTU of the path to destinations. IPv4 packets with the DF flag set exceeding the
+    /// MTU will be dropped, and an ICMPv4 fragmentation required will be sent to the source for
+    /// Path MTU Discovery. The MTU should be from 68 to 65535.
+    pub fn set_path_mtu(&mut self, path_mtu: Option<usize>) -> io::Result<()> {
+        if let Some(path_mtu) = path_mtu {
+            if path_mtu < MIN_MTU || path_mtu > u16::MAX as usize {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "path MTU should be from {} to {}, but got {}",
+                        MIN_MTU,
+                        u16::MAX,
+                        path_mtu
+                    ),
+                ));
             }
         }
+        self.path_mtu = path_mtu;
 
         Ok(())
     }
 
-    async fn handle_ipv4(&mut self, indicator: &Indicator, frame: &[u8]) -> io::Result<()> {
-        if let Some(ipv4) = indicator.ipv4() {
-            let src = ipv4.src();
-            if src != self.local_ip_addr && self.src_ip_addr.contains(src) {
-                let src = ipv4.src();
-                debug!(
-                    "receive from pcap: {} ({} + {} Bytes)",
-                    indicator.brief(),
-                    indicator.len(),
-                    indicator.content_len() - indicator.len()
-                );
-                // Set forwarder's hardware address
-                self.set_tx_hardware_addr(src, indicator.ethernet().unwrap().src());
+    /// Sets the mode of verifying checksums of packets from sources. Packets with invalid
+    /// checksums will be dropped. Checksums are not verified by default, and TCP and UDP checksums
+    /// should not be verified if the traffic is generated by the local host with checksum offload,
+    /// since they are only placeholders in the capture.
+    pub fn set_verify_checksums(&mut self, mode: ChecksumMode) {
+        self.checksum_mode = mode;
+        trace!("set checksum mode to {:?}", mode);
+    }
 
-                let frame_without_padding = &frame[..indicator.content_len()];
-                if ipv4.is_fragment() {
-                    // Fragmentation
-                    let frag = match self.defrag.add(indicator, frame_without_padding) {
-                        Some(frag) => frag,
-                        None => return Ok(()),
-                    };
-                    let (transport, payload) = frag.concatenate();
+    /// Sets the strategy choosing initial sequence numbers of TCP connections. Initial sequence
+    /// numbers are chosen randomly by default, while `IsnStrategy::new_seeded` or
+    /// `IsnStrategy::Custom` makes them deterministic in tests.
+    pub fn set_isn_strategy(&mut self, strategy: IsnStrategy) {
+        // The secret key is not logged
+        match strategy {
+            IsnStrategy::Random => trace!("set ISN strategy to random"),
+            IsnStrategy::Rfc6528 { .. } => trace!("set ISN strategy to RFC 6528"),
+            IsnStrategy::Custom(_) => trace!("set ISN strategy to custom"),
+        }
+        self.isn_strategy = strategy;
+    }
 
-                    if let Some(transport) = transport {
-                        match transport {
-                            Layers::Icmpv4(ref icmpv4) => self.handle_icmpv4(icmpv4)?,
-                            Layers::Tcp(ref tcp) => self.handle_tcp(tcp, payload).await?,
-                            Layers::Udp(ref udp) => self.handle_udp(udp, payload).await?,
-                            _ => unreachable!(),
-                        }
-                    }
-                } else {
-                    #[allow(clippy::collapsible_if)]
-                    if let Some(transport) = indicator.transport() {
-                        match transport {
-                            Layers::Icmpv4(icmpv4) => self.handle_icmpv4(icmpv4)?,
-                            Layers::Tcp(tcp) => {
-                                self.handle_tcp(tcp, &frame_without_padding[indicator.len()..])
-                                    .await?
-                            }
-                            Layers::Udp(udp) => {
-                                self.handle_udp(udp, &frame_without_padding[indicator.len()..])
-                                    .await?
-                            }
-                            _ => unreachable!(),
-                        }
-                    }
-                }
+    /// Sets if the redirector runs dry. In a dry run, frames are parsed and classified, and what
+    /// would be done is logged, but no connections are opened and no frames are sent.
+    pub fn set_dry_run(&mut self, is_dry_run: bool) {
+        self.is_dry_run = is_dry_run;
+        trace!("set dry run to {}", is_dry_run);
+    }
 
-                // Monitor
-                if let Some(size) = &self.traffic_size {
-                    size.fetch_add(indicator.content_len(), Ordering::Relaxed);
-                }
-                if let Some(count) = &self.traffic_count {
-                    count.fetch_add(1, Ordering::Relaxed);
-                }
-            }
-        }
+    /// Returns the flows seen in the dry run with their number of packets and size. The least
+    /// recently seen flow is dropped if the limit is reached.
+    pub fn dry_run_flows(&self) -> Vec<(LayerKind, SocketAddrV4, SocketAddrV4, usize, usize)> {
+        let mut flows = self
+            .dry_run_flows
+            .iter()
+            .map(|(&(kind, src, dst), &(count, size))| (kind, src, dst, count, size))
+            .collect::<Vec<_>>();
+        flows.sort_by_key(|&(_, src, dst, _, _)| (src, dst));
+
+        flows
+    }
 
-        Ok(())
+    /// Sets the filter of destinations.
+    pub fn set_filter(&mut self, filter: Filter) {
+        *self.filter.lock().unwrap() = filter;
     }
 
-    fn handle_icmpv4(&mut self, icmpv4: &Icmpv4) -> io::Result<()> {
-        if icmpv4.is_destination_port_unreachable() {
-            // Destination port unreachable
-            let kind = match icmpv4.next_level_layer_kind() {
-                Some(kind) => kind,
-                None => return Ok(()),
-            };
-            #[allow(clippy::single_match)]
-            match kind {
-                LayerKinds::Udp => {
-                    let dst = icmpv4.dst().unwrap();
-                    self.unbind_local_udp_port(dst);
-                }
-                _ => {}
+    /// Returns the filter of destinations, which can be updated at runtime.
+    pub fn get_filter(&self) -> Arc<Mutex<Filter>> {
+        Arc::clone(&self.filter)
+    }
+
+    /// Sets the rewriter of destinations.
+    pub fn set_rewriter(&mut self, rewriter: Rewriter) {
+        *self.rewriter.lock().unwrap() = rewriter;
+    }
+
+    /// Returns the rewriter of destinations, which can be updated at runtime.
+    pub fn get_rewriter(&self) -> Arc<Mutex<Rewriter>> {
+        Arc::clone(&self.rewriter)
+    }
+
+    /// Sets the max limit of concurrent TCP streams. `None` represents no limit.
+    pub fn set_max_streams(&mut self, max_streams: Option<usize>) {
+        self.max_streams = max_streams;
+        trace!("set max streams to {:?}", max_streams);
+    }
+
+    /// Sets the max number of gaps tracked in the receive window of each TCP connection. If the
+    /// gaps exceed the limit, the most out of order data will be dropped and left to be
+    /// retransmitted by the source. `None` represents no limit.
+    pub fn set_max_recv_gaps(&mut self, max_recv_gaps: Option<usize>) {
+        self.max_recv_gaps = max_recv_gaps;
+        trace!("set max receive gaps to {:?}", max_recv_gaps);
+    }
+
+    /// Disables the window scale of TCP connections to the destination. The window scale option
+    /// will be neither honored nor advertised in later connections to the destination.
+    pub fn set_disable_wscale(&mut self, dst: SocketAddrV4) {
+        self.wscale_disabled_dsts.insert(dst);
+        trace!("disable window scale of {}", dst);
+    }
+
+    /// Sets the tee of TCP connections to the destination, which the data from the sources is
+    /// mirrored to through the upstream, like a second SOCKS proxy receiving shadow traffic. The
+    /// tee is best-effort and read-only, so its responses and failures never affect the
+    /// connections.
+    pub fn set_tee(&mut self, dst: SocketAddrV4, tee: Option<Arc<dyn UpstreamConnector>>) {
+        match tee {
+            Some(tee) => {
+                self.tees.insert(dst, tee);
+                trace!("set tee of {}", dst);
             }
-        } else if icmpv4.is_fragmentation_required_and_df_flag_set() {
-            // Fragmentation required, and DF flag set
-            let mtu = icmpv4.next_hop_mtu().unwrap();
-            if self
-                .tx
-                .lock()
-                .unwrap()
-                .set_src_mtu(icmpv4.dst_ip_addr().unwrap(), mtu as usize)
-            {
-                info!("Update MTU of {} to {}", icmpv4.dst_ip_addr().unwrap(), mtu);
+            None => {
+                self.tees.remove(&dst);
+                trace!("remove tee of {}", dst);
             }
         }
+    }
 
-        Ok(())
+    /// Sets if the window scale falls back to no scaling in later connections to the destination
+    /// on detecting a source which does not scale its windows as negotiated. The window scale is
+    /// always disabled in the connection itself.
+    pub fn set_wscale_fallback(&mut self, is_wscale_fallback: bool) {
+        self.is_wscale_fallback = is_wscale_fallback;
+        trace!("set window scale fallback to {}", is_wscale_fallback);
     }
 
-    async fn handle_tcp(&mut self, tcp: &Tcp, payload: &[u8]) -> io::Result<()> {
-        if tcp.is_rst() {
-            self.handle_tcp_rst(tcp);
-        } else if tcp.is_ack() {
-            self.handle_tcp_ack(tcp, payload)?;
-        } else if tcp.is_syn() {
-            // Pure TCP SYN
-            self.handle_tcp_syn(tcp).await?;
-        } else if tcp.is_fin() {
-            // Pure TCP FIN
-            self.handle_tcp_fin(tcp, payload)?;
-        } else {
-            unreachable!();
+    /// Sets the cool down time between 2 fast retransmissions of each TCP connection. `None`
+    /// represents one smoothed RTT of the connection, which is adaptive to the link.
+    pub fn set_retrans_cool_down(&mut self, cool_down: Option<Duration>) {
+        self.retrans_cool_down = cool_down;
+        trace!("set retransmission cool down to {:?}", cool_down);
+    }
+
+    /// Sets the max limit of UDP datagram workers. If the limit is reached, workers without any
+    /// traffic beyond the association timeout will be closed first, otherwise the worker with the
+    /// least recent traffic will be reused.
+    pub fn set_max_datagrams(&mut self, max_datagrams: usize) {
+        let max_datagrams = max(max_datagrams, 1);
+        while self.udp_lru.len() > max_datagrams {
+            let (port, src) = self.udp_lru.pop_lru().unwrap();
+            self.datagrams.remove(&port);
+            self.datagram_map.remove(&src);
+
+            trace!("unbind UDP port {} = {}", port, src);
+            if let Some(observer) = &self.observer {
+                observer.on_unbind(src, port);
+            }
+            self.send_event(Event::Unbind { src, port });
         }
+        self.udp_lru.resize(max_datagrams);
+        trace!("set max datagrams to {}", max_datagrams);
+    }
 
-        Ok(())
+    /// Sets the timeout of UDP associations. Datagram workers without any traffic beyond the
+    /// timeout will be closed in the sweep, or on demand if the limit of datagram workers is
+    /// reached. `None` represents no timeout.
+    pub fn set_association_timeout(&mut self, timeout: Option<Duration>) {
+        self.association_timeout = timeout;
+        trace!("set association timeout to {:?}", timeout);
     }
 
-    fn handle_tcp_ack(&mut self, tcp: &Tcp, payload: &[u8]) -> io::Result<()> {
-        let src = SocketAddrV4::new(tcp.src_ip_addr(), tcp.src());
-        let dst = SocketAddrV4::new(tcp.dst_ip_addr(), tcp.dst());
-        let key = (src, dst);
-        let is_exist = self.streams.get(&key).is_some();
-        let is_writable = match self.streams.get(&key) {
-            Some(stream) => !stream.is_tx_closed(),
-            None => false,
-        };
+    /// Sets the maximum number of retries of connecting to the proxy on transient errors.
+    pub fn set_connect_retries(&mut self, retries: usize) {
+        self.connect_retries = retries;
+        trace!("set connect retries to {}", retries);
+    }
 
-        if is_exist {
-            // ACK
-            let state = self
-                .states
-                .get_mut(&key)
-                .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
-            if tcp.sequence() != state.recv_next() {
-                trace!(
-                    "TCP out of order of {} -> {} at {}",
-                    src,
-                    dst,
-                    tcp.sequence()
-                );
-            }
-            {
-                let mut tx_locked = self.tx.lock().unwrap();
-                let tx_state = tx_locked
-                    .get_state_mut(dst, src)
-                    .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+    /// Sets the initial wait time before retrying connecting to the proxy. The wait time will be
+    /// doubled after each retry.
+    pub fn set_connect_backoff(&mut self, backoff: Duration) {
+        self.connect_backoff = backoff;
+        trace!("set connect backoff to {:?}", backoff);
+    }
 
-                tx_state.acknowledge(tcp.acknowledgement());
-                tx_state.set_src_window((tcp.window() as usize) << state.wscale() as usize);
-            }
+    /// Sets the maximum time waiting for a frame before handling timed events, and the minimum
+    /// time between 2 reads from the interface if no frame arrives. The redirector wakes once a
+    /// frame arrives, and reads only wait for the remaining time if they return earlier than the
+    /// read timeout, like the timeout is not honored by the platform. A shorter time makes timed
+    /// events like shutting down more punctual, but burns more CPU.
+    pub fn set_timedout_wait(&mut self, wait: Duration) {
+        self.timedout_wait = wait;
+        trace!("set timed out wait to {:?}", wait);
+    }
 
-            if !payload.is_empty() {
-                // ACK
-                if is_writable {
-                    // Append to cache
-                    let cont_payload = state.append_cache(tcp.sequence(), payload)?;
+    /// Sets if captured frames are dropped instead of being waited for if the redirection falls
+    /// behind the capture. The capture waits by default, which keeps frames in the buffer of the
+    /// interface, and the kernel or the driver drops frames once the buffer is full. If frames are
+    /// dropped by the redirector, they are counted in `Stats::dropped_frames`.
+    pub fn set_capture_dropping(&mut self, is_dropping: bool) {
+        self.is_capture_dropping = is_dropping;
+        trace!("set capture dropping to {}", is_dropping);
+    }
 
-                    // SACK
-                    if state.sack_perm() {
-                        let sacks = state.cache().filled();
-                        self.tx
-                            .lock()
-                            .unwrap()
-                            .get_state_mut(dst, src)
-                            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?
-                            .set_sacks(&sacks);
-                    }
+    /// Sets if the interface will be reopened in `open_interface` once it is gone, like the adapter
+    /// is unplugged or removed. Connections are preserved while waiting for the interface.
+    pub fn set_auto_reopen(&mut self, is_auto_reopened: bool) {
+        self.is_auto_reopened = is_auto_reopened;
+        trace!("set auto reopen to {}", is_auto_reopened);
+    }
 
-                    match cont_payload {
-                        Some(payload) => {
-                            // Send
-                            let stream = self
-                                .streams
-                                .get_mut(&key)
-                                .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
-                            let size = payload.len();
-                            match stream.send(payload) {
-                                Ok(_) => {
-                                    let cache_remaining_size = (state.cache().remaining()
-                                        >> state.wscale() as usize)
-                                        as u16;
+    /// Sets the idle timeout of TCP connections. Connections idle beyond the timeout will be reset.
+    /// `None` represents no timeout.
+    pub fn set_idle_timeout(&mut self, idle_timeout: Option<Duration>) {
+        self.idle_timeout = idle_timeout;
+        trace!("set idle timeout to {:?}", idle_timeout);
+    }
 
-                                    state.add_recv_next(size as u32);
+    /// Sets the expire time of incomplete fragments. Fragments will be dropped if the group is not
+    /// completed in the time.
+    pub fn set_fragment_timeout(&mut self, timeout: Duration) {
+        self.defrag.set_expire_time(timeout);
+        trace!("set fragment timeout to {:?}", timeout);
+    }
 
-                                    let mut tx_locked = self.tx.lock().unwrap();
-                                    let tx_state = tx_locked
-                                        .get_state_mut(dst, src)
-                                        .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+    /// Sets the interval between 2 sweeps of idle connections.
+    pub fn set_reap_interval(&mut self, reap_interval: Duration) {
+        self.reap_interval = reap_interval;
+        trace!("set reap interval to {:?}", reap_interval);
+    }
 
-                                    // Update window size
-                                    tx_state.set_window(cache_remaining_size);
+    /// Sets the interval between 2 ticks of all the TCP connections. Ticks run on a timer
+    /// independent of the traffic, and retransmit timed out data of connections which are silent
+    /// in both directions. The interval takes effect the next time the redirector is opened.
+    pub fn set_tick_interval(&mut self, tick_interval: Duration) {
+        self.tick_interval = tick_interval;
+        trace!("set tick interval to {:?}", tick_interval);
+    }
 
-                                    // Update TCP acknowledgement
-                                    tx_state.add_acknowledgement(size as u32);
+    /// Sets the grace period of draining connections in shutting down. Connections which are not
+    /// closed in the grace period will be reset.
+    pub fn set_shutdown_grace_period(&mut self, grace_period: Duration) {
+        self.shutdown_grace_period = grace_period;
+        trace!("set shutdown grace period to {:?}", grace_period);
+    }
 
-                                    // Send delayed ACK0
-                                    // If there is a heavy traffic, the ACK reported may be inaccurate, which would results in retransmission
-                                    tx_locked.send_tcp_delay_ack_0(dst, src)?;
-                                }
-                                Err(e) => {
-                                    // Send ACK/RST
-                                    self.tx.lock().unwrap().send_tcp_ack_rst(dst, src)?;
+    /// Returns a handle which shuts down the redirector gracefully.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle {
+            is_shutdown: Arc::clone(&self.is_shutdown),
+        }
+    }
 
-                                    // Clean up
-                                    self.clean_up(src, dst);
+    /// Pauses the redirection. New TCP connections and UDP traffic from sources without an
+    /// association will be dropped, and ARP requests from sources without connections will not be
+    /// replied, while established connections and associations keep being serviced, including the
+    /// ACKs and retransmissions of their in-flight data. Idle connections will still be reaped in
+    /// pausing.
+    pub fn pause(&self) {
+        self.is_paused.store(true, Ordering::Relaxed);
+        trace!("pause redirection");
+    }
 
-                                    return Err(e);
-                                }
-                            }
-                        }
-                        None => {
-                            // Retransmission or unordered
-                            let cache_remaining_size =
-                                (state.cache().remaining() >> state.wscale() as usize) as u16;
+    /// Resumes the redirection.
+    pub fn resume(&self) {
+        self.is_paused.store(false, Ordering::Relaxed);
+        trace!("resume redirection");
+    }
 
-                            // Update window size
-                            let mut tx_locked = self.tx.lock().unwrap();
-                            let tx_state = tx_locked
-                                .get_state_mut(dst, src)
-                                .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+    /// Returns if the redirection is paused.
+    pub fn is_paused(&self) -> bool {
+        self.is_paused.load(Ordering::Relaxed)
+    }
 
-                            tx_state.set_window(cache_remaining_size);
+    /// Returns a handle which pauses and resumes the redirection while the redirector is open.
+    pub fn pause_handle(&self) -> PauseHandle {
+        PauseHandle {
+            is_paused: Arc::clone(&self.is_paused),
+        }
+    }
 
-                            // Send ACK0
-                            tx_locked.send_tcp_ack_0(dst, src)?;
-                        }
-                    }
-                } else {
-                    // Send ACK/RST
-                    self.tx.lock().unwrap().send_tcp_ack_rst(dst, src)?;
+    /// Sets if new TCP connections are reset instead of being dropped silently in pausing.
+    /// Dropped connections may be retried by the source and accepted after resuming.
+    pub fn set_paused_reset(&mut self, is_paused_reset: bool) {
+        self.is_paused_reset = is_paused_reset;
+        trace!("set paused reset to {}", is_paused_reset);
+    }
 
-                    // Clean up
-                    self.clean_up(src, dst);
+    /// Sets if a local UDP port only delivers datagrams from destinations the current source sent
+    /// to, so unsolicited datagrams and late replies to the prior source of a reused port are
+    /// dropped alike. It is enabled by default.
+    pub fn set_udp_strict(&mut self, is_udp_strict: bool) {
+        self.is_udp_strict = is_udp_strict;
+        for worker in self.datagrams.values_mut() {
+            worker.set_strict(is_udp_strict);
+        }
+        trace!("set UDP strict to {}", is_udp_strict);
+    }
+
+    /// Sets the size of the pool of warm connections to the proxy. `None` represents no pool. The
+    /// pool is only available with the default SOCKS upstream.
+    pub fn set_pool_size(&mut self, size: Option<usize>) {
+        let proxy = match &self.proxy {
+            Some(proxy) => proxy,
+            None => {
+                if size.is_some() {
+                    warn!("pool is not available with a custom upstream");
+                }
+
+                return;
+            }
+        };
+        self.pool = size.map(|size| {
+            let remote = match proxy {
+                ProxyConfig::Socks(remote, _) => *remote,
+            };
+            let mut pool = ConnectionPool::new(remote, size);
+            pool.set_socket(*proxy.socket());
+
+            Arc::new(pool)
+        });
+        self.upstream = Arc::new(SocksUpstream::new_with_pool(
+            proxy.clone(),
+            self.pool.clone(),
+        ));
+        trace!("set pool size to {:?}", size);
+    }
+
+    /// Returns the pool of warm connections to the proxy.
+    pub fn get_pool(&self) -> Option<Arc<ConnectionPool>> {
+        self.pool.clone()
+    }
+
+    /// Sets the minimum time of a zero window advertised to the source before a TCP connection is
+    /// reported to the observer as full.
+    pub fn set_window_full_threshold(&mut self, threshold: Duration) {
+        self.window_full_threshold = threshold;
+        trace!("set window full threshold to {:?}", threshold);
+    }
+
+    /// Sets the observer of connections.
+    pub fn set_observer(&mut self, observer: Option<Arc<dyn Observer>>) {
+        self.observer = observer;
+    }
+
+    /// Subscribes to the events of connections, returning the receiver of a queue of
+    /// `EVENT_QUEUE_SIZE` events. The receiver of a previous subscription ends.
+    pub fn subscribe(&mut self) -> EventReceiver {
+        self.subscribe_with_capacity(observer::EVENT_QUEUE_SIZE)
+    }
+
+    /// Subscribes to the events of connections, returning the receiver of a queue of the given
+    /// capacity. The oldest events are dropped if the receiver falls behind, which are counted
+    /// in `EventReceiver::dropped`. The receiver of a previous subscription ends.
+    pub fn subscribe_with_capacity(&mut self, capacity: usize) -> EventReceiver {
+        let (events_tx, events_rx) = observer::event_queue(capacity);
+        self.tx.lock().unwrap().events = Some(events_tx.clone());
+        self.events = Some(events_tx);
+        trace!("subscribe to events in {}", capacity);
+
+        events_rx
+    }
+
+    fn send_event(&self, event: Event) {
+        if let Some(events) = &self.events {
+            events.send(event);
+        }
+    }
+
+    /// Returns the statistics of the redirector.
+    pub fn stats(&self) -> Stats {
+        self.stats.clone()
+    }
+
+    /// Returns the size of data buffered in all the TCP connections, including data sent to the
+    /// sources but not acknowledged, data queued to send to the sources, and out-of-order data
+    /// received from the sources. The size is also recorded in the statistics.
+    pub fn buffered_size(&self) -> usize {
+        let tx_size = self.tx.lock().unwrap().get_total_cache_size();
+        let rx_size = self.get_total_cache_size();
+        self.stats.set_tx_buffered(tx_size);
+        self.stats.set_rx_buffered(rx_size);
+
+        tx_size + rx_size
+    }
+
+    /// Returns the total capacity of the send and receive windows of all the TCP connections,
+    /// against which the buffered size can be compared. The capacity is also recorded in the
+    /// statistics.
+    pub fn buffer_capacity(&self) -> usize {
+        let capacity = self.states.values().fold(
+            self.tx.lock().unwrap().get_total_cache_capacity(),
+            |capacity, state| capacity.saturating_add(state.cache().capacity()),
+        );
+        self.stats.set_buffer_capacity(capacity);
+
+        capacity
+    }
+
+    fn get_total_cache_size(&self) -> usize {
+        self.states.values().map(|state| state.cache().len()).sum()
+    }
+
+    /// Returns a snapshot of the active connections.
+    pub fn connections(&self) -> Vec<ConnectionInfo> {
+        let tx_locked = self.tx.lock().unwrap();
+        let now = Instant::now();
+
+        let mut connections = Vec::with_capacity(self.streams.len() + self.datagrams.len());
+        for (&(src, dst), stream) in &self.streams {
+            let tx_state = tx_locked.get_state(dst, src);
+            let (sent_segments, retrans_segments) = tx_locked.get_segment_counts(dst, src);
+            connections.push(ConnectionInfo {
+                kind: LayerKinds::Tcp,
+                src,
+                dst: Some(dst),
+                local_port: None,
+                name: stream.name().cloned(),
+                is_direct: stream.is_direct(),
+                tx_size: stream.tx_size(),
+                rx_size: stream.rx_size(),
+                send_next: tx_state.map(|state| state.sequence()),
+                state: self.states.get(&(src, dst)).map(|state| state.state()),
+                recv_next: self.states.get(&(src, dst)).map(|state| state.recv_next()),
+                window: tx_state.map(|state| state.src_window()),
+                mss: tx_state.map(|state| state.send_mss(tx_locked.get_tcp_mss(dst, src))),
+                zero_window: tx_state
+                    .and_then(|state| state.zero_window())
+                    .map(|instant| now.saturating_duration_since(instant)),
+                srtt: tx_state.and_then(|state| state.srtt()),
+                rttvar: tx_state.and_then(|state| state.rttvar()),
+                sent_segments,
+                retrans_segments,
+                retrans_ratio: stat::retrans_ratio(sent_segments, retrans_segments),
+                cache_size: tx_state.map_or(0, |state| state.cache().len()),
+                queue_size: tx_state.map_or(0, |state| state.queue().len()),
+                is_tx_closed: stream.is_tx_closed(),
+                is_rx_closed: stream.is_rx_closed(),
+            });
+        }
+        for (&port, datagram) in &self.datagrams {
+            connections.push(ConnectionInfo {
+                kind: LayerKinds::Udp,
+                src: datagram.src(),
+                dst: None,
+                local_port: Some(port),
+                name: None,
+                is_direct: datagram.is_direct(),
+                tx_size: datagram.tx_size(),
+                rx_size: datagram.rx_size(),
+                state: None,
+                send_next: None,
+                recv_next: None,
+                window: None,
+                mss: None,
+                zero_window: None,
+                srtt: None,
+                rttvar: None,
+                sent_segments: 0,
+                retrans_segments: 0,
+                retrans_ratio: None,
+                cache_size: 0,
+                queue_size: 0,
+                is_tx_closed: datagram.is_closed(),
+                is_rx_closed: datagram.is_closed(),
+            });
+        }
+
+        connections
+    }
+
+    /// Opens an `Interface` for redirection. The receive half is read in a blocking thread of the
+    /// runtime and frames are passed to the task in a channel, so the task wakes once a frame
+    /// arrives, and handles timed events on schedule without blocking the executor. The receive
+    /// half is given back once the redirection stops.
+    pub async fn open(&mut self, rx: &mut Receiver) -> error::Result<()> {
+        self.open_monitored(rx, None).await
+    }
+
+    /// Opens an `Interface` for redirection and monitoring.
+    pub async fn open_monitored(
+        &mut self,
+        rx: &mut Receiver,
+        is_running: Option<Arc<AtomicBool>>,
+    ) -> error::Result<()> {
+        let result = self.run(rx, is_running).await;
+        if self.is_dry_run {
+            self.report_dry_run();
+        }
+
+        Ok(result?)
+    }
+
+    /// Opens an `Interface` for redirection like `open`, but takes the ownership of the receive
+    /// half. The interface is not reopened even if the auto reopening is enabled.
+    pub async fn open_blocking(&mut self, mut rx: Receiver) -> error::Result<()> {
+        self.open(&mut rx).await
+    }
+
+    /// Runs the redirection on the runtime of the handle like `open_blocking`, which integrates
+    /// the redirector into an existing runtime without spawning threads other than the blocking
+    /// thread of the runtime reading the receive half. The redirection can be stopped through the
+    /// `ShutdownHandle`.
+    pub fn run_on(mut self, handle: &Handle, rx: Receiver) -> JoinHandle<error::Result<()>> {
+        handle.spawn(async move { self.open_blocking(rx).await })
+    }
+
+    async fn run(
+        &mut self,
+        rx: &mut Receiver,
+        is_running: Option<Arc<AtomicBool>>,
+    ) -> io::Result<()> {
+        self.start()?;
+
+        let (frames_tx, mut frames_rx) = mpsc::channel(CAPTURE_CHANNEL_SIZE);
+        let is_capturing = Arc::new(AtomicBool::new(true));
+        let capture = spawn_capture(
+            std::mem::replace(rx, Box::new(DetachedReceiver)),
+            frames_tx,
+            Arc::clone(&is_capturing),
+            self.is_capture_dropping,
+            self.timedout_wait,
+            self.stats.clone(),
+        );
+
+        let result = self.run_frames(&mut frames_rx, is_running).await;
 
+        // Wait for the blocking thread, which stops in a read timeout
+        is_capturing.store(false, Ordering::Relaxed);
+        drop(frames_rx);
+        *rx = capture
+            .await
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+
+        result
+    }
+
+    async fn run_frames(
+        &mut self,
+        frames: &mut mpsc::Receiver<io::Result<Vec<u8>>>,
+        is_running: Option<Arc<AtomicBool>>,
+    ) -> io::Result<()> {
+        loop {
+            // Monitor
+            if let Some(is_running) = &is_running {
+                if !is_running.load(Ordering::Relaxed) {
                     return Ok(());
                 }
-            } else {
-                // ACK0
-                if !is_writable {
-                    if self.tx.lock().unwrap().get_cache_size(dst, src) == 0 {
-                        // LAST_ACK
-                        // Clean up
-                        self.clean_up(src, dst);
+            }
+            if self.handle_timed() {
+                return Ok(());
+            }
+            self.retry_connects().await;
+            // Time out to handle timed events
+            let deadline = self.next_timed();
+            match time::timeout_at(deadline, frames.recv()).await {
+                Ok(Some(Ok(frame))) => self.handle_frame(&frame).await,
+                Ok(Some(Err(e))) => return Err(e),
+                Ok(None) => return Err(io::Error::from(io::ErrorKind::BrokenPipe)),
+                Err(_) => {}
+            }
+        }
+    }
+
+    /// Returns the instant of the next timed event. Flags like shutting down are checked at
+    /// least every timed out wait.
+    fn next_timed(&self) -> Instant {
+        let mut deadline = min(
+            Instant::now() + self.timedout_wait,
+            self.last_reap + self.reap_interval,
+        );
+        if let Some(arp_interval) = self.arp_interval.filter(|_| !self.is_dry_run) {
+            deadline = min(deadline, self.last_arp + arp_interval);
+        }
+        if let Some(shutdown_deadline) = self.shutdown_deadline {
+            deadline = min(deadline, shutdown_deadline);
+        }
+        if let Some(connect) = self.connects.values().map(|connect| connect.deadline).min() {
+            deadline = min(deadline, connect);
+        }
+
+        deadline
+    }
+
+    async fn handle_frame(&mut self, frame: &[u8]) {
+        match Indicator::from(frame) {
+            Some(ref indicator) => self.handle_indicator(indicator, frame).await,
+            None => {
+                trace!("drop frame: malformed ({} Bytes)", frame.len());
+                self.stats.increase_malformed_frames();
+            }
+        }
+    }
+
+    /// Opens the receive half of the interface for redirection like `open`. If the interface is
+    /// gone and the auto reopening is enabled, the interface will be reopened with a backoff, and
+    /// the redirection will be resumed once it is back. Errors are returned as is if the interface
+    /// is still available.
+    pub async fn open_interface(
+        &mut self,
+        rx: &mut Receiver,
+        inter: &Interface,
+        config: &CaptureConfig,
+    ) -> error::Result<()> {
+        loop {
+            let e = match self.open_monitored(rx, None).await {
+                Ok(()) => return Ok(()),
+                Err(e) => e,
+            };
+            if !self.is_auto_reopened || inter.is_available() {
+                return Err(e);
+            }
+            warn!("interface {} is gone: {}", inter.name(), e);
+            if let Some(observer) = &self.observer {
+                observer.on_interface_down(inter.name());
+            }
 
+            // Reopen
+            let mut backoff = Duration::from_millis(REOPEN_BACKOFF);
+            *rx = loop {
+                let deadline = Instant::now() + backoff;
+                while Instant::now() < deadline {
+                    if self.handle_timed() {
                         return Ok(());
                     }
-                } else {
-                    // Duplicate ACK
-                    state.admit(tcp.acknowledgement());
-                    if state.duplicate() >= DUPLICATES_THRESHOLD {
-                        let is_cooled_down = match state.last_retrans() {
-                            Some(ref instant) => instant.elapsed().as_millis() < RETRANS_COOL_DOWN,
-                            None => false,
-                        };
+                    time::sleep(self.timedout_wait).await;
+                }
+                match inter.open_with_config(config) {
+                    Ok((tx, rx)) => {
+                        self.tx.lock().unwrap().set_sender(tx);
 
-                        if !is_cooled_down && !tcp.is_zero_window() {
-                            // Fast retransmit
-                            let mut is_sr = false;
-                            if state.sack_perm() {
-                                if let Some(sacks) = tcp.sack() {
-                                    if !sacks.is_empty() {
-                                        // Selective retransmission
-                                        self.tx.lock().unwrap().retransmit_tcp(
-                                            dst,
-                                            src,
-                                            Some(sacks),
-                                        )?;
-                                        is_sr = true;
-                                    }
-                                }
-                            }
+                        break rx;
+                    }
+                    Err(ref e) => {
+                        debug!("reopen interface {}: {}", inter.name(), e);
+                        backoff = min(backoff * 2, Duration::from_millis(MAX_REOPEN_BACKOFF));
+                    }
+                }
+            };
+            info!("Reopen interface {}", inter.name());
+            if let Some(observer) = &self.observer {
+                observer.on_interface_up(inter.name());
+            }
+        }
+    }
 
-                            if !is_sr {
-                                // Back N
-                                self.tx.lock().unwrap().retransmit_tcp(dst, src, None)?;
-                            }
+    fn start(&mut self) -> io::Result<()> {
+        if self.is_dry_run {
+            info!("Run dry, no connections will be opened and no frames will be sent");
 
-                            state.admit_retrans();
+            return Ok(());
+        }
+
+        // Send gratuitous ARP
+        if self.gw_ip_addr.is_some() {
+            self.tx.lock().unwrap().send_gratuitous_arp()?;
+        }
+
+        // Warm up the pool
+        if let Some(pool) = &self.pool {
+            pool.fill();
+        }
+
+        // Tick
+        if self.ticker.is_none() {
+            self.ticker = Some(spawn_ticker(Arc::downgrade(&self.tx), self.tick_interval));
+        }
+
+        Ok(())
+    }
+
+    fn report_dry_run(&self) {
+        let flows = self.dry_run_flows();
+        info!("Dry run saw {} flows", flows.len());
+        for (kind, src, dst, count, size) in flows {
+            info!(
+                "{} {} -> {}: {} packets ({} Bytes)",
+                kind, src, dst, count, size
+            );
+        }
+    }
+
+    /// Handles timed events. Returns `true` if the redirector is shut down.
+    fn handle_timed(&mut self) -> bool {
+        // Shut down
+        if self.is_shutdown.load(Ordering::Relaxed) {
+            match self.shutdown_deadline {
+                Some(deadline) => {
+                    if self.states.is_empty() || Instant::now() >= deadline {
+                        let keys = self.states.keys().cloned().collect::<Vec<_>>();
+                        for (src, dst) in keys {
+                            debug!("reset TCP {} -> {} due to shutdown", src, dst);
+                            self.abort(src, dst);
                         }
+
+                        return true;
+                    }
+                }
+                None => {
+                    info!("Shut down, wait for {} connections", self.streams.len());
+                    self.shutdown_deadline = Some(Instant::now() + self.shutdown_grace_period);
+
+                    // Close all the streams, the queued data will be sent before the FIN
+                    for stream in self.streams.values_mut() {
+                        stream.shutdown(Shutdown::Read);
                     }
                 }
             }
+        }
+        // Reset connections whose streams are dropped without being torn down. The stream may
+        // belong to a prior connection of the same source and destination
+        while let Ok((id, src, dst)) = self.teardown_rx.try_recv() {
+            if self.stream_ids.get(&(src, dst)) != Some(&id) {
+                trace!(
+                    "ignore teardown of TCP {} -> {}: prior connection",
+                    src,
+                    dst
+                );
+                continue;
+            }
+            debug!("reset TCP {} -> {}: stream is dropped", src, dst);
+            self.abort(src, dst);
+        }
+        // Announce
+        if let Some(arp_interval) = self.arp_interval.filter(|_| !self.is_dry_run) {
+            if self.last_arp.elapsed() >= arp_interval {
+                if let Err(ref e) = self.announce() {
+                    warn!("announce: {}", e);
+                }
+                self.last_arp = Instant::now();
+            }
+        }
+        // Reap idle connections and incomplete fragments
+        if self.last_reap.elapsed() >= self.reap_interval {
+            let now = Instant::now();
+            self.reap_idle(now);
+            self.reap_idle_datagrams(now);
+            self.report_full_windows(now);
+            self.buffered_size();
+            self.buffer_capacity();
+            let size = self.defrag.sweep(now);
+            if size > 0 {
+                trace!("drop {} incomplete groups of fragments", size);
+            }
+            self.last_reap = now;
+        }
 
-            // Trigger sending remaining data
-            self.tx.lock().unwrap().send_tcp(dst, src)?;
+        false
+    }
 
-            // FIN
-            if tcp.is_fin() || state.fin_sequence().is_some() {
-                self.handle_tcp_fin(tcp, payload)?;
+    async fn handle_indicator(&mut self, indicator: &Indicator, frame: &[u8]) {
+        match indicator.network_kind() {
+            Some(LayerKinds::Arp) => {
+                if let Err(ref e) = self.handle_arp(indicator) {
+                    warn!("handle {}: {}", indicator.brief(), e);
+                }
             }
-        } else {
-            // Send ACK/RST
-            self.tx
-                .lock()
-                .unwrap()
-                .send_tcp_ack_rst_untracked(dst, src, tcp.acknowledgement())?;
+            Some(LayerKinds::Ipv4) => {
+                if let Err(ref e) = self.handle_ipv4(indicator, frame).await {
+                    warn!("handle {}: {}", indicator.brief(), e);
+                }
+            }
+            Some(t) => {
+                warn!("drop frame: unsupported network {}", t);
+                self.stats.increase_unsupported_frames();
+            }
+            None => match indicator.ethernet() {
+                // The network layer is known but cannot be parsed
+                Some(ethernet) => match ethernet.next_level_layer_kind() {
+                    Some(t) => {
+                        trace!("drop {}: malformed {}", ethernet, t);
+                        self.stats.increase_malformed_frames();
+                    }
+                    None => {
+                        trace!("drop {}: unsupported network", ethernet);
+                        self.stats.increase_unsupported_frames();
+                    }
+                },
+                None => {
+                    trace!("drop frame: unsupported link {}", indicator.link().kind());
+                    self.stats.increase_unsupported_frames();
+                }
+            },
+        }
+    }
+
+    fn drop_unsupported_transport(&mut self, transport: &Layers) {
+        warn!("drop {}: unsupported transport", transport.kind());
+        self.stats.increase_unsupported_frames();
+    }
+
+    fn announce(&mut self) -> io::Result<()> {
+        if self.gw_ip_addr.is_none() {
+            return Ok(());
+        }
+
+        let mut tx_locked = self.tx.lock().unwrap();
+        tx_locked.send_gratuitous_arp()?;
+        for src in self.tx_src_hardware_addr_map.keys() {
+            tx_locked.send_arp_reply(*src)?;
+        }
+
+        Ok(())
+    }
+
+    fn handle_arp(&mut self, indicator: &Indicator) -> io::Result<()> {
+        if !self.is_vlan(indicator.vlan()) {
+            self.stats.increase_foreign_frames();
+
+            return Ok(());
+        }
+        if let Some(arp) = indicator.arp() {
+            let src = arp.src();
+            let dst = arp.dst();
+            let is_gw = self.gw_ip_addr == Some(dst);
+            let is_proxy_arp = arp.is_request() && dst != src && self.is_proxy_arp(dst);
+            // Learn the unspecified source
+            if self.src_ip_addrs.is_empty()
+                && arp.is_request()
+                && (is_gw || dst == self.local_ip_addr)
+                && !src.is_unspecified()
+                && src != self.local_ip_addr
+            {
+                info!("Learn source {} from ARP", src);
+                self.src_ip_addrs.insert(Ipv4Network::from(src));
+            }
+            if src != self.local_ip_addr && self.is_src(src) && (is_gw || is_proxy_arp) {
+                // Pause
+                if self.is_paused() && !self.has_connections(src) {
+                    trace!("drop {}: redirector is paused", indicator.brief());
+
+                    return Ok(());
+                }
+                debug!(
+                    "receive from pcap: {} ({} Bytes)",
+                    indicator.brief(),
+                    indicator.len()
+                );
+
+                // Set forwarder's hardware address
+                self.set_tx_hardware_addr(src, arp.src_hardware_addr(), indicator.vlan());
+
+                // Send
+                if self.is_dry_run {
+                    debug!("would reply ARP {} to {}", dst, src);
+                } else if is_gw {
+                    self.tx.lock().unwrap().send_arp_reply(src)?;
+                } else {
+                    self.tx.lock().unwrap().send_proxy_arp_reply(dst, src)?;
+                }
+
+                // Monitor
+                if let Some(size) = &self.traffic_size {
+                    size.fetch_add(indicator.content_len(), Ordering::Relaxed);
+                }
+                if let Some(count) = &self.traffic_count {
+                    count.fetch_add(1, Ordering::Relaxed);
+                }
+            } else if src == self.local_ip_addr || !self.is_src(src) {
+                self.stats.increase_foreign_frames();
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn handle_ipv4(&mut self, indicator: &Indicator, frame: &[u8]) -> io::Result<()> {
+        if !self.is_vlan(indicator.vlan()) {
+            self.stats.increase_foreign_frames();
+
+            return Ok(());
+        }
+        if let Some(ipv4) = indicator.ipv4() {
+            let src = ipv4.src();
+            if src != self.local_ip_addr && self.is_src(src) {
+                let src = ipv4.src();
+                debug!(
+                    "receive from pcap: {} ({} + {} Bytes)",
+                    indicator.brief(),
+                    indicator.len(),
+                    indicator.content_len() - indicator.len()
+                );
+                // Drop truncated packets
+                if frame.len() < indicator.content_len() {
+                    trace!("drop {}: truncated", indicator.brief());
+                    self.stats.increase_malformed_frames();
+
+                    return Ok(());
+                }
+                // Verify checksums
+                if !packet::verify_checksums(frame, self.checksum_mode) {
+                    trace!("drop {}: invalid checksum", indicator.brief());
+                    self.stats.increase_malformed_frames();
+
+                    return Ok(());
+                }
+                let ethernet = match indicator.ethernet() {
+                    Some(ethernet) => ethernet,
+                    None => {
+                        trace!("drop {}: unsupported link", indicator.brief());
+                        self.stats.increase_unsupported_frames();
+
+                        return Ok(());
+                    }
+                };
+                // Set forwarder's hardware address
+                self.set_tx_hardware_addr(src, ethernet.src(), indicator.vlan());
+                // Mirror DSCP
+                if self.is_dscp_mirrored && !self.is_dry_run {
+                    self.tx
+                        .lock()
+                        .unwrap()
+                        .set_mirrored_dscp(ipv4.dst(), src, ipv4.dscp());
+                }
+
+                let frame_without_padding = &frame[..indicator.content_len()];
+                // Original IPv4 header and the first 8 bytes of the payload
+                let size = min(frame_without_padding.len(), ethernet.len() + ipv4.len() + 8);
+                let quote = &frame_without_padding[ethernet.len()..size];
+                // TTL decrement
+                let is_local =
+                    ipv4.dst() == self.local_ip_addr || Some(ipv4.dst()) == self.gw_ip_addr;
+                if self.is_ttl_decremented && !is_local && ipv4.ttl() <= 1 {
+                    debug!("{} -> {} exceeds TTL", src, ipv4.dst());
+                    if self.is_dry_run {
+                        debug!("would send ICMPv4 time exceeded to {}", src);
+
+                        return Ok(());
+                    }
+                    self.tx.lock().unwrap().send_icmpv4_time_exceeded(
+                        self.gw_ip_addr.unwrap_or(self.local_ip_addr),
+                        src,
+                        quote,
+                    )?;
+
+                    return Ok(());
+                }
+                // Path MTU Discovery
+                if let Some(path_mtu) = self.path_mtu {
+                    // TCP is terminated locally, the size of segments is limited by the MSS
+                    let is_tcp = matches!(indicator.transport(), Some(Layers::Tcp(_)));
+                    if ipv4.is_dont_fragment() && ipv4.total_length() as usize > path_mtu && !is_tcp
+                    {
+                        debug!(
+                            "{} -> {} exceeds path MTU {} ({} Bytes)",
+                            src,
+                            ipv4.dst(),
+                            path_mtu,
+                            ipv4.total_length()
+                        );
+                        if self.is_dry_run {
+                            debug!("would send ICMPv4 fragmentation required to {}", src);
+
+                            return Ok(());
+                        }
+                        self.tx.lock().unwrap().send_icmpv4_fragmentation_required(
+                            ipv4.dst(),
+                            src,
+                            path_mtu as u16,
+                            quote,
+                        )?;
+
+                        return Ok(());
+                    }
+                }
+                if ipv4.is_fragment() {
+                    // Fragmentation
+                    let frag = match self.defrag.add(indicator, frame_without_padding) {
+                        Some(frag) => frag,
+                        None => return Ok(()),
+                    };
+                    let (transport, payload) = frag.concatenate();
+
+                    if let Some(transport) = transport {
+                        if self.is_dry_run {
+                            self.handle_dry_run(&transport, payload.len());
+                        } else {
+                            match transport {
+                                Layers::Icmpv4(ref icmpv4) => self.handle_icmpv4(icmpv4)?,
+                                Layers::Tcp(ref tcp) => {
+                                    self.handle_tcp(tcp, payload, &frag.quote()?).await?
+                                }
+                                Layers::Udp(ref udp) => self.handle_udp(udp, payload).await?,
+                                ref transport => self.drop_unsupported_transport(transport),
+                            }
+                        }
+                    }
+                } else {
+                    #[allow(clippy::collapsible_if)]
+                    if let Some(transport) = indicator.transport() {
+                        if self.is_dry_run {
+                            self.handle_dry_run(
+                                transport,
+                                indicator.content_len() - indicator.len(),
+                            );
+                        } else {
+                            match transport {
+                                Layers::Icmpv4(icmpv4) => self.handle_icmpv4(icmpv4)?,
+                                Layers::Tcp(tcp) => {
+                                    self.handle_tcp(
+                                        tcp,
+                                        &frame_without_padding[indicator.len()..],
+                                        quote,
+                                    )
+                                    .await?
+                                }
+                                Layers::Udp(udp) => {
+                                    self.handle_udp(udp, &frame_without_padding[indicator.len()..])
+                                        .await?
+                                }
+                                transport => self.drop_unsupported_transport(transport),
+                            }
+                        }
+                    } else {
+                        // The transport layer is known but cannot be parsed
+                        match ipv4.next_level_layer_kind() {
+                            Some(t) => {
+                                trace!("drop {}: malformed {}", indicator.brief(), t);
+                                self.stats.increase_malformed_frames();
+                            }
+                            None => {
+                                trace!("drop {}: unsupported transport", indicator.brief());
+                                self.stats.increase_unsupported_frames();
+                            }
+                        }
+
+                        return Ok(());
+                    }
+                }
+
+                // Monitor
+                if let Some(size) = &self.traffic_size {
+                    size.fetch_add(indicator.content_len(), Ordering::Relaxed);
+                }
+                if let Some(count) = &self.traffic_count {
+                    count.fetch_add(1, Ordering::Relaxed);
+                }
+            } else {
+                self.stats.increase_foreign_frames();
+            }
+        }
+
+        Ok(())
+    }
+
+    fn handle_dry_run(&mut self, transport: &Layers, size: usize) {
+        let (kind, src, dst) = match transport {
+            Layers::Icmpv4(icmpv4) => {
+                debug!("would handle {}", icmpv4);
+
+                return;
+            }
+            Layers::Tcp(tcp) => (
+                LayerKinds::Tcp,
+                SocketAddrV4::new(tcp.src_ip_addr(), tcp.src()),
+                SocketAddrV4::new(tcp.dst_ip_addr(), tcp.dst()),
+            ),
+            Layers::Udp(udp) => (
+                LayerKinds::Udp,
+                SocketAddrV4::new(udp.src_ip_addr(), udp.src()),
+                SocketAddrV4::new(udp.dst_ip_addr(), udp.dst()),
+            ),
+            transport => return self.drop_unsupported_transport(transport),
+        };
+
+        let key = (kind, src, dst);
+        if let Some(flow) = self.dry_run_flows.get_mut(&key) {
+            flow.0 += 1;
+            flow.1 += size;
+
+            return;
+        }
+
+        // Log the decision once per flow
+        if !self.filter.lock().unwrap().is_allowed(dst) {
+            info!(
+                "Would refuse {} {} -> {}: destination is denied",
+                kind, src, dst
+            );
+        } else {
+            let target = self.rewriter.lock().unwrap().rewrite(dst);
+            if target != dst {
+                info!("Would redirect {} {} -> {} to {}", kind, src, dst, target);
+            } else {
+                info!("Would redirect {} {} -> {}", kind, src, dst);
+            }
+        }
+
+        self.dry_run_flows.put(key, (1, size));
+    }
+
+    fn handle_icmpv4(&mut self, icmpv4: &Icmpv4) -> io::Result<()> {
+        if icmpv4.is_destination_port_unreachable() {
+            // Destination port unreachable
+            let kind = match icmpv4.next_level_layer_kind() {
+                Some(kind) => kind,
+                None => return Ok(()),
+            };
+            #[allow(clippy::single_match)]
+            match kind {
+                LayerKinds::Udp => match icmpv4.dst() {
+                    Some(dst) => self.unbind_local_udp_port(dst),
+                    None => {
+                        trace!("drop ICMPv4: truncated datagram in destination port unreachable");
+                        self.stats.increase_malformed_frames();
+                    }
+                },
+                _ => {}
+            }
+        } else if icmpv4.is_fragmentation_required_and_df_flag_set() {
+            // Fragmentation required, and DF flag set
+            let (mtu, dst_ip_addr) = match (icmpv4.next_hop_mtu(), icmpv4.dst_ip_addr()) {
+                (Some(mtu), Some(dst_ip_addr)) => (mtu as usize, dst_ip_addr),
+                _ => {
+                    trace!("drop ICMPv4: truncated fragmentation required");
+                    self.stats.increase_malformed_frames();
+
+                    return Ok(());
+                }
+            };
+            if mtu < MIN_MTU {
+                trace!("ignore MTU {} of {}: below {}", mtu, dst_ip_addr, MIN_MTU);
+
+                return Ok(());
+            }
+            if self.tx.lock().unwrap().set_src_mtu(dst_ip_addr, mtu) {
+                info!("Update MTU of {} to {}", dst_ip_addr, mtu);
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn handle_tcp(&mut self, tcp: &Tcp, payload: &[u8], quote: &[u8]) -> io::Result<()> {
+        let src = SocketAddrV4::new(tcp.src_ip_addr(), tcp.src());
+        let dst = SocketAddrV4::new(tcp.dst_ip_addr(), tcp.dst());
+        let span = match self.spans.get(&(src, dst)) {
+            Some(span) => span.clone(),
+            None => debug_span!("tcp", src = %src, dst = %dst),
+        };
+
+        self.handle_tcp_in_span(tcp, payload, quote)
+            .instrument(span)
+            .await
+    }
+
+    async fn handle_tcp_in_span(
+        &mut self,
+        tcp: &Tcp,
+        payload: &[u8],
+        quote: &[u8],
+    ) -> io::Result<()> {
+        let src = SocketAddrV4::new(tcp.src_ip_addr(), tcp.src());
+        let dst = SocketAddrV4::new(tcp.dst_ip_addr(), tcp.dst());
+        if let Some(state) = self.states.get_mut(&(src, dst)) {
+            state.update_activity();
+        }
+
+        // TCP MD5 signature
+        let is_md5_valid = self
+            .tx
+            .lock()
+            .unwrap()
+            .get_tcp_md5(dst, src)
+            .map(|key| tcp.md5() == Some(tcp.md5_digest(payload, key)));
+        match is_md5_valid {
+            Some(true) => {}
+            Some(false) => {
+                trace!("drop TCP {} -> {}: bad MD5 signature", src, dst);
+
+                return Ok(());
+            }
+            None => {
+                if tcp.is_md5() {
+                    return self.refuse_tcp_md5(tcp, payload);
+                }
+            }
+        }
+
+        if tcp.is_rst() {
+            self.handle_tcp_rst(tcp);
+        } else if tcp.is_ack() {
+            self.handle_tcp_ack(tcp, payload)?;
+
+            // Measure the buffered size as out-of-order data is held
+            let is_held =
+                matches!(self.states.get(&(src, dst)), Some(state) if !state.cache().is_empty());
+            if !payload.is_empty() && is_held {
+                self.stats.set_rx_buffered(self.get_total_cache_size());
+            }
+        } else if tcp.is_syn() {
+            // Pure TCP SYN
+            self.handle_tcp_syn(tcp, payload, quote).await?;
+        } else if tcp.is_fin() {
+            // Pure TCP FIN
+            self.handle_tcp_fin(tcp, payload)?;
+        } else {
+            warn!("drop TCP {}: no valid flag", tcp.flag_string());
+            self.stats.increase_malformed_frames();
+        }
+
+        Ok(())
+    }
+
+    /// Returns if data from the source is accepted in the TCP connection, which requires the write
+    /// half to the proxy to be open and the source not to have sent a TCP FIN.
+    fn is_tcp_writable(&self, src: SocketAddrV4, dst: SocketAddrV4) -> bool {
+        match (self.streams.get(&(src, dst)), self.states.get(&(src, dst))) {
+            (Some(stream), Some(state)) => {
+                !stream.is_tx_closed() && !state.state().is_recv_closed()
+            }
+            _ => false,
+        }
+    }
+
+    fn handle_tcp_ack(&mut self, tcp: &Tcp, payload: &[u8]) -> io::Result<()> {
+        let src = SocketAddrV4::new(tcp.src_ip_addr(), tcp.src());
+        let dst = SocketAddrV4::new(tcp.dst_ip_addr(), tcp.dst());
+        let key = (src, dst);
+        let is_exist = self.streams.get(&key).is_some();
+        let is_writable = self.is_tcp_writable(src, dst);
+
+        if is_exist {
+            // ACK
+            let state = self
+                .states
+                .get_mut(&key)
+                .ok_or_else(|| io::Error::from(StateError::UnknownConnection))?;
+            if tcp.sequence() != state.recv_next() {
+                trace!(
+                    "TCP out of order of {} -> {} at {}",
+                    src,
+                    dst,
+                    tcp.sequence()
+                );
+            }
+            if state.check_wscale(tcp.window()) {
+                warn!(
+                    "disable TCP window scale of {} -> {}: source does not scale its window",
+                    src, dst
+                );
+                if self.is_wscale_fallback {
+                    self.wscale_disabled_dsts.insert(dst);
+                }
+            }
+            {
+                let mut tx_locked = self.tx.lock().unwrap();
+                let tx_state = tx_locked
+                    .get_state_mut(dst, src)
+                    .ok_or_else(|| io::Error::from(StateError::UnknownConnection))?;
+
+                tx_state.acknowledge(tcp.acknowledgement());
+                tx_state.set_src_window((tcp.window() as usize) << state.wscale() as usize);
+                let is_black_hole = tx_state.take_black_hole();
+
+                state.update_state(tx_state);
+
+                // Record the latest SACK blocks
+                if state.sack_perm() {
+                    tx_locked.set_src_sacks(dst, src, tcp.sack().unwrap_or_default());
+                }
+
+                if is_black_hole {
+                    tx_locked.report_black_hole(dst, src);
+                }
+            }
+            if state.state().is_closed() {
+                // CLOSING or LAST_ACK
+                // Clean up
+                self.close(src, dst);
+
+                return Ok(());
+            }
+
+            if !payload.is_empty() {
+                // ACK
+                if is_writable {
+                    // Append to cache
+                    let cont_payload = state.append_cache(tcp.sequence(), payload)?;
+
+                    // SACK
+                    if state.sack_perm() {
+                        let sacks = state.cache().filled();
+                        self.tx
+                            .lock()
+                            .unwrap()
+                            .get_state_mut(dst, src)
+                            .ok_or_else(|| io::Error::from(StateError::UnknownConnection))?
+                            .set_sacks(&sacks);
+                    }
+
+                    match cont_payload {
+                        Some(payload) => {
+                            // Send
+                            let stream = self
+                                .streams
+                                .get_mut(&key)
+                                .ok_or_else(|| io::Error::from(StateError::UnknownConnection))?;
+                            let size = payload.len();
+                            // The window is held by the payload until it is written to the
+                            // proxy, which is added before sending so the drain comes after
+                            self.tx
+                                .lock()
+                                .unwrap()
+                                .get_state_mut(dst, src)
+                                .ok_or_else(|| io::Error::from(StateError::UnknownConnection))?
+                                .add_pending(size);
+                            match stream.send(payload) {
+                                Ok(_) => {
+                                    let cache_remaining_size = state.window();
+
+                                    state.add_recv_next(size as u32);
+
+                                    let mut tx_locked = self.tx.lock().unwrap();
+                                    let tx_state =
+                                        tx_locked.get_state_mut(dst, src).ok_or_else(|| {
+                                            io::Error::from(StateError::UnknownConnection)
+                                        })?;
+
+                                    // Update window size
+                                    tx_state.set_window(cache_remaining_size);
+
+                                    // Update TCP acknowledgement
+                                    tx_state.add_acknowledgement(size as u32);
+
+                                    // Send ACK0 immediately if the source pushes, otherwise delayed ACK0
+                                    // If there is a heavy traffic, the ACK reported may be inaccurate, which would results in retransmission
+                                    if tcp.is_psh() {
+                                        tx_locked.send_tcp_ack_0(dst, src)?;
+                                    } else {
+                                        tx_locked.send_tcp_delay_ack_0(dst, src)?;
+                                    }
+                                }
+                                Err(e) => {
+                                    // Send ACK/RST
+                                    self.tx.lock().unwrap().send_tcp_ack_rst(dst, src)?;
+
+                                    // Clean up
+                                    self.reset(src, dst);
+
+                                    return Err(e);
+                                }
+                            }
+                        }
+                        None => {
+                            // Retransmission or unordered
+                            let cache_remaining_size = state.window();
+
+                            // Update window size
+                            let mut tx_locked = self.tx.lock().unwrap();
+                            let tx_state = tx_locked
+                                .get_state_mut(dst, src)
+                                .ok_or_else(|| io::Error::from(StateError::UnknownConnection))?;
+
+                            tx_state.set_window(cache_remaining_size);
+
+                            // Send ACK0
+                            tx_locked.send_tcp_ack_0(dst, src)?;
+                        }
+                    }
+                } else {
+                    // Send ACK/RST
+                    self.tx.lock().unwrap().send_tcp_ack_rst(dst, src)?;
+
+                    // Clean up
+                    self.reset(src, dst);
+
+                    return Ok(());
+                }
+            } else {
+                // ACK0
+                if is_writable {
+                    // Duplicate ACK
+                    state.admit(tcp.acknowledgement());
+                    if state.duplicate() > 0 && !state.sack_perm() {
+                        if let Some(tx_state) = self.tx.lock().unwrap().get_state_mut(dst, src) {
+                            tx_state.deliver_duplicate();
+                        }
+                    }
+                    if state.duplicate() >= DUPLICATES_THRESHOLD {
+                        let srtt = self
+                            .tx
+                            .lock()
+                            .unwrap()
+                            .get_state(dst, src)
+                            .and_then(|tx_state| tx_state.srtt());
+                        let cool_down = retrans_cool_down(self.retrans_cool_down, srtt);
+                        let is_cooled_down = match state.last_retrans() {
+                            Some(ref instant) => instant.elapsed() < cool_down,
+                            None => false,
+                        };
+
+                        if !is_cooled_down && !tcp.is_zero_window() {
+                            // Fast retransmit
+                            let mut is_sr = false;
+                            if state.sack_perm() {
+                                if let Some(sacks) = tcp.sack() {
+                                    if !sacks.is_empty() {
+                                        // Selective retransmission
+                                        self.tx.lock().unwrap().retransmit_tcp(
+                                            dst,
+                                            src,
+                                            Some(sacks),
+                                        )?;
+                                        is_sr = true;
+                                    }
+                                }
+                            }
+
+                            if !is_sr {
+                                // Back N
+                                self.tx.lock().unwrap().retransmit_tcp(dst, src, None)?;
+                            }
+
+                            state.admit_retrans();
+                        }
+                    }
+                }
+            }
+
+            // Trigger sending remaining data
+            self.tx.lock().unwrap().send_tcp(dst, src)?;
+
+            // FIN
+            if tcp.is_fin() || state.fin_sequence().is_some() {
+                self.handle_tcp_fin(tcp, payload)?;
+            }
+        } else {
+            self.reset_untracked(tcp, payload)?;
+        }
+
+        Ok(())
+    }
+
+    async fn handle_tcp_syn(&mut self, tcp: &Tcp, payload: &[u8], quote: &[u8]) -> io::Result<()> {
+        let src = SocketAddrV4::new(tcp.src_ip_addr(), tcp.src());
+        let dst = SocketAddrV4::new(tcp.dst_ip_addr(), tcp.dst());
+        let key = (src, dst);
+        let is_exist = self.streams.get(&key).is_some();
+
+        // Drop if the connect is waiting for a retry
+        if self.connects.contains_key(&key) {
+            trace!("drop TCP SYN {} -> {}: connect is retrying", src, dst);
+
+            return Ok(());
+        }
+
+        // Connect if not connected, drop if established
+        if !is_exist {
+            // Clean up
+            self.clean_up(src, dst);
+
+            let unknown_options = tcp.unknown_options();
+            if !unknown_options.is_empty() {
+                trace!(
+                    "ignore unknown TCP options of {} -> {}: {:?}",
+                    src,
+                    dst,
+                    unknown_options
+                        .iter()
+                        .map(|(number, _)| number)
+                        .collect::<Vec<_>>()
+                );
+            }
+
+            // Admit SYN
+            let wscale = match ENABLE_WSCALE && !self.wscale_disabled_dsts.contains(&dst) {
+                true => tcp.wscale(),
+                false => None,
+            };
+            let recv_wscale = wscale.map(|wscale| min(wscale, MAX_RECV_WSCALE));
+            let sack_perm = ENABLE_SACK && tcp.is_sack_perm();
+            let mut state = TcpRxState::new(
+                src,
+                dst,
+                tcp.sequence(),
+                wscale.unwrap_or(0),
+                recv_wscale.unwrap_or(0),
+                sack_perm,
+            );
+            if let Some(max_recv_gaps) = self.max_recv_gaps {
+                state.set_max_gaps(max_recv_gaps);
+            }
+            // The window scale is only applied if both sides negotiated it, which is checked
+            // against the next window from the source
+            if wscale.unwrap_or(0) > 0 {
+                state.set_syn_window(tcp.window());
+            }
+            // Data in the SYN, like from TCP Fast Open, is acknowledged in the ACK/SYN and
+            // forwarded once the stream is connected
+            if !payload.is_empty() {
+                trace!(
+                    "admit {} Bytes in TCP SYN of {} -> {}",
+                    payload.len(),
+                    src,
+                    dst
+                );
+                state.append_cache(tcp.sequence().wrapping_add(1), payload)?;
+                state.add_recv_next(payload.len() as u32);
+            }
+
+            {
+                let mut tx_locked = self.tx.lock().unwrap();
+
+                let sequence = self.isn_strategy.isn(src, dst, self.isn_epoch.elapsed());
+                let acknowledgement = state.recv_next();
+                if let Some(mss) = tcp.mss() {
+                    let mtu = Ipv4::minimum_len() + Tcp::minimum_len() + mss as usize;
+                    if tx_locked.set_src_mtu(tcp.src_ip_addr(), mtu) {
+                        info!("Update MTU of {} to {}", tcp.src_ip_addr(), mtu);
+                    }
+                }
+
+                let tx_state = TcpTxState::new(
+                    src,
+                    dst,
+                    sequence,
+                    acknowledgement,
+                    tcp.window(),
+                    recv_wscale,
+                    sack_perm,
+                    wscale,
+                    tx_locked.get_src_mtu(tcp.src_ip_addr())
+                        - (Ipv4::minimum_len() + Tcp::minimum_len()),
+                );
+                tx_locked.set_state(dst, src, tx_state);
+            }
+
+            // Filter
+            if !self.filter.lock().unwrap().is_allowed(dst) {
+                debug!("refuse TCP {} -> {}: destination is denied", src, dst);
+                self.stats.increase_filtered_frames();
+
+                return self.refuse_tcp_syn(src, dst);
+            }
+
+            // Shut down
+            if self.shutdown_deadline.is_some() {
+                debug!("refuse TCP {} -> {}: redirector is shutting down", src, dst);
+
+                return self.refuse_tcp_syn(src, dst);
+            }
+
+            // Pause
+            if self.is_paused() {
+                if self.is_paused_reset {
+                    debug!("refuse TCP {} -> {}: redirector is paused", src, dst);
+
+                    return self.refuse_tcp_syn(src, dst);
+                }
+                debug!("drop TCP {} -> {}: redirector is paused", src, dst);
+                self.clean_up(src, dst);
+
+                return Ok(());
+            }
+
+            // Limit concurrent streams
+            if let Some(max_streams) = self.max_streams {
+                if self.streams.len() >= max_streams {
+                    debug!(
+                        "refuse TCP {} -> {}: reach the limit of {} concurrent streams",
+                        src, dst, max_streams
+                    );
+                    self.stats.increase_rejected_streams();
+
+                    return self.refuse_tcp_syn(src, dst);
+                }
+            }
+
+            // Rewrite
+            let target = self.rewriter.lock().unwrap().rewrite(dst);
+            let name = match target == dst {
+                true => self.tx.lock().unwrap().get_dns_name(*dst.ip()),
+                false => {
+                    debug!("rewrite TCP {} -> {} to {}", src, dst, target);
+
+                    None
+                }
+            };
+
+            // Connect
+            if let Some(ref name) = name {
+                debug!("connect TCP {} -> {} as {}", src, dst, name);
+            }
+            self.connect_tcp(TcpConnect {
+                src,
+                dst,
+                state,
+                target,
+                name,
+                payload: payload.to_vec(),
+                syn: tcp.clone(),
+                quote: quote.to_vec(),
+                retries: 0,
+                deadline: Instant::now(),
+            })
+            .await?;
+        } else {
+            // Retransmit ACK/SYN if the handshake is not completed, the previous ACK/SYN may be lost
+            let is_retrans = self.states.get(&key).map(|state| state.recv_next())
+                == Some(tcp.sequence().wrapping_add(1 + payload.len() as u32));
+            let is_handshaking =
+                self.states.get(&key).map(|state| state.state()) == Some(TcpState::SynReceived);
+            let mut tx_locked = self.tx.lock().unwrap();
+            if is_retrans && is_handshaking {
+                trace!(
+                    "retransmit TCP ACK/SYN {} -> {} due to duplicate SYN",
+                    dst,
+                    src
+                );
+
+                tx_locked.send_tcp_ack_syn(dst, src)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Connects a TCP connection to the proxy. If the connect fails on a transient error, the
+    /// retry is scheduled with a backoff in the timed events, so the redirection is not blocked.
+    async fn connect_tcp(&mut self, mut connect: TcpConnect) -> io::Result<()> {
+        let (src, dst) = (connect.src, connect.dst);
+        let key = (src, dst);
+
+        let stream = StreamWorker::connect_with_upstream(
+            self.get_tx(),
+            src,
+            dst,
+            connect.target,
+            connect.name.clone(),
+            self.upstream.as_ref(),
+        )
+        .await;
+
+        let mut stream = match stream {
+            Ok(stream) => stream,
+            Err(ref e) if connect.retries < self.connect_retries && is_transient(e) => {
+                let backoff = self
+                    .connect_backoff
+                    .saturating_mul(1 << min(connect.retries, 16) as u32);
+                debug!(
+                    "retry TCP {} -> {} in {:?} ({}/{}): {}",
+                    src,
+                    dst,
+                    backoff,
+                    connect.retries + 1,
+                    self.connect_retries,
+                    e
+                );
+                connect.retries += 1;
+                connect.deadline = Instant::now() + backoff;
+                self.connects.insert(key, connect);
+
+                return Ok(());
+            }
+            Err(e) => {
+                match SocksReply::from_io_error(&e) {
+                    Some(reply) if reply.is_unreachable() => {
+                        debug!("unreach TCP {} -> {}: {}", src, dst, reply);
+
+                        self.unreach_tcp_syn(&connect.syn, &connect.quote)?;
+                    }
+                    _ => self.refuse_tcp_syn(src, dst)?,
+                }
+
+                return Err(e);
+            }
+        };
+
+        let id = self.next_stream_id;
+        self.next_stream_id = self.next_stream_id.wrapping_add(1);
+        stream.set_teardown(self.teardown_tx.clone(), id);
+        if let Some(tee) = self.tees.get(&dst) {
+            debug!("tee TCP {} -> {}", src, dst);
+            stream.set_tee(Arc::clone(tee), connect.target, connect.name);
+        }
+
+        // Forward the data in the SYN
+        if !connect.payload.is_empty() {
+            self.tx
+                .lock()
+                .unwrap()
+                .get_state_mut(dst, src)
+                .ok_or_else(|| io::Error::from(StateError::UnknownConnection))?
+                .add_pending(connect.payload.len());
+            stream.send(connect.payload)?;
+        }
+        self.states.insert(key, connect.state);
+        self.streams.insert(key, stream);
+        self.stream_ids.insert(key, id);
+        // The span of the SYN is kept, so the following segments are in the same span
+        self.spans.insert(key, Span::current());
+
+        if let Some(observer) = &self.observer {
+            observer.on_connect(src, dst);
+        }
+        self.send_event(Event::Connect { src, dst });
+
+        Ok(())
+    }
+
+    /// Retries connecting the TCP connections whose backoff is over.
+    async fn retry_connects(&mut self) {
+        let now = Instant::now();
+        let keys = self
+            .connects
+            .iter()
+            .filter(|(_, connect)| connect.deadline <= now)
+            .map(|(key, _)| *key)
+            .collect::<Vec<_>>();
+        for (src, dst) in keys {
+            let connect = match self.connects.remove(&(src, dst)) {
+                Some(connect) => connect,
+                None => continue,
+            };
+            let span = debug_span!("tcp", src = %src, dst = %dst);
+            if let Err(ref e) = self.connect_tcp(connect).instrument(span).await {
+                warn!("handle {}: {} -> {}: {}", "TCP", src, dst, e);
+            }
+        }
+    }
+
+    fn refuse_tcp_syn(&mut self, src: SocketAddrV4, dst: SocketAddrV4) -> io::Result<()> {
+        {
+            let mut tx_locked = self.tx.lock().unwrap();
+            let tx_state = tx_locked
+                .get_state_mut(dst, src)
+                .ok_or_else(|| io::Error::from(StateError::UnknownConnection))?;
+
+            tx_state.add_acknowledgement(1);
+
+            // Send ACK/RST
+            tx_locked.send_tcp_ack_rst(dst, src)?;
+        }
+
+        // Clean up
+        self.clean_up(src, dst);
+
+        Ok(())
+    }
+
+    fn unreach_tcp_syn(&mut self, tcp: &Tcp, quote: &[u8]) -> io::Result<()> {
+        let src = SocketAddrV4::new(tcp.src_ip_addr(), tcp.src());
+        let dst = SocketAddrV4::new(tcp.dst_ip_addr(), tcp.dst());
+
+        // Send ICMPv4 destination host unreachable quoting the original IPv4 header and the first
+        // 8 bytes of the TCP header
+        self.tx
+            .lock()
+            .unwrap()
+            .send_icmpv4_destination_host_unreachable(*dst.ip(), *src.ip(), quote)?;
+
+        // Clean up
+        self.clean_up(src, dst);
+
+        Ok(())
+    }
+
+    fn refuse_tcp_md5(&mut self, tcp: &Tcp, payload: &[u8]) -> io::Result<()> {
+        let src = SocketAddrV4::new(tcp.src_ip_addr(), tcp.src());
+        let dst = SocketAddrV4::new(tcp.dst_ip_addr(), tcp.dst());
+
+        if tcp.is_rst() {
+            return Ok(());
+        }
+        if tcp.is_syn() && !tcp.is_ack() {
+            warn!(
+                "TCP {} -> {} is signed with MD5 signature but no key is set, reset",
+                src, dst
+            );
+        }
+
+        self.reset_untracked(tcp, payload)
+    }
+
+    /// Resets an untracked connection in response to the given TCP packet. Following RFC 793, the
+    /// RST takes the acknowledgement of the packet as its sequence if the packet has the ACK flag,
+    /// or acknowledges the packet otherwise, so the source will accept it.
+    fn reset_untracked(&mut self, tcp: &Tcp, payload: &[u8]) -> io::Result<()> {
+        let src = SocketAddrV4::new(tcp.src_ip_addr(), tcp.src());
+        let dst = SocketAddrV4::new(tcp.dst_ip_addr(), tcp.dst());
+
+        if tcp.is_ack() {
+            // Send RST
+            self.tx
+                .lock()
+                .unwrap()
+                .send_tcp_rst(dst, src, tcp.acknowledgement())
+        } else {
+            // SYN and FIN occupy a sequence
+            let len = payload.len() as u32 + tcp.is_syn() as u32 + tcp.is_fin() as u32;
+
+            // Send ACK/RST
+            self.tx.lock().unwrap().send_tcp_ack_rst_acknowledging(
+                dst,
+                src,
+                tcp.sequence().wrapping_add(len),
+            )
+        }
+    }
+
+    fn handle_tcp_rst(&mut self, tcp: &Tcp) {
+        let src = SocketAddrV4::new(tcp.src_ip_addr(), tcp.src());
+        let dst = SocketAddrV4::new(tcp.dst_ip_addr(), tcp.dst());
+        let key = (src, dst);
+
+        if tcp.is_ack() {
+            match self.states.get_mut(&key) {
+                Some(state) => {
+                    // Exam ACK
+                    if tcp.sequence() == state.recv_next() {
+                        // Admit RST
+                        state.admit_rst();
+
+                        // Clean up
+                        self.reset(src, dst);
+                    }
+                }
+                None => {
+                    // Clean up
+                    self.reset(src, dst);
+                }
+            }
+        } else {
+            // Clean up
+            self.reset(src, dst);
+        }
+    }
+
+    fn handle_tcp_fin(&mut self, tcp: &Tcp, payload: &[u8]) -> io::Result<()> {
+        let src = SocketAddrV4::new(tcp.src_ip_addr(), tcp.src());
+        let dst = SocketAddrV4::new(tcp.dst_ip_addr(), tcp.dst());
+        let key = (src, dst);
+        let is_exist = self.streams.get(&key).is_some();
+        let is_writable = self.is_tcp_writable(src, dst);
+
+        if is_exist {
+            if is_writable {
+                let state = self
+                    .states
+                    .get_mut(&key)
+                    .ok_or_else(|| io::Error::from(StateError::UnknownConnection))?;
+                if tcp.is_fin() {
+                    // Update FIN sequence
+                    state.set_fin_sequence(tcp.sequence().wrapping_add(payload.len() as u32));
+                }
+
+                // If the receive next is the same as the FIN sequence, the FIN should be popped
+                if let Some(fin_sequence) = state.fin_sequence() {
+                    if fin_sequence == state.recv_next() {
+                        {
+                            let mut tx_locked = self.tx.lock().unwrap();
+                            let tx_state = tx_locked
+                                .get_state_mut(dst, src)
+                                .ok_or_else(|| io::Error::from(StateError::UnknownConnection))?;
+
+                            state.update_state(tx_state);
+
+                            // Admit FIN
+                            state.admit_fin();
+                            state.add_recv_next(1);
+
+                            tx_state.add_acknowledgement(1);
+
+                            // Send ACK0
+                            tx_locked.send_tcp_ack_0(dst, src)?;
+                        }
+                        if state.state().is_closed() {
+                            // Close by remote, TIME_WAIT
+                            // Clean up
+                            self.close(src, dst);
+                        } else {
+                            // Close by local, CLOSE_WAIT or CLOSING
+                            let stream = self
+                                .streams
+                                .get_mut(&key)
+                                .ok_or_else(|| io::Error::from(StateError::UnknownConnection))?;
+                            stream.shutdown(Shutdown::Write);
+                        }
+                    } else {
+                        trace!(
+                            "TCP out of order of {} -> {} at {}",
+                            src,
+                            dst,
+                            tcp.sequence()
+                        );
+
+                        if payload.is_empty() {
+                            // Send ACK0
+                            self.tx.lock().unwrap().send_tcp_ack_0(dst, src)?;
+                        }
+                    }
+                }
+            } else {
+                // Retransmission
+                // Send ACK0
+                self.tx.lock().unwrap().send_tcp_ack_0(dst, src)?;
+            }
+        } else {
+            self.reset_untracked(tcp, payload)?;
+        }
+
+        Ok(())
+    }
+
+    fn reap_idle(&mut self, now: Instant) {
+        let idle_timeout = match self.idle_timeout {
+            Some(idle_timeout) => idle_timeout,
+            None => return,
+        };
+
+        let keys = {
+            let tx_locked = self.tx.lock().unwrap();
+
+            self.states
+                .iter()
+                .filter(|(&(src, dst), state)| {
+                    let activity = match tx_locked.get_state(dst, src) {
+                        Some(tx_state) => max(state.activity(), tx_state.activity()),
+                        None => state.activity(),
+                    };
+
+                    now.saturating_duration_since(activity) >= idle_timeout
+                })
+                .map(|(&key, _)| key)
+                .collect::<Vec<_>>()
+        };
+
+        for (src, dst) in keys {
+            debug!("reset idle TCP {} -> {}", src, dst);
+            self.send_event(Event::Reap {
+                src,
+                dst: Some(dst),
+            });
+            self.abort(src, dst);
+        }
+    }
+
+    fn report_full_windows(&mut self, now: Instant) {
+        let mut full_windows = Vec::new();
+        {
+            let tx_locked = self.tx.lock().unwrap();
+
+            // Windows which open again will be reported next time they are full
+            self.full_windows.retain(|&(src, dst)| {
+                tx_locked
+                    .get_state(dst, src)
+                    .and_then(|state| state.zero_window())
+                    .is_some()
+            });
+            for &(src, dst) in self.streams.keys() {
+                if self.full_windows.contains(&(src, dst)) {
+                    continue;
+                }
+                if let Some(instant) = tx_locked
+                    .get_state(dst, src)
+                    .and_then(|state| state.zero_window())
+                {
+                    let duration = now.saturating_duration_since(instant);
+                    if duration >= self.window_full_threshold {
+                        full_windows.push((src, dst, duration));
+                    }
+                }
+            }
+        }
+
+        for (src, dst, duration) in full_windows {
+            debug!(
+                "TCP {} -> {} has a full window for {:?}",
+                src, dst, duration
+            );
+            self.full_windows.insert((src, dst));
+            if let Some(observer) = &self.observer {
+                observer.on_window_full(src, dst, duration);
+            }
+        }
+    }
+
+    fn reap_idle_datagrams(&mut self, now: Instant) {
+        let timeout = match self.association_timeout {
+            Some(timeout) => timeout,
+            None => return,
+        };
+
+        let srcs = self
+            .datagrams
+            .values()
+            .filter(|worker| now.saturating_duration_since(worker.activity()) >= timeout)
+            .map(|worker| worker.src())
+            .collect::<Vec<_>>();
+
+        for src in srcs {
+            debug!("close idle UDP {}", src);
+            self.send_event(Event::Reap { src, dst: None });
+            self.unbind_local_udp_port(src);
+        }
+    }
+
+    fn abort(&mut self, src: SocketAddrV4, dst: SocketAddrV4) {
+        {
+            let mut tx_locked = self.tx.lock().unwrap();
+            if tx_locked.get_state(dst, src).is_some() {
+                // Send ACK/RST
+                if let Err(ref e) = tx_locked.send_tcp_ack_rst(dst, src) {
+                    warn!("handle {}: {} -> {}: {}", "TCP", dst, src, e);
+                }
+            }
+        }
+
+        // Clean up
+        self.reset(src, dst);
+    }
+
+    fn close(&mut self, src: SocketAddrV4, dst: SocketAddrV4) {
+        if self.streams.contains_key(&(src, dst)) {
+            if let Some(observer) = &self.observer {
+                observer.on_close(src, dst);
+            }
+            self.send_event(Event::Close { src, dst });
+        }
+
+        self.clean_up(src, dst);
+    }
+
+    fn reset(&mut self, src: SocketAddrV4, dst: SocketAddrV4) {
+        if self.streams.contains_key(&(src, dst)) {
+            if let Some(observer) = &self.observer {
+                observer.on_reset(src, dst);
+            }
+            self.send_event(Event::Reset { src, dst });
+        }
+
+        self.clean_up(src, dst);
+    }
+
+    fn clean_up(&mut self, src: SocketAddrV4, dst: SocketAddrV4) {
+        let key = (src, dst);
+
+        if let Some(mut stream) = self.streams.remove(&key) {
+            stream.release();
+        }
+        self.stream_ids.remove(&key);
+        self.connects.remove(&key);
+        self.states.remove(&key);
+        self.spans.remove(&key);
+        self.full_windows.remove(&key);
+
+        self.tx.lock().unwrap().clean_up(dst, src);
+    }
+
+    async fn handle_udp(&mut self, udp: &Udp, payload: &[u8]) -> io::Result<()> {
+        let src = SocketAddrV4::new(udp.src_ip_addr(), udp.src());
+        let dst = SocketAddrV4::new(udp.dst_ip_addr(), udp.dst());
+        let span = debug_span!("udp", src = %src, dst = %dst);
+
+        self.handle_udp_in_span(udp, payload).instrument(span).await
+    }
+
+    async fn handle_udp_in_span(&mut self, udp: &Udp, payload: &[u8]) -> io::Result<()> {
+        let src = SocketAddrV4::new(udp.src_ip_addr(), udp.src());
+        let dst = SocketAddrV4::new(udp.dst_ip_addr(), udp.dst());
+
+        // Filter
+        if !self.filter.lock().unwrap().is_allowed(dst) {
+            debug!("drop UDP {} -> {}: destination is denied", src, dst);
+            self.stats.increase_filtered_frames();
+
+            return Ok(());
+        }
+
+        // Pause
+        if self.is_paused() && !self.datagram_map.contains_key(&src) {
+            debug!("drop UDP {} -> {}: redirector is paused", src, dst);
+
+            return Ok(());
+        }
+
+        // Rewrite
+        let target = self.rewriter.lock().unwrap().rewrite(dst);
+        if target != dst {
+            trace!("rewrite UDP {} -> {} to {}", src, dst, target);
+        }
+
+        // Bind
+        let port = self.bind_local_udp_port(src).await?;
+
+        // Send
+        self.datagrams
+            .get_mut(&port)
+            .ok_or_else(|| io::Error::from(StateError::UnknownConnection))?
+            .send_to_target(payload.to_vec(), dst, target)?;
+
+        Ok(())
+    }
+
+    async fn bind_local_udp_port(&mut self, src: SocketAddrV4) -> io::Result<u16> {
+        // Rebind if the association is terminated
+        if let Some(&local_port) = self.datagram_map.get(&src) {
+            let is_closed = match self.datagrams.get(&local_port) {
+                Some(worker) => worker.is_closed(),
+                None => false,
+            };
+            if is_closed {
+                debug!("rebind UDP {}: association is terminated", src);
+                self.unbind_local_udp_port(src);
+            }
+        }
+
+        let local_port = self.datagram_map.get(&src);
+        match local_port {
+            Some(&local_port) => {
+                // Update LRU
+                self.udp_lru.get(&local_port);
+
+                Ok(local_port)
+            }
+            None => {
+                // Close idle associations before reusing any port
+                if self.udp_lru.len() >= self.udp_lru.cap() {
+                    self.reap_idle_datagrams(Instant::now());
+                }
+
+                let bind_port = if self.udp_lru.len() < self.udp_lru.cap() {
+                    match DatagramWorker::bind_with_upstream(
+                        self.get_tx(),
+                        src,
+                        self.upstream.as_ref(),
+                    )
+                    .await
+                    {
+                        Ok((mut worker, port)) => {
+                            worker.set_strict(self.is_udp_strict);
+                            self.datagrams.insert(port, worker);
+
+                            // Update map and LRU
+                            self.datagram_map.insert(src, port);
+                            self.udp_lru.put(port, src);
+
+                            trace!("bind UDP port {} = {}", port, src);
+                            if let Some(observer) = &self.observer {
+                                observer.on_bind(src, port);
+                            }
+                            self.send_event(Event::Bind { src, port });
+
+                            Ok(port)
+                        }
+                        Err(e) => Err(e),
+                    }
+                } else {
+                    Err(io::Error::from(StateError::PortsExhausted))
+                };
+
+                match bind_port {
+                    Ok(port) => Ok(port),
+                    Err(e) => {
+                        let datagrams = &self.datagrams;
+                        let idle_port = find_idle_udp_port(&self.udp_lru, |port| {
+                            datagrams.get(&port).map(|worker| worker.activity())
+                        });
+                        match idle_port {
+                            None => Err(e),
+                            Some(port) => {
+                                let prev_src = self.udp_lru.pop(&port).unwrap();
+
+                                // Reuse
+                                self.stats.increase_evicted_datagrams();
+                                self.datagram_map.remove(&prev_src);
+                                trace!("reuse UDP port {} = {} to {}", port, prev_src, src);
+                                self.datagram_map.insert(src, port);
+                                self.datagrams.get_mut(&port).unwrap().set_src(&src);
+                                if let Some(observer) = &self.observer {
+                                    observer.on_unbind(prev_src, port);
+                                    observer.on_bind(src, port);
+                                }
+                                self.send_event(Event::Unbind {
+                                    src: prev_src,
+                                    port,
+                                });
+                                self.send_event(Event::Bind { src, port });
+
+                                // Update LRU
+                                self.udp_lru.put(port, src);
+
+                                Ok(port)
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn unbind_local_udp_port(&mut self, src: SocketAddrV4) {
+        let local_port = self.datagram_map.get(&src);
+        if let Some(&local_port) = local_port {
+            self.datagrams.remove(&local_port);
+            self.udp_lru.pop(&local_port);
+            self.datagram_map.remove(&src);
+
+            trace!("unbind UDP port {} = {}", local_port, src);
+            if let Some(observer) = &self.observer {
+                observer.on_unbind(src, local_port);
+            }
+            self.send_event(Event::Unbind {
+                src,
+                port: local_port,
+            });
+        }
+    }
+
+    fn is_src(&self, ip_addr: Ipv4Addr) -> bool {
+        self.src_ip_addrs.contains(ip_addr)
+    }
+
+    fn is_proxy_arp(&self, ip_addr: Ipv4Addr) -> bool {
+        self.proxy_arp_networks.contains(ip_addr)
+    }
+
+    fn has_connections(&self, ip_addr: Ipv4Addr) -> bool {
+        self.streams.keys().any(|(src, _)| *src.ip() == ip_addr)
+            || self.datagram_map.keys().any(|src| *src.ip() == ip_addr)
+    }
+
+    fn get_tx(&self) -> Arc<Mutex<Forwarder>> {
+        Arc::clone(&self.tx)
+    }
+
+    fn is_vlan(&self, vlan: Option<u16>) -> bool {
+        match self.vlan {
+            Some(_) => vlan == self.vlan,
+            None => true,
+        }
+    }
+
+    fn set_tx_hardware_addr(
+        &mut self,
+        ip_addr: Ipv4Addr,
+        hardware_addr: HardwareAddr,
+        vlan: Option<u16>,
+    ) {
+        match self.tx_src_hardware_addr_map.get(&ip_addr) {
+            Some(&(prev_hardware_addr, prev_vlan, instant)) => {
+                if prev_hardware_addr == hardware_addr && prev_vlan == vlan {
+                    return;
+                }
+                // Debounce to avoid thrashing between flapping addresses
+                if instant.elapsed() < Duration::from_millis(HARDWARE_ADDR_DEBOUNCE) {
+                    trace!(
+                        "ignore hardware address {} of {} in debouncing",
+                        hardware_addr,
+                        ip_addr
+                    );
+                    return;
+                }
+                info!(
+                    "Device {} changed from {} to {}",
+                    ip_addr, prev_hardware_addr, hardware_addr
+                );
+            }
+            None => info!("Device {} ({}) joined the network", ip_addr, hardware_addr),
+        }
+
+        {
+            let mut tx_locked = self.tx.lock().unwrap();
+            tx_locked.set_src_hardware_addr(ip_addr, hardware_addr);
+            tx_locked.set_src_vlan(hardware_addr, vlan);
+        }
+        self.tx_src_hardware_addr_map
+            .insert(ip_addr, (hardware_addr, vlan, Instant::now()));
+    }
+}
+
+impl Drop for Redirector {
+    fn drop(&mut self) {
+        if let Some(ticker) = &self.ticker {
+            ticker.abort();
+        }
+
+        // Reset the remaining connections, which cannot be served anymore. The forwarder is used
+        // even if it is poisoned by a panic in another thread
+        if !thread::panicking() {
+            let keys = self.streams.keys().cloned().collect::<Vec<_>>();
+            for (src, dst) in keys {
+                debug!("reset TCP {} -> {} due to drop", src, dst);
+                {
+                    let mut tx_locked = self.tx.lock().unwrap_or_else(PoisonError::into_inner);
+                    if tx_locked.get_state(dst, src).is_some() {
+                        // Send ACK/RST
+                        if let Err(ref e) = tx_locked.send_tcp_ack_rst(dst, src) {
+                            warn!("handle {}: {} -> {}: {}", "TCP", dst, src, e);
+                        }
+                    }
+                    tx_locked.clean_up(dst, src);
+                }
+
+                if let Some(mut stream) = self.streams.remove(&(src, dst)) {
+                    stream.release();
+                }
+                if let Some(observer) = &self.observer {
+                    observer.on_reset(src, dst);
+                }
+                self.send_event(Event::Reset { src, dst });
+            }
+        }
+    }
+}
+
+#[test]
+fn redirector_reap_idle() {
+    let tx = Forwarder::new(
+        Box::new(pcap::BlackHole::new()),
+        1500,
+        pcap::HARDWARE_ADDR_UNSPECIFIED,
+        Ipv4Addr::new(192, 168, 1, 1),
+    );
+    let mut redirector = Redirector::new(
+        Arc::new(Mutex::new(tx)),
+        Ipv4Network::new(Ipv4Addr::new(192, 168, 1, 2), 32).unwrap(),
+        Ipv4Addr::new(192, 168, 1, 1),
+        None,
+        ProxyConfig::new_socks(
+            SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1080),
+            false,
+            false,
+            None,
+        ),
+        None,
+    );
+    redirector.set_idle_timeout(Some(Duration::from_secs(60)));
+
+    let src = SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 2), 10000);
+    let dst = SocketAddrV4::new(Ipv4Addr::new(1, 1, 1, 1), 80);
+    redirector
+        .states
+        .insert((src, dst), TcpRxState::new(src, dst, 0, 0, 0, false));
+    redirector.tx.lock().unwrap().set_state(
+        dst,
+        src,
+        TcpTxState::new(src, dst, 0, 1, u16::MAX, None, false, None, 1460),
+    );
+
+    let now = Instant::now();
+    redirector.reap_idle(now);
+    assert!(redirector.states.contains_key(&(src, dst)));
+
+    redirector.reap_idle(now + Duration::from_secs(61));
+    assert!(!redirector.states.contains_key(&(src, dst)));
+    assert!(redirector.tx.lock().unwrap().get_state(dst, src).is_none());
+}
+
+#[test]
+fn forwarder_send_udp_fragments() {
+    let mut tx = Forwarder::new(
+        Box::new(pcap::BlackHole::new()),
+        576,
+        pcap::HARDWARE_ADDR_UNSPECIFIED,
+        Ipv4Addr::new(192, 168, 1, 1),
+    );
+
+    let src = SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 2), 10000);
+    let dst = SocketAddrV4::new(Ipv4Addr::new(1, 1, 1, 1), 53);
+    let payload = vec![0u8; 4000];
+    tx.send_udp(dst, src, &payload).unwrap();
+
+    // The scratch buffer is kept for later fragmentation
+    let capacity = tx.fragment_buffer.capacity();
+    assert!(capacity >= Udp::minimum_len() + payload.len());
+    tx.send_udp(dst, src, &payload[..2000]).unwrap();
+    assert_eq!(tx.fragment_buffer.capacity(), capacity);
+}
+
+#[test]
+fn forwarder_send_udp_fragments_identification() {
+    use pnet::packet::ethernet::EthernetPacket;
+    use pnet::packet::ipv4::Ipv4Packet;
+    use pnet::packet::Packet;
+
+    let src = SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 2), 10000);
+    let dst = SocketAddrV4::new(Ipv4Addr::new(1, 1, 1, 1), 53);
+
+    let frames = Frames::default();
+    let mut tx = Forwarder::new(
+        Box::new(Recorder(Arc::clone(&frames))),
+        576,
+        pcap::HARDWARE_ADDR_UNSPECIFIED,
+        Ipv4Addr::new(192, 168, 1, 1),
+    );
+    tx.send_udp(dst, src, &[0u8; 4000]).unwrap();
+    tx.send_udp(dst, src, &[0u8; 1000]).unwrap();
+
+    let frames = frames.lock().unwrap();
+    let identifications = frames
+        .iter()
+        .map(|frame| {
+            let ethernet = EthernetPacket::new(frame).unwrap();
+            Ipv4Packet::new(ethernet.payload())
+                .unwrap()
+                .get_identification()
+        })
+        .collect::<Vec<_>>();
+    // 4008 Bytes are sent in 8 fragments, and 1008 Bytes in 2 fragments
+    assert_eq!(identifications, vec![0, 0, 0, 0, 0, 0, 0, 0, 1, 1]);
+}
+
+#[tokio::test]
+async fn redirector_update_hardware_addr() {
+    time::pause();
+    let tx = Forwarder::new(
+        Box::new(pcap::BlackHole::new()),
+        1500,
+        pcap::HARDWARE_ADDR_UNSPECIFIED,
+        Ipv4Addr::new(192, 168, 1, 1),
+    );
+    let mut redirector = Redirector::new(
+        Arc::new(Mutex::new(tx)),
+        Ipv4Network::new(Ipv4Addr::new(192, 168, 1, 2), 32).unwrap(),
+        Ipv4Addr::new(192, 168, 1, 1),
+        None,
+        ProxyConfig::new_socks(
+            SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1080),
+            false,
+            false,
+            None,
+        ),
+        None,
+    );
+
+    let src = Ipv4Addr::new(192, 168, 1, 2);
+    let first = "11:11:11:11:11:11".parse().unwrap();
+    let second = "22:22:22:22:22:22".parse().unwrap();
+    let get = |redirector: &Redirector| {
+        *redirector
+            .tx
+            .lock()
+            .unwrap()
+            .src_hardware_addr_map
+            .get(&src)
+            .unwrap()
+    };
+
+    redirector.set_tx_hardware_addr(src, first, None);
+    assert_eq!(get(&redirector), first);
+
+    // The change in debouncing is ignored
+    redirector.set_tx_hardware_addr(src, second, None);
+    assert_eq!(get(&redirector), first);
+
+    time::advance(Duration::from_millis(HARDWARE_ADDR_DEBOUNCE - 1)).await;
+    redirector.set_tx_hardware_addr(src, second, None);
+    assert_eq!(get(&redirector), first);
+
+    time::advance(Duration::from_millis(1)).await;
+    redirector.set_tx_hardware_addr(src, second, None);
+    assert_eq!(get(&redirector), second);
+}
+
+#[test]
+fn redirector_proxy_arp() {
+    use pnet::packet::arp::ArpPacket;
+    use pnet::packet::ethernet::EthernetPacket;
+    use pnet::packet::Packet;
+
+    let frames = Frames::default();
+    let tx = Forwarder::new(
+        Box::new(Recorder(Arc::clone(&frames))),
+        1500,
+        "22:22:22:22:22:22".parse().unwrap(),
+        Ipv4Addr::new(192, 168, 1, 1),
+    );
+    let mut redirector = Redirector::new(
+        Arc::new(Mutex::new(tx)),
+        Ipv4Network::new(Ipv4Addr::new(192, 168, 1, 0), 24).unwrap(),
+        Ipv4Addr::new(192, 168, 1, 1),
+        None,
+        ProxyConfig::new_socks(
+            SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1080),
+            false,
+            false,
+            None,
+        ),
+        None,
+    );
+    redirector.set_proxy_arp(vec![Ipv4Network::new(Ipv4Addr::new(0, 0, 0, 0), 0).unwrap()]);
+
+    let src = Ipv4Addr::new(192, 168, 1, 2);
+    let src_hardware_addr = "11:11:11:11:11:11".parse().unwrap();
+    let mut request = |dst: Ipv4Addr| {
+        let mut arp = Arp::gratuitous_arp(src_hardware_addr, src);
+        arp.layer.target_proto_addr = dst;
+        let ethernet = Ethernet::new(
+            LayerKinds::Arp,
+            src_hardware_addr,
+            pcap::HARDWARE_ADDR_BROADCAST,
+        )
+        .unwrap();
+        let indicator = Indicator::new(Layers::Ethernet(ethernet), Some(Layers::Arp(arp)), None);
+        redirector.handle_arp(&indicator).unwrap();
+    };
+
+    // The source's own address is not replied
+    request(Ipv4Addr::new(192, 168, 1, 254));
+    request(src);
+    request(Ipv4Addr::new(1, 1, 1, 1));
+
+    let frames = frames.lock().unwrap();
+    let replied = frames
+        .iter()
+        .map(|frame| {
+            let ethernet = EthernetPacket::new(frame).unwrap();
+            ArpPacket::new(ethernet.payload())
+                .unwrap()
+                .get_sender_proto_addr()
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        replied,
+        vec![Ipv4Addr::new(192, 168, 1, 254), Ipv4Addr::new(1, 1, 1, 1)]
+    );
+}
+
+#[test]
+fn redirector_learn_src_ip_addr() {
+    let frames = Frames::default();
+    let tx = Forwarder::new(
+        Box::new(Recorder(Arc::clone(&frames))),
+        1500,
+        "22:22:22:22:22:22".parse().unwrap(),
+        Ipv4Addr::new(192, 168, 1, 1),
+    );
+    let mut redirector = Redirector::new(
+        Arc::new(Mutex::new(tx)),
+        Ipv4Network::new(Ipv4Addr::new(192, 168, 1, 2), 32).unwrap(),
+        Ipv4Addr::new(192, 168, 1, 1),
+        Some(Ipv4Addr::new(192, 168, 1, 1)),
+        ProxyConfig::new_socks(
+            SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1080),
+            false,
+            false,
+            None,
+        ),
+        None,
+    );
+    redirector.set_src_ip_addr(None);
+
+    let request = |redirector: &mut Redirector, src: Ipv4Addr| {
+        let src_hardware_addr = "11:11:11:11:11:11".parse().unwrap();
+        let mut arp = Arp::gratuitous_arp(src_hardware_addr, src);
+        arp.layer.target_proto_addr = Ipv4Addr::new(192, 168, 1, 1);
+        let ethernet = Ethernet::new(
+            LayerKinds::Arp,
+            src_hardware_addr,
+            pcap::HARDWARE_ADDR_BROADCAST,
+        )
+        .unwrap();
+        let indicator = Indicator::new(Layers::Ethernet(ethernet), Some(Layers::Arp(arp)), None);
+        redirector.handle_arp(&indicator).unwrap();
+    };
+
+    // ARP probes are ignored
+    request(&mut redirector, Ipv4Addr::UNSPECIFIED);
+    assert!(redirector.get_src_ip_addrs().is_empty());
+
+    // The first source is pinned
+    request(&mut redirector, Ipv4Addr::new(192, 168, 1, 5));
+    request(&mut redirector, Ipv4Addr::new(192, 168, 1, 6));
+    assert_eq!(
+        redirector.get_src_ip_addrs(),
+        &[Ipv4Network::from(Ipv4Addr::new(192, 168, 1, 5))]
+    );
+    assert_eq!(frames.lock().unwrap().len(), 1);
+
+    redirector.set_src_ip_addr(Some(Ipv4Network::from(Ipv4Addr::new(192, 168, 1, 6))));
+    request(&mut redirector, Ipv4Addr::new(192, 168, 1, 6));
+    assert_eq!(frames.lock().unwrap().len(), 2);
+}
+
+#[tokio::test]
+async fn redirector_reuse_udp_port() {
+    let frames = Frames::default();
+    let mut redirector = redirector(
+        &frames,
+        *src().ip(),
+        Arc::new(proxy::LoopbackUpstream::new()),
+    );
+    redirector.set_max_datagrams(16);
+
+    // 8 active sources
+    let actives = (0..8)
+        .map(|i| SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 2), 10000 + i))
+        .collect::<Vec<_>>();
+    let mut ports = Vec::new();
+    for &src in &actives {
+        ports.push(redirector.bind_local_udp_port(src).await.unwrap());
+    }
+
+    // Interleave 100 sources sending one datagram each, while the active sources keep receiving
+    // traffic, which does not update the LRU
+    for i in 0..100 {
+        let src = SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 3), 10000 + i);
+        redirector.bind_local_udp_port(src).await.unwrap();
+        for port in &ports {
+            let worker = redirector.datagrams.get_mut(port).unwrap();
+            worker.send_to(vec![0], dst()).unwrap();
+        }
+    }
+
+    for (src, port) in actives.iter().zip(&ports) {
+        assert_eq!(redirector.datagram_map.get(src), Some(port));
+    }
+    assert_eq!(redirector.stats.evicted_datagrams(), 92);
+
+    // The map and the LRU are kept consistent
+    assert_eq!(redirector.datagram_map.len(), 16);
+    assert_eq!(redirector.datagrams.len(), 16);
+    for (src, port) in &redirector.datagram_map {
+        assert_eq!(redirector.udp_lru.peek(port), Some(src));
+    }
+}
+
+#[tokio::test]
+async fn redirector_reap_idle_datagrams() {
+    let frames = Frames::default();
+    let mut redirector = redirector(
+        &frames,
+        *src().ip(),
+        Arc::new(proxy::LoopbackUpstream::new()),
+    );
+    redirector.set_max_datagrams(4);
+
+    let srcs = (0..4)
+        .map(|i| SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 2), 10000 + i))
+        .collect::<Vec<_>>();
+    for &src in &srcs {
+        redirector.bind_local_udp_port(src).await.unwrap();
+    }
+
+    // Associations within the timeout are kept
+    redirector.reap_idle_datagrams(Instant::now());
+    assert_eq!(redirector.datagrams.len(), 4);
+
+    // Idle associations are closed and free their slots
+    redirector.reap_idle_datagrams(Instant::now() + Duration::from_millis(ASSOCIATION_TIMEOUT));
+    assert!(redirector.datagrams.is_empty());
+    assert!(redirector.datagram_map.is_empty());
+    assert_eq!(redirector.udp_lru.len(), 0);
+
+    // Idle associations are closed on demand instead of reused if the limit is reached
+    redirector.set_association_timeout(Some(Duration::ZERO));
+    for &src in &srcs {
+        redirector.bind_local_udp_port(src).await.unwrap();
+    }
+    let src = SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 3), 10000);
+    redirector.bind_local_udp_port(src).await.unwrap();
+    assert_eq!(
+        redirector.datagram_map.keys().collect::<Vec<_>>(),
+        vec![&src]
+    );
+    assert_eq!(redirector.stats.evicted_datagrams(), 0);
+
+    // No association is closed without the timeout
+    redirector.set_association_timeout(None);
+    redirector.reap_idle_datagrams(Instant::now() + Duration::from_secs(86400));
+    assert_eq!(redirector.datagrams.len(), 1);
+}
+
+#[test]
+fn retrans_cool_down_srtt() {
+    let ms = Duration::from_millis;
+
+    // The default before the RTT is measured
+    assert_eq!(retrans_cool_down(None, None), ms(RETRANS_COOL_DOWN));
+
+    // One smoothed RTT, in the bounds
+    assert_eq!(retrans_cool_down(None, Some(ms(50))), ms(50));
+    assert_eq!(
+        retrans_cool_down(None, Some(ms(1))),
+        ms(MIN_RETRANS_COOL_DOWN)
+    );
+    assert_eq!(
+        retrans_cool_down(None, Some(ms(60000))),
+        ms(MAX_RETRANS_COOL_DOWN)
+    );
+
+    // A fixed cool down overrides the RTT
+    assert_eq!(retrans_cool_down(Some(ms(300)), Some(ms(50))), ms(300));
+    assert_eq!(retrans_cool_down(Some(ms(300)), None), ms(300));
+}
+
+#[test]
+fn disjoint_u32_range_wrap() {
+    for i in 0..32u32 {
+        let base = (u32::MAX - 16).wrapping_add(i);
+        let at = |n: u32| base.wrapping_add(n);
+
+        assert_eq!(
+            disjoint_u32_range((at(0), at(10)), (at(3), at(5))),
+            vec![(at(0), at(3)), (at(5), at(10))]
+        );
+        assert_eq!(
+            disjoint_u32_range((at(0), at(10)), (base.wrapping_sub(2), at(4))),
+            vec![(at(4), at(10))]
+        );
+        assert_eq!(
+            disjoint_u32_range((at(0), at(10)), (at(6), at(12))),
+            vec![(at(0), at(6))]
+        );
+        assert!(disjoint_u32_range((at(0), at(10)), (base.wrapping_sub(1), at(11))).is_empty());
+    }
+}
+
+#[test]
+fn redirector_forwarder_stats() {
+    let tx = Arc::new(Mutex::new(forwarder(&Frames::default())));
+    let new = || {
+        Redirector::new_with_upstream(
+            Arc::clone(&tx),
+            Ipv4Network::from(*src().ip()),
+            Ipv4Addr::new(192, 168, 1, 1),
+            None,
+            Arc::new(RejectingUpstream(SocksReply::GeneralFailure)),
+            None,
+        )
+    };
+    let (first, second) = (new(), new());
+
+    // Redirectors sharing a forwarder report its statistics together
+    tx.lock().unwrap().stats.increase_black_holes();
+    assert_eq!(first.stats().black_holes(), 1);
+    assert_eq!(second.stats().black_holes(), 1);
+}
+
+#[test]
+fn redirector_futures_send() {
+    fn assert_send<T: Send>(_: &T) {}
+
+    let frames = Frames::default();
+    let mut redirector = redirector(
+        &frames,
+        *src().ip(),
+        Arc::new(RejectingUpstream(SocksReply::GeneralFailure)),
+    );
+    let mut rx: Receiver = Box::new(Replayer::new(vec![]));
+
+    // A guard of the forwarder held across an await would make the futures not `Send`
+    assert_send(&redirector.handle_frame(&[]));
+    assert_send(&redirector.open(&mut rx));
+}
+
+#[tokio::test]
+async fn redirector_retransmit_tcp_ack_syn() {
+    use pnet::packet::tcp::TcpFlags;
+
+    let (src, dst) = (src(), dst());
+
+    let frames = Frames::default();
+    let mut redirector = redirector(&frames, *src.ip(), Arc::new(proxy::LoopbackUpstream::new()));
+    let syn = segment(src, dst, TcpFlags::SYN, 100, 0);
+
+    // The first ACK/SYN is lost
+    receive(&mut redirector, &syn, &[]).await.unwrap();
+    assert_eq!(sent(&frames).len(), 1);
+
+    // The retransmitted SYN is answered with the same ACK/SYN
+    receive(&mut redirector, &syn, &[]).await.unwrap();
+    let sent = sent(&frames);
+    assert_eq!(sent.len(), 1);
+    assert!(sent[0].0.is_syn() && sent[0].0.is_ack());
+    assert_eq!(sent[0].0.acknowledgement(), 101);
+}
+
+#[tokio::test]
+async fn redirector_rewrite_tcp() {
+    use pnet::packet::tcp::TcpFlags;
+    use proxy::SocksVersion;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let remote = match listener.local_addr().unwrap() {
+        std::net::SocketAddr::V4(addr) => addr,
+        std::net::SocketAddr::V6(_) => unreachable!(),
+    };
+    let (src, dst) = (src(), dst());
+    let target = SocketAddrV4::new(Ipv4Addr::new(2, 2, 2, 2), 8080);
+
+    // The SOCKS4 server expects the CONNECT to the target
+    let server = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buf = [0u8; 9];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, [4, 1, 0x1F, 0x90, 2, 2, 2, 2, 0]);
+
+        stream.write_all(&[0, 90, 0, 0, 0, 0, 0, 0]).await.unwrap();
+
+        stream
+    });
+
+    let frames = Frames::default();
+    let proxy =
+        ProxyConfig::new_socks_with_version(remote, SocksVersion::Socks4, false, false, None);
+    let mut redirector = Redirector::new(
+        Arc::new(Mutex::new(forwarder(&frames))),
+        Ipv4Network::new(*src.ip(), 32).unwrap(),
+        testing::LOCAL_IP_ADDR,
+        None,
+        proxy,
+        None,
+    );
+    redirector
+        .get_rewriter()
+        .lock()
+        .unwrap()
+        .add_rule(dst, target);
+
+    let syn = segment(src, dst, TcpFlags::SYN, 100, 0);
+    receive(&mut redirector, &syn, &[]).await.unwrap();
+    let _stream = server.await.unwrap();
+
+    // The source still sees the original destination
+    let sent = sent(&frames);
+    assert_eq!(sent.len(), 1);
+    let tcp = &sent[0].0;
+    assert_eq!(tcp.src_ip_addr(), *dst.ip());
+    assert_eq!(tcp.src(), dst.port());
+    assert!(tcp.is_syn() && tcp.is_ack());
+}
+
+#[test]
+fn forwarder_min_frame_size() {
+    let (src, dst) = (src(), SocketAddrV4::new(Ipv4Addr::new(1, 1, 1, 1), 53));
+
+    let frames = Frames::default();
+    let mut tx = forwarder(&frames);
+    tx.set_src_hardware_addr(*src.ip(), src_hardware_addr());
+
+    // Padded to the minimum frame size by default
+    tx.send_udp(dst, src, &[1, 2]).unwrap();
+    tx.set_min_frame_size(None);
+    tx.send_udp(dst, src, &[1, 2]).unwrap();
+
+    {
+        let frames = frames.lock().unwrap();
+        assert_eq!(frames[0].len(), MINIMUM_FRAME_SIZE);
+        assert_eq!(frames[1].len(), 44);
+        assert!(frames[0][44..].iter().all(|&b| b == 0));
+    }
+    assert_eq!(sizes(&frames), vec![30, 30]);
+}
+
+#[test]
+fn forwarder_mirrored_dscp() {
+    let (src, dst) = (src(), SocketAddrV4::new(Ipv4Addr::new(1, 1, 1, 1), 53));
+
+    let frames = Frames::default();
+    let mut tx = forwarder(&frames);
+    tx.set_src_hardware_addr(*src.ip(), src_hardware_addr());
+    tx.set_dscp(8).unwrap();
+    assert!(tx.set_dscp(64).is_err());
+
+    tx.set_mirrored_dscp(*dst.ip(), *src.ip(), 46);
+    tx.send_udp(dst, src, &[1, 2]).unwrap();
+    assert_eq!(frames.lock().unwrap()[0][15] >> 2, 46);
+
+    // The least recently set pair is dropped if the limit is reached
+    for i in 0..MAX_MIRRORED_DSCP as u32 {
+        tx.set_mirrored_dscp(Ipv4Addr::from(0x0a00_0000 + i), *src.ip(), 46);
+    }
+    assert_eq!(tx.dscp_map.len(), MAX_MIRRORED_DSCP);
+    assert_eq!(tx.get_dscp(*dst.ip(), *src.ip()), 8);
+    assert_eq!(tx.get_dscp(Ipv4Addr::new(10, 0, 0, 0), *src.ip()), 46);
+}
+
+#[test]
+fn forwarder_send_raw() {
+    let frames = Frames::default();
+    let mut tx = forwarder(&frames);
+
+    // Frames are sent as is, padded to the minimum frame size
+    let frame = (0..20).collect::<Vec<u8>>();
+    tx.send_raw(&frame).unwrap();
+    tx.send_raw(&vec![1u8; 1518]).unwrap();
+
+    // Frames shorter than an Ethernet header or exceeding the MTU are rejected
+    let e = tx.send_raw(&frame[..13]).err().unwrap();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+    let e = tx.send_raw(&vec![1u8; 1519]).err().unwrap();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+
+    let frames = frames.lock().unwrap();
+    assert_eq!(frames.len(), 2);
+    assert_eq!(frames[0].len(), MINIMUM_FRAME_SIZE);
+    assert_eq!(&frames[0][..20], &frame[..]);
+    assert!(frames[0][20..].iter().all(|&b| b == 0));
+    assert_eq!(frames[1].len(), 1518);
+}
+
+#[test]
+fn forwarder_retransmit_tcp_event() {
+    let (src, dst) = (src(), dst());
+
+    let frames = Frames::default();
+    let mut tx = tcp_forwarder(&frames, src, dst);
+    let (events_tx, mut events_rx) = observer::event_queue(16);
+    tx.events = Some(events_tx);
+
+    // Nothing is retransmitted without data in the cache
+    tx.retransmit_tcp(dst, src, None).unwrap();
+    assert!(frames.lock().unwrap().is_empty());
+    assert_eq!(events_rx.try_recv(), None);
+
+    tx.queue_tcp(dst, src, &[0u8; 100]).unwrap();
+    tx.retransmit_tcp(dst, src, None).unwrap();
+    assert_eq!(frames.lock().unwrap().len(), 2);
+    assert_eq!(
+        events_rx.try_recv(),
+        Some(Event::Retransmit {
+            src,
+            dst,
+            is_timedout: false
+        })
+    );
+}
+
+#[test]
+fn forwarder_unknown_connection() {
+    let (src, dst) = (src(), dst());
+
+    let frames = Frames::default();
+    let mut tx = forwarder(&frames);
+
+    // The TCP methods report a connection which is not tracked
+    let e = tx.send_tcp(dst, src).unwrap_err();
+    assert!(matches!(
+        e,
+        PcapSocksError::State(StateError::UnknownConnection)
+    ));
+    assert_eq!(e.kind(), io::ErrorKind::NotFound);
+    assert!(matches!(
+        tx.send_tcp_ack_rst(dst, src),
+        Err(PcapSocksError::State(StateError::UnknownConnection))
+    ));
+    assert!(matches!(
+        tx.retransmit_tcp_timedout(dst, src),
+        Err(PcapSocksError::State(StateError::UnknownConnection))
+    ));
+    assert!(frames.lock().unwrap().is_empty());
+}
+
+#[test]
+fn forwarder_set_local_hardware_addr() {
+    use pnet::packet::arp::ArpPacket;
+    use pnet::packet::ethernet::EthernetPacket;
+    use pnet::packet::Packet;
+
+    let hardware_addr = "33:33:33:33:33:33".parse().unwrap();
+
+    let frames = Frames::default();
+    let mut tx = Forwarder::new(
+        Box::new(Recorder(Arc::clone(&frames))),
+        1500,
+        "22:22:22:22:22:22".parse().unwrap(),
+        Ipv4Addr::new(192, 168, 1, 1),
+    );
+    tx.set_src_hardware_addr(
+        Ipv4Addr::new(192, 168, 1, 2),
+        "11:11:11:11:11:11".parse().unwrap(),
+    );
+
+    // Announced only if changed
+    tx.set_local_hardware_addr(hardware_addr).unwrap();
+    tx.set_local_hardware_addr(hardware_addr).unwrap();
+    tx.send_arp_reply(Ipv4Addr::new(192, 168, 1, 2)).unwrap();
+
+    let frames = frames.lock().unwrap();
+    assert_eq!(frames.len(), 2);
+    let ethernet = EthernetPacket::new(&frames[0]).unwrap();
+    assert_eq!(ethernet.get_destination(), pcap::HARDWARE_ADDR_BROADCAST);
+    for frame in frames.iter() {
+        let ethernet = EthernetPacket::new(frame).unwrap();
+        let arp = ArpPacket::new(ethernet.payload()).unwrap();
+        assert_eq!(ethernet.get_source(), hardware_addr);
+        assert_eq!(arp.get_sender_hw_addr(), hardware_addr);
+    }
+}
+
+#[test]
+fn forwarder_segment_counts() {
+    let (src, dst) = (src(), dst());
+
+    let frames = Frames::default();
+    let mut tx = tcp_forwarder(&frames, src, dst);
+
+    // Sent and retransmitted
+    tx.queue_tcp(dst, src, &[0u8; 1000]).unwrap();
+    assert_eq!(tx.get_segment_counts(dst, src), (1, 0));
+    tx.retransmit_tcp(dst, src, None).unwrap();
+    assert_eq!(tx.get_segment_counts(dst, src), (2, 1));
+    assert_eq!(frames.lock().unwrap().len(), 2);
+
+    // The aggregate remains after the connection is cleaned up
+    tx.clean_up(dst, src);
+    assert_eq!(tx.get_segment_counts(dst, src), (0, 0));
+    assert_eq!(tx.stats.sent_segments(), 2);
+    assert_eq!(tx.stats.retrans_ratio(), Some(0.5));
+    assert_eq!(stat::retrans_ratio(0, 0), None);
+}
+
+#[tokio::test]
+async fn forwarder_retransmit_tcp_timedout_sacks() {
+    time::pause();
+    let (src, dst) = (src(), dst());
+
+    let frames = Frames::default();
+    let mut tx = tcp_forwarder(&frames, src, dst);
+
+    // The middle of the segment is SACKed
+    tx.queue_tcp(dst, src, &[0u8; 1000]).unwrap();
+    tx.set_src_sacks(dst, src, vec![(300, 600)]);
+    frames.lock().unwrap().clear();
+
+    // Only the ranges not SACKed are retransmitted after the initial RTO
+    time::advance(Duration::from_millis(1100)).await;
+    tx.retransmit_tcp_timedout(dst, src).unwrap();
+    let ranges = sent(&frames)
+        .iter()
+        .map(|(tcp, payload)| (tcp.sequence(), payload.len()))
+        .collect::<Vec<_>>();
+    assert_eq!(ranges, vec![(0, 300), (600, 400)]);
+
+    // SACK blocks are cleared with the connection
+    tx.clean_up(dst, src);
+    assert!(tx.get_src_sacks(dst, src).is_empty());
+}
+
+#[test]
+fn forwarder_send_tcp_send_mss() {
+    let (src, dst) = (src(), dst());
+
+    let frames = Frames::default();
+    let mut tx = tcp_forwarder(&frames, src, dst);
+
+    // Full-sized segments are sent in the MSS of the path
+    tx.queue_tcp(dst, src, &[0u8; 1460]).unwrap();
+    assert_eq!(sizes(&frames), vec![1500]);
+
+    // Data is segmented in the lowered send MSS
+    let state = tx.get_state_mut(dst, src).unwrap();
+    state.acknowledge(1460);
+    state.lower_send_mss(1460);
+    tx.queue_tcp(dst, src, &[0u8; 1460]).unwrap();
+    assert_eq!(sizes(&frames), vec![770, 770]);
+}
+
+#[test]
+fn forwarder_buffered_stats() {
+    let (src, dst) = (src(), dst());
+
+    let frames = Frames::default();
+    let mut tx = tcp_forwarder(&frames, src, dst);
+    assert_eq!(
+        tx.get_total_cache_capacity(),
+        tx.get_state(dst, src).unwrap().cache().capacity()
+    );
+
+    // The high-water mark follows the queued data without waiting for a sweep
+    tx.queue_tcp(dst, src, &[0u8; 4000]).unwrap();
+    assert_eq!(tx.stats.buffered(), 4000);
+    assert_eq!(tx.stats.max_buffered(), 4000);
+
+    let state = tx.get_state_mut(dst, src).unwrap();
+    state.acknowledge(state.cache().recv_next());
+    tx.queue_tcp(dst, src, &[0u8; 1]).unwrap();
+    assert_eq!(tx.stats.buffered(), tx.get_total_cache_size());
+    assert!(tx.stats.buffered() < 4000);
+    assert_eq!(tx.stats.max_buffered(), 4000);
+}
+
+#[test]
+fn forwarder_send_batch() {
+    use pnet::datalink::{DataLinkSender, NetworkInterface};
+
+    struct CallRecorder(Arc<Mutex<Vec<(usize, usize)>>>);
+
+    impl DataLinkSender for CallRecorder {
+        fn build_and_send(
+            &mut self,
+            num_packets: usize,
+            packet_size: usize,
+            func: &mut dyn FnMut(&mut [u8]),
+        ) -> Option<io::Result<()>> {
+            let mut buffer = vec![0u8; packet_size];
+            for _ in 0..num_packets {
+                func(&mut buffer);
+            }
+            self.0.lock().unwrap().push((num_packets, packet_size));
+
+            Some(Ok(()))
+        }
+
+        fn send_to(&mut self, _: &[u8], _: Option<NetworkInterface>) -> Option<io::Result<()>> {
+            Some(Ok(()))
+        }
+    }
+
+    let (src, dst) = (src(), dst());
+
+    let calls = Arc::new(Mutex::new(Vec::new()));
+    let mut tx = Forwarder::new(
+        Box::new(CallRecorder(Arc::clone(&calls))),
+        1500,
+        pcap::HARDWARE_ADDR_UNSPECIFIED,
+        testing::LOCAL_IP_ADDR,
+    );
+    tx.set_src_hardware_addr(*src.ip(), testing::src_hardware_addr());
+    tx.set_state(
+        dst,
+        src,
+        TcpTxState::new(src, dst, 0, 1, u16::MAX, None, false, None, 1460),
+    );
+
+    // Segments of the same size are flushed in one operation
+    let sequence = tx.get_state(dst, src).unwrap().sequence();
+    tx.send_tcp_ack(dst, src, sequence, &[0u8; 4000], false, false)
+        .unwrap();
+    assert_eq!(*calls.lock().unwrap(), vec![(2, 1514), (1, 1134)]);
+    calls.lock().unwrap().clear();
+
+    // So are fragments
+    tx.send_udp(dst, src, &[0u8; 4000]).unwrap();
+    assert_eq!(*calls.lock().unwrap(), vec![(2, 1514), (1, 1082)]);
+    calls.lock().unwrap().clear();
+
+    // A single frame is sent as is
+    tx.send_udp(dst, src, &[0u8; 100]).unwrap();
+    assert_eq!(*calls.lock().unwrap(), vec![(1, 142)]);
+}
+
+#[test]
+fn forwarder_forward_after_close() {
+    let (src, dst) = (src(), dst());
+
+    let frames = Frames::default();
+    let mut tx = tcp_forwarder(&frames, src, dst);
+
+    // The FIN waits for the data in flight, and late data is refused
+    ForwardStream::forward(&mut tx, dst, src, &[0u8; 100]).unwrap();
+    ForwardStream::close(&mut tx, dst, src).unwrap();
+    let e = ForwardStream::forward(&mut tx, dst, src, &[1u8; 100])
+        .err()
+        .unwrap();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    assert!(tx.get_state(dst, src).unwrap().queue().is_empty());
+
+    // The FIN follows all the data once acknowledged
+    tx.get_state_mut(dst, src).unwrap().acknowledge(100);
+    tx.send_tcp(dst, src).unwrap();
+    assert!(tx.queue_tcp(dst, src, &[1u8; 100]).is_err());
+
+    let fins = sent(&frames)
+        .iter()
+        .map(|(tcp, _)| (tcp.sequence(), tcp.is_fin()))
+        .collect::<Vec<_>>();
+    assert_eq!(fins, vec![(0, false), (100, true)]);
+}
+
+#[test]
+fn forwarder_send_tcp_ack_psh() {
+    let (src, dst) = (src(), dst());
+
+    let frames = Frames::default();
+    let mut tx = tcp_forwarder(&frames, src, dst);
+
+    // Only the last segment is pushed
+    tx.send_tcp_ack(dst, src, 0, &[0u8; 2000], false, false)
+        .unwrap();
+
+    let pushes = sent(&frames)
+        .iter()
+        .map(|(tcp, _)| (tcp.is_ack(), tcp.is_psh()))
+        .collect::<Vec<_>>();
+    assert_eq!(pushes, vec![(true, false), (true, true)]);
+}
+
+#[test]
+fn forwarder_send_tcp_ack_syn_mss() {
+    let (src, dst) = (src(), dst());
+
+    let frames = Frames::default();
+    let mut tx = forwarder(&frames);
+    tx.set_src_hardware_addr(*src.ip(), src_hardware_addr());
+    tx.set_state(
+        dst,
+        src,
+        TcpTxState::new(src, dst, 0, 1, u16::MAX, Some(7), false, Some(8), 1460),
+    );
+
+    // The MSS is derived from the MTU
+    tx.send_tcp_ack_syn(dst, src).unwrap();
+    let (tcp, _) = sent(&frames).pop().unwrap();
+    assert!(tcp.is_syn() && tcp.is_ack());
+    assert_eq!(tcp.mss(), Some(1460));
+    assert_eq!(tx.get_state(dst, src).unwrap().recv_mss(), Some(1460));
+
+    // The silly window is compared with the MSS in bytes after scaling by the window scale
+    // advertised to the source, 12 << 7 = 1536 and 11 << 7 = 1408
+    tx.get_state_mut(dst, src).unwrap().set_window(12);
+    assert_eq!(tx.get_tcp_window(dst, src), 12);
+    tx.get_state_mut(dst, src).unwrap().set_window(11);
+    assert_eq!(tx.get_tcp_window(dst, src), 0);
+    tx.get_state_mut(dst, src).unwrap().set_window(8);
+    assert_eq!(tx.get_tcp_window(dst, src), 0);
+}
+
+#[test]
+fn forwarder_set_tcp_window_override() {
+    let (src, dst) = (src(), dst());
+
+    let frames = Frames::default();
+    let mut tx = tcp_forwarder(&frames, src, dst);
+    tx.get_state_mut(dst, src).unwrap().set_window(8192);
+    let window = |frames: &Frames| sent(frames).pop().unwrap().0.window();
+
+    // The window is closed for all the source ports, and the override of the port takes
+    // precedence
+    tx.set_tcp_window_override(dst, None, Some(0)).unwrap();
+    assert!(frames.lock().unwrap().is_empty());
+    tx.send_tcp_ack_0(dst, src).unwrap();
+    assert_eq!(window(&frames), 0);
+    tx.set_tcp_window_override(dst, Some(src.port()), Some(1024))
+        .unwrap();
+    assert_eq!(window(&frames), 1024);
+    assert!(frames.lock().unwrap().is_empty());
+
+    // The window computed from the buffer is resumed
+    tx.set_tcp_window_override(dst, None, None).unwrap();
+    tx.set_tcp_window_override(dst, Some(src.port()), Some(0))
+        .unwrap();
+    tx.set_tcp_window_override(dst, Some(src.port()), None)
+        .unwrap();
+    assert_eq!(window(&frames), 8192);
+    assert_eq!(tx.get_tcp_window_override(dst, src), None);
+}
+
+#[tokio::test]
+async fn redirector_tcp_simultaneous_close() {
+    use pnet::packet::tcp::TcpFlags;
+    use tcp::TcpState;
+
+    let (src, dst) = (src(), dst());
+    let key = (src, dst);
+
+    // The destination closes as soon as the connection is made
+    let (upstream, remote) = DuplexUpstream::new();
+    drop(remote);
+    let frames = Frames::default();
+    let mut redirector = redirector(&frames, *src.ip(), Arc::new(upstream));
+
+    // Handshake
+    let syn = segment(src, dst, TcpFlags::SYN, 100, 0);
+    receive(&mut redirector, &syn, &[]).await.unwrap();
+    let sequence = sent(&frames)[0].0.sequence();
+    let ack = segment(src, dst, TcpFlags::ACK, 101, sequence.wrapping_add(1));
+    receive(&mut redirector, &ack, &[]).await.unwrap();
+    assert_eq!(
+        redirector.states.get(&key).unwrap().state(),
+        TcpState::Established
+    );
+
+    // The FIN from the destination
+    let mut fin = None;
+    for _ in 0..100 {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        fin = sent(&frames).into_iter().find(|(tcp, _)| tcp.is_fin());
+        if fin.is_some() {
+            break;
+        }
+    }
+    assert_eq!(fin.unwrap().0.sequence(), sequence.wrapping_add(1));
+
+    // The FIN from the source crosses the FIN from the destination
+    let fin = segment(
+        src,
+        dst,
+        TcpFlags::FIN | TcpFlags::ACK,
+        101,
+        sequence.wrapping_add(1),
+    );
+    receive(&mut redirector, &fin, &[]).await.unwrap();
+    assert_eq!(
+        redirector.states.get(&key).unwrap().state(),
+        TcpState::Closing
+    );
+    assert!(sent(&frames)
+        .iter()
+        .any(|(tcp, _)| !tcp.is_fin() && tcp.acknowledgement() == 102));
+
+    // The FIN from the destination is acknowledged
+    let ack = segment(src, dst, TcpFlags::ACK, 102, sequence.wrapping_add(2));
+    receive(&mut redirector, &ack, &[]).await.unwrap();
+    assert!(!redirector.states.contains_key(&key));
+    assert!(!redirector.streams.contains_key(&key));
+}
+
+#[test]
+fn select_interface_candidates() {
+    assert_eq!(
+        select_interface(vec![]).err(),
+        Some(InterfaceError::NotFound)
+    );
+    assert!(select_interface(vec![Interface::new()]).is_ok());
+    assert_eq!(
+        select_interface(vec![Interface::new(), Interface::new()]).err(),
+        Some(InterfaceError::Ambiguous(vec![
+            String::new(),
+            String::new()
+        ]))
+    );
+}
+
+#[tokio::test]
+async fn redirector_open_gives_back_receiver() {
+    let frames = Frames::default();
+    let mut redirector = redirector(
+        &frames,
+        *src().ip(),
+        Arc::new(RejectingUpstream(SocksReply::GeneralFailure)),
+    );
+    let mut rx: Receiver = Box::new(Replayer::new(vec![vec![0u8; 4]]));
+
+    // The frame is read in the capture task, and the error of the read stops the redirection
+    let e = redirector.open(&mut rx).await.unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+    assert_eq!(redirector.stats().malformed_frames(), 1);
+
+    // The receive half is given back rather than the stand-in
+    assert_eq!(rx.next().unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+}
+
+#[test]
+fn wait_timedout_remaining() {
+    let wait = Duration::from_millis(50);
+
+    let instant = std::time::Instant::now();
+    wait_timedout(instant, wait);
+    assert!(instant.elapsed() >= wait);
+
+    // A read blocked beyond the wait is not delayed further
+    let instant = std::time::Instant::now();
+    thread::sleep(wait);
+    let elapsed = instant.elapsed();
+    wait_timedout(instant, wait);
+    assert!(instant.elapsed() < elapsed + wait);
+}
+
+#[tokio::test]
+async fn spawn_ticker_retransmit() {
+    time::pause();
+    let (src, dst) = (src(), dst());
+
+    let frames = Frames::default();
+    let mut tx = tcp_forwarder(&frames, src, dst);
+    tx.queue_tcp(dst, src, &[0u8; 100]).unwrap();
+    assert_eq!(frames.lock().unwrap().len(), 1);
+
+    // The data is retransmitted after the RTO without any traffic
+    let tx = Arc::new(Mutex::new(tx));
+    let ticker = spawn_ticker(Arc::downgrade(&tx), Duration::from_millis(100));
+    for _ in 0..15 {
+        time::advance(Duration::from_millis(100)).await;
+    }
+    assert_eq!(frames.lock().unwrap().len(), 2);
+
+    // The ticker stops once the forwarder is dropped
+    drop(tx);
+    ticker.await.unwrap();
+}
+
+#[tokio::test]
+async fn redirector_upstream() {
+    use pnet::packet::tcp::TcpFlags;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let (src, dst) = (src(), dst());
+
+    let (upstream, mut remote) = DuplexUpstream::new();
+    let frames = Frames::default();
+    let mut redirector = redirector(&frames, *src.ip(), Arc::new(upstream));
+
+    // Handshake
+    let syn = segment(src, dst, TcpFlags::SYN, 100, 0);
+    receive(&mut redirector, &syn, &[]).await.unwrap();
+    let sequence = sent(&frames)[0].0.sequence().wrapping_add(1);
+    let ack = segment(src, dst, TcpFlags::ACK, 101, sequence);
+    receive(&mut redirector, &ack, &[]).await.unwrap();
+
+    // From the source to the upstream
+    let psh = segment(src, dst, TcpFlags::ACK | TcpFlags::PSH, 101, sequence);
+    receive(&mut redirector, &psh, b"ping").await.unwrap();
+    let mut buffer = [0u8; 4];
+    remote.read_exact(&mut buffer).await.unwrap();
+    assert_eq!(&buffer, b"ping");
+
+    // From the upstream to the source
+    remote.write_all(b"pong").await.unwrap();
+    let mut payload = None;
+    for _ in 0..100 {
+        time::sleep(Duration::from_millis(10)).await;
+        payload = sent(&frames)
+            .into_iter()
+            .find(|(_, payload)| !payload.is_empty());
+        if payload.is_some() {
+            break;
+        }
+    }
+    let (tcp, payload) = payload.unwrap();
+    assert_eq!((tcp.sequence(), payload), (sequence, b"pong".to_vec()));
+}
+
+#[tokio::test]
+async fn redirector_loopback() {
+    use pnet::packet::tcp::TcpFlags;
+
+    let (src, dst) = (src(), dst());
+
+    let frames = Frames::default();
+    let upstream = proxy::LoopbackUpstream::new_with_handler(|_, data| data.to_ascii_uppercase());
+    let mut redirector = redirector(&frames, *src.ip(), Arc::new(upstream));
+
+    // Handshake
+    let syn = segment(src, dst, TcpFlags::SYN, 100, 0);
+    receive(&mut redirector, &syn, &[]).await.unwrap();
+    let sequence = sent(&frames)[0].0.sequence().wrapping_add(1);
+    let ack = segment(src, dst, TcpFlags::ACK, 101, sequence);
+    receive(&mut redirector, &ack, &[]).await.unwrap();
+
+    // The data from the source is responded by the handler through the packet path
+    let psh = segment(src, dst, TcpFlags::ACK | TcpFlags::PSH, 101, sequence);
+    receive(&mut redirector, &psh, b"ping").await.unwrap();
+    let mut payload = None;
+    for _ in 0..100 {
+        time::sleep(Duration::from_millis(10)).await;
+        payload = sent(&frames)
+            .into_iter()
+            .find(|(_, payload)| !payload.is_empty());
+        if payload.is_some() {
+            break;
         }
+    }
+    let (tcp, payload) = payload.unwrap();
+    assert_eq!((tcp.sequence(), payload), (sequence, b"PING".to_vec()));
+    assert_eq!(tcp.acknowledgement(), 105);
+}
 
-        Ok(())
+#[tokio::test]
+async fn redirector_connections() {
+    use pnet::packet::tcp::TcpFlags;
+
+    let (src, dst) = (src(), dst());
+
+    let frames = Frames::default();
+    let mut redirector = redirector(&frames, *src.ip(), Arc::new(proxy::LoopbackUpstream::new()));
+    assert!(redirector.connections().is_empty());
+
+    // An established TCP connection with data echoed
+    let syn = segment(src, dst, TcpFlags::SYN, 100, 0);
+    receive(&mut redirector, &syn, &[]).await.unwrap();
+    let sequence = sent(&frames)[0].0.sequence().wrapping_add(1);
+    let ack = segment(src, dst, TcpFlags::ACK, 101, sequence);
+    receive(&mut redirector, &ack, &[]).await.unwrap();
+    let psh = segment(src, dst, TcpFlags::ACK | TcpFlags::PSH, 101, sequence);
+    receive(&mut redirector, &psh, b"ping").await.unwrap();
+    for _ in 0..100 {
+        time::sleep(Duration::from_millis(10)).await;
+        if sent(&frames).iter().any(|(_, payload)| !payload.is_empty()) {
+            break;
+        }
     }
 
-    async fn handle_tcp_syn(&mut self, tcp: &Tcp) -> io::Result<()> {
-        let src = SocketAddrV4::new(tcp.src_ip_addr(), tcp.src());
-        let dst = SocketAddrV4::new(tcp.dst_ip_addr(), tcp.dst());
-        let key = (src, dst);
-        let is_exist = self.streams.get(&key).is_some();
+    // A UDP connection
+    let udp_src = SocketAddrV4::new(*src.ip(), 10000);
+    let port = redirector.bind_local_udp_port(udp_src).await.unwrap();
+
+    let connections = redirector.connections();
+    assert_eq!(connections.len(), 2);
+    let tcp = connections
+        .iter()
+        .find(|connection| connection.kind == LayerKinds::Tcp)
+        .unwrap();
+    assert_eq!((tcp.src, tcp.dst, tcp.local_port), (src, Some(dst), None));
+    assert_eq!(tcp.state, Some(TcpState::Established));
+    assert_eq!(tcp.send_next, Some(sequence.wrapping_add(4)));
+    assert_eq!(tcp.recv_next, Some(105));
+    assert_eq!(tcp.tx_size, 4);
+    let udp = connections
+        .iter()
+        .find(|connection| connection.kind == LayerKinds::Udp)
+        .unwrap();
+    assert_eq!(
+        (udp.src, udp.dst, udp.local_port),
+        (udp_src, None, Some(port))
+    );
+    assert_eq!(udp.state, None);
+}
 
-        // Connect if not connected, drop if established
-        if !is_exist {
-            // Clean up
-            self.clean_up(src, dst);
+#[tokio::test]
+async fn redirector_observer() {
+    use pnet::packet::tcp::TcpFlags;
 
-            // Admit SYN
-            let wscale = match ENABLE_WSCALE {
-                true => tcp.wscale(),
-                false => None,
-            };
-            let recv_wscale = wscale.map(|wscale| min(wscale, MAX_RECV_WSCALE));
-            let sack_perm = ENABLE_SACK && tcp.is_sack_perm();
-            let state = TcpRxState::new(src, dst, tcp.sequence(), wscale.unwrap_or(0), sack_perm);
+    struct EventRecorder(Mutex<Vec<String>>);
 
-            {
-                let mut tx_locked = self.tx.lock().unwrap();
+    impl EventRecorder {
+        fn push(&self, event: String) {
+            self.0.lock().unwrap().push(event);
+        }
+    }
 
-                let mut rng = rand::thread_rng();
-                let sequence = rng.gen::<u32>();
-                let acknowledgement = tcp.sequence().checked_add(1).unwrap_or(0);
-                if let Some(mss) = tcp.mss() {
-                    let mtu = Ipv4::minimum_len() + Tcp::minimum_len() + mss as usize;
-                    if tx_locked.set_src_mtu(tcp.src_ip_addr(), mtu) {
-                        info!("Update MTU of {} to {}", tcp.src_ip_addr(), mtu);
-                    }
-                }
+    impl Observer for EventRecorder {
+        fn on_connect(&self, src: SocketAddrV4, dst: SocketAddrV4) {
+            self.push(format!("connect {} {}", src, dst));
+        }
 
-                let tx_state = TcpTxState::new(
-                    src,
-                    dst,
-                    sequence,
-                    acknowledgement,
-                    tcp.window(),
-                    recv_wscale,
-                    sack_perm,
-                    wscale,
-                    tx_locked.get_src_mtu(tcp.src_ip_addr())
-                        - (Ipv4::minimum_len() + Tcp::minimum_len()),
-                );
-                tx_locked.set_state(dst, src, tx_state);
-            }
+        fn on_close(&self, src: SocketAddrV4, dst: SocketAddrV4) {
+            self.push(format!("close {} {}", src, dst));
+        }
 
-            // Connect
-            let stream = StreamWorker::connect(self.get_tx(), src, dst, &self.proxy).await;
+        fn on_reset(&self, src: SocketAddrV4, dst: SocketAddrV4) {
+            self.push(format!("reset {} {}", src, dst));
+        }
 
-            let stream = match stream {
-                Ok(stream) => stream,
-                Err(e) => {
-                    {
-                        let mut tx_locked = self.tx.lock().unwrap();
-                        let tx_state = tx_locked
-                            .get_state_mut(dst, src)
-                            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+        fn on_bind(&self, src: SocketAddrV4, port: u16) {
+            self.push(format!("bind {} {}", src, port));
+        }
 
-                        tx_state.add_acknowledgement(1);
+        fn on_unbind(&self, src: SocketAddrV4, port: u16) {
+            self.push(format!("unbind {} {}", src, port));
+        }
+    }
 
-                        // Send ACK/RST
-                        tx_locked.send_tcp_ack_rst(dst, src)?;
-                    }
+    let (src, dst) = (src(), dst());
+
+    let frames = Frames::default();
+    let mut redirector = redirector(&frames, *src.ip(), Arc::new(proxy::LoopbackUpstream::new()));
+    let recorder = Arc::new(EventRecorder(Mutex::new(Vec::new())));
+    redirector.set_observer(Some(recorder.clone()));
+
+    // A connection reset, and a connection closed
+    let syn = segment(src, dst, TcpFlags::SYN, 100, 0);
+    receive(&mut redirector, &syn, &[]).await.unwrap();
+    redirector.abort(src, dst);
+    receive(&mut redirector, &syn, &[]).await.unwrap();
+    redirector.close(src, dst);
+    // Closing again is not reported
+    redirector.close(src, dst);
+
+    // A UDP port bound, and unbound as idle
+    let udp_src = SocketAddrV4::new(*src.ip(), 10000);
+    let port = redirector.bind_local_udp_port(udp_src).await.unwrap();
+    redirector.set_association_timeout(Some(Duration::ZERO));
+    redirector.reap_idle_datagrams(Instant::now());
+
+    assert_eq!(
+        *recorder.0.lock().unwrap(),
+        vec![
+            format!("connect {} {}", src, dst),
+            format!("reset {} {}", src, dst),
+            format!("connect {} {}", src, dst),
+            format!("close {} {}", src, dst),
+            format!("bind {} {}", udp_src, port),
+            format!("unbind {} {}", udp_src, port),
+        ]
+    );
+}
 
-                    // Clean up
-                    self.clean_up(src, dst);
+#[tokio::test]
+async fn redirector_report_full_windows() {
+    use pnet::packet::tcp::TcpFlags;
 
-                    return Err(e);
-                }
-            };
+    struct WindowRecorder(Mutex<Vec<(SocketAddrV4, SocketAddrV4)>>);
 
-            self.states.insert(key, state);
-            self.streams.insert(key, stream);
+    impl Observer for WindowRecorder {
+        fn on_window_full(&self, src: SocketAddrV4, dst: SocketAddrV4, _: Duration) {
+            self.0.lock().unwrap().push((src, dst));
         }
-
-        Ok(())
     }
 
-    fn handle_tcp_rst(&mut self, tcp: &Tcp) {
-        let src = SocketAddrV4::new(tcp.src_ip_addr(), tcp.src());
-        let dst = SocketAddrV4::new(tcp.dst_ip_addr(), tcp.dst());
-        let key = (src, dst);
+    let (src, dst) = (src(), dst());
+
+    let frames = Frames::default();
+    let mut redirector = redirector(&frames, *src.ip(), Arc::new(proxy::LoopbackUpstream::new()));
+    let recorder = Arc::new(WindowRecorder(Mutex::new(Vec::new())));
+    redirector.set_observer(Some(recorder.clone()));
+    redirector.set_window_full_threshold(Duration::from_secs(1));
+    let syn = segment(src, dst, TcpFlags::SYN, 100, 0);
+    receive(&mut redirector, &syn, &[]).await.unwrap();
+    let set_window = |redirector: &mut Redirector, window| {
+        let mut tx_locked = redirector.tx.lock().unwrap();
+        tx_locked
+            .get_state_mut(dst, src)
+            .unwrap()
+            .set_window(window);
+    };
+    let reported = || recorder.0.lock().unwrap().len();
+
+    // A zero window is reported beyond the threshold, and only once
+    set_window(&mut redirector, 0);
+    let now = Instant::now();
+    redirector.report_full_windows(now);
+    assert_eq!(reported(), 0);
+    redirector.report_full_windows(now + Duration::from_secs(1));
+    assert_eq!(reported(), 1);
+    redirector.report_full_windows(now + Duration::from_secs(2));
+    assert_eq!(reported(), 1);
+    assert!(redirector.connections()[0].zero_window.is_some());
+
+    // A window which opens again is reported next time it is full
+    set_window(&mut redirector, 1);
+    redirector.report_full_windows(now + Duration::from_secs(3));
+    assert!(redirector.connections()[0].zero_window.is_none());
+    set_window(&mut redirector, 0);
+    redirector.report_full_windows(Instant::now() + Duration::from_secs(1));
+    assert_eq!(*recorder.0.lock().unwrap(), vec![(src, dst), (src, dst)]);
+}
 
-        if tcp.is_ack() {
-            match self.states.get(&key) {
-                Some(state) => {
-                    // Exam ACK
-                    if tcp.sequence() == state.recv_next() {
-                        // Admit RST
-                        // Clean up
-                        self.clean_up(src, dst);
-                    }
-                }
-                None => {
-                    // Clean up
-                    self.clean_up(src, dst);
-                }
+#[tokio::test]
+async fn redirector_tcp_unreachable() {
+    use pnet::packet::ethernet::EthernetPacket;
+    use pnet::packet::icmp::{IcmpPacket, IcmpTypes};
+    use pnet::packet::ip::IpNextHeaderProtocols;
+    use pnet::packet::ipv4::Ipv4Packet;
+    use pnet::packet::tcp::{TcpFlags, TcpPacket};
+    use pnet::packet::Packet;
+
+    let (src, dst) = (src(), dst());
+
+    let ipv4s = |frames: &Frames| {
+        frames
+            .lock()
+            .unwrap()
+            .drain(..)
+            .map(|frame| {
+                let ethernet = EthernetPacket::new(&frame).unwrap();
+                let ipv4 = Ipv4Packet::new(ethernet.payload()).unwrap();
+
+                (
+                    ipv4.get_next_level_protocol(),
+                    ipv4.payload().to_vec(),
+                    (ipv4.get_source(), ipv4.get_destination()),
+                )
+            })
+            .collect::<Vec<_>>()
+    };
+
+    for &(reply, is_unreachable) in &[
+        (SocksReply::HostUnreachable, true),
+        (SocksReply::NetworkUnreachable, true),
+        (SocksReply::ConnectionRefused, false),
+    ] {
+        let frames = Frames::default();
+        let mut redirector = redirector(&frames, *src.ip(), Arc::new(RejectingUpstream(reply)));
+
+        let syn = segment(src, dst, TcpFlags::SYN, 100, 0);
+        let e = receive(&mut redirector, &syn, &[]).await.unwrap_err();
+        assert_eq!(SocksReply::from_io_error(&e), Some(reply));
+
+        // Unreachable destinations are reported in ICMPv4 instead of ACK/RST
+        let sent = ipv4s(&frames);
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].2, (*dst.ip(), *src.ip()));
+        match is_unreachable {
+            true => {
+                assert_eq!(sent[0].0, IpNextHeaderProtocols::Icmp);
+                let icmp = IcmpPacket::new(&sent[0].1).unwrap();
+                assert_eq!(icmp.get_icmp_type(), IcmpTypes::DestinationUnreachable);
+                assert_eq!(icmp.get_icmp_code().0, 1);
+                // The received IPv4 header and the first 8 bytes of the TCP header are quoted
+                assert_eq!(&icmp.payload()[4..], &frame(&syn, &[])[14..14 + 20 + 8]);
+                assert_eq!(
+                    &icmp.payload()[4 + 20..],
+                    &[0x27, 0x10, 0, 80, 0, 0, 0, 100]
+                );
+            }
+            false => {
+                assert_eq!(sent[0].0, IpNextHeaderProtocols::Tcp);
+                let tcp = TcpPacket::new(&sent[0].1).unwrap();
+                assert_eq!(tcp.get_flags(), TcpFlags::ACK | TcpFlags::RST);
             }
-        } else {
-            // Clean up
-            self.clean_up(src, dst);
         }
+        assert!(!redirector.states.contains_key(&(src, dst)));
     }
+}
 
-    fn handle_tcp_fin(&mut self, tcp: &Tcp, payload: &[u8]) -> io::Result<()> {
-        let src = SocketAddrV4::new(tcp.src_ip_addr(), tcp.src());
-        let dst = SocketAddrV4::new(tcp.dst_ip_addr(), tcp.dst());
-        let key = (src, dst);
-        let is_exist = self.streams.get(&key).is_some();
-        let (is_writable, is_readable) = match self.streams.get(&key) {
-            Some(stream) => (!stream.is_tx_closed(), !stream.is_rx_closed()),
-            None => (false, false),
-        };
+#[tokio::test]
+async fn redirector_tcp_reset_untracked() {
+    use pnet::packet::tcp::TcpFlags;
+
+    let (src, dst) = (src(), dst());
+
+    let frames = Frames::default();
+    let mut redirector = redirector(
+        &frames,
+        *src.ip(),
+        Arc::new(RejectingUpstream(SocksReply::GeneralFailure)),
+    );
+    let reset = |frames: &Frames| {
+        let (tcp, _) = sent(frames).pop().unwrap();
+
+        (
+            tcp.is_ack(),
+            tcp.is_rst(),
+            tcp.sequence(),
+            tcp.acknowledgement(),
+        )
+    };
 
-        if is_exist {
-            if is_writable {
-                let state = self
-                    .states
-                    .get_mut(&key)
-                    .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
-                if tcp.is_fin() {
-                    // Update FIN sequence
-                    state.set_fin_sequence(
-                        tcp.sequence()
-                            .checked_add(payload.len() as u32)
-                            .unwrap_or_else(|| payload.len() as u32 - (u32::MAX - tcp.sequence())),
-                    );
-                }
+    // A stray segment with the ACK flag is reset at its acknowledgement
+    let tcp = segment(src, dst, TcpFlags::ACK | TcpFlags::PSH, 100, 200);
+    receive(&mut redirector, &tcp, &[0u8; 10]).await.unwrap();
+    assert_eq!(reset(&frames), (false, true, 200, 0));
 
-                // If the receive next is the same as the FIN sequence, the FIN should be popped
-                if let Some(fin_sequence) = state.fin_sequence() {
-                    if fin_sequence == state.recv_next() {
-                        // Admit FIN
-                        state.admit_fin();
-                        state.add_recv_next(1);
+    // A stray segment without the ACK flag is acknowledged, including the FIN
+    let tcp = segment(src, dst, TcpFlags::FIN, 100, 200);
+    receive(&mut redirector, &tcp, &[0u8; 10]).await.unwrap();
+    assert_eq!(reset(&frames), (true, true, 0, 111));
+}
 
-                        {
-                            let mut tx_locked = self.tx.lock().unwrap();
-                            let tx_state = tx_locked
-                                .get_state_mut(dst, src)
-                                .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+#[tokio::test]
+async fn redirector_tcp_connect_retry() {
+    use pnet::packet::tcp::TcpFlags;
+
+    let (src, dst) = (src(), dst());
+
+    let upstream = Arc::new(CountingUpstream::new(Some(
+        io::ErrorKind::ConnectionRefused,
+    )));
+    let frames = Frames::default();
+    let mut redirector = redirector(&frames, *src.ip(), upstream.clone());
+    redirector.set_connect_retries(1);
+    redirector.set_connect_backoff(Duration::ZERO);
+    let syn = segment(src, dst, TcpFlags::SYN, 100, 0);
+
+    // A transient error schedules a retry instead of resetting the connection
+    receive(&mut redirector, &syn, &[]).await.unwrap();
+    assert_eq!(upstream.count(), 1);
+    assert!(frames.lock().unwrap().is_empty());
+
+    // A retransmitted SYN does not connect again while waiting for the retry
+    receive(&mut redirector, &syn, &[]).await.unwrap();
+    assert_eq!(upstream.count(), 1);
+
+    // The connection is reset once the retries are exhausted
+    redirector.retry_connects().await;
+    assert_eq!(upstream.count(), 2);
+    let (tcp, _) = sent(&frames).pop().unwrap();
+    assert!(tcp.is_rst());
+    assert!(redirector.connects.is_empty());
+}
 
-                            tx_state.add_acknowledgement(1);
+#[tokio::test]
+async fn redirector_tcp_teardown() {
+    use pnet::packet::tcp::TcpFlags;
+
+    let (src, dst) = (src(), dst());
+
+    let frames = Frames::default();
+    let mut redirector = redirector(&frames, *src.ip(), Arc::new(proxy::LoopbackUpstream::new()));
+    let syn = segment(src, dst, TcpFlags::SYN, 100, 0);
+    let is_rst = |frames: &Frames| sent(frames).pop().unwrap().0.is_rst();
+
+    // A stream dropped without being torn down is reset in the timed events
+    receive(&mut redirector, &syn, &[]).await.unwrap();
+    drop(redirector.streams.remove(&(src, dst)));
+    assert!(!redirector.handle_timed());
+    assert!(is_rst(&frames));
+    assert!(redirector.states.is_empty());
+
+    // A stream torn down orderly is not reset again
+    receive(&mut redirector, &syn, &[]).await.unwrap();
+    redirector.abort(src, dst);
+    frames.lock().unwrap().clear();
+    assert!(!redirector.handle_timed());
+    assert!(frames.lock().unwrap().is_empty());
+
+    // A stream of a prior connection dropped late does not reset a later connection
+    receive(&mut redirector, &syn, &[]).await.unwrap();
+    let prior = redirector.streams.remove(&(src, dst)).unwrap();
+    redirector.abort(src, dst);
+    receive(&mut redirector, &syn, &[]).await.unwrap();
+    frames.lock().unwrap().clear();
+    drop(prior);
+    assert!(!redirector.handle_timed());
+    assert!(frames.lock().unwrap().is_empty());
+    assert!(redirector.states.contains_key(&(src, dst)));
+
+    // The remaining connections are reset when the redirector is dropped
+    frames.lock().unwrap().clear();
+    drop(redirector);
+    assert!(is_rst(&frames));
+}
 
-                            // Send ACK0
-                            tx_locked.send_tcp_ack_0(dst, src)?;
-                        }
-                        if is_readable {
-                            // Close by local
-                            let stream = self
-                                .streams
-                                .get_mut(&key)
-                                .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
-                            stream.shutdown(Shutdown::Write);
-                        } else {
-                            // Close by remote
-                            // Clean up
-                            self.clean_up(src, dst);
-                        }
-                    } else {
-                        trace!(
-                            "TCP out of order of {} -> {} at {}",
-                            src,
-                            dst,
-                            tcp.sequence()
-                        );
+#[tokio::test]
+async fn redirector_isn_strategy() {
+    use pnet::packet::tcp::TcpFlags;
 
-                        if payload.is_empty() {
-                            // Send ACK0
-                            self.tx.lock().unwrap().send_tcp_ack_0(dst, src)?;
-                        }
-                    }
-                }
-            } else {
-                // Retransmission
-                // Send ACK0
-                self.tx.lock().unwrap().send_tcp_ack_0(dst, src)?;
-            }
-        } else {
-            // Send ACK/RST
-            self.tx
-                .lock()
-                .unwrap()
-                .send_tcp_ack_rst_untracked(dst, src, tcp.acknowledgement())?;
-        }
+    let (src, dst) = (src(), dst());
 
-        Ok(())
-    }
+    let frames = Frames::default();
+    let mut redirector = redirector(&frames, *src.ip(), Arc::new(proxy::LoopbackUpstream::new()));
+    redirector.set_isn_strategy(IsnStrategy::Custom(Arc::new(|_, _| 1000)));
 
-    fn clean_up(&mut self, src: SocketAddrV4, dst: SocketAddrV4) {
-        let key = (src, dst);
+    // The ACK/SYN carries the pinned initial sequence number
+    let syn = segment(src, dst, TcpFlags::SYN, 100, 0);
+    receive(&mut redirector, &syn, &[]).await.unwrap();
+    let (tcp, _) = sent(&frames).pop().unwrap();
+    assert!(tcp.is_syn() && tcp.is_ack());
+    assert_eq!(tcp.sequence(), 1000);
+    assert_eq!(tcp.acknowledgement(), 101);
+}
 
-        self.streams.remove(&key);
-        self.states.remove(&key);
+#[tokio::test]
+async fn redirector_events() {
+    use pnet::packet::tcp::TcpFlags;
 
-        self.tx.lock().unwrap().clean_up(dst, src);
-    }
+    let (src, dst) = (src(), dst());
 
-    async fn handle_udp(&mut self, udp: &Udp, payload: &[u8]) -> io::Result<()> {
-        let src = SocketAddrV4::new(udp.src_ip_addr(), udp.src());
+    let frames = Frames::default();
+    let mut redirector = redirector(&frames, *src.ip(), Arc::new(proxy::LoopbackUpstream::new()));
+    let mut events = redirector.subscribe();
 
-        // Bind
-        let port = self.bind_local_udp_port(src).await?;
+    let syn = segment(src, dst, TcpFlags::SYN, 100, 0);
+    receive(&mut redirector, &syn, &[]).await.unwrap();
+    assert_eq!(events.recv().await, Some(Event::Connect { src, dst }));
+    let rst = segment(src, dst, TcpFlags::RST, 100, 0);
+    receive(&mut redirector, &rst, &[]).await.unwrap();
+    assert_eq!(events.recv().await, Some(Event::Reset { src, dst }));
+    assert_eq!(events.try_recv(), None);
 
-        // Send
-        self.datagrams
-            .get_mut(&port)
-            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?
-            .send_to(
-                payload.to_vec(),
-                SocketAddrV4::new(udp.dst_ip_addr(), udp.dst()),
-            )?;
+    // A new subscription ends the previous one
+    let _ = redirector.subscribe();
+    assert_eq!(events.recv().await, None);
+}
 
-        Ok(())
+#[tokio::test]
+async fn redirector_tcp_syn_payload() {
+    use pnet::packet::tcp::TcpFlags;
+    use tokio::io::AsyncReadExt;
+
+    let (src, dst) = (src(), dst());
+
+    let (upstream, mut remote) = DuplexUpstream::new();
+    let frames = Frames::default();
+    let mut redirector = redirector(&frames, *src.ip(), Arc::new(upstream));
+    let syn = segment(src, dst, TcpFlags::SYN, 100, 0);
+
+    // The data in the SYN is acknowledged in the ACK/SYN
+    receive(&mut redirector, &syn, b"ping").await.unwrap();
+    let (tcp, _) = sent(&frames).pop().unwrap();
+    assert!(tcp.is_syn() && tcp.is_ack());
+    assert_eq!(tcp.acknowledgement(), 105);
+
+    // And forwarded to the upstream
+    let mut buffer = [0u8; 4];
+    remote.read_exact(&mut buffer).await.unwrap();
+    assert_eq!(&buffer, b"ping");
+
+    // A duplicate SYN with the data is replied with the ACK/SYN again
+    receive(&mut redirector, &syn, b"ping").await.unwrap();
+    let (tcp, _) = sent(&frames).pop().unwrap();
+    assert_eq!(tcp.acknowledgement(), 105);
+}
+
+#[tokio::test]
+async fn redirector_tcp_pending_window() {
+    use pnet::packet::tcp::TcpFlags;
+    use tokio::io::AsyncReadExt;
+
+    let (src, dst) = (src(), dst());
+
+    // The upstream does not read until the end
+    let (upstream, mut remote) = DuplexUpstream::new();
+    let frames = Frames::default();
+    let mut redirector = redirector(&frames, *src.ip(), Arc::new(upstream));
+    let acks = |frames: &Frames| {
+        sent(frames)
+            .iter()
+            .map(|(tcp, _)| (tcp.sequence(), tcp.acknowledgement(), tcp.window()))
+            .collect::<Vec<_>>()
+    };
+
+    // Handshake
+    let syn = segment(src, dst, TcpFlags::SYN, 100, 0);
+    receive(&mut redirector, &syn, &[]).await.unwrap();
+    let sequence = sent(&frames)[0].0.sequence().wrapping_add(1);
+    let ack = segment(src, dst, TcpFlags::ACK, 101, sequence);
+    receive(&mut redirector, &ack, &[]).await.unwrap();
+
+    // The window shrinks with the data pending to be written to the upstream
+    let payload = vec![0u8; 16300];
+    let mut acknowledgement = 101u32;
+    for _ in 0..4 {
+        let psh = segment(
+            src,
+            dst,
+            TcpFlags::ACK | TcpFlags::PSH,
+            acknowledgement,
+            sequence,
+        );
+        receive(&mut redirector, &psh, &payload).await.unwrap();
+        acknowledgement = acknowledgement.wrapping_add(payload.len() as u32);
+    }
+    let acks = acks(&frames);
+    assert_eq!(acks.len(), 4);
+    assert_eq!(acks[0].2, u16::MAX - 16300);
+    assert_eq!(acks[3], (sequence, acknowledgement, 0));
+
+    // The window is reopened once the data is written
+    let mut buffer = vec![0u8; payload.len() * 4];
+    remote.read_exact(&mut buffer).await.unwrap();
+    let mut update = None;
+    for _ in 0..100 {
+        time::sleep(Duration::from_millis(10)).await;
+        update = sent(&frames).pop();
+        if update.is_some() {
+            break;
+        }
     }
+    let (tcp, _) = update.unwrap();
+    assert_eq!(
+        (tcp.sequence(), tcp.acknowledgement()),
+        (sequence, acknowledgement)
+    );
+    assert!(tcp.window() > 0);
+}
 
-    async fn bind_local_udp_port(&mut self, src: SocketAddrV4) -> io::Result<u16> {
-        let local_port = self.datagram_map.get(&src);
-        match local_port {
-            Some(&local_port) => {
-                // Update LRU
-                self.udp_lru.get(&local_port);
+#[tokio::test]
+async fn redirector_tcp_wscale_fallback() {
+    use pnet::packet::tcp::{TcpFlags, TcpOption};
 
-                Ok(local_port)
-            }
-            None => {
-                let bind_port = if self.udp_lru.len() < self.udp_lru.cap() {
-                    match DatagramWorker::bind(self.get_tx(), src, &self.proxy).await {
-                        Ok((worker, port)) => {
-                            self.datagrams.insert(port, worker);
+    let (src, dst) = (src(), dst());
 
-                            // Update map and LRU
-                            self.datagram_map.insert(src, port);
-                            self.udp_lru.put(port, src);
+    let (upstream, _remote) = DuplexUpstream::new();
+    let frames = Frames::default();
+    let mut redirector = redirector(&frames, *src.ip(), Arc::new(upstream));
+    redirector.set_wscale_fallback(true);
+    let tx = redirector.get_tx();
 
-                            trace!("bind UDP port {} = {}", port, src);
+    // TCP SYNs carry a window scale
+    let syn = |src| {
+        let options = vec![TcpOption::wscale(7)];
 
-                            Ok(port)
-                        }
-                        Err(e) => Err(e),
-                    }
-                } else {
-                    Err(io::Error::new(io::ErrorKind::Other, "cannot bind UDP port"))
-                };
+        segment_with_options(src, dst, TcpFlags::SYN, 100, 0, 64240, options)
+    };
+    let syn_ack = |frames: &Frames| {
+        let (tcp, _) = sent(frames).pop().unwrap();
+        assert!(tcp.is_syn());
 
-                match bind_port {
-                    Ok(port) => Ok(port),
-                    Err(e) => {
-                        if self.udp_lru.is_empty() {
-                            Err(e)
-                        } else {
-                            let pair = self.udp_lru.pop_lru().unwrap();
-                            let port = pair.0;
-                            let prev_src = pair.1;
+        (tcp.sequence(), tcp.wscale())
+    };
 
-                            // Reuse
-                            self.datagram_map.remove(&prev_src);
-                            trace!("reuse UDP port {} = {} to {}", port, prev_src, src);
-                            self.datagram_map.insert(src, port);
-                            self.datagrams.get_mut(&port).unwrap().set_src(&src);
+    // The window scale is negotiated
+    receive(&mut redirector, &syn(src), &[]).await.unwrap();
+    let (sequence, wscale) = syn_ack(&frames);
+    assert!(wscale.is_some());
+
+    // The source does not scale its window
+    let ack = segment_with_options(
+        src,
+        dst,
+        TcpFlags::ACK,
+        101,
+        sequence.wrapping_add(1),
+        64240,
+        vec![],
+    );
+    receive(&mut redirector, &ack, &[]).await.unwrap();
+    assert_eq!(redirector.states.get(&(src, dst)).unwrap().wscale(), 0);
+    let src_window = tx.lock().unwrap().get_state(dst, src).unwrap().src_window();
+    assert_eq!(src_window, 64240);
+
+    // The window scale is disabled in later connections to the destination
+    let (upstream, _remote) = DuplexUpstream::new();
+    redirector.upstream = Arc::new(upstream);
+    let src = SocketAddrV4::new(*src.ip(), 10001);
+    receive(&mut redirector, &syn(src), &[]).await.unwrap();
+    assert_eq!(syn_ack(&frames).1, None);
+    assert_eq!(redirector.states.get(&(src, dst)).unwrap().wscale(), 0);
+}
 
-                            // Update LRU
-                            self.udp_lru.put(port, src);
+#[tokio::test]
+async fn redirector_tcp_wscale_one_side() {
+    use pnet::packet::tcp::{TcpFlags, TcpOption};
+
+    let (src, dst) = (src(), dst());
+    let other = SocketAddrV4::new(*dst.ip(), 443);
+
+    let frames = Frames::default();
+    let mut redirector = redirector(&frames, *src.ip(), Arc::new(proxy::LoopbackUpstream::new()));
+    redirector.set_disable_wscale(other);
+    let tx = redirector.get_tx();
+
+    let with_wscale = || vec![TcpOption::wscale(7)];
+    let syn =
+        |src, dst, options| segment_with_options(src, dst, TcpFlags::SYN, 100, 0, 64240, options);
+    let ack = |src, dst, acknowledgement, window| {
+        segment_with_options(
+            src,
+            dst,
+            TcpFlags::ACK,
+            101,
+            acknowledgement,
+            window,
+            vec![],
+        )
+    };
+    let syn_ack = |frames: &Frames| {
+        let (tcp, _) = sent(frames).pop().unwrap();
+        assert!(tcp.is_syn());
 
-                            Ok(port)
-                        }
-                    }
-                }
-            }
-        }
+        (tcp.sequence().wrapping_add(1), tcp.wscale())
+    };
+    let src_window = |src, dst| tx.lock().unwrap().get_state(dst, src).unwrap().src_window();
+
+    // Only the source sends the window scale, but it is disabled locally
+    let syn_other = syn(src, other, with_wscale());
+    receive(&mut redirector, &syn_other, &[]).await.unwrap();
+    let (acknowledgement, wscale) = syn_ack(&frames);
+    assert_eq!(wscale, None);
+    assert_eq!(src_window(src, other), 64240);
+    let ack_other = ack(src, other, acknowledgement, 64240);
+    receive(&mut redirector, &ack_other, &[]).await.unwrap();
+    assert_eq!(src_window(src, other), 64240);
+
+    // Only the local sends the window scale
+    let src = SocketAddrV4::new(*src.ip(), 10001);
+    receive(&mut redirector, &syn(src, dst, vec![]), &[])
+        .await
+        .unwrap();
+    let (acknowledgement, wscale) = syn_ack(&frames);
+    assert_eq!(wscale, None);
+    receive(&mut redirector, &ack(src, dst, acknowledgement, 502), &[])
+        .await
+        .unwrap();
+    assert_eq!(src_window(src, dst), 502);
+
+    // Both sides negotiate, while the window in the SYN is not scaled, and the window scale
+    // falls back in the connection only if the source does not scale its window
+    let src = SocketAddrV4::new(*src.ip(), 10002);
+    receive(&mut redirector, &syn(src, dst, with_wscale()), &[])
+        .await
+        .unwrap();
+    let (acknowledgement, wscale) = syn_ack(&frames);
+    assert!(wscale.is_some());
+    assert_eq!(src_window(src, dst), 64240);
+    receive(&mut redirector, &ack(src, dst, acknowledgement, 64240), &[])
+        .await
+        .unwrap();
+    assert_eq!(redirector.states.get(&(src, dst)).unwrap().wscale(), 0);
+    assert_eq!(src_window(src, dst), 64240);
+
+    let src = SocketAddrV4::new(*src.ip(), 10003);
+    receive(&mut redirector, &syn(src, dst, with_wscale()), &[])
+        .await
+        .unwrap();
+    let (acknowledgement, wscale) = syn_ack(&frames);
+    assert!(wscale.is_some());
+    receive(&mut redirector, &ack(src, dst, acknowledgement, 502), &[])
+        .await
+        .unwrap();
+    assert_eq!(redirector.states.get(&(src, dst)).unwrap().wscale(), 7);
+    assert_eq!(src_window(src, dst), 502 << 7);
+}
+
+#[tokio::test]
+async fn redirector_dry_run() {
+    use pnet::packet::tcp::TcpFlags;
+
+    let (src, dst) = (src(), dst());
+
+    let frames = Frames::default();
+    let mut redirector = Redirector::new_with_upstream(
+        Arc::new(Mutex::new(forwarder(&frames))),
+        Ipv4Network::new(*src.ip(), 32).unwrap(),
+        testing::LOCAL_IP_ADDR,
+        Some(Ipv4Addr::new(192, 168, 1, 254)),
+        Arc::new(RejectingUpstream(SocksReply::GeneralFailure)),
+        None,
+    );
+    redirector.set_dry_run(true);
+    redirector.start().unwrap();
+
+    // ARP
+    let mut arp = Arp::gratuitous_arp(src_hardware_addr(), *src.ip());
+    arp.layer.target_proto_addr = Ipv4Addr::new(192, 168, 1, 254);
+    let ethernet = Ethernet::new(
+        LayerKinds::Arp,
+        src_hardware_addr(),
+        pcap::HARDWARE_ADDR_BROADCAST,
+    )
+    .unwrap();
+    let indicator = Indicator::new(Layers::Ethernet(ethernet), Some(Layers::Arp(arp)), None);
+    redirector.handle_arp(&indicator).unwrap();
+
+    // TCP
+    let syn = segment(src, dst, TcpFlags::SYN, 100, 0);
+    for _ in 0..2 {
+        receive(&mut redirector, &syn, &[]).await.unwrap();
     }
 
-    fn unbind_local_udp_port(&mut self, src: SocketAddrV4) {
-        let local_port = self.datagram_map.get(&src);
-        if let Some(&local_port) = local_port {
-            self.datagrams.remove(&local_port);
-            self.udp_lru.pop(&local_port);
-            self.datagram_map.remove(&src);
+    // Nothing is sent or opened, but the flow is seen
+    assert!(frames.lock().unwrap().is_empty());
+    assert!(redirector.states.is_empty());
+    assert!(redirector.streams.is_empty());
+    assert_eq!(
+        redirector.dry_run_flows(),
+        vec![(LayerKinds::Tcp, src, dst, 2, 0)]
+    );
+
+    // The least recently seen flow is dropped if the limit is reached
+    for i in 0..MAX_DRY_RUN_FLOWS as u16 {
+        let other = SocketAddrV4::new(*dst.ip(), 10000 + i);
+        let syn = segment(src, other, TcpFlags::SYN, 100, 0);
+        receive(&mut redirector, &syn, &[]).await.unwrap();
+    }
+    let flows = redirector.dry_run_flows();
+    assert_eq!(flows.len(), MAX_DRY_RUN_FLOWS);
+    assert!(!flows.iter().any(|flow| flow.2 == dst));
+}
 
-            trace!("unbind UDP port {} = {}", local_port, src);
-        }
+#[tokio::test]
+async fn redirector_ttl_decrement() {
+    let src = SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 2), 33434);
+    let dst = SocketAddrV4::new(Ipv4Addr::new(1, 1, 1, 1), 33434);
+
+    // A datagram from the source with the TTL of 1
+    let frames = Frames::default();
+    let mut recorder = Forwarder::new(
+        Box::new(Recorder(Arc::clone(&frames))),
+        1500,
+        pcap::HARDWARE_ADDR_UNSPECIFIED,
+        *dst.ip(),
+    );
+    recorder.set_ttl(1);
+    recorder.send_udp(src, dst, &[0u8; 32]).unwrap();
+    let frame = frames.lock().unwrap().pop().unwrap();
+
+    let mut redirector = redirector(
+        &frames,
+        *src.ip(),
+        Arc::new(RejectingUpstream(SocksReply::GeneralFailure)),
+    );
+    redirector.get_tx().lock().unwrap().set_ttl(64);
+    redirector.set_ttl_decrement(true);
+    let indicator = Indicator::from(&frame).unwrap();
+    assert_eq!(indicator.ipv4().unwrap().ttl(), 1);
+    redirector.handle_indicator(&indicator, &frame).await;
+
+    // Time exceeded is sent to the source from the gateway in the configured TTL
+    let frame = frames.lock().unwrap().pop().unwrap();
+    let indicator = Indicator::from(&frame).unwrap();
+    let ipv4 = indicator.ipv4().unwrap();
+    assert_eq!(
+        (ipv4.src(), ipv4.dst()),
+        (testing::LOCAL_IP_ADDR, *src.ip())
+    );
+    assert_eq!(ipv4.ttl(), 64);
+    let icmpv4 = indicator.icmpv4().unwrap();
+    assert!(icmpv4.is_time_exceeded());
+    assert_eq!(icmpv4.dst(), Some(dst));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn redirector_run_on() {
+    let src = SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 2), 33434);
+    let dst = SocketAddrV4::new(Ipv4Addr::new(1, 1, 1, 1), 33434);
+
+    // A datagram from the source with the TTL of 1
+    let frames = Frames::default();
+    let mut recorder = Forwarder::new(
+        Box::new(Recorder(Arc::clone(&frames))),
+        1500,
+        pcap::HARDWARE_ADDR_UNSPECIFIED,
+        *dst.ip(),
+    );
+    recorder.set_ttl(1);
+    recorder.send_udp(src, dst, &[0u8; 32]).unwrap();
+    let frame = frames.lock().unwrap().pop().unwrap();
+
+    let mut redirector = redirector(
+        &frames,
+        *src.ip(),
+        Arc::new(RejectingUpstream(SocksReply::GeneralFailure)),
+    );
+    redirector.set_ttl_decrement(true);
+
+    // The frame is read in a blocking thread and handled on the runtime, and the read error
+    // stops the redirection
+    let rx = Box::new(Replayer::new(vec![frame]));
+    let e = redirector
+        .run_on(&Handle::current(), rx)
+        .await
+        .unwrap()
+        .err()
+        .unwrap();
+    assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+
+    let frame = frames.lock().unwrap().pop().unwrap();
+    let indicator = Indicator::from(&frame).unwrap();
+    assert!(indicator.icmpv4().unwrap().is_time_exceeded());
+}
+
+#[tokio::test]
+async fn spawn_capture_back_pressure() {
+    let stats = Stats::new();
+    let (frames_tx, mut frames_rx) = mpsc::channel(1);
+    frames_tx.try_send(Ok(vec![])).unwrap();
+
+    // The capture waits for the channel instead of dropping frames
+    let rx = Box::new(Replayer::new(vec![vec![0u8; 14]; 3]));
+    let capture = spawn_capture(
+        rx,
+        frames_tx,
+        Arc::new(AtomicBool::new(true)),
+        false,
+        Duration::from_millis(TIMEDOUT_WAIT),
+        stats.clone(),
+    );
+    assert!(frames_rx.recv().await.unwrap().unwrap().is_empty());
+    for _ in 0..3 {
+        assert_eq!(frames_rx.recv().await.unwrap().unwrap().len(), 14);
     }
+    let e = frames_rx.recv().await.unwrap().err().unwrap();
+    assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+    capture.await.unwrap();
+    assert_eq!(stats.dropped_frames(), 0);
+}
 
-    fn get_tx(&self) -> Arc<Mutex<Forwarder>> {
-        Arc::clone(&self.tx)
+#[tokio::test]
+async fn spawn_capture_dropped_frames() {
+    let stats = Stats::new();
+    let (frames_tx, mut frames_rx) = mpsc::channel(1);
+    frames_tx.try_send(Ok(vec![])).unwrap();
+
+    // Frames are dropped while the channel is full, but the read error is still delivered
+    let rx = Box::new(Replayer::new(vec![vec![0u8; 14]; 3]));
+    let capture = spawn_capture(
+        rx,
+        frames_tx,
+        Arc::new(AtomicBool::new(true)),
+        true,
+        Duration::from_millis(TIMEDOUT_WAIT),
+        stats.clone(),
+    );
+    while stats.dropped_frames() < 3 {
+        time::sleep(Duration::from_millis(1)).await;
     }
+    assert!(frames_rx.recv().await.unwrap().unwrap().is_empty());
+    let e = frames_rx.recv().await.unwrap().err().unwrap();
+    assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+    capture.await.unwrap();
+    assert_eq!(stats.dropped_frames(), 3);
+}
 
-    fn set_tx_hardware_addr(&mut self, ip_addr: Ipv4Addr, hardware_addr: HardwareAddr) {
-        if !self.tx_src_hardware_addr_set_ip_addr_set.contains(&ip_addr) {
-            self.tx
-                .lock()
-                .unwrap()
-                .set_src_hardware_addr(ip_addr, hardware_addr);
-            self.tx_src_hardware_addr_set_ip_addr_set.insert(ip_addr);
-            info!("Device {} ({}) joined the network", ip_addr, hardware_addr);
+#[tokio::test]
+async fn redirector_path_mtu() {
+    let src = SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 2), 10000);
+    let dst = SocketAddrV4::new(Ipv4Addr::new(1, 1, 1, 1), 53);
+
+    // A datagram from the source with the DF flag set
+    let frames = Frames::default();
+    let mut recorder = Forwarder::new(
+        Box::new(Recorder(Arc::clone(&frames))),
+        1500,
+        pcap::HARDWARE_ADDR_UNSPECIFIED,
+        *dst.ip(),
+    );
+    recorder.send_udp(src, dst, &[0u8; 1000]).unwrap();
+    let mut frame = frames.lock().unwrap().pop().unwrap();
+    frame[14 + 6] |= 0x40;
+
+    let mut redirector = redirector(
+        &frames,
+        *src.ip(),
+        Arc::new(RejectingUpstream(SocksReply::GeneralFailure)),
+    );
+    redirector.set_path_mtu(Some(576)).unwrap();
+    assert!(redirector.set_path_mtu(Some(65536)).is_err());
+    assert!(redirector.set_path_mtu(Some(67)).is_err());
+    let indicator = Indicator::from(&frame).unwrap();
+    redirector.handle_indicator(&indicator, &frame).await;
+
+    // Fragmentation required is sent to the source on behalf of the destination
+    let frame = frames.lock().unwrap().pop().unwrap();
+    let indicator = Indicator::from(&frame).unwrap();
+    let ipv4 = indicator.ipv4().unwrap();
+    assert_eq!((ipv4.src(), ipv4.dst()), (*dst.ip(), *src.ip()));
+    let icmpv4 = indicator.icmpv4().unwrap();
+    assert_eq!(icmpv4.next_hop_mtu(), Some(576));
+    assert_eq!(icmpv4.dst(), Some(dst));
+}
+
+#[tokio::test]
+async fn redirector_drop_frames() {
+    use filter::{Action, Rule};
+    use pnet::packet::tcp::TcpFlags;
+
+    let (src, dst) = (src(), dst());
+
+    let mut redirector = redirector(
+        &Frames::default(),
+        *src.ip(),
+        Arc::new(RejectingUpstream(SocksReply::GeneralFailure)),
+    );
+    let mut filter = Filter::new(Action::Allow);
+    filter.deny(Rule::new(Ipv4Network::from(*dst.ip()).into()));
+    redirector.set_filter(filter);
+
+    let syn = |src, flags| frame(&segment(src, dst, flags, 100, 0), &[]);
+    let stats = redirector.stats();
+
+    // Malformed, a TCP segment without any valid flag, and truncated packets
+    for frame in [syn(src, 0), syn(src, TcpFlags::SYN)[..40].to_vec()].iter() {
+        let indicator = Indicator::from(frame).unwrap();
+        redirector.handle_indicator(&indicator, frame).await;
+    }
+    assert_eq!(stats.malformed_frames(), 2);
+
+    // Foreign
+    let frame_foreign = syn(
+        SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 3), 10000),
+        TcpFlags::SYN,
+    );
+    let indicator = Indicator::from(&frame_foreign).unwrap();
+    redirector
+        .handle_indicator(&indicator, &frame_foreign)
+        .await;
+    assert_eq!(stats.foreign_frames(), 1);
+
+    // Unsupported, an IPv6 packet
+    let mut frame_ipv6 = syn(src, TcpFlags::SYN);
+    frame_ipv6[12..14].copy_from_slice(&[0x86, 0xdd]);
+    let indicator = Indicator::from(&frame_ipv6).unwrap();
+    redirector.handle_indicator(&indicator, &frame_ipv6).await;
+    assert_eq!(stats.unsupported_frames(), 1);
+
+    // Filtered
+    let frame_filtered = syn(src, TcpFlags::SYN);
+    let indicator = Indicator::from(&frame_filtered).unwrap();
+    redirector
+        .handle_indicator(&indicator, &frame_filtered)
+        .await;
+    assert_eq!(stats.filtered_frames(), 1);
+
+    // Unsupported, indicators without an Ethernet layer
+    let mut frame_link = syn(src, TcpFlags::SYN);
+    let indicator = Indicator::from(&frame_link).unwrap();
+    frame_link.resize(MINIMUM_FRAME_SIZE, 0);
+    let ipv4 = Layers::Ipv4(indicator.ipv4().unwrap().clone());
+    let tcp = Layers::Tcp(indicator.tcp().unwrap().clone());
+    for indicator in [
+        Indicator::new(ipv4.clone(), None, None),
+        Indicator::new(ipv4.clone(), Some(ipv4), Some(tcp)),
+    ]
+    .iter()
+    {
+        redirector.handle_indicator(indicator, &frame_link).await;
+    }
+    assert_eq!(stats.unsupported_frames(), 3);
+
+    assert_eq!(stats.malformed_frames(), 2);
+    assert_eq!(stats.foreign_frames(), 1);
+    assert_eq!(stats.filtered_frames(), 1);
+}
+
+#[tokio::test]
+async fn redirector_icmpv4_malformed() {
+    let src = SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 2), 10000);
+    let dst = SocketAddrV4::new(Ipv4Addr::new(1, 1, 1, 1), 53);
+
+    // ICMPv4 from the source, carrying the leading bytes of a datagram to the source
+    let frames = Frames::default();
+    let mut recorder = Forwarder::new(
+        Box::new(Recorder(Arc::clone(&frames))),
+        1500,
+        pcap::HARDWARE_ADDR_UNSPECIFIED,
+        *dst.ip(),
+    );
+    recorder.send_udp(dst, src, &[0u8; 32]).unwrap();
+    let datagram = frames.lock().unwrap().pop().unwrap()[14..14 + 28].to_vec();
+    recorder
+        .send_icmpv4_fragmentation_required(*src.ip(), *dst.ip(), 10, &datagram)
+        .unwrap();
+    recorder
+        .send_icmpv4_fragmentation_required(*src.ip(), *dst.ip(), 1000, &[])
+        .unwrap();
+    recorder
+        .send_icmpv4_destination_port_unreachable(*src.ip(), *dst.ip(), &datagram[..24])
+        .unwrap();
+    recorder
+        .send_icmpv4_fragmentation_required(*src.ip(), *dst.ip(), 1000, &datagram)
+        .unwrap();
+    let frames = frames.lock().unwrap().drain(..).collect::<Vec<_>>();
+
+    let mut redirector = redirector(
+        &Frames::default(),
+        *src.ip(),
+        Arc::new(RejectingUpstream(SocksReply::GeneralFailure)),
+    );
+    let tx = redirector.get_tx();
+    let indicators = frames
+        .iter()
+        .map(|frame| Indicator::from(frame).unwrap())
+        .collect::<Vec<_>>();
+    let stats = redirector.stats();
+
+    // Next-hop MTU below the minimum
+    redirector
+        .handle_indicator(&indicators[0], &frames[0])
+        .await;
+    assert_eq!(tx.lock().unwrap().get_src_mtu(*src.ip()), 1500);
+    assert_eq!(stats.malformed_frames(), 0);
+
+    // Truncated datagrams
+    redirector
+        .handle_indicator(&indicators[1], &frames[1])
+        .await;
+    redirector
+        .handle_indicator(&indicators[2], &frames[2])
+        .await;
+    assert_eq!(stats.malformed_frames(), 2);
+
+    redirector
+        .handle_indicator(&indicators[3], &frames[3])
+        .await;
+    assert_eq!(tx.lock().unwrap().get_src_mtu(*src.ip()), 1000);
+}
+
+#[tokio::test]
+async fn redirector_random_frames() {
+    use pnet::packet::tcp::TcpFlags;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    let src = SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 2), 10000);
+    let dst = SocketAddrV4::new(Ipv4Addr::new(1, 1, 1, 1), 53);
+
+    // Valid frames from the source as templates, including UDP fragments
+    let frames = Frames::default();
+    let mut tx = Forwarder::new(
+        Box::new(Recorder(Arc::clone(&frames))),
+        576,
+        pcap::HARDWARE_ADDR_UNSPECIFIED,
+        *dst.ip(),
+    );
+    tx.send_udp(src, dst, &[0u8; 32]).unwrap();
+    tx.send_udp(src, dst, &[0u8; 1000]).unwrap();
+    let mut templates = frames.lock().unwrap().drain(..).collect::<Vec<_>>();
+    templates.push(frame(&segment(src, dst, TcpFlags::SYN, 100, 0), &[]));
+    let arp = Arp::gratuitous_arp(src_hardware_addr(), *src.ip());
+    let ethernet = Ethernet::new(
+        LayerKinds::Arp,
+        src_hardware_addr(),
+        pcap::HARDWARE_ADDR_BROADCAST,
+    )
+    .unwrap();
+    let indicator = Indicator::new(Layers::Ethernet(ethernet), Some(Layers::Arp(arp)), None);
+    let mut frame = vec![0u8; indicator.len()];
+    indicator.serialize(&mut frame).unwrap();
+    templates.push(frame);
+
+    let tx = Forwarder::new(
+        Box::new(pcap::BlackHole::new()),
+        1500,
+        pcap::HARDWARE_ADDR_UNSPECIFIED,
+        testing::LOCAL_IP_ADDR,
+    );
+    let mut redirector = Redirector::new_with_upstream(
+        Arc::new(Mutex::new(tx)),
+        Ipv4Network::new(*src.ip(), 24).unwrap(),
+        testing::LOCAL_IP_ADDR,
+        Some(Ipv4Addr::new(192, 168, 1, 254)),
+        Arc::new(RejectingUpstream(SocksReply::GeneralFailure)),
+        None,
+    );
+    redirector.set_path_mtu(Some(576)).unwrap();
+
+    // Mutated and truncated templates, and random bytes
+    let mut rng = StdRng::seed_from_u64(0);
+    for i in 0..20000 {
+        let mut frame = match i % 5 {
+            4 => (0..rng.gen_range(0..128)).map(|_| rng.gen()).collect(),
+            n => templates[n % templates.len()].clone(),
+        };
+        for _ in 0..rng.gen_range(0..8) {
+            if !frame.is_empty() {
+                let i = rng.gen_range(0..frame.len());
+                frame[i] = rng.gen();
+            }
+        }
+        if rng.gen_bool(0.2) {
+            frame.truncate(rng.gen_range(0..=frame.len()));
+        }
+
+        if let Some(ref indicator) = Indicator::from(&frame) {
+            redirector.handle_indicator(indicator, &frame).await;
         }
     }
+    let stats = redirector.stats();
+    assert!(stats.malformed_frames() > 0);
+    assert!(stats.foreign_frames() > 0);
+    assert!(stats.unsupported_frames() > 0);
 }
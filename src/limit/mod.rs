@@ -0,0 +1,123 @@
+//! Support for limiting rates.
+
+use std::cmp::min;
+use tokio::time::Instant;
+
+/// Represents a token bucket limiting the rate of traffic in bytes per second.
+#[derive(Clone, Debug)]
+pub struct TokenBucket {
+    rate: usize,
+    capacity: usize,
+    tokens: usize,
+    instant: Instant,
+}
+
+impl TokenBucket {
+    /// Creates a new `TokenBucket`. The bucket is full at the beginning.
+    pub fn new(rate: usize, capacity: usize) -> TokenBucket {
+        TokenBucket {
+            rate,
+            capacity,
+            tokens: capacity,
+            instant: Instant::now(),
+        }
+    }
+
+    /// Sets the rate and the capacity of the bucket.
+    pub fn set_rate(&mut self, rate: usize, capacity: usize) {
+        self.refill();
+        self.rate = rate;
+        self.capacity = capacity;
+        self.tokens = min(self.tokens, capacity);
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.instant).as_micros();
+        let tokens = elapsed.saturating_mul(self.rate as u128) / 1_000_000;
+
+        // The timestamp is not updated if no token is generated, or the fraction will be lost
+        if tokens > 0 || self.tokens >= self.capacity {
+            self.tokens = min(
+                (self.tokens as u128).saturating_add(tokens),
+                self.capacity as u128,
+            ) as usize;
+            self.instant = now;
+        }
+    }
+
+    /// Consumes tokens from the bucket.
+    pub fn consume(&mut self, size: usize) {
+        self.refill();
+        self.tokens = self.tokens.saturating_sub(size);
+    }
+
+    /// Consumes tokens from the bucket if there are enough tokens.
+    pub fn try_consume(&mut self, size: usize) -> bool {
+        self.refill();
+        if self.tokens >= size {
+            self.tokens -= size;
+
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Returns the available tokens of the bucket.
+    pub fn available(&mut self) -> usize {
+        self.refill();
+        self.tokens
+    }
+
+    /// Returns the rate of the bucket.
+    pub fn rate(&self) -> usize {
+        self.rate
+    }
+
+    /// Returns the capacity of the bucket.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
+
+#[tokio::test]
+async fn token_bucket_consume() {
+    tokio::time::pause();
+
+    let mut bucket = TokenBucket::new(1000, 1500);
+    assert_eq!(bucket.available(), 1500);
+
+    bucket.consume(1000);
+    assert_eq!(bucket.available(), 500);
+    assert!(!bucket.try_consume(1500));
+    assert!(bucket.try_consume(500));
+
+    bucket.consume(2000);
+    assert_eq!(bucket.available(), 0);
+}
+
+#[tokio::test]
+async fn token_bucket_refill() {
+    use std::time::Duration;
+    use tokio::time;
+
+    time::pause();
+
+    let mut bucket = TokenBucket::new(100_000, 100_000);
+    bucket.consume(100_000);
+
+    time::advance(Duration::from_millis(20)).await;
+    assert_eq!(bucket.available(), 2000);
+
+    // The fraction of a token is kept until a token is generated
+    bucket.consume(2000);
+    time::advance(Duration::from_micros(5)).await;
+    assert_eq!(bucket.available(), 0);
+    time::advance(Duration::from_micros(5)).await;
+    assert_eq!(bucket.available(), 1);
+
+    time::advance(Duration::from_secs(1)).await;
+    bucket.set_rate(100_000, 1000);
+    assert_eq!(bucket.available(), 1000);
+}
@@ -7,8 +7,16 @@ use std::io::{self, Write};
 use std::net::{AddrParseError, IpAddr, Ipv4Addr, SocketAddrV4};
 use std::str::FromStr;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use structopt::StructOpt;
 
+use pcap2socks::cidr::parse_network;
+use pcap2socks::packet::ChecksumMode;
+use pcap2socks::pcap::{CaptureConfig, HardwareAddr, MIN_SNAPLEN};
+use pcap2socks::proxy::{
+    FailoverPolicy, FailoverUpstream, GssapiAuth, GssapiProtection, HttpUpstream, SocketOption,
+    SocksUpstream, SocksVersion, UpstreamConnector,
+};
 use pcap2socks::{self as lib, Forwarder, ProxyConfig, Redirector};
 
 #[tokio::main]
@@ -20,11 +28,19 @@ async fn main() {
     set_logger(flags.verbose);
 
     // Interface
-    let inter = match lib::interface(flags.inter) {
-        Some(inter) => inter,
-        None => {
-            error!("Cannot determine the interface. Available interfaces are listed below, and please use -i <INTERFACE> to designate:");
-            for inter in lib::interfaces().iter() {
+    let inter = match flags.inter.as_ref().and_then(|inter| inter.parse().ok()) {
+        Some(network) => lib::try_interface_by_network_with_loopback(network, flags.loopback),
+        None => lib::try_interface_with_loopback(flags.inter, flags.loopback),
+    };
+    let inter = match inter {
+        Ok(inter) => inter,
+        Err(e) => {
+            error!("Cannot determine the interface: {}. Available interfaces are listed below, and please use -i <INTERFACE> to designate:", e);
+            let inters = match flags.loopback {
+                true => lib::interfaces_including_loopback(),
+                false => lib::interfaces(),
+            };
+            for inter in inters.iter() {
                 info!("    {}", inter);
             }
             return;
@@ -33,37 +49,33 @@ async fn main() {
     info!("Listen on {}", inter);
 
     // MTU
-    let mtu = match flags.mtu {
+    let mtu = match flags.mtu.or_else(|| inter.mtu()) {
         Some(mtu) => mtu,
         None => {
-            if inter.mtu() == 0 {
-                error!("Cannot obtain the MTU. Please use --mtu <VALUE> to set");
-                return;
-            }
-
-            inter.mtu()
+            error!("Cannot obtain the MTU. Please use --mtu <VALUE> to set");
+            return;
         }
     };
     info!("Use MTU {}", mtu);
 
     // Route
-    let src = match flags.preset {
+    let srcs = match flags.preset {
         Some(ref preset) => match preset.as_str() {
-            "t" | "tencent" => Ipv4Network::new(Ipv4Addr::new(10, 6, 0, 1), 32).unwrap(),
+            "t" | "tencent" => vec![Ipv4Network::new(Ipv4Addr::new(10, 6, 0, 1), 32).unwrap()],
             "n" | "netease" | "u" | "uu" => {
                 let mut ip_octets = inter.ip_addr().unwrap().octets();
                 ip_octets[0] = 172;
                 ip_octets[1] = 24;
                 ip_octets[2] = ip_octets[2].checked_add(1).unwrap_or(0);
 
-                Ipv4Network::new(Ipv4Addr::from(ip_octets), 32).unwrap()
+                vec![Ipv4Network::new(Ipv4Addr::from(ip_octets), 32).unwrap()]
             }
             _ => {
                 error!("The preset {} is not available", preset);
                 return;
             }
         },
-        None => flags.src.unwrap(),
+        None => flags.src.clone(),
     };
     let publish = match flags.preset {
         Some(ref preset) => match preset.as_str() {
@@ -90,45 +102,266 @@ async fn main() {
 
     // Gateway
     let gw = publish.unwrap_or_else(|| inter.ip_addr().unwrap());
-    if src.size() == 1 && src.network() == gw {
+    if srcs
+        .iter()
+        .any(|src| src.size() == 1 && src.network() == gw)
+    {
         error!("The source cannot be the same with the gateway (publish)");
         return;
     }
 
     // Instructions
-    show_info(src, gw, mtu);
+    for src in srcs.iter() {
+        show_info(*src, gw, mtu);
+    }
 
     // Proxy
-    let (tx, mut rx) = match inter.open() {
+    let mut capture_config = CaptureConfig::new();
+    capture_config.set_promiscuous(!flags.no_promiscuous);
+    if let Some(buffer_size) = flags.buffer_size {
+        capture_config.set_buffer_size(buffer_size);
+    }
+    if let Some(snaplen) = flags.snaplen {
+        capture_config.set_snaplen(snaplen);
+    }
+    if let Some(read_timeout) = flags.read_timeout {
+        capture_config.set_read_timeout(Duration::from_millis(read_timeout));
+    }
+    let (tx, mut rx) = match inter.open_with_config(&capture_config) {
         Ok((tx, rx)) => (tx, rx),
         Err(ref e) => {
             error!("{}", e);
+            if !flags.no_promiscuous {
+                error!("Please use --no-promiscuous if promiscuous mode is not permitted");
+            }
             return;
         }
     };
-    let forwarder = Forwarder::new(tx, mtu, inter.hardware_addr(), inter.ip_addr().unwrap());
+    let hardware_addr = flags.hardware_addr.unwrap_or_else(|| inter.hardware_addr());
+    let mut forwarder = Forwarder::new(tx, mtu, hardware_addr, inter.ip_addr().unwrap());
+    if let Some(rate_limit) = flags.rate_limit {
+        info!(
+            "Limit the rate of each connection to {} Bytes/s",
+            rate_limit
+        );
+        forwarder.set_default_rate_limit(Some(rate_limit));
+    }
+    if flags.snoop_dns {
+        forwarder.set_dns_snooping(true);
+    }
+    if let Some(dscp) = flags.dscp {
+        if let Err(ref e) = forwarder.set_dscp(dscp) {
+            error!("{}", e);
+            return;
+        }
+    }
+    if let Some(ttl) = flags.ttl {
+        forwarder.set_ttl(ttl);
+    }
+    // Frames on loopback interfaces are not padded
+    if flags.no_padding || inter.is_loopback() {
+        forwarder.set_min_frame_size(None);
+    }
+    if flags.clamp_black_hole {
+        forwarder.set_black_hole_clamping(true);
+    }
     let auth = match flags.username {
         Some(ref username) => Some((username.clone(), flags.password.unwrap())),
         None => None,
     };
-    let mut redirector = Redirector::new(
-        Arc::new(Mutex::new(forwarder)),
-        src,
-        gw,
-        publish,
-        ProxyConfig::new_socks(
-            flags.dst.addr(),
-            flags.force_associate_dst,
-            flags.force_associate_bind_addr,
-            auth,
-        ),
-        None,
+    let is_http = flags.proxy_type == "http";
+    if is_http
+        && (flags.gssapi.is_some()
+            || !flags.bypass.is_empty()
+            || flags.direct_fallback
+            || flags.pool_size.is_some())
+    {
+        error!("HTTP proxies do not support GSSAPI, bypassing, direct fallback and pooling");
+        return;
+    }
+    if let Some(egress_addr) = flags.egress_addr {
+        if !lib::interfaces()
+            .iter()
+            .any(|inter| inter.ip_addrs().contains(&egress_addr))
+        {
+            error!(
+                "Cannot find an interface of the egress address {}",
+                egress_addr
+            );
+            return;
+        }
+    }
+    if flags.dst.len() > 1 && flags.pool_size.is_some() {
+        error!("Pooling is not supported with multiple destinations");
+        return;
+    }
+    let version = match flags.socks_version {
+        4 => SocksVersion::Socks4,
+        _ => SocksVersion::Socks5,
+    };
+    if version == SocksVersion::Socks4 && (auth.is_some() || flags.gssapi.is_some()) {
+        error!("SOCKS4 does not support authentication");
+        return;
+    }
+    if flags.gssapi.is_some() && !cfg!(feature = "gssapi") {
+        error!("GSSAPI authentication is not supported, rebuild with the gssapi feature");
+        return;
+    }
+    let mut proxy = ProxyConfig::new_socks_with_version(
+        flags.dst[0].addr(),
+        version,
+        flags.force_associate_dst,
+        flags.force_associate_bind_addr,
+        auth.clone(),
     );
+    if let Some(connect_timeout) = flags.connect_timeout {
+        proxy.set_connect_timeout(Duration::from_secs(connect_timeout));
+    }
+    if !flags.bypass.is_empty() {
+        proxy.set_bypass(flags.bypass.clone());
+    }
+    if flags.direct_fallback {
+        proxy.set_direct_fallback(true);
+    }
+    let mut socket = SocketOption::new();
+    socket.set_bind_addr(flags.egress_addr);
+    socket.set_nodelay(!flags.no_nodelay);
+    socket.set_send_buffer_size(flags.send_buffer_size);
+    socket.set_recv_buffer_size(flags.recv_buffer_size);
+    proxy.set_socket(socket);
+    if let Some(ref service) = flags.gssapi {
+        proxy.set_gssapi(Some(GssapiAuth::new_with_protection(
+            service.clone(),
+            flags
+                .gssapi_protection
+                .unwrap_or(GssapiProtection::Integrity),
+        )));
+    }
+    let forwarder = Arc::new(Mutex::new(forwarder));
+    let policy = match flags.failover.as_str() {
+        "round-robin" => FailoverPolicy::RoundRobin,
+        _ => FailoverPolicy::Priority,
+    };
+    let mut redirector = match is_http {
+        true => {
+            let mut upstreams: Vec<Arc<dyn UpstreamConnector>> = Vec::new();
+            for dst in flags.dst.iter() {
+                let mut upstream = HttpUpstream::new(dst.addr(), auth.clone());
+                if let Some(connect_timeout) = flags.connect_timeout {
+                    upstream.set_connect_timeout(Duration::from_secs(connect_timeout));
+                }
+                upstream.set_socket(socket);
+                upstreams.push(Arc::new(upstream));
+            }
+            let upstream = match upstreams.len() {
+                1 => upstreams.pop().unwrap(),
+                _ => Arc::new(FailoverUpstream::new(upstreams, policy)),
+            };
+            warn!("UDP is not supported through HTTP proxies, UDP traffic will be dropped");
+
+            Redirector::new_with_upstream(forwarder, srcs[0], gw, publish, upstream, None)
+        }
+        false if flags.dst.len() == 1 => {
+            Redirector::new(forwarder, srcs[0], gw, publish, proxy, None)
+        }
+        false => {
+            let mut upstreams: Vec<Arc<dyn UpstreamConnector>> = Vec::new();
+            for (i, dst) in flags.dst.iter().enumerate() {
+                let mut proxy = proxy.clone();
+                proxy.set_remote(dst.addr());
+                // Only the last destination falls back on direct connections
+                proxy.set_direct_fallback(flags.direct_fallback && i == flags.dst.len() - 1);
+                upstreams.push(Arc::new(SocksUpstream::new(proxy)));
+            }
+            let upstream = Arc::new(FailoverUpstream::new(upstreams, policy));
+
+            Redirector::new_with_upstream(forwarder, srcs[0], gw, publish, upstream, None)
+        }
+    };
+    for src in srcs.iter().skip(1) {
+        redirector.add_src_ip_addr(*src);
+    }
+    if let Some(vlan) = flags.vlan {
+        redirector.set_vlan(Some(vlan));
+    }
+    if flags.mirror_dscp {
+        redirector.set_dscp_mirroring(true);
+    }
+    if flags.decrement_ttl {
+        redirector.set_ttl_decrement(true);
+    }
+    redirector.set_verify_checksums(match flags.verify_checksums.as_str() {
+        "ipv4" => ChecksumMode::Ipv4,
+        "full" => ChecksumMode::Full,
+        _ => ChecksumMode::Off,
+    });
+    if let Some(path_mtu) = flags.path_mtu {
+        if let Err(ref e) = redirector.set_path_mtu(Some(path_mtu)) {
+            error!("{}", e);
+            return;
+        }
+    }
+    if !flags.proxy_arp.is_empty() {
+        redirector.set_proxy_arp(flags.proxy_arp.clone());
+    }
+    for dst in flags.no_wscale.iter() {
+        redirector.set_disable_wscale(*dst);
+    }
+    if flags.wscale_fallback {
+        redirector.set_wscale_fallback(true);
+    }
+    if let Some(arp_interval) = flags.arp_interval {
+        redirector.set_arp_interval(Some(Duration::from_secs(arp_interval)));
+    }
+    if let Some(max_streams) = flags.max_streams {
+        redirector.set_max_streams(Some(max_streams));
+    }
+    if let Some(pool_size) = flags.pool_size {
+        redirector.set_pool_size(Some(pool_size));
+    }
+    if let Some(connect_retries) = flags.connect_retries {
+        redirector.set_connect_retries(connect_retries);
+    }
+    if let Some(idle_timeout) = flags.idle_timeout {
+        redirector.set_idle_timeout(Some(Duration::from_secs(idle_timeout)));
+    }
+    if let Some(max_datagrams) = flags.max_datagrams {
+        redirector.set_max_datagrams(max_datagrams);
+    }
+    if let Some(association_timeout) = flags.association_timeout {
+        redirector.set_association_timeout(match association_timeout {
+            0 => None,
+            _ => Some(Duration::from_secs(association_timeout)),
+        });
+    }
+    let src_str = srcs
+        .iter()
+        .map(|src| src.to_string())
+        .collect::<Vec<_>>()
+        .join(", ");
+    let dst_str = flags
+        .dst
+        .iter()
+        .map(|dst| dst.to_string())
+        .collect::<Vec<_>>()
+        .join(", ");
     match flags.username {
-        Some(username) => info!("Proxy {} to {}@{}", src, username, flags.dst),
-        None => info!("Proxy {} to {}", src, flags.dst),
+        Some(username) => info!("Proxy {} to {}@{}", src_str, username, dst_str),
+        None => info!("Proxy {} to {}", src_str, dst_str),
     }
-    if let Err(ref e) = redirector.open(&mut rx).await {
+    if flags.auto_reopen {
+        redirector.set_auto_reopen(true);
+    }
+    if flags.dry_run {
+        redirector.set_dry_run(true);
+    }
+    if flags.drop_frames {
+        redirector.set_capture_dropping(true);
+    }
+    if let Err(ref e) = redirector
+        .open_interface(&mut rx, &inter, &capture_config)
+        .await
+    {
         error!("{}", e);
     }
 }
@@ -197,7 +430,7 @@ struct Flags {
     #[structopt(
         long = "interface",
         short,
-        help = "Interface for listening",
+        help = "Interface for listening, or an IPv4 address or network owned by the interface",
         value_name = "INTERFACE",
         display_order(0)
     )]
@@ -218,9 +451,11 @@ struct Flags {
         help = "Source",
         value_name = "ADDRESS",
         required_unless("preset"),
+        number_of_values(1),
+        parse(try_from_str = parse_network),
         display_order(3)
     )]
-    pub src: Option<Ipv4Network>,
+    pub src: Vec<Ipv4Network>,
     #[structopt(
         long,
         short,
@@ -235,9 +470,26 @@ struct Flags {
         help = "Destination",
         value_name = "ADDRESS",
         default_value = "127.0.0.1:1080",
+        number_of_values(1),
         display_order(5)
     )]
-    pub dst: ResolvableSocketAddrV4,
+    pub dst: Vec<ResolvableSocketAddrV4>,
+    #[structopt(
+        long,
+        help = "SOCKS version",
+        value_name = "VERSION",
+        default_value = "5",
+        possible_values(&["4", "5"]),
+        display_order(6)
+    )]
+    pub socks_version: u8,
+    #[structopt(
+        long,
+        help = "VLAN identifier of the source",
+        value_name = "VID",
+        display_order(7)
+    )]
+    pub vlan: Option<u16>,
     #[structopt(
         long = "force-associate-destination",
         help = "Force to associate with the destination",
@@ -251,6 +503,84 @@ struct Flags {
         display_order(1001)
     )]
     pub force_associate_bind_addr: bool,
+    #[structopt(
+        long = "snoop-dns",
+        help = "Connect to destinations in names snooped from DNS responses",
+        display_order(1002)
+    )]
+    pub snoop_dns: bool,
+    #[structopt(
+        long = "mirror-dscp",
+        help = "Mirror the DSCP set by the source",
+        display_order(1003)
+    )]
+    pub mirror_dscp: bool,
+    #[structopt(
+        long = "no-promiscuous",
+        help = "Open the interface without promiscuous mode",
+        display_order(1004)
+    )]
+    pub no_promiscuous: bool,
+    #[structopt(
+        long = "direct-fallback",
+        help = "Connect to destinations directly if the proxy is unreachable",
+        display_order(1005)
+    )]
+    pub direct_fallback: bool,
+    #[structopt(
+        long = "no-padding",
+        help = "Send frames without padding to the minimum Ethernet size",
+        display_order(1006)
+    )]
+    pub no_padding: bool,
+    #[structopt(
+        long = "clamp-black-hole",
+        help = "Clamp the MTU of the source on detecting an MTU black hole",
+        display_order(1007)
+    )]
+    pub clamp_black_hole: bool,
+    #[structopt(
+        long = "auto-reopen",
+        help = "Reopen the interface once it is gone",
+        display_order(1008)
+    )]
+    pub auto_reopen: bool,
+    #[structopt(
+        long = "dry-run",
+        help = "Parse and classify the traffic without forwarding",
+        display_order(1009)
+    )]
+    pub dry_run: bool,
+    #[structopt(
+        long = "loopback",
+        help = "Include loopback interfaces",
+        display_order(1010)
+    )]
+    pub loopback: bool,
+    #[structopt(
+        long = "wscale-fallback",
+        help = "Disable the window scale of destinations whose sources do not scale their windows",
+        display_order(1011)
+    )]
+    pub wscale_fallback: bool,
+    #[structopt(
+        long = "decrement-ttl",
+        help = "Decrement the TTL of packets to destinations, and reply time exceeded",
+        display_order(1012)
+    )]
+    pub decrement_ttl: bool,
+    #[structopt(
+        long = "no-nodelay",
+        help = "Connect to the destination without TCP_NODELAY",
+        display_order(1013)
+    )]
+    pub no_nodelay: bool,
+    #[structopt(
+        long = "drop-frames",
+        help = "Drop captured frames if the redirection falls behind",
+        display_order(1014)
+    )]
+    pub drop_frames: bool,
     #[structopt(
         long,
         help = "Username",
@@ -267,6 +597,272 @@ struct Flags {
         display_order(1001)
     )]
     pub password: Option<String>,
+    #[structopt(
+        long,
+        help = "Rate limit of each connection in Bytes per second",
+        value_name = "VALUE",
+        display_order(1002)
+    )]
+    pub rate_limit: Option<usize>,
+    #[structopt(
+        long,
+        help = "Max concurrent TCP streams",
+        value_name = "VALUE",
+        display_order(1003)
+    )]
+    pub max_streams: Option<usize>,
+    #[structopt(
+        long,
+        help = "Idle timeout of TCP connections in seconds",
+        value_name = "VALUE",
+        display_order(1004)
+    )]
+    pub idle_timeout: Option<u64>,
+    #[structopt(
+        long,
+        help = "Timeout of connecting to the proxy in seconds",
+        value_name = "VALUE",
+        display_order(1005)
+    )]
+    pub connect_timeout: Option<u64>,
+    #[structopt(
+        long,
+        help = "Size of the pool of warm connections to the proxy",
+        value_name = "VALUE",
+        display_order(1006)
+    )]
+    pub pool_size: Option<usize>,
+    #[structopt(
+        long,
+        help = "Max retries of connecting to the proxy",
+        value_name = "VALUE",
+        display_order(1007)
+    )]
+    pub connect_retries: Option<usize>,
+    #[structopt(
+        long,
+        help = "Interval of ARP announcements in seconds",
+        value_name = "VALUE",
+        display_order(1008)
+    )]
+    pub arp_interval: Option<u64>,
+    #[structopt(
+        long,
+        help = "DSCP of the packets to the source",
+        value_name = "VALUE",
+        parse(try_from_str = parse_dscp),
+        display_order(1009)
+    )]
+    pub dscp: Option<u8>,
+    #[structopt(
+        long = "path-mtu",
+        help = "MTU of the path to destinations",
+        value_name = "VALUE",
+        parse(try_from_str = parse_path_mtu),
+        display_order(1010)
+    )]
+    pub path_mtu: Option<usize>,
+    #[structopt(
+        long = "buffer-size",
+        help = "Buffer size of the interface",
+        value_name = "VALUE",
+        parse(try_from_str = parse_buffer_size),
+        display_order(1011)
+    )]
+    pub buffer_size: Option<usize>,
+    #[structopt(
+        long,
+        help = "Snapshot length of the interface, which only sizes the read buffer",
+        value_name = "VALUE",
+        parse(try_from_str = parse_snaplen),
+        display_order(1012)
+    )]
+    pub snaplen: Option<usize>,
+    #[structopt(
+        long = "max-datagrams",
+        help = "Maximum number of UDP source ports",
+        value_name = "VALUE",
+        display_order(1013)
+    )]
+    pub max_datagrams: Option<usize>,
+    #[structopt(
+        long = "association-timeout",
+        help = "Timeout of UDP associations in seconds",
+        value_name = "VALUE",
+        display_order(1015)
+    )]
+    pub association_timeout: Option<u64>,
+    #[structopt(
+        long,
+        help = "Destination connected directly",
+        value_name = "ADDRESS",
+        number_of_values(1),
+        parse(try_from_str = parse_network),
+        display_order(1016)
+    )]
+    pub bypass: Vec<Ipv4Network>,
+    #[structopt(
+        long = "verify-checksums",
+        help = "Checksums of packets from the source to verify",
+        value_name = "MODE",
+        default_value = "off",
+        possible_values(&["off", "ipv4", "full"]),
+        display_order(1017)
+    )]
+    pub verify_checksums: String,
+    #[structopt(
+        long = "hardware-address",
+        help = "Hardware address presented to the source",
+        value_name = "ADDRESS",
+        display_order(1018)
+    )]
+    pub hardware_addr: Option<HardwareAddr>,
+    #[structopt(
+        long = "proxy-arp",
+        help = "Address replied in ARP as owned by the interface",
+        value_name = "ADDRESS",
+        number_of_values(1),
+        parse(try_from_str = parse_network),
+        display_order(1019)
+    )]
+    pub proxy_arp: Vec<Ipv4Network>,
+    #[structopt(
+        long = "read-timeout",
+        help = "Read timeout of the interface in milliseconds",
+        value_name = "VALUE",
+        parse(try_from_str = parse_read_timeout),
+        display_order(1020)
+    )]
+    pub read_timeout: Option<u64>,
+    #[structopt(
+        long = "gssapi",
+        help = "Service of the GSSAPI authentication",
+        value_name = "SERVICE",
+        display_order(1021)
+    )]
+    pub gssapi: Option<String>,
+    #[structopt(
+        long = "gssapi-protection",
+        help = "Protection level of the GSSAPI authentication",
+        value_name = "LEVEL",
+        requires("gssapi"),
+        parse(try_from_str = parse_gssapi_protection),
+        display_order(1022)
+    )]
+    pub gssapi_protection: Option<GssapiProtection>,
+    #[structopt(
+        long = "proxy-type",
+        help = "Type of the proxy",
+        value_name = "TYPE",
+        default_value = "socks",
+        possible_values(&["socks", "http"]),
+        display_order(1023)
+    )]
+    pub proxy_type: String,
+    #[structopt(
+        long = "no-wscale",
+        help = "Destination which the window scale is disabled for",
+        value_name = "ADDRESS",
+        number_of_values(1),
+        display_order(1024)
+    )]
+    pub no_wscale: Vec<SocketAddrV4>,
+    #[structopt(
+        long,
+        help = "TTL of the packets to the source",
+        value_name = "VALUE",
+        parse(try_from_str = parse_ttl),
+        display_order(1025)
+    )]
+    pub ttl: Option<u8>,
+    #[structopt(
+        long,
+        help = "Policy of selecting destinations in failover",
+        value_name = "POLICY",
+        default_value = "priority",
+        possible_values(&["priority", "round-robin"]),
+        display_order(1026)
+    )]
+    pub failover: String,
+    #[structopt(
+        long = "egress-address",
+        help = "Local address of connections to the destination",
+        value_name = "ADDRESS",
+        display_order(1027)
+    )]
+    pub egress_addr: Option<Ipv4Addr>,
+    #[structopt(
+        long = "send-buffer",
+        help = "Send buffer size of connections to the destination",
+        value_name = "VALUE",
+        display_order(1028)
+    )]
+    pub send_buffer_size: Option<u32>,
+    #[structopt(
+        long = "recv-buffer",
+        help = "Receive buffer size of connections to the destination",
+        value_name = "VALUE",
+        display_order(1029)
+    )]
+    pub recv_buffer_size: Option<u32>,
+}
+
+fn parse_buffer_size(s: &str) -> Result<usize, String> {
+    match s.parse::<usize>() {
+        Ok(buffer_size) if buffer_size > 0 => Ok(buffer_size),
+        _ => Err(String::from("buffer size should be a positive integer")),
+    }
+}
+
+fn parse_snaplen(s: &str) -> Result<usize, String> {
+    match s.parse::<usize>() {
+        Ok(snaplen) if snaplen >= MIN_SNAPLEN => Ok(snaplen),
+        _ => Err(format!(
+            "snaplen should be an integer at least {}",
+            MIN_SNAPLEN
+        )),
+    }
+}
+
+fn parse_read_timeout(s: &str) -> Result<u64, String> {
+    match s.parse::<u64>() {
+        Ok(read_timeout) if read_timeout > 0 => Ok(read_timeout),
+        _ => Err(String::from("read timeout should be a positive integer")),
+    }
+}
+
+fn parse_gssapi_protection(s: &str) -> Result<GssapiProtection, String> {
+    match s {
+        "clear" => Ok(GssapiProtection::Clear),
+        "integrity" => Ok(GssapiProtection::Integrity),
+        "confidentiality" => Ok(GssapiProtection::Confidentiality),
+        _ => Err(String::from(
+            "GSSAPI protection level should be clear, integrity or confidentiality",
+        )),
+    }
+}
+
+fn parse_dscp(s: &str) -> Result<u8, String> {
+    match s.parse::<u8>() {
+        Ok(dscp) if dscp < 64 => Ok(dscp),
+        _ => Err(String::from("DSCP should be an integer from 0 to 63")),
+    }
+}
+
+fn parse_path_mtu(s: &str) -> Result<usize, String> {
+    match s.parse::<u16>() {
+        Ok(path_mtu) if path_mtu >= 68 => Ok(path_mtu as usize),
+        _ => Err(String::from(
+            "path MTU should be an integer from 68 to 65535",
+        )),
+    }
+}
+
+fn parse_ttl(s: &str) -> Result<u8, String> {
+    match s.parse::<u8>() {
+        Ok(ttl) if ttl > 0 => Ok(ttl),
+        _ => Err(String::from("TTL should be an integer from 1 to 255")),
+    }
 }
 
 /// Represents a logger.
@@ -296,11 +892,13 @@ impl Logger {
         let stderr_logger = env_logger::builder()
             .target(Target::Stderr)
             .filter_level(level)
+            .filter_module("tracing::span", LevelFilter::Off)
             .format(fmt)
             .build();
         let stdout_logger = env_logger::builder()
             .target(Target::Stdout)
             .filter_level(level)
+            .filter_module("tracing::span", LevelFilter::Off)
             .format(fmt)
             .build();
 
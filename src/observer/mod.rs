@@ -0,0 +1,214 @@
+//! Support for observing connections.
+
+use crate::packet::layer::LayerKind;
+use crate::tcp::TcpState;
+use std::net::SocketAddrV4;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::{RecvError, TryRecvError};
+
+/// Represents the default capacity of the event queue.
+pub const EVENT_QUEUE_SIZE: usize = 1024;
+
+/// Trait for observing lifecycle events of connections. All methods do nothing by default.
+pub trait Observer: Send + Sync {
+    /// Called when a TCP connection is established with the proxy.
+    fn on_connect(&self, _src: SocketAddrV4, _dst: SocketAddrV4) {}
+
+    /// Called when a TCP connection is closed gracefully.
+    fn on_close(&self, _src: SocketAddrV4, _dst: SocketAddrV4) {}
+
+    /// Called when a TCP connection is reset.
+    fn on_reset(&self, _src: SocketAddrV4, _dst: SocketAddrV4) {}
+
+    /// Called when the window advertised to the source of a TCP connection has been zero beyond
+    /// the threshold, which usually indicates the proxy or the destination is slower than the
+    /// source. It will be called once until the window opens again.
+    fn on_window_full(&self, _src: SocketAddrV4, _dst: SocketAddrV4, _duration: Duration) {}
+
+    /// Called when a local UDP port is bound for a source.
+    fn on_bind(&self, _src: SocketAddrV4, _port: u16) {}
+
+    /// Called when a local UDP port is unbound from a source.
+    fn on_unbind(&self, _src: SocketAddrV4, _port: u16) {}
+
+    /// Called when the interface is gone, like the adapter is unplugged or removed.
+    fn on_interface_down(&self, _name: &str) {}
+
+    /// Called when the interface is reopened after it was gone.
+    fn on_interface_up(&self, _name: &str) {}
+}
+
+/// Represents a snapshot of a connection.
+#[derive(Clone, Debug)]
+pub struct ConnectionInfo {
+    /// Represents the protocol of the connection, TCP or UDP.
+    pub kind: LayerKind,
+    /// Represents the source of the connection.
+    pub src: SocketAddrV4,
+    /// Represents the destination of the connection. UDP connections have no fixed destination.
+    pub dst: Option<SocketAddrV4>,
+    /// Represents the local port bound for the connection. Only UDP connections bind a port.
+    pub local_port: Option<u16>,
+    /// Represents the name of the destination sent to the proxy.
+    pub name: Option<String>,
+    /// Represents if the connection is connected directly instead of through the proxy.
+    pub is_direct: bool,
+    /// Represents the size of data sent to the proxy.
+    pub tx_size: usize,
+    /// Represents the size of data received from the proxy.
+    pub rx_size: usize,
+    /// Represents the state of the connection. Only TCP connections have states.
+    pub state: Option<TcpState>,
+    /// Represents the next sequence to send to the source.
+    pub send_next: Option<u32>,
+    /// Represents the next sequence to receive from the source.
+    pub recv_next: Option<u32>,
+    /// Represents the window of the source.
+    pub window: Option<usize>,
+    /// Represents the MSS segmenting data sent to the source, which is lowered as full-sized
+    /// segments keep timing out, like in an MTU black hole, and probed up as data flows again.
+    pub mss: Option<usize>,
+    /// Represents how long the window advertised to the source has been zero.
+    pub zero_window: Option<Duration>,
+    /// Represents the smoothed RTT to the source, which is updated as ACKs arrive.
+    pub srtt: Option<Duration>,
+    /// Represents the RTT variation to the source, which is updated as ACKs arrive.
+    pub rttvar: Option<Duration>,
+    /// Represents the count of data and FIN segments sent to the source, including
+    /// retransmissions.
+    pub sent_segments: usize,
+    /// Represents the count of data and FIN segments retransmitted to the source.
+    pub retrans_segments: usize,
+    /// Represents the ratio of retransmitted segments in all the segments sent to the source,
+    /// which is a rough estimate of the packet loss toward the source.
+    pub retrans_ratio: Option<f64>,
+    /// Represents the size of data sent to the source but not acknowledged.
+    pub cache_size: usize,
+    /// Represents the size of data queued to send to the source.
+    pub queue_size: usize,
+    /// Represents if the connection is closed for writing to the proxy.
+    pub is_tx_closed: bool,
+    /// Represents if the connection is closed for reading from the proxy.
+    pub is_rx_closed: bool,
+}
+
+/// Enumeration of lifecycle events of connections.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Event {
+    /// Represents a TCP connection is established with the proxy.
+    Connect {
+        src: SocketAddrV4,
+        dst: SocketAddrV4,
+    },
+    /// Represents a TCP connection is closed gracefully.
+    Close {
+        src: SocketAddrV4,
+        dst: SocketAddrV4,
+    },
+    /// Represents a TCP connection is reset.
+    Reset {
+        src: SocketAddrV4,
+        dst: SocketAddrV4,
+    },
+    /// Represents a local UDP port is bound for a source.
+    Bind { src: SocketAddrV4, port: u16 },
+    /// Represents a local UDP port is unbound from a source.
+    Unbind { src: SocketAddrV4, port: u16 },
+    /// Represents an idle connection is reaped. UDP associations have no destination.
+    Reap {
+        src: SocketAddrV4,
+        dst: Option<SocketAddrV4>,
+    },
+    /// Represents data or a FIN of a TCP connection is retransmitted to the source, either fast or
+    /// due to timeout.
+    Retransmit {
+        src: SocketAddrV4,
+        dst: SocketAddrV4,
+        is_timedout: bool,
+    },
+}
+
+/// Creates a bounded queue of events in a `tokio::sync::broadcast` channel, returning the sender
+/// and the receiver. Unlike a `tokio::sync::mpsc` channel, the sender never blocks or fails when
+/// the queue is full, but drops the oldest event instead, so a lagging consumer never holds back
+/// the redirector. The capacity is rounded up to a power of 2.
+pub fn event_queue(capacity: usize) -> (EventSender, EventReceiver) {
+    let (tx, rx) = broadcast::channel(capacity.max(1));
+
+    (EventSender(tx), EventReceiver { rx, dropped: 0 })
+}
+
+/// Represents the sending half of an event queue.
+#[derive(Clone)]
+pub struct EventSender(broadcast::Sender<Event>);
+
+impl EventSender {
+    /// Sends an event. The oldest event is dropped if the queue is full.
+    pub fn send(&self, event: Event) {
+        // Fails only if the receiver is dropped
+        let _ = self.0.send(event);
+    }
+}
+
+/// Represents the receiving half of an event queue.
+pub struct EventReceiver {
+    rx: broadcast::Receiver<Event>,
+    dropped: usize,
+}
+
+impl EventReceiver {
+    /// Receives the next event. Returns `None` if the queue is empty and all the senders are
+    /// dropped.
+    pub async fn recv(&mut self) -> Option<Event> {
+        loop {
+            match self.rx.recv().await {
+                Ok(event) => return Some(event),
+                Err(RecvError::Lagged(n)) => self.dropped += n as usize,
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    }
+
+    /// Tries to receive the next event without waiting.
+    pub fn try_recv(&mut self) -> Option<Event> {
+        loop {
+            match self.rx.try_recv() {
+                Ok(event) => return Some(event),
+                Err(TryRecvError::Lagged(n)) => self.dropped += n as usize,
+                Err(_) => return None,
+            }
+        }
+    }
+
+    /// Returns the count of events dropped because the receiver falls behind. Drops are counted
+    /// once the receiver catches up with the queue.
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+}
+
+#[tokio::test]
+async fn event_queue_drop_oldest() {
+    use std::net::Ipv4Addr;
+
+    let src = SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 2), 10000);
+    let (tx, mut rx) = event_queue(2);
+    for port in 1..=3 {
+        tx.send(Event::Bind { src, port });
+    }
+
+    // The oldest event is dropped
+    assert_eq!(rx.recv().await, Some(Event::Bind { src, port: 2 }));
+    assert_eq!(rx.dropped(), 1);
+    assert_eq!(rx.recv().await, Some(Event::Bind { src, port: 3 }));
+
+    // Events sent from another task wake the receiver, which ends once the senders are dropped
+    let tx_cloned = tx.clone();
+    drop(tx);
+    tokio::spawn(async move {
+        tx_cloned.send(Event::Unbind { src, port: 3 });
+    });
+    assert_eq!(rx.recv().await, Some(Event::Unbind { src, port: 3 }));
+    assert_eq!(rx.recv().await, None);
+}
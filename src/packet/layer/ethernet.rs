@@ -1,16 +1,23 @@
 //! Support for serializing and deserializing the Ethernet layer.
 
 use super::{Layer, LayerKind, LayerKinds};
-use pnet::packet::ethernet::{self, EtherTypes, EthernetPacket, MutableEthernetPacket};
+use pnet::packet::ethernet::{self, EtherType, EtherTypes, EthernetPacket, MutableEthernetPacket};
+use pnet::packet::{MutablePacket, Packet};
 use pnet::util::MacAddr;
 use std::clone::Clone;
 use std::fmt::{self, Display, Formatter};
 use std::io;
 
+/// Represents the size of an 802.1Q VLAN tag.
+const VLAN_TAG_SIZE: usize = 4;
+/// Represents the mask of the VLAN identifier in the tag control information.
+const VID_MASK: u16 = 0x0fff;
+
 /// Represents an Ethernet layer.
 #[derive(Clone, Debug)]
 pub struct Ethernet {
     pub layer: ethernet::Ethernet,
+    vlan: Option<u16>,
 }
 
 impl Ethernet {
@@ -32,18 +39,51 @@ impl Ethernet {
 
     /// Creates an `Ethernet` according to the given `Ethernet`.
     pub fn from(ethernet: ethernet::Ethernet) -> Ethernet {
-        Ethernet { layer: ethernet }
+        Ethernet {
+            layer: ethernet,
+            vlan: None,
+        }
     }
 
-    /// Creates an `Ethernet` according to the given Ethernet packet.
+    /// Creates an `Ethernet` according to the given Ethernet packet. The ethertype of the layer
+    /// will be the inner one if the packet carries an 802.1Q VLAN tag.
     pub fn parse(packet: &EthernetPacket) -> Ethernet {
+        let (ethertype, vlan) = match packet.get_ethertype() {
+            EtherTypes::Vlan if packet.payload().len() >= VLAN_TAG_SIZE => {
+                let payload = packet.payload();
+                let tci = u16::from_be_bytes([payload[0], payload[1]]);
+
+                (
+                    EtherType::new(u16::from_be_bytes([payload[2], payload[3]])),
+                    Some(tci & VID_MASK),
+                )
+            }
+            ethertype => (ethertype, None),
+        };
         let ethernet = ethernet::Ethernet {
             destination: packet.get_destination(),
             source: packet.get_source(),
-            ethertype: packet.get_ethertype(),
+            ethertype,
             payload: vec![],
         };
-        Ethernet::from(ethernet)
+        let mut ethernet = Ethernet::from(ethernet);
+        ethernet.set_vlan(vlan);
+        ethernet
+    }
+
+    /// Sets the VLAN identifier of the layer. The layer will carry an 802.1Q VLAN tag if the VLAN
+    /// identifier is set.
+    pub fn set_vlan(&mut self, vlan: Option<u16>) {
+        self.vlan = vlan.map(|vlan| vlan & VID_MASK);
+    }
+
+    /// Returns the next level layer kind of the layer.
+    pub fn next_level_layer_kind(&self) -> Option<LayerKind> {
+        match self.layer.ethertype {
+            EtherTypes::Arp => Some(LayerKinds::Arp),
+            EtherTypes::Ipv4 => Some(LayerKinds::Ipv4),
+            _ => None,
+        }
     }
 
     /// Returns the source of the layer.
@@ -55,16 +95,46 @@ impl Ethernet {
     pub fn dst(&self) -> MacAddr {
         self.layer.destination
     }
+
+    /// Returns the VLAN identifier of the layer.
+    pub fn vlan(&self) -> Option<u16> {
+        self.vlan
+    }
+
+    /// Returns the size of the VLAN tag of the layer.
+    pub fn tag_len(&self) -> usize {
+        match self.vlan {
+            Some(_) => VLAN_TAG_SIZE,
+            None => 0,
+        }
+    }
+
+    /// Returns the minimum of the layer when converted into a byte-array.
+    pub fn minimum_len() -> usize {
+        EthernetPacket::minimum_packet_size()
+    }
+
+    /// Returns the maximum of the layer when converted into a byte-array, which carries a VLAN
+    /// tag.
+    pub fn maximum_len() -> usize {
+        Ethernet::minimum_len() + VLAN_TAG_SIZE
+    }
 }
 
 impl Display for Ethernet {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let vlan = match self.vlan {
+            Some(vlan) => format!(", VLAN = {}", vlan),
+            None => String::new(),
+        };
+
         write!(
             f,
-            "{}: {} -> {}",
+            "{}: {} -> {}{}",
             LayerKinds::Ethernet,
             self.layer.source,
-            self.layer.destination
+            self.layer.destination,
+            vlan
         )
     }
 }
@@ -75,15 +145,26 @@ impl Layer for Ethernet {
     }
 
     fn len(&self) -> usize {
-        EthernetPacket::packet_size(&self.layer)
+        EthernetPacket::packet_size(&self.layer) + self.tag_len()
     }
 
     fn serialize(&self, buffer: &mut [u8], _: usize) -> io::Result<usize> {
+        if buffer.len() < self.len() {
+            return Err(io::Error::new(io::ErrorKind::WriteZero, "buffer too small"));
+        }
         let mut packet = MutableEthernetPacket::new(buffer)
             .ok_or_else(|| io::Error::new(io::ErrorKind::WriteZero, "buffer too small"))?;
 
         packet.populate(&self.layer);
 
+        // VLAN tag
+        if let Some(vlan) = self.vlan {
+            packet.set_ethertype(EtherTypes::Vlan);
+            let tag = &mut packet.payload_mut()[..VLAN_TAG_SIZE];
+            tag[..2].copy_from_slice(&vlan.to_be_bytes());
+            tag[2..].copy_from_slice(&self.layer.ethertype.0.to_be_bytes());
+        }
+
         Ok(self.len())
     }
 
@@ -4,6 +4,7 @@ use super::{Layer, LayerKind, LayerKinds};
 use pnet::packet::icmp::destination_unreachable;
 use pnet::packet::icmp::echo_reply;
 use pnet::packet::icmp::echo_request;
+use pnet::packet::icmp::time_exceeded;
 use pnet::packet::icmp::{self, Icmp, IcmpPacket, IcmpTypes, MutableIcmpPacket};
 use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
 use pnet::packet::ipv4::Ipv4Packet;
@@ -67,6 +68,33 @@ impl Icmpv4 {
         Icmpv4::from(icmp)
     }
 
+    /// Creates a `Icmpv4` represents an ICMPv4 fragmentation required, and DF flag set.
+    pub fn new_fragmentation_required_and_df_flag_set(next_hop_mtu: u16, payload: &[u8]) -> Icmpv4 {
+        let mut next_payload = vec![0u8; 4 + payload.len()];
+        next_payload[2..4].copy_from_slice(&next_hop_mtu.to_be_bytes());
+        next_payload[4..].copy_from_slice(payload);
+        let icmp = Icmp {
+            icmp_type: IcmpTypes::DestinationUnreachable,
+            icmp_code: destination_unreachable::IcmpCodes::FragmentationRequiredAndDFFlagSet,
+            checksum: 0,
+            payload: next_payload,
+        };
+        Icmpv4::from(icmp)
+    }
+
+    /// Creates a `Icmpv4` represents an ICMPv4 time to live exceeded in transit.
+    pub fn new_time_exceeded(payload: &[u8]) -> Icmpv4 {
+        let mut next_payload = vec![0u8; 4 + payload.len()];
+        next_payload[4..].copy_from_slice(payload);
+        let icmp = Icmp {
+            icmp_type: IcmpTypes::TimeExceeded,
+            icmp_code: time_exceeded::IcmpCodes::TimeToLiveExceededInTransit,
+            checksum: 0,
+            payload: next_payload,
+        };
+        Icmpv4::from(icmp)
+    }
+
     /// Creates an `Icmpv4` according to the given `Icmp`.
     pub fn from(icmp: Icmp) -> Icmpv4 {
         Icmpv4 { layer: icmp }
@@ -89,6 +117,8 @@ impl Icmpv4 {
             String::from("Destination port unreachable")
         } else if self.is_fragmentation_required_and_df_flag_set() {
             String::from("Fragmentation required, and DF flag set")
+        } else if self.is_time_exceeded() {
+            String::from("Time to live exceeded in transit")
         } else if self.is_echo_request() {
             String::from("Echo request")
         } else {
@@ -101,7 +131,7 @@ impl Icmpv4 {
 
     /// Returns the identifier (NE) of the layer.
     pub fn identifier(&self) -> Option<u16> {
-        if self.is_echo_reply() || self.is_echo_request() {
+        if (self.is_echo_reply() || self.is_echo_request()) && self.layer.payload.len() >= 4 {
             let buffer = [self.layer.payload[0], self.layer.payload[1]];
             Some(u16::from_ne_bytes(buffer))
         } else {
@@ -111,7 +141,7 @@ impl Icmpv4 {
 
     /// Returns the sequence number (NE) of the layer.
     pub fn sequence_number(&self) -> Option<u16> {
-        if self.is_echo_reply() || self.is_echo_request() {
+        if (self.is_echo_reply() || self.is_echo_request()) && self.layer.payload.len() >= 4 {
             let buffer = [self.layer.payload[2], self.layer.payload[3]];
             Some(u16::from_ne_bytes(buffer))
         } else {
@@ -121,7 +151,7 @@ impl Icmpv4 {
 
     /// Returns the next-hop MTU of the layer.
     pub fn next_hop_mtu(&self) -> Option<u16> {
-        if self.is_fragmentation_required_and_df_flag_set() {
+        if self.is_fragmentation_required_and_df_flag_set() && self.layer.payload.len() >= 4 {
             let buffer = [self.layer.payload[2], self.layer.payload[3]];
             Some(u16::from_be_bytes(buffer))
         } else {
@@ -131,10 +161,8 @@ impl Icmpv4 {
 
     /// Returns the source IP address in the payload of the layer.
     pub fn src_ip_addr(&self) -> Option<Ipv4Addr> {
-        if self.is_destination_port_unreachable()
-            || self.is_fragmentation_required_and_df_flag_set()
-        {
-            let (ipv4, _) = self.parse_payload().unwrap();
+        if self.is_quoting() {
+            let (ipv4, _) = self.parse_payload()?;
             Some(ipv4.src())
         } else {
             None
@@ -143,10 +171,8 @@ impl Icmpv4 {
 
     /// Returns the destination IP address in the payload of the layer.
     pub fn dst_ip_addr(&self) -> Option<Ipv4Addr> {
-        if self.is_destination_port_unreachable()
-            || self.is_fragmentation_required_and_df_flag_set()
-        {
-            let (ipv4, _) = self.parse_payload().unwrap();
+        if self.is_quoting() {
+            let (ipv4, _) = self.parse_payload()?;
             Some(ipv4.dst())
         } else {
             None
@@ -155,10 +181,8 @@ impl Icmpv4 {
 
     /// Returns the next level protocol in the payload of the layer.
     pub fn next_level_protocol(&self) -> Option<IpNextHeaderProtocol> {
-        if self.is_destination_port_unreachable()
-            || self.is_fragmentation_required_and_df_flag_set()
-        {
-            let (ipv4, _) = self.parse_payload().unwrap();
+        if self.is_quoting() {
+            let (ipv4, _) = self.parse_payload()?;
             Some(ipv4.next_level_protocol())
         } else {
             None
@@ -167,10 +191,8 @@ impl Icmpv4 {
 
     /// Returns the next level layer kind in the payload of the layer.
     pub fn next_level_layer_kind(&self) -> Option<LayerKind> {
-        if self.is_destination_port_unreachable()
-            || self.is_fragmentation_required_and_df_flag_set()
-        {
-            let (ipv4, _) = self.parse_payload().unwrap();
+        if self.is_quoting() {
+            let (ipv4, _) = self.parse_payload()?;
             ipv4.next_level_layer_kind()
         } else {
             None
@@ -179,10 +201,8 @@ impl Icmpv4 {
 
     /// Returns the source in the payload of the layer.
     pub fn src(&self) -> Option<SocketAddrV4> {
-        if self.is_destination_port_unreachable()
-            || self.is_fragmentation_required_and_df_flag_set()
-        {
-            let (_, transport) = self.parse_payload().unwrap();
+        if self.is_quoting() {
+            let (_, transport) = self.parse_payload()?;
             match transport {
                 Some(transport) => match transport {
                     Layers::Tcp(ref tcp) => Some(SocketAddrV4::new(tcp.src_ip_addr(), tcp.src())),
@@ -198,10 +218,8 @@ impl Icmpv4 {
 
     /// Returns the destination in the payload of the layer.
     pub fn dst(&self) -> Option<SocketAddrV4> {
-        if self.is_destination_port_unreachable()
-            || self.is_fragmentation_required_and_df_flag_set()
-        {
-            let (_, transport) = self.parse_payload().unwrap();
+        if self.is_quoting() {
+            let (_, transport) = self.parse_payload()?;
             match transport {
                 Some(transport) => match transport {
                     Layers::Tcp(ref tcp) => Some(SocketAddrV4::new(tcp.dst_ip_addr(), tcp.dst())),
@@ -215,13 +233,20 @@ impl Icmpv4 {
         }
     }
 
+    /// Returns if the layer quotes the IPv4 header and the leading payload of a datagram.
+    fn is_quoting(&self) -> bool {
+        self.is_destination_port_unreachable()
+            || self.is_fragmentation_required_and_df_flag_set()
+            || self.is_time_exceeded()
+    }
+
     fn parse_payload(&self) -> Option<(Ipv4, Option<Layers>)> {
         if self.layer.payload.len() < 4 {
             return None;
         }
         let payload = &self.layer.payload[4..];
         match Ipv4Packet::new(payload) {
-            Some(ref ipv4_packet) => {
+            Some(ref ipv4_packet) if Ipv4::is_parsable(ipv4_packet) => {
                 let ipv4 = Ipv4::parse(ipv4_packet);
                 // Fragment
                 if !ipv4.is_fragment() {
@@ -230,10 +255,9 @@ impl Icmpv4 {
                             IcmpPacket::new(ipv4_packet.payload())
                                 .map(|icmp_packet| Layers::Icmpv4(Icmpv4::parse(&icmp_packet)))
                         }
-                        IpNextHeaderProtocols::Tcp => {
-                            TcpPacket::new(ipv4_packet.payload())
-                                .map(|tcp_packet| Layers::Tcp(Tcp::parse(&tcp_packet, &ipv4)))
-                        },
+                        IpNextHeaderProtocols::Tcp => TcpPacket::new(ipv4_packet.payload())
+                            .filter(Tcp::is_parsable)
+                            .map(|tcp_packet| Layers::Tcp(Tcp::parse(&tcp_packet, &ipv4))),
                         IpNextHeaderProtocols::Udp => {
                             UdpPacket::new(ipv4_packet.payload())
                                 .map(|udp_packet| Layers::Udp(Udp::parse(&udp_packet, &ipv4)))
@@ -246,7 +270,7 @@ impl Icmpv4 {
                     Some((ipv4, None))
                 }
             }
-            None => None,
+            _ => None,
         }
     }
 
@@ -277,6 +301,12 @@ impl Icmpv4 {
                 == destination_unreachable::IcmpCodes::FragmentationRequiredAndDFFlagSet
     }
 
+    /// Returns if the layer is an ICMPv4 time to live exceeded in transit.
+    pub fn is_time_exceeded(&self) -> bool {
+        self.layer.icmp_type == IcmpTypes::TimeExceeded
+            && self.layer.icmp_code == time_exceeded::IcmpCodes::TimeToLiveExceededInTransit
+    }
+
     /// Returns if the layer is an ICMPv4 echo request.
     pub fn is_echo_request(&self) -> bool {
         self.layer.icmp_type == IcmpTypes::EchoRequest
@@ -1,9 +1,11 @@
 //! Support for serializing and deserializing the IPv4 layer.
 
-use super::{Layer, LayerKind, LayerKinds};
+use super::{is_options_parsable, Layer, LayerKind, LayerKinds};
 use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
 use pnet::packet::ipv4::{self, Ipv4Flags, Ipv4OptionPacket, Ipv4Packet, MutableIpv4Packet};
+use pnet::packet::Packet;
 use std::clone::Clone;
+use std::cmp::max;
 use std::fmt::{self, Display, Formatter};
 use std::io;
 use std::net::Ipv4Addr;
@@ -11,6 +13,12 @@ use std::net::Ipv4Addr;
 /// Represents the TTL in the sent packets.
 const TTL: u8 = 128;
 
+/// Represents the maximum DSCP.
+const MAX_DSCP: u8 = 0x3f;
+
+/// Represents the mask of the number in the type of an option.
+const OPTION_NUMBER_MASK: u8 = 0x1f;
+
 /// Represents an IPv4 layer.
 #[derive(Clone, Debug)]
 pub struct Ipv4 {
@@ -81,6 +89,21 @@ impl Ipv4 {
         None
     }
 
+    /// Sets the DSCP of the layer. The DSCP should be less than 64.
+    pub fn set_dscp(&mut self, dscp: u8) -> io::Result<()> {
+        if dscp > MAX_DSCP {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "DSCP too big"));
+        }
+        self.layer.dscp = dscp;
+
+        Ok(())
+    }
+
+    /// Sets the TTL of the layer.
+    pub fn set_ttl(&mut self, ttl: u8) {
+        self.layer.ttl = ttl;
+    }
+
     /// Creates an `Ipv4` according to the given `Ipv4`.
     pub fn from(ipv4: ipv4::Ipv4) -> Ipv4 {
         Ipv4 { layer: ipv4 }
@@ -108,11 +131,40 @@ impl Ipv4 {
         Ipv4::from(d_ipv4)
     }
 
+    /// Returns if the given IPv4 packet can be parsed, which requires an IHL not exceeding both the
+    /// total length and the buffer, and well-formed options.
+    pub fn is_parsable(packet: &Ipv4Packet) -> bool {
+        let header_length = packet.get_header_length() as usize * 4;
+
+        header_length >= Ipv4::minimum_len()
+            && header_length <= packet.get_total_length() as usize
+            && header_length <= packet.packet().len()
+            && is_options_parsable(
+                &packet.packet()[Ipv4::minimum_len()..header_length],
+                OPTION_NUMBER_MASK,
+            )
+    }
+
     /// Returns the minimum of the layer when converted into a byte-array.
     pub fn minimum_len() -> usize {
         20
     }
 
+    /// Returns the header length of the layer indicated by the IHL, including the options.
+    pub fn header_len(&self) -> usize {
+        self.layer.header_length as usize * 4
+    }
+
+    /// Returns the DSCP of the layer.
+    pub fn dscp(&self) -> u8 {
+        self.layer.dscp
+    }
+
+    /// Returns the TTL of the layer.
+    pub fn ttl(&self) -> u8 {
+        self.layer.ttl
+    }
+
     /// Returns the total length of the layer.
     pub fn total_length(&self) -> u16 {
         self.layer.total_length
@@ -123,6 +175,11 @@ impl Ipv4 {
         self.layer.identification
     }
 
+    /// Returns if the layer is not allowed to be fragmented.
+    pub fn is_dont_fragment(&self) -> bool {
+        self.layer.flags & Ipv4Flags::DontFragment != 0
+    }
+
     /// Returns if more fragments are follows this layer.
     pub fn is_more_fragment(&self) -> bool {
         self.layer.flags & Ipv4Flags::MoreFragments != 0
@@ -196,7 +253,8 @@ impl Layer for Ipv4 {
             ipv4_options_size += Ipv4OptionPacket::packet_size(option);
         }
 
-        ipv4_size + ipv4_options_size
+        // Honor the IHL in case the options are not parsed in full
+        max(ipv4_size + ipv4_options_size, self.header_len())
     }
 
     fn serialize(&self, buffer: &mut [u8], n: usize) -> io::Result<usize> {
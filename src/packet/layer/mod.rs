@@ -156,3 +156,23 @@ impl Layer for Layers {
         }
     }
 }
+
+/// Returns if the given options can be parsed, where the number of each option is masked by the
+/// given mask, and the options numbered 0 (end of option list) and 1 (no-operation) are single
+/// bytes while the others carry a length covering themselves.
+pub(crate) fn is_options_parsable(options: &[u8], number_mask: u8) -> bool {
+    let mut i = 0;
+    while i < options.len() {
+        match options[i] & number_mask {
+            0 | 1 => i += 1,
+            _ => match options.get(i + 1) {
+                Some(&length) if length >= 2 && i + length as usize <= options.len() => {
+                    i += length as usize
+                }
+                _ => return false,
+            },
+        }
+    }
+
+    true
+}
@@ -1,16 +1,33 @@
 //! Support for serializing and deserializing the TCP layer.
 
 use super::ipv4::Ipv4;
-use super::{Layer, LayerKind, LayerKinds};
+use super::{is_options_parsable, Layer, LayerKind, LayerKinds};
+use md5::{Digest, Md5};
+use pnet::packet::ip::IpNextHeaderProtocols;
 use pnet::packet::tcp::{
     self, MutableTcpOptionPacket, MutableTcpPacket, TcpFlags, TcpOption, TcpOptionNumber,
     TcpOptionNumbers, TcpOptionPacket, TcpPacket,
 };
+use pnet::packet::{MutablePacket, Packet};
 use std::clone::Clone;
 use std::cmp::min;
 use std::fmt::{self, Display, Formatter};
 use std::io;
 use std::net::Ipv4Addr;
+use std::sync::Arc;
+
+/// Represents the number of the TCP MD5 signature option.
+const MD5_OPTION_NUMBER: u8 = 19;
+/// Represents the length of the TCP MD5 signature option.
+const MD5_OPTION_LEN: usize = 18;
+/// Represents the length of the TCP MSS option.
+const MSS_OPTION_LEN: u8 = 4;
+/// Represents the length of the TCP window scale option.
+const WSCALE_OPTION_LEN: u8 = 3;
+/// Represents the length of the TCP timestamps option.
+const TIMESTAMPS_OPTION_LEN: u8 = 10;
+/// Represents the maximum length of the TCP header.
+const MAXIMUM_LEN: usize = 60;
 
 /// Represents a TCP packet.
 #[derive(Clone, Debug)]
@@ -18,6 +35,7 @@ pub struct Tcp {
     layer: tcp::Tcp,
     src: Ipv4Addr,
     dst: Ipv4Addr,
+    md5_key: Option<Arc<[u8]>>,
 }
 
 impl Tcp {
@@ -161,6 +179,20 @@ impl Tcp {
         tcp
     }
 
+    /// Creates a `Tcp` represents a TCP ACK/PSH.
+    pub fn new_ack_psh(
+        src: u16,
+        dst: u16,
+        sequence: u32,
+        acknowledgement: u32,
+        window: u16,
+        ts: Option<(u32, u32)>,
+    ) -> Tcp {
+        let mut tcp = Tcp::new_ack(src, dst, sequence, acknowledgement, window, None, ts);
+        tcp.layer.flags |= TcpFlags::PSH;
+        tcp
+    }
+
     /// Creates a `Tcp` represents a TCP ACK/FIN.
     pub fn new_ack_fin(
         src: u16,
@@ -209,6 +241,7 @@ impl Tcp {
             layer: tcp,
             src: Ipv4Addr::UNSPECIFIED,
             dst: Ipv4Addr::UNSPECIFIED,
+            md5_key: None,
         }
     }
 
@@ -234,17 +267,75 @@ impl Tcp {
         tcp
     }
 
+    /// Returns if the given TCP packet can be parsed, which requires a data offset not exceeding
+    /// the buffer, and well-formed options.
+    pub fn is_parsable(packet: &TcpPacket) -> bool {
+        let header_length = packet.get_data_offset() as usize * 4;
+
+        header_length >= Tcp::minimum_len()
+            && header_length <= packet.packet().len()
+            && is_options_parsable(&packet.packet()[Tcp::minimum_len()..header_length], 0xff)
+    }
+
     /// Returns the minimum of the layer when converted into a byte-array.
     pub fn minimum_len() -> usize {
         20
     }
 
+    /// Returns the length of the TCP MD5 signature option including its padding.
+    pub fn md5_len() -> usize {
+        MD5_OPTION_LEN + 2
+    }
+
     /// Sets the source and destination IP address for the layer with the given `Ipv4`.
     pub fn set_ipv4_layer(&mut self, ipv4: &Ipv4) {
         self.src = ipv4.src();
         self.dst = ipv4.dst();
     }
 
+    /// Sets the key for signing the layer with a TCP MD5 signature (RFC 2385). Selective
+    /// acknowledgements may be trimmed to leave space for the signature. The key is shared
+    /// instead of copied.
+    pub fn set_md5_key(&mut self, key: Arc<[u8]>) {
+        if self.md5_key.is_none() {
+            // Trim sacks
+            while self.len() + Tcp::md5_len() > MAXIMUM_LEN {
+                if !self.trim_sack() {
+                    break;
+                }
+            }
+
+            // The signature is always the first option, see `serialize_with_payload`
+            self.layer.data_offset += (Tcp::md5_len() / 4) as u8;
+            self.layer.options.insert(0, TcpOption::nop());
+            self.layer.options.insert(0, TcpOption::nop());
+            self.layer.options.insert(
+                0,
+                TcpOption {
+                    number: TcpOptionNumber::new(MD5_OPTION_NUMBER),
+                    length: vec![MD5_OPTION_LEN as u8],
+                    data: vec![0u8; MD5_OPTION_LEN - 2],
+                },
+            );
+        }
+        self.md5_key = Some(key);
+    }
+
+    fn trim_sack(&mut self) -> bool {
+        for option in self.layer.options.iter_mut() {
+            if get_number_from_option(option) == TcpOptionNumbers::SACK && option.data.len() > 8 {
+                let size = option.data.len() - 8;
+                option.data.truncate(size);
+                option.length[0] -= 8;
+                self.layer.data_offset -= 2;
+
+                return true;
+            }
+        }
+
+        false
+    }
+
     /// Returns the source IP address of the layer.
     pub fn src_ip_addr(&self) -> Ipv4Addr {
         self.src
@@ -300,7 +391,8 @@ impl Tcp {
         self.layer.window
     }
 
-    /// Returns the MSS of the layer. This function allocates space for serializing options.
+    /// Returns the MSS of the layer. An option in a malformed length is ignored. This function
+    /// allocates space for serializing options.
     pub fn mss(&self) -> Option<u16> {
         let mut buffer = vec![0u8; 40];
         let mut packet = MutableTcpOptionPacket::new(buffer.as_mut_slice()).unwrap();
@@ -309,7 +401,7 @@ impl Tcp {
 
             #[allow(clippy::single_match)]
             match packet.get_number() {
-                TcpOptionNumbers::MSS => {
+                TcpOptionNumbers::MSS if packet.packet()[1] == MSS_OPTION_LEN => {
                     let mss = bytes_to_u16(&buffer[2..4]);
 
                     return Some(mss);
@@ -321,7 +413,8 @@ impl Tcp {
         None
     }
 
-    /// Returns the window scale of the layer. This function allocates space for serializing options.
+    /// Returns the window scale of the layer. An option in a malformed length is ignored. This
+    /// function allocates space for serializing options.
     pub fn wscale(&self) -> Option<u8> {
         let mut buffer = vec![0u8; 40];
         let mut packet = MutableTcpOptionPacket::new(buffer.as_mut_slice()).unwrap();
@@ -330,7 +423,7 @@ impl Tcp {
 
             #[allow(clippy::single_match)]
             match packet.get_number() {
-                TcpOptionNumbers::WSCALE => {
+                TcpOptionNumbers::WSCALE if packet.packet()[1] == WSCALE_OPTION_LEN => {
                     let wscale = buffer[2];
 
                     return Some(wscale);
@@ -355,7 +448,8 @@ impl Tcp {
                 TcpOptionNumbers::SACK => {
                     let mut vector = Vec::with_capacity(4);
 
-                    let pair_length = (buffer[1] as usize - 2) / 8;
+                    // Ignore the pairs out of the option or the buffer
+                    let pair_length = min(buffer[1] as usize, buffer.len()).saturating_sub(2) / 8;
                     for i in 0..pair_length {
                         let left = bytes_to_u32(&buffer[2 + 8 * i..2 + 8 * i + 4]);
                         let right = bytes_to_u32(&buffer[2 + 8 * i + 4..2 + 8 * i + 8]);
@@ -371,7 +465,8 @@ impl Tcp {
         None
     }
 
-    /// Returns the timestamp of the layer. This function allocates space for serializing options.
+    /// Returns the timestamp of the layer. An option in a malformed length is ignored. This
+    /// function allocates space for serializing options.
     pub fn ts(&self) -> Option<u32> {
         let mut buffer = vec![0u8; 40];
         let mut packet = MutableTcpOptionPacket::new(buffer.as_mut_slice()).unwrap();
@@ -380,7 +475,7 @@ impl Tcp {
 
             #[allow(clippy::single_match)]
             match packet.get_number() {
-                TcpOptionNumbers::TIMESTAMPS => {
+                TcpOptionNumbers::TIMESTAMPS if packet.packet()[1] == TIMESTAMPS_OPTION_LEN => {
                     let ts = bytes_to_u32(&buffer[2..6]);
 
                     return Some(ts);
@@ -392,8 +487,8 @@ impl Tcp {
         None
     }
 
-    /// Returns the timestamp echo reply of the layer. This function allocates space for serializing
-    /// options.
+    /// Returns the timestamp echo reply of the layer. An option in a malformed length is ignored.
+    /// This function allocates space for serializing options.
     pub fn ts_ecr(&self) -> Option<u32> {
         let mut buffer = vec![0u8; 40];
         let mut packet = MutableTcpOptionPacket::new(buffer.as_mut_slice()).unwrap();
@@ -402,7 +497,7 @@ impl Tcp {
 
             #[allow(clippy::single_match)]
             match packet.get_number() {
-                TcpOptionNumbers::TIMESTAMPS => {
+                TcpOptionNumbers::TIMESTAMPS if packet.packet()[1] == TIMESTAMPS_OPTION_LEN => {
                     let ts = bytes_to_u32(&buffer[6..10]);
 
                     return Some(ts);
@@ -414,6 +509,58 @@ impl Tcp {
         None
     }
 
+    /// Returns the TCP MD5 signature of the layer. This function allocates space for serializing
+    /// options.
+    pub fn md5(&self) -> Option<Vec<u8>> {
+        let mut buffer = vec![0u8; 40];
+        let mut packet = MutableTcpOptionPacket::new(buffer.as_mut_slice()).unwrap();
+        for option in &self.layer.options {
+            packet.populate(option);
+
+            if packet.get_number() == TcpOptionNumber::new(MD5_OPTION_NUMBER) {
+                if buffer[1] as usize != MD5_OPTION_LEN {
+                    return None;
+                }
+
+                return Some(buffer[2..MD5_OPTION_LEN].to_vec());
+            }
+        }
+
+        None
+    }
+
+    /// Returns the expected TCP MD5 signature of the layer with the given payload and key.
+    pub fn md5_digest(&self, payload: &[u8], key: &[u8]) -> Vec<u8> {
+        // Options are excluded
+        let mut layer = self.layer.clone();
+        layer.options = vec![];
+        layer.payload = vec![];
+
+        let mut buffer = vec![0u8; layer.data_offset as usize * 4];
+        let mut packet = MutableTcpPacket::new(buffer.as_mut_slice()).unwrap();
+        packet.populate(&layer);
+
+        md5_digest(
+            &self.src_ip_addr(),
+            &self.dst_ip_addr(),
+            &buffer,
+            payload,
+            key,
+        )
+    }
+
+    /// Returns if the layer is signed with a TCP MD5 signature. An option in a malformed length is
+    /// ignored.
+    pub fn is_md5(&self) -> bool {
+        let option = self
+            .layer
+            .options
+            .iter()
+            .find(|option| option.number == TcpOptionNumber::new(MD5_OPTION_NUMBER));
+
+        matches!(option, Some(option) if option.length.first() == Some(&(MD5_OPTION_LEN as u8)))
+    }
+
     /// Returns if the layer is a TCP acknowledgement.
     pub fn is_ack(&self) -> bool {
         self.layer.flags & TcpFlags::ACK != 0
@@ -439,6 +586,11 @@ impl Tcp {
         self.layer.flags & TcpFlags::FIN != 0
     }
 
+    /// Returns if the layer is a TCP push.
+    pub fn is_psh(&self) -> bool {
+        self.layer.flags & TcpFlags::PSH != 0
+    }
+
     /// Returns if the layer is a TCP reset or finish.
     pub fn is_rst_or_fin(&self) -> bool {
         self.is_rst() || self.is_fin()
@@ -462,6 +614,26 @@ impl Tcp {
 
         false
     }
+
+    /// Returns the numbers and the data of the options of the layer which are not recognized.
+    /// These options are skipped by their lengths in parsing, and their data is copied out.
+    pub fn unknown_options(&self) -> Vec<(u8, Vec<u8>)> {
+        self.layer
+            .options
+            .iter()
+            .filter_map(|option| match get_number_from_option(option) {
+                TcpOptionNumbers::EOL
+                | TcpOptionNumbers::NOP
+                | TcpOptionNumbers::MSS
+                | TcpOptionNumbers::WSCALE
+                | TcpOptionNumbers::SACK_PERMITTED
+                | TcpOptionNumbers::SACK
+                | TcpOptionNumbers::TIMESTAMPS => None,
+                number if number == TcpOptionNumber::new(MD5_OPTION_NUMBER) => None,
+                number => Some((number.0, option.data.clone())),
+            })
+            .collect()
+    }
 }
 
 impl Display for Tcp {
@@ -501,11 +673,24 @@ impl Layer for Tcp {
 
         // Fix length
         let header_length = self.len();
-        if header_length / 4 > u8::MAX as usize {
+        if header_length > MAXIMUM_LEN {
             return Err(io::Error::new(io::ErrorKind::Other, "TCP too big"));
         }
         packet.set_data_offset((header_length / 4) as u8);
 
+        // Sign
+        if let Some(ref key) = self.md5_key {
+            packet.set_checksum(0);
+            let digest = md5_digest(
+                &self.src_ip_addr(),
+                &self.dst_ip_addr(),
+                &packet.packet()[..header_length],
+                &[],
+                key,
+            );
+            packet.packet_mut()[22..22 + digest.len()].copy_from_slice(&digest);
+        }
+
         // Compute checksum
         let checksum = tcp::ipv4_checksum(
             &packet.to_immutable(),
@@ -533,11 +718,24 @@ impl Layer for Tcp {
 
         // Fix length
         let header_length = self.len();
-        if header_length / 4 > u8::MAX as usize {
+        if header_length > MAXIMUM_LEN {
             return Err(io::Error::new(io::ErrorKind::Other, "TCP too big"));
         }
         packet.set_data_offset((header_length / 4) as u8);
 
+        // Sign
+        if let Some(ref key) = self.md5_key {
+            packet.set_checksum(0);
+            let digest = md5_digest(
+                &self.src_ip_addr(),
+                &self.dst_ip_addr(),
+                &packet.packet()[..header_length],
+                payload,
+                key,
+            );
+            packet.packet_mut()[22..22 + digest.len()].copy_from_slice(&digest);
+        }
+
         // Compute checksum
         let checksum = tcp::ipv4_checksum(
             &packet.to_immutable(),
@@ -550,6 +748,33 @@ impl Layer for Tcp {
     }
 }
 
+fn md5_digest(
+    src: &Ipv4Addr,
+    dst: &Ipv4Addr,
+    header: &[u8],
+    payload: &[u8],
+    key: &[u8],
+) -> Vec<u8> {
+    let header_length = (header[12] >> 4) as usize * 4;
+    let length = (header_length + payload.len()) as u16;
+
+    let mut hasher = Md5::new();
+    // Pseudo header
+    hasher.update(src.octets());
+    hasher.update(dst.octets());
+    hasher.update([0, IpNextHeaderProtocols::Tcp.0]);
+    hasher.update(length.to_be_bytes());
+    // TCP header excluding options, with a zero checksum
+    hasher.update(&header[..16]);
+    hasher.update([0, 0]);
+    hasher.update(&header[18..20]);
+    // Payload and key
+    hasher.update(payload);
+    hasher.update(key);
+
+    hasher.finalize().to_vec()
+}
+
 fn bytes_to_u16(bytes: &[u8]) -> u16 {
     let mut result = 0;
 
@@ -583,3 +808,117 @@ fn get_number_from_option(option: &TcpOption) -> TcpOptionNumber {
     }
     */
 }
+
+#[test]
+fn tcp_md5() {
+    let ipv4 = Ipv4::new(
+        0,
+        LayerKinds::Tcp,
+        Ipv4Addr::new(10, 0, 0, 1),
+        Ipv4Addr::new(192, 168, 1, 2),
+    )
+    .unwrap();
+    let payload = b"hello";
+    let key = b"secret";
+
+    let mut tcp = Tcp::new_ack(179, 1234, 1, 2, 1000, None, None);
+    tcp.set_ipv4_layer(&ipv4);
+    assert!(!tcp.is_md5());
+    tcp.set_md5_key(Arc::from(&key[..]));
+    assert_eq!(tcp.len(), Tcp::minimum_len() + Tcp::md5_len());
+
+    let mut buffer = vec![0u8; tcp.len() + payload.len()];
+    tcp.serialize_with_payload(&mut buffer, payload, payload.len())
+        .unwrap();
+    assert_eq!(
+        &buffer[22..38],
+        &[226, 82, 160, 95, 80, 133, 166, 186, 48, 123, 195, 89, 184, 36, 85, 148]
+    );
+
+    // Verify
+    let parsed = Tcp::parse(&TcpPacket::new(&buffer).unwrap(), &ipv4);
+    assert!(parsed.is_md5());
+    assert_eq!(parsed.md5(), Some(parsed.md5_digest(payload, key)));
+    assert_ne!(parsed.md5(), Some(parsed.md5_digest(payload, b"other")));
+
+    // Trim sacks
+    let sacks = (0..4).map(|i| (i * 10, i * 10 + 5)).collect();
+    let mut tcp = Tcp::new_ack(179, 1234, 1, 2, 1000, Some(sacks), None);
+    tcp.set_md5_key(Arc::from(&key[..]));
+    assert!(tcp.len() <= MAXIMUM_LEN);
+    assert_eq!(tcp.sack().map(|sacks| sacks.len()), Some(2));
+}
+
+#[test]
+fn tcp_sack_malformed() {
+    let option = |length: u8| TcpOption {
+        number: TcpOptionNumbers::SACK,
+        length: vec![length],
+        data: vec![0u8; length.saturating_sub(2) as usize],
+    };
+    let tcp = |options| {
+        Tcp::from(tcp::Tcp {
+            source: 1234,
+            destination: 179,
+            sequence: 1,
+            acknowledgement: 2,
+            data_offset: 5,
+            reserved: 0,
+            flags: TcpFlags::ACK,
+            window: 1000,
+            checksum: 0,
+            urgent_ptr: 0,
+            options,
+            payload: vec![],
+        })
+    };
+
+    // Length shorter than the option itself, or leaving a partial pair
+    assert_eq!(tcp(vec![option(1)]).sack(), Some(vec![]));
+    assert_eq!(tcp(vec![option(13)]).sack(), Some(vec![(0, 0)]));
+}
+
+#[test]
+fn tcp_unknown_options() {
+    let ipv4 = Ipv4::new(
+        0,
+        LayerKinds::Tcp,
+        Ipv4Addr::new(192, 168, 1, 2),
+        Ipv4Addr::new(1, 1, 1, 1),
+    )
+    .unwrap();
+    let unknown = |number: u8, data: &[u8]| TcpOption {
+        number: TcpOptionNumber::new(number),
+        length: vec![data.len() as u8 + 2],
+        data: data.to_vec(),
+    };
+
+    // An unknown option sandwiched between known ones
+    let mut tcp = Tcp::new_ack_syn(10000, 80, 100, 0, 1000, Some(1460), Some(7), false, None);
+    tcp.layer.options.insert(2, unknown(253, &[0xbe, 0xef]));
+    tcp.layer.data_offset += 1;
+    tcp.set_ipv4_layer(&ipv4);
+    let mut buffer = vec![0u8; tcp.len()];
+    tcp.serialize(&mut buffer, 0).unwrap();
+    let packet = TcpPacket::new(&buffer).unwrap();
+    assert!(Tcp::is_parsable(&packet));
+    let parsed = Tcp::parse(&packet, &ipv4);
+    assert_eq!(parsed.mss(), Some(1460));
+    assert_eq!(parsed.wscale(), Some(7));
+    assert_eq!(parsed.unknown_options(), vec![(253, vec![0xbe, 0xef])]);
+
+    // Known options in malformed lengths are ignored
+    tcp.layer.options = vec![
+        unknown(2, &[0x05]),
+        unknown(3, &[]),
+        unknown(2, &[0x05, 0xb4]),
+    ];
+    assert_eq!(tcp.mss(), Some(1460));
+    assert_eq!(tcp.wscale(), None);
+    assert!(tcp.unknown_options().is_empty());
+
+    // An option in the zero length makes the segment malformed instead of looping
+    let length = buffer.len();
+    buffer[length - 3..].copy_from_slice(&[253, 0, 1]);
+    assert!(!Tcp::is_parsable(&TcpPacket::new(&buffer).unwrap()));
+}
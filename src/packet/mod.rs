@@ -1,18 +1,21 @@
 //! Support for serializing and deserializing packets.
 
+use log::warn;
 use pnet::packet::arp::ArpPacket;
 use pnet::packet::ethernet::{EtherTypes, EthernetPacket};
 use pnet::packet::icmp::IcmpPacket;
 use pnet::packet::ip::IpNextHeaderProtocols;
-use pnet::packet::ipv4::Ipv4Packet;
-use pnet::packet::tcp::TcpPacket;
-use pnet::packet::udp::UdpPacket;
+use pnet::packet::ipv4::{self, Ipv4Packet};
+use pnet::packet::tcp::{self, TcpPacket};
+use pnet::packet::udp::{self, UdpPacket};
 use pnet::packet::Packet;
+use std::cmp::min;
 use std::collections::HashMap;
 use std::fmt::{self, Display, Formatter};
 use std::io;
 use std::net::Ipv4Addr;
-use std::time::Instant;
+use std::time::Duration;
+use tokio::time::Instant;
 
 pub mod layer;
 use layer::arp::Arp;
@@ -45,11 +48,15 @@ impl Indicator {
     pub fn parse(packet: &EthernetPacket) -> Indicator {
         let mut transport = None;
 
-        let link = Layers::Ethernet(Ethernet::parse(packet));
-        let network = match packet.get_ethertype() {
-            EtherTypes::Arp => ArpPacket::new(packet.payload()).map(|arp_packet| Layers::Arp(Arp::parse(&arp_packet))),
-            EtherTypes::Ipv4 => match Ipv4Packet::new(packet.payload()) {
-                Some(ref ipv4_packet) => {
+        let ethernet = Ethernet::parse(packet);
+        let payload = &packet.payload()[ethernet.tag_len()..];
+        let network = match ethernet.layer.ethertype {
+            EtherTypes::Arp => {
+                ArpPacket::new(payload).map(|arp_packet| Layers::Arp(Arp::parse(&arp_packet)))
+            }
+            EtherTypes::Ipv4 => match Ipv4Packet::new(payload) {
+                // Ignore the packet if the IHL is invalid
+                Some(ref ipv4_packet) if Ipv4::is_parsable(ipv4_packet) => {
                     let ipv4 = Ipv4::parse(ipv4_packet);
                     // Fragment
                     if !ipv4.is_fragment() {
@@ -58,10 +65,10 @@ impl Indicator {
                                 IcmpPacket::new(ipv4_packet.payload())
                                     .map(|icmp_packet| Layers::Icmpv4(Icmpv4::parse(&icmp_packet)))
                             }
-                            IpNextHeaderProtocols::Tcp => {
-                                TcpPacket::new(ipv4_packet.payload())
-                                    .map(|tcp_packet| Layers::Tcp(Tcp::parse(&tcp_packet, &ipv4)))
-                            }
+                            // Ignore the segment if the data offset is invalid
+                            IpNextHeaderProtocols::Tcp => TcpPacket::new(ipv4_packet.payload())
+                                .filter(Tcp::is_parsable)
+                                .map(|tcp_packet| Layers::Tcp(Tcp::parse(&tcp_packet, &ipv4))),
                             IpNextHeaderProtocols::Udp => {
                                 UdpPacket::new(ipv4_packet.payload())
                                     .map(|udp_packet| Layers::Udp(Udp::parse(&udp_packet, &ipv4)))
@@ -72,10 +79,11 @@ impl Indicator {
 
                     Some(Layers::Ipv4(ipv4))
                 }
-                None => None,
+                _ => None,
             },
             _ => None,
         };
+        let link = Layers::Ethernet(ethernet);
 
         Indicator {
             link,
@@ -121,16 +129,13 @@ impl Indicator {
                             udp.dst(),
                             udp.length(),
                         ),
-                        _ => unreachable!(),
+                        transport => format!("{}: {} -> {}", transport, ipv4.src(), ipv4.dst()),
                     },
                     None => format!("{}", ipv4),
                 },
-                _ => unreachable!(),
-            },
-            None => match self.link() {
-                Layers::Ethernet(ethernet) => format!("{}", ethernet),
-                _ => unreachable!(),
+                network => format!("{}", network),
             },
+            None => format!("{}", self.link()),
         }
     }
 
@@ -160,11 +165,11 @@ impl Indicator {
                 Some(network) => match network {
                     Layers::Arp(arp) => ethernet.len() + arp.len(),
                     Layers::Ipv4(ipv4) => ethernet.len() + ipv4.total_length() as usize,
-                    _ => unreachable!(),
+                    network => ethernet.len() + network.len(),
                 },
                 None => ethernet.len(),
             },
-            _ => unreachable!(),
+            _ => self.len(),
         }
     }
 
@@ -237,6 +242,11 @@ impl Indicator {
         None
     }
 
+    /// Returns the VLAN identifier of the Ethernet layer.
+    pub fn vlan(&self) -> Option<u16> {
+        self.ethernet().and_then(|ethernet| ethernet.vlan())
+    }
+
     /// Returns the network layer.
     pub fn network(&self) -> Option<&Layers> {
         if let Some(layer) = &self.network {
@@ -354,8 +364,71 @@ impl Display for Indicator {
     }
 }
 
+/// Represents the mode of verifying checksums of incoming packets.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum ChecksumMode {
+    /// Represents no checksum will be verified.
+    Off,
+    /// Represents only the checksums of IPv4 headers will be verified. TCP and UDP checksums are
+    /// skipped, because they are only placeholders in locally generated packets with checksum
+    /// offload.
+    Ipv4,
+    /// Represents the checksums of IPv4 headers, TCP and UDP will be verified.
+    Full,
+}
+
+/// Returns if the checksums in the frame are valid in the given mode. Checksums of TCP and UDP in
+/// fragments are not verified. Frames other than IPv4 are always valid.
+pub fn verify_checksums(frame: &[u8], mode: ChecksumMode) -> bool {
+    if mode == ChecksumMode::Off {
+        return true;
+    }
+
+    let packet = match EthernetPacket::new(frame) {
+        Some(packet) => packet,
+        None => return true,
+    };
+    let ethernet = Ethernet::parse(&packet);
+    if ethernet.layer.ethertype != EtherTypes::Ipv4 {
+        return true;
+    }
+    let ipv4_packet = match Ipv4Packet::new(&packet.payload()[ethernet.tag_len()..]) {
+        Some(packet) => packet,
+        None => return true,
+    };
+    if ipv4::checksum(&ipv4_packet) != ipv4_packet.get_checksum() {
+        return false;
+    }
+    if mode == ChecksumMode::Ipv4 || Ipv4::parse(&ipv4_packet).is_fragment() {
+        return true;
+    }
+
+    let src = ipv4_packet.get_source();
+    let dst = ipv4_packet.get_destination();
+    match ipv4_packet.get_next_level_protocol() {
+        IpNextHeaderProtocols::Tcp => match TcpPacket::new(ipv4_packet.payload()) {
+            Some(ref packet) => tcp::ipv4_checksum(packet, &src, &dst) == packet.get_checksum(),
+            None => true,
+        },
+        IpNextHeaderProtocols::Udp => match UdpPacket::new(ipv4_packet.payload()) {
+            // UDP checksum is optional in IPv4
+            Some(ref packet) if packet.get_checksum() != 0 => {
+                udp::ipv4_checksum(packet, &src, &dst) == packet.get_checksum()
+            }
+            _ => true,
+        },
+        _ => true,
+    }
+}
+
 /// Represents the expire time of each group of fragments.
-const EXPIRE_TIME: u128 = 10000;
+const EXPIRE_TIME: u64 = 30000;
+
+/// Represents the max size of buffered fragments.
+const MAX_FRAGMENTS_SIZE: usize = 4 * 1024 * 1024;
+
+/// Represents the max size of a reassembled datagram.
+const MAX_DATAGRAM_SIZE: usize = u16::MAX as usize;
 
 /// Represents a fragmentation.
 #[allow(dead_code)]
@@ -364,6 +437,7 @@ pub struct Fragmentation {
     ethernet: Ethernet,
     ipv4: Ipv4,
     buffer: Vec<u8>,
+    ranges: Vec<(usize, usize)>,
     length: usize,
     total_length: Option<usize>,
     last_seen: Instant,
@@ -384,7 +458,8 @@ impl Fragmentation {
         let frag = Fragmentation {
             ethernet: ethernet.clone(),
             ipv4: ipv4.clone(),
-            buffer: vec![0; u16::MAX as usize],
+            buffer: Vec::new(),
+            ranges: Vec::new(),
             length: 0,
             total_length: None,
             last_seen: Instant::now(),
@@ -393,20 +468,73 @@ impl Fragmentation {
         Some(frag)
     }
 
-    /// Adds a fragmentation.
-    pub fn add(&mut self, indicator: &Indicator, payload: &[u8]) {
+    /// Adds a fragmentation. Returns an error if the fragmentation conflicts with previous ones,
+    /// and the whole group should be dropped.
+    pub fn add(&mut self, indicator: &Indicator, payload: &[u8]) -> io::Result<()> {
         // Payload
         let ipv4 = match indicator.ipv4() {
             Some(ipv4) => ipv4,
-            None => return,
+            None => return Err(io::Error::from(io::ErrorKind::InvalidInput)),
         };
         let offset = (ipv4.fragment_offset() as usize) * 8;
-        if !ipv4.is_more_fragment() {
-            self.total_length = Some(offset + payload.len());
+        let end = offset + payload.len();
+        if end > MAX_DATAGRAM_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "fragment exceeds the maximum datagram size",
+            ));
         }
 
-        self.buffer[offset..offset + payload.len()].copy_from_slice(payload);
+        // Duplicate fragments are ignored, but other overlaps are not allowed
+        if self.ranges.contains(&(offset, end)) {
+            self.last_seen = Instant::now();
+
+            return Ok(());
+        }
+        if self
+            .ranges
+            .iter()
+            .any(|&(start, stop)| offset < stop && start < end)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "fragment overlaps",
+            ));
+        }
+        if ipv4.is_more_fragment() {
+            if payload.len() % 8 != 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "fragment is not aligned",
+                ));
+            }
+            if let Some(total_length) = self.total_length {
+                if end > total_length {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "fragment is beyond the last fragment",
+                    ));
+                }
+            }
+        } else {
+            if self.total_length.is_some() || self.buffer.len() > end {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "last fragment conflicts",
+                ));
+            }
+            self.total_length = Some(end);
+        }
+
+        if self.buffer.len() < end {
+            self.buffer.resize(end, 0);
+        }
+        self.buffer[offset..end].copy_from_slice(payload);
+        self.ranges.push((offset, end));
         self.length += payload.len();
+        self.last_seen = Instant::now();
+
+        Ok(())
     }
 
     /// Concatenates fragmentations and returns the transport layer and the payload.
@@ -434,6 +562,20 @@ impl Fragmentation {
         (transport, &self.buffer[header_size..self.length])
     }
 
+    /// Returns the IPv4 header and the first 8 bytes of the reassembled datagram, which are quoted
+    /// in ICMPv4 error messages. The header is taken from the first received fragment.
+    pub fn quote(&self) -> io::Result<Vec<u8>> {
+        let size = min(self.length, 8);
+        let mut buffer = vec![0u8; self.ipv4.len() + size];
+        self.ipv4.serialize_with_payload(
+            &mut buffer,
+            &self.buffer[..size],
+            self.ipv4.len() + self.length,
+        )?;
+
+        Ok(buffer)
+    }
+
     /// Returns if the fragmentation is completed.
     pub fn is_completed(&self) -> bool {
         match self.total_length {
@@ -444,14 +586,33 @@ impl Fragmentation {
 
     /// Returns if the fragmentation is expired.
     pub fn is_expired(&self) -> bool {
-        self.last_seen.elapsed().as_millis() > EXPIRE_TIME
+        self.is_expired_at(Instant::now(), Duration::from_millis(EXPIRE_TIME))
+    }
+
+    fn is_expired_at(&self, now: Instant, expire_time: Duration) -> bool {
+        now.saturating_duration_since(self.last_seen) > expire_time
+    }
+
+    /// Returns the size of the buffer.
+    pub fn size(&self) -> usize {
+        self.buffer.len()
     }
 }
 
 /// Represents a defragmentation machine.
-#[derive(Debug, Default)]
+#[derive(Debug)]
 pub struct Defraggler {
     frags: HashMap<(Ipv4Addr, Ipv4Addr, LayerKind, u16), Fragmentation>,
+    size: usize,
+    expire_time: Duration,
+    max_size: usize,
+    max_datagram_size: usize,
+}
+
+impl Default for Defraggler {
+    fn default() -> Self {
+        Defraggler::new()
+    }
 }
 
 impl Defraggler {
@@ -459,37 +620,134 @@ impl Defraggler {
     pub fn new() -> Defraggler {
         Defraggler {
             frags: HashMap::new(),
+            size: 0,
+            expire_time: Duration::from_millis(EXPIRE_TIME),
+            max_size: MAX_FRAGMENTS_SIZE,
+            max_datagram_size: MAX_DATAGRAM_SIZE,
         }
     }
 
+    /// Sets the expire time of each group of fragments.
+    pub fn set_expire_time(&mut self, expire_time: Duration) {
+        self.expire_time = expire_time;
+    }
+
+    /// Sets the max size of buffered fragments.
+    pub fn set_max_size(&mut self, max_size: usize) {
+        self.max_size = max_size;
+    }
+
+    /// Sets the max size of a reassembled datagram. The size cannot exceed the IPv4 maximum.
+    pub fn set_max_datagram_size(&mut self, max_datagram_size: usize) {
+        self.max_datagram_size = min(max_datagram_size, MAX_DATAGRAM_SIZE);
+    }
+
+    /// Drops all the expired groups of fragments and returns the number of dropped groups.
+    pub fn sweep(&mut self, now: Instant) -> usize {
+        let expire_time = self.expire_time;
+        let len = self.frags.len();
+        let mut size = 0;
+        self.frags.retain(|_, frag| {
+            let is_expired = frag.is_expired_at(now, expire_time);
+            if is_expired {
+                size += frag.size();
+            }
+
+            !is_expired
+        });
+        self.size -= size;
+
+        len - self.frags.len()
+    }
+
+    /// Returns the number of groups of fragments.
+    pub fn len(&self) -> usize {
+        self.frags.len()
+    }
+
+    /// Returns if there is no group of fragments.
+    pub fn is_empty(&self) -> bool {
+        self.frags.is_empty()
+    }
+
+    /// Returns the size of buffered fragments.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    fn remove(&mut self, key: &(Ipv4Addr, Ipv4Addr, LayerKind, u16)) -> Option<Fragmentation> {
+        let frag = self.frags.remove(key)?;
+        self.size -= frag.size();
+
+        Some(frag)
+    }
+
     /// Adds a fragmentation and returns the fragmentation if it is completed.
     pub fn add(&mut self, indicator: &Indicator, frame: &[u8]) -> Option<Fragmentation> {
-        let ipv4 = match indicator.ipv4() {
-            Some(ipv4) => ipv4,
-            None => return None,
-        };
+        let ipv4 = indicator.ipv4()?;
 
         let key = (ipv4.src(), ipv4.dst(), ipv4.kind(), ipv4.identification());
 
         let is_create = match self.frags.get(&key) {
-            Some(frag) => frag.is_expired(),
+            Some(frag) => frag.is_expired_at(Instant::now(), self.expire_time),
             None => true,
         };
         if is_create {
-            let frag = match Fragmentation::new(indicator) {
-                Some(frag) => frag,
-                None => return None,
-            };
+            let frag = Fragmentation::new(indicator)?;
 
-            self.frags.insert(key, frag);
+            if let Some(prev_frag) = self.frags.insert(key, frag) {
+                self.size -= prev_frag.size();
+            }
+        }
+
+        // Limit size
+        let header_size = indicator.ethernet()?.len() + ipv4.len();
+        let payload = frame.get(header_size..)?;
+        let end = (ipv4.fragment_offset() as usize) * 8 + payload.len();
+        let prev_size = self.frags.get(&key).unwrap().size();
+        if end > self.max_datagram_size {
+            warn!(
+                "drop fragments {} -> {} ({}): reassembled size {} exceeds {}",
+                ipv4.src(),
+                ipv4.dst(),
+                ipv4.identification(),
+                end,
+                self.max_datagram_size
+            );
+            self.remove(&key);
+
+            return None;
+        }
+        let grown = end.saturating_sub(prev_size);
+        if grown > 0 && self.size + grown > self.max_size {
+            self.sweep(Instant::now());
+            if self.size + grown > self.max_size {
+                // Drop the fragment, and the group if it is just created
+                if prev_size == 0 {
+                    self.frags.remove(&key);
+                }
+
+                return None;
+            }
         }
 
         // Add fragmentation
-        let frag = self.frags.get_mut(&key).unwrap();
-        let header_size = indicator.ethernet().unwrap().len() + ipv4.len();
-        frag.add(indicator, &frame[header_size..]);
+        let frag = self.frags.get_mut(&key)?;
+        if let Err(ref e) = frag.add(indicator, payload) {
+            warn!(
+                "drop fragments {} -> {} ({}): {}",
+                ipv4.src(),
+                ipv4.dst(),
+                ipv4.identification(),
+                e
+            );
+            self.remove(&key);
+
+            return None;
+        }
+        self.size += frag.size() - prev_size;
         if frag.is_completed() {
-            self.frags.remove(&key)
+            self.remove(&key)
         } else {
             None
         }
@@ -549,4 +807,332 @@ fn defraggler_add() {
     let (_, p) = f.concatenate();
 
     assert_eq!(p, &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15]);
+
+    // The IPv4 header of the reassembled datagram and its first 8 bytes
+    let q = f.quote().unwrap();
+    let ipv4 = Ipv4Packet::new(&q).unwrap();
+    assert_eq!(q.len(), Ipv4::minimum_len() + 8);
+    assert_eq!(
+        ipv4.get_total_length() as usize,
+        Ipv4::minimum_len() + Udp::minimum_len() + 16
+    );
+    assert_eq!(
+        &q[Ipv4::minimum_len()..Ipv4::minimum_len() + 4],
+        &[0, 1, 0, 2]
+    );
+}
+
+#[test]
+fn defraggler_sweep() {
+    use layer::LayerKinds;
+
+    let mut d = Defraggler::new();
+    let ethernet = Ethernet::new(
+        LayerKinds::Ipv4,
+        "11:11:11:11:11:11".parse().unwrap(),
+        "22:22:22:22:22:22".parse().unwrap(),
+    )
+    .unwrap();
+    let mut b = vec![0u8; ethernet.len() + Ipv4::minimum_len() + Udp::minimum_len() + 8];
+
+    let ipv4 = Ipv4::new_more_fragment(
+        0,
+        LayerKinds::Udp,
+        0,
+        "1.1.1.1".parse().unwrap(),
+        "2.2.2.2".parse().unwrap(),
+    )
+    .unwrap();
+    let i = Indicator::new(
+        Layers::Ethernet(ethernet),
+        Some(Layers::Ipv4(ipv4)),
+        Some(Layers::Udp(Udp::new(1, 2))),
+    );
+    i.serialize_with_payload(b.as_mut_slice(), &[0u8; 8])
+        .unwrap();
+
+    // The last fragment never arrives
+    let i = Indicator::from(b.as_slice()).unwrap();
+    assert!(d.add(&i, &b[..i.content_len()]).is_none());
+    assert_eq!(d.len(), 1);
+    assert_eq!(d.size(), Udp::minimum_len() + 8);
+
+    let now = Instant::now();
+    assert_eq!(d.sweep(now), 0);
+    assert_eq!(d.sweep(now + Duration::from_millis(EXPIRE_TIME + 1)), 1);
+    assert!(d.is_empty());
+    assert_eq!(d.size(), 0);
+
+    // Fragments exceeding the max size are dropped
+    d.set_max_size(8);
+    assert!(d.add(&i, &b[..i.content_len()]).is_none());
+    assert!(d.is_empty());
+}
+
+#[test]
+fn defraggler_invalid() {
+    use layer::LayerKinds;
+
+    let mut d = Defraggler::new();
+    let ethernet = Ethernet::new(
+        LayerKinds::Ipv4,
+        "11:11:11:11:11:11".parse().unwrap(),
+        "22:22:22:22:22:22".parse().unwrap(),
+    )
+    .unwrap();
+    let mut b = vec![0u8; ethernet.len() + Ipv4::minimum_len() + 16];
+    let mut add = |d: &mut Defraggler, offset: u16, size: usize, is_last: bool| {
+        let ipv4 = match is_last {
+            true => Ipv4::new_last_fragment(
+                0,
+                LayerKinds::Udp,
+                offset,
+                "1.1.1.1".parse().unwrap(),
+                "2.2.2.2".parse().unwrap(),
+            ),
+            false => Ipv4::new_more_fragment(
+                0,
+                LayerKinds::Udp,
+                offset,
+                "1.1.1.1".parse().unwrap(),
+                "2.2.2.2".parse().unwrap(),
+            ),
+        }
+        .unwrap();
+        let i = Indicator::new(
+            Layers::Ethernet(ethernet.clone()),
+            Some(Layers::Ipv4(ipv4)),
+            None,
+        );
+        let len = ethernet.len() + Ipv4::minimum_len() + size;
+        i.serialize_with_payload(&mut b[..len], &vec![0u8; size])
+            .unwrap();
+
+        let i = Indicator::from(&b[..len]).unwrap();
+        d.add(&i, &b[..i.content_len()])
+    };
+
+    // Overlapping fragments
+    assert!(add(&mut d, 0, 16, false).is_none());
+    assert!(add(&mut d, 0, 16, false).is_none());
+    assert_eq!(d.len(), 1);
+    assert!(add(&mut d, 1, 16, false).is_none());
+    assert!(d.is_empty());
+    assert_eq!(d.size(), 0);
+
+    // Fragments exceeding the max datagram size
+    d.set_max_datagram_size(1024);
+    assert!(add(&mut d, 0, 16, false).is_none());
+    assert!(add(&mut d, 1024 / 8, 16, true).is_none());
+    assert!(d.is_empty());
+    assert_eq!(d.size(), 0);
+}
+
+#[test]
+fn indicator_vlan() {
+    use layer::LayerKinds;
+
+    let mut ethernet = Ethernet::new(
+        LayerKinds::Ipv4,
+        "11:11:11:11:11:11".parse().unwrap(),
+        "22:22:22:22:22:22".parse().unwrap(),
+    )
+    .unwrap();
+    ethernet.set_vlan(Some(100));
+    let ipv4 = Ipv4::new(
+        0,
+        LayerKinds::Udp,
+        "1.1.1.1".parse().unwrap(),
+        "2.2.2.2".parse().unwrap(),
+    )
+    .unwrap();
+    let mut udp = Udp::new(1, 2);
+    udp.set_ipv4_layer(&ipv4);
+    let i = Indicator::new(
+        Layers::Ethernet(ethernet),
+        Some(Layers::Ipv4(ipv4)),
+        Some(Layers::Udp(udp)),
+    );
+    let mut b = vec![0u8; i.len() + 4];
+    i.serialize_with_payload(b.as_mut_slice(), &[0, 1, 2, 3])
+        .unwrap();
+    assert_eq!(&b[12..18], &[0x81, 0x00, 0x00, 100, 0x08, 0x00]);
+
+    let i = Indicator::from(b.as_slice()).unwrap();
+    assert_eq!(i.vlan(), Some(100));
+    assert_eq!(i.udp().unwrap().dst(), 2);
+    assert_eq!(&b[i.len()..i.content_len()], &[0, 1, 2, 3]);
+}
+
+#[test]
+fn packet_verify_checksums() {
+    use layer::LayerKinds;
+
+    let ethernet = Ethernet::new(
+        LayerKinds::Ipv4,
+        "11:11:11:11:11:11".parse().unwrap(),
+        "22:22:22:22:22:22".parse().unwrap(),
+    )
+    .unwrap();
+    let ipv4 = Ipv4::new(
+        0,
+        LayerKinds::Udp,
+        "1.1.1.1".parse().unwrap(),
+        "2.2.2.2".parse().unwrap(),
+    )
+    .unwrap();
+    let mut udp = Udp::new(1, 2);
+    udp.set_ipv4_layer(&ipv4);
+    let i = Indicator::new(
+        Layers::Ethernet(ethernet),
+        Some(Layers::Ipv4(ipv4)),
+        Some(Layers::Udp(udp)),
+    );
+    let mut b = vec![0u8; i.len() + 8];
+    i.serialize_with_payload(b.as_mut_slice(), &[0, 1, 2, 3, 4, 5, 6, 7])
+        .unwrap();
+    assert!(verify_checksums(&b, ChecksumMode::Full));
+
+    // Corrupted payload
+    let mut c = b.clone();
+    c[i.len()] = 0xff;
+    assert!(!verify_checksums(&c, ChecksumMode::Full));
+    assert!(verify_checksums(&c, ChecksumMode::Ipv4));
+
+    // Corrupted IPv4 header
+    let mut c = b.clone();
+    c[14 + 8] = 0;
+    assert!(!verify_checksums(&c, ChecksumMode::Ipv4));
+    assert!(verify_checksums(&c, ChecksumMode::Off));
+
+    // Zero UDP checksum
+    let mut c = b;
+    c[i.len() - 2] = 0;
+    c[i.len() - 1] = 0;
+    c[i.len()] = 0xff;
+    assert!(verify_checksums(&c, ChecksumMode::Full));
+}
+
+#[test]
+fn indicator_parse_ipv4_options() {
+    use layer::LayerKinds;
+
+    let ethernet = Ethernet::new(
+        LayerKinds::Ipv4,
+        "11:11:11:11:11:11".parse().unwrap(),
+        "22:22:22:22:22:22".parse().unwrap(),
+    )
+    .unwrap();
+    let ipv4 = Ipv4::new(
+        0,
+        LayerKinds::Udp,
+        "1.1.1.1".parse().unwrap(),
+        "2.2.2.2".parse().unwrap(),
+    )
+    .unwrap();
+    let i = Indicator::new(
+        Layers::Ethernet(ethernet.clone()),
+        Some(Layers::Ipv4(ipv4)),
+        Some(Layers::Udp(Udp::new(1, 2))),
+    );
+    let mut b = vec![0u8; i.len() + 8];
+    i.serialize_with_payload(b.as_mut_slice(), &[1, 2, 3, 4, 5, 6, 7, 8])
+        .unwrap();
+
+    // Insert a Router Alert option
+    let offset = ethernet.len() + Ipv4::minimum_len();
+    b.splice(offset..offset, [0x94, 0x04, 0x00, 0x00].iter().cloned());
+    b[ethernet.len()] = 0x46;
+    b[ethernet.len() + 3] += 4;
+
+    let i = Indicator::from(b.as_slice()).unwrap();
+    assert_eq!(i.ipv4().unwrap().header_len(), 24);
+    assert_eq!(i.ipv4().unwrap().len(), 24);
+    assert_eq!(i.udp().unwrap().dst(), 2);
+    assert_eq!(i.content_len(), b.len());
+    assert_eq!(&b[i.len()..i.content_len()], &[1, 2, 3, 4, 5, 6, 7, 8]);
+
+    // Invalid IHL
+    b[ethernet.len()] = 0x44;
+    let i = Indicator::from(b.as_slice()).unwrap();
+    assert!(i.ipv4().is_none());
+}
+
+#[cfg(test)]
+fn decode_hex(s: &str) -> Vec<u8> {
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+        .collect()
+}
+
+#[test]
+fn indicator_parse_malformed() {
+    // IPv4 option exceeding the header
+    let b = decode_hex(
+        "22222222222211111111111108000e0000380001400040017718d105010201010101030400000000fa40\
+         45000020000140004011772001010101c0a8010227100035000c0000",
+    );
+    let i = Indicator::from(b.as_slice()).unwrap();
+    assert!(i.ipv4().is_none());
+
+    // TCP data offset below the minimum
+    let b = decode_hex(
+        "222222222222fe11111111110800450000380001400040067713c0a8010201010101271000500000006400\
+         0000004d10ffff000000000101050a000000100000002064617461",
+    );
+    let i = Indicator::from(b.as_slice()).unwrap();
+    assert!(i.ipv4().is_some());
+    assert!(i.tcp().is_none());
+
+    // TCP data offset exceeding the buffer
+    let b = decode_hex(
+        "2222222222221111111111110800450000380001400040067713c0a801020101010127100050000000640000\
+         00008010ffff00000000",
+    );
+    let i = Indicator::from(b.as_slice()).unwrap();
+    assert!(i.tcp().is_none());
+
+    // TCP option truncated, or with an invalid length
+    let b = decode_hex(
+        "222222222222111111111111080045000040000140004006770bc073010201010101271000507800006400\
+         000000b002ffff00000000020405b4010303070402080a000000010000000001010111",
+    );
+    let i = Indicator::from(b.as_slice()).unwrap();
+    assert!(i.tcp().is_none());
+    let b = decode_hex(
+        "222222222222111111111111080045000040000140004006770bc0a8010201010101e5100050a600006400\
+         000000b002ffff00000000020405b4010303070402080a000000010000000001050100",
+    );
+    let i = Indicator::from(b.as_slice()).unwrap();
+    assert!(i.tcp().is_none());
+
+    // ICMPv4 echo request without identifier and sequence number
+    let b =
+        decode_hex("222222222222111111111111080045000020001e400040017730c0a8d9020101010108000000");
+    let i = Indicator::from(b.as_slice()).unwrap();
+    let icmpv4 = i.icmpv4().unwrap();
+    assert!(icmpv4.identifier().is_none());
+    assert!(icmpv4.sequence_number().is_none());
+    i.brief();
+
+    // ICMPv4 fragmentation required without next-hop MTU and datagram
+    let b = decode_hex(
+        "2222222222221111111111110800450000380001400040017718c0a80102010101010304000000",
+    );
+    let i = Indicator::from(b.as_slice()).unwrap();
+    let icmpv4 = i.icmpv4().unwrap();
+    assert!(icmpv4.next_hop_mtu().is_none());
+    assert!(icmpv4.dst_ip_addr().is_none());
+    assert!(icmpv4.dst().is_none());
+
+    // Fragment shorter than its headers
+    let mut b = decode_hex(
+        "2222222222221111111111110800450000200001200040117720c0a801020101010127100035000c0000",
+    );
+    let i = Indicator::from(b.as_slice()).unwrap();
+    assert!(i.ipv4().unwrap().is_fragment());
+    b.truncate(20);
+    let mut defraggler = Defraggler::new();
+    assert!(defraggler.add(&i, &b).is_none());
 }
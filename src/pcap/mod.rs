@@ -1,10 +1,13 @@
 //! Support for handling pcap interfaces.
 
 use pnet::datalink::{self, Channel, Config, DataLinkReceiver, DataLinkSender, MacAddr};
+use pnet::packet::ethernet::EtherTypes;
 use std::clone::Clone;
+use std::cmp::{max, min};
 use std::fmt::{self, Display, Formatter};
 use std::io;
 use std::net::Ipv4Addr;
+use std::time::Duration;
 
 #[cfg(windows)]
 use netifs;
@@ -25,17 +28,227 @@ pub type Sender = Box<dyn DataLinkSender>;
 /// Represents the receive half of a pcap device.
 pub type Receiver = Box<dyn DataLinkReceiver>;
 
+/// Represents frames queued to be sent to a pcap device in one operation.
+#[derive(Clone, Debug, Default)]
+pub struct Batch {
+    buffer: Vec<u8>,
+    sizes: Vec<usize>,
+}
+
+impl Batch {
+    /// Creates an empty `Batch`.
+    pub fn new() -> Batch {
+        Batch::default()
+    }
+
+    /// Appends a frame of the size, which is built by the function.
+    pub fn push(&mut self, size: usize, func: &mut dyn FnMut(&mut [u8])) {
+        let start = self.buffer.len();
+        self.buffer.resize(start + size, 0);
+        func(&mut self.buffer[start..]);
+        self.sizes.push(size);
+    }
+
+    /// Removes all the frames. The buffer is kept for later frames.
+    pub fn clear(&mut self) {
+        self.buffer.clear();
+        self.sizes.clear();
+    }
+
+    /// Returns the number of frames in the batch.
+    pub fn len(&self) -> usize {
+        self.sizes.len()
+    }
+
+    /// Returns if the batch has no frame.
+    pub fn is_empty(&self) -> bool {
+        self.sizes.is_empty()
+    }
+
+    /// Returns the frames in the order they were appended.
+    pub fn frames(&self) -> impl Iterator<Item = &[u8]> {
+        let mut start = 0;
+        self.sizes.iter().map(move |&size| {
+            let frame = &self.buffer[start..start + size];
+            start += size;
+
+            frame
+        })
+    }
+}
+
+/// Trait for sending a batch of frames to a pcap device.
+pub trait BatchSender {
+    /// Sends the frames in the batch. Consecutive frames of the same size are built and sent in
+    /// a single `build_and_send`, which a backend may send in one operation. Frames are sent one
+    /// by one if the backend cannot send them together.
+    fn send_batch(&mut self, batch: &Batch) -> io::Result<()>;
+}
+
+impl<T: DataLinkSender + ?Sized> BatchSender for T {
+    fn send_batch(&mut self, batch: &Batch) -> io::Result<()> {
+        let mut frames = batch.frames().peekable();
+        while let Some(first) = frames.next() {
+            let mut group = vec![first];
+            while let Some(frame) = frames.next_if(|frame| frame.len() == first.len()) {
+                group.push(frame);
+            }
+
+            let mut i = 0;
+            let result =
+                self.build_and_send(group.len(), first.len(), &mut |buffer: &mut [u8]| {
+                    buffer.copy_from_slice(group[i]);
+                    i += 1;
+                });
+            match result {
+                Some(result) => result?,
+                // Fall back to sending frames one by one
+                None => {
+                    for frame in &group {
+                        self.build_and_send(1, frame.len(), &mut |buffer: &mut [u8]| {
+                            buffer.copy_from_slice(frame)
+                        })
+                        .unwrap_or(Ok(()))?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
 /// Represents the buffer size of pcap channels.
 const BUFFER_SIZE: usize = 256 * 1024;
 
+/// Represents the read timeout of pcap channels.
+const READ_TIMEOUT: u64 = 100;
+
+/// Represents the snapshot length of pcap channels.
+const SNAPLEN: usize = 65535;
+
+/// Represents the minimum snapshot length of pcap channels, which holds an Ethernet header with a
+/// VLAN tag, and an IPv4 header and a TCP header with the maximum options.
+pub const MIN_SNAPLEN: usize = 18 + 60 + 60;
+
+/// Represents the size of an Ethernet header without a VLAN tag.
+const ETHERNET_HEADER_SIZE: usize = 14;
+
+/// Represents the size of the header of the BSD loopback encapsulation, also the null link type.
+const NULL_HEADER_SIZE: usize = 4;
+
+/// Represents the address family of IPv4 in the header of the BSD loopback encapsulation.
+const NULL_AF_INET: u32 = 2;
+
+/// Represents the offset of the BSD loopback encapsulation header in frames captured on loopback
+/// interfaces, or `None` if the frames are in Ethernet. The pcap driver on Windows captures the
+/// header as is, while BPF replaces it with a fake Ethernet header which keeps the address family
+/// in place.
+#[cfg(windows)]
+const LOOPBACK_NULL_OFFSET: Option<usize> = Some(0);
+#[cfg(any(
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "illumos",
+    target_os = "solaris"
+))]
+const LOOPBACK_NULL_OFFSET: Option<usize> = Some(ETHERNET_HEADER_SIZE - NULL_HEADER_SIZE);
+#[cfg(not(any(
+    windows,
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "illumos",
+    target_os = "solaris"
+)))]
+const LOOPBACK_NULL_OFFSET: Option<usize> = None;
+
+/// Represents if the Ethernet header of frames sent on loopback interfaces should be replaced with
+/// the header of the BSD loopback encapsulation. BPF strips the Ethernet header itself.
+const IS_LOOPBACK_NULL_SENT: bool = cfg!(windows);
+
+/// Represents the configuration of opening a network interface.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct CaptureConfig {
+    promiscuous: bool,
+    buffer_size: usize,
+    snaplen: usize,
+    read_timeout: Duration,
+}
+
+impl CaptureConfig {
+    /// Creates a new `CaptureConfig` in promiscuous mode.
+    pub fn new() -> CaptureConfig {
+        CaptureConfig {
+            promiscuous: true,
+            buffer_size: BUFFER_SIZE,
+            snaplen: SNAPLEN,
+            read_timeout: Duration::from_millis(READ_TIMEOUT),
+        }
+    }
+
+    /// Sets if the interface is opened in promiscuous mode.
+    pub fn set_promiscuous(&mut self, promiscuous: bool) {
+        self.promiscuous = promiscuous;
+    }
+
+    /// Sets the buffer size of the channel.
+    pub fn set_buffer_size(&mut self, buffer_size: usize) {
+        self.buffer_size = buffer_size;
+    }
+
+    /// Sets the snapshot length of the channel. The snapshot length only sizes the read buffer to
+    /// hold a frame of the length, since the capture handle does not take a snapshot length.
+    pub fn set_snaplen(&mut self, snaplen: usize) {
+        self.snaplen = snaplen;
+    }
+
+    /// Sets the read timeout of the channel. Reads block until a frame arrives or the timeout is
+    /// reached, so a longer timeout saves CPU on idle, but delays timed events like sweeping idle
+    /// connections.
+    pub fn set_read_timeout(&mut self, read_timeout: Duration) {
+        self.read_timeout = read_timeout;
+    }
+
+    /// Returns if the interface is opened in promiscuous mode.
+    pub fn is_promiscuous(&self) -> bool {
+        self.promiscuous
+    }
+
+    /// Returns the buffer size of the channel.
+    pub fn buffer_size(&self) -> usize {
+        self.buffer_size
+    }
+
+    /// Returns the snapshot length of the channel.
+    pub fn snaplen(&self) -> usize {
+        self.snaplen
+    }
+
+    /// Returns the read timeout of the channel.
+    pub fn read_timeout(&self) -> Duration {
+        self.read_timeout
+    }
+}
+
+impl Default for CaptureConfig {
+    fn default() -> Self {
+        CaptureConfig::new()
+    }
+}
+
 /// Represents a network interface and its associated addresses.
 #[derive(Clone, Debug, Default, Eq, Hash, PartialEq)]
 pub struct Interface {
     name: String,
     alias: Option<String>,
+    description: Option<String>,
     hardware_addr: MacAddr,
     ip_addrs: Vec<Ipv4Addr>,
-    mtu: usize,
+    mtu: Option<usize>,
     is_up: bool,
     is_loopback: bool,
 }
@@ -46,16 +259,45 @@ impl Interface {
         Interface {
             name: String::new(),
             alias: None,
+            description: None,
             hardware_addr: MacAddr::zero(),
             ip_addrs: vec![],
-            mtu: 0,
+            mtu: None,
             is_up: false,
             is_loopback: false,
         }
     }
 
-    /// Opens the network interface for sending and receiving data.
+    /// Opens the network interface for sending and receiving data in promiscuous mode.
     pub fn open(&self) -> io::Result<(Sender, Receiver)> {
+        self.open_with_promiscuous(true)
+    }
+
+    /// Opens the network interface for sending and receiving data. Promiscuous mode is required
+    /// to capture frames to other hosts, like proxying for another device's IP address.
+    pub fn open_with_promiscuous(&self, promiscuous: bool) -> io::Result<(Sender, Receiver)> {
+        let mut config = CaptureConfig::new();
+        config.set_promiscuous(promiscuous);
+
+        self.open_with_config(&config)
+    }
+
+    /// Opens the network interface for sending and receiving data with the given configuration.
+    pub fn open_with_config(&self, config: &CaptureConfig) -> io::Result<(Sender, Receiver)> {
+        if config.snaplen() < MIN_SNAPLEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("snaplen should be at least {}", MIN_SNAPLEN),
+            ));
+        }
+        if config.buffer_size() == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "buffer size should be positive",
+            ));
+        }
+        let promiscuous = config.is_promiscuous();
+
         let inters = datalink::interfaces();
         let inter = inters
             .into_iter()
@@ -65,18 +307,40 @@ impl Interface {
                 "interface not found",
             ))?;
 
+        // Frames are read into the read buffer, the buffer should hold a frame of the snaplen
         let config = Config {
-            write_buffer_size: BUFFER_SIZE,
-            read_buffer_size: BUFFER_SIZE,
+            write_buffer_size: config.buffer_size(),
+            read_buffer_size: max(config.buffer_size(), config.snaplen()),
+            read_timeout: Some(config.read_timeout()),
+            promiscuous,
             ..Config::default()
         };
-        let channel = datalink::channel(&inter, config)?;
-        let channel = match channel {
+        let channel = datalink::channel(&inter, config).map_err(|e| {
+            if promiscuous {
+                io::Error::new(
+                    e.kind(),
+                    format!("cannot open interface in promiscuous mode: {}", e),
+                )
+            } else {
+                e
+            }
+        })?;
+        let (mut tx, mut rx) = match channel {
             Channel::Ethernet(tx, rx) => (tx, rx),
             _ => return Err(io::Error::new(io::ErrorKind::Other, "unknown link type")),
         };
 
-        Ok(channel)
+        // Loopback interfaces may not be framed in Ethernet
+        if self.is_loopback {
+            if let Some(offset) = LOOPBACK_NULL_OFFSET {
+                rx = Box::new(LoopbackReceiver::new(rx, offset));
+            }
+            if IS_LOOPBACK_NULL_SENT {
+                tx = Box::new(LoopbackSender::new(tx));
+            }
+        }
+
+        Ok((tx, rx))
     }
 
     /// Returns the name of the interface.
@@ -89,6 +353,20 @@ impl Interface {
         &self.alias
     }
 
+    /// Returns the description of the interface.
+    pub fn description(&self) -> &Option<String> {
+        &self.description
+    }
+
+    /// Returns the GUID of the interface, like `{4E273621-5161-46C8-895A-48D0E52A0B83}` in the
+    /// name `\Device\NPF_{4E273621-5161-46C8-895A-48D0E52A0B83}` on Windows.
+    pub fn guid(&self) -> Option<&str> {
+        let begin = self.name.find('{')?;
+        let end = self.name[begin..].find('}')? + begin;
+
+        Some(&self.name[begin..=end])
+    }
+
     /// Returns the hardware address of the interface.
     pub fn hardware_addr(&self) -> MacAddr {
         self.hardware_addr
@@ -103,11 +381,23 @@ impl Interface {
         }
     }
 
-    /// Returns the MTU of the interface.
-    pub fn mtu(&self) -> usize {
+    /// Returns the IPv4 addresses of the interface.
+    pub fn ip_addrs(&self) -> &Vec<Ipv4Addr> {
+        &self.ip_addrs
+    }
+
+    /// Returns the MTU of the interface, or `None` if the MTU cannot be obtained.
+    pub fn mtu(&self) -> Option<usize> {
         self.mtu
     }
 
+    /// Returns if the interface is still present and up on the current machine.
+    pub fn is_available(&self) -> bool {
+        all_interfaces()
+            .iter()
+            .any(|inter| inter.name == self.name && inter.is_up)
+    }
+
     /// Returns if the interface is up.
     pub fn is_up(&self) -> bool {
         self.is_up
@@ -146,8 +436,42 @@ impl Display for Interface {
     }
 }
 
+/// Represents an error when determining a network interface.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum InterfaceError {
+    /// Represents no interface is found.
+    NotFound,
+    /// Represents multiple interfaces are found, with the names of the candidates.
+    Ambiguous(Vec<String>),
+    /// Represents the interface is found but has no IPv4 address.
+    NoAddress,
+}
+
+impl Display for InterfaceError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            InterfaceError::NotFound => write!(f, "no interface is found"),
+            InterfaceError::Ambiguous(names) => {
+                write!(f, "multiple interfaces are found: {}", names.join(", "))
+            }
+            InterfaceError::NoAddress => write!(f, "the interface has no IPv4 address"),
+        }
+    }
+}
+
+impl std::error::Error for InterfaceError {}
+
 /// Gets a list of available network interfaces for the current machine.
 pub fn interfaces() -> Vec<Interface> {
+    all_interfaces()
+        .into_iter()
+        .filter(|inter| !inter.ip_addrs.is_empty())
+        .collect()
+}
+
+/// Gets a list of network interfaces for the current machine, including those without any IPv4
+/// address.
+pub fn all_interfaces() -> Vec<Interface> {
     let inters = datalink::interfaces();
 
     let ifs = inters
@@ -161,8 +485,13 @@ pub fn interfaces() -> Vec<Interface> {
 
             let mut i = Interface::new();
             i.name = inter.name.clone();
+            if !inter.description.is_empty() {
+                i.description = Some(inter.description.clone());
+            }
             i.hardware_addr = match inter.mac {
                 Some(mac) => mac,
+                // Loopback interfaces may have no hardware address
+                None if inter.is_loopback() => HARDWARE_ADDR_UNSPECIFIED,
                 None => return Err(()),
             };
             i.ip_addrs = inter
@@ -175,11 +504,6 @@ pub fn interfaces() -> Vec<Interface> {
                 .filter_map(Result::ok)
                 .collect();
 
-            // Exclude interface without any IPv4 address
-            if i.ip_addrs.is_empty() {
-                return Err(());
-            }
-
             i.is_up = inter.is_up();
             i.is_loopback = inter.is_loopback();
 
@@ -191,6 +515,42 @@ pub fn interfaces() -> Vec<Interface> {
     mark_interfaces(ifs)
 }
 
+/// Finds the interfaces of the given name, which can be the name, the GUID, the alias like the
+/// friendly name on Windows, or the description of an interface. If interfaces match in different
+/// ways, they are disambiguated in the above order, and only those matched in the first way are
+/// returned. The GUID, the alias and the description are matched case-insensitively.
+pub fn find_interfaces(inters: Vec<Interface>, name: &str) -> Vec<Interface> {
+    let guid = name.trim_start_matches('{').trim_end_matches('}');
+    let matchers: [&dyn Fn(&Interface) -> bool; 4] = [
+        &|inter| inter.name == name,
+        &|inter| match inter.guid() {
+            Some(inter_guid) => inter_guid[1..inter_guid.len() - 1].eq_ignore_ascii_case(guid),
+            None => false,
+        },
+        &|inter| match &inter.alias {
+            Some(alias) => alias.eq_ignore_ascii_case(name),
+            None => false,
+        },
+        &|inter| match &inter.description {
+            Some(description) => description.eq_ignore_ascii_case(name),
+            None => false,
+        },
+    ];
+
+    for matcher in matchers.iter() {
+        let matched = inters
+            .iter()
+            .filter(|inter| matcher(inter))
+            .cloned()
+            .collect::<Vec<_>>();
+        if !matched.is_empty() {
+            return matched;
+        }
+    }
+
+    vec![]
+}
+
 #[cfg(windows)]
 fn mark_interfaces(mut ifs: Vec<Interface>) -> Vec<Interface> {
     if let Ok(sys_inters) = netifs::get_interfaces() {
@@ -198,7 +558,7 @@ fn mark_interfaces(mut ifs: Vec<Interface>) -> Vec<Interface> {
             for i in &mut ifs {
                 if i.name.ends_with(&inter.name) {
                     i.alias = Some(inter.display_name.clone());
-                    i.mtu = inter.mtu;
+                    i.mtu = Some(inter.mtu).filter(|mtu| *mtu > 0);
                     i.is_up = inter.is_up;
                     i.is_loopback = inter.is_loopback;
                 }
@@ -216,7 +576,7 @@ fn mark_interfaces(mut ifs: Vec<Interface>) -> Vec<Interface> {
             for i in &mut ifs {
                 if i.name == inter.name {
                     if let Ok(mtu) = inter.get_mtu() {
-                        i.mtu = mtu as usize;
+                        i.mtu = Some(mtu as usize);
                     }
                 }
             }
@@ -226,6 +586,115 @@ fn mark_interfaces(mut ifs: Vec<Interface>) -> Vec<Interface> {
     ifs
 }
 
+/// Converts a frame in the BSD loopback encapsulation into an Ethernet frame with unspecified
+/// hardware addresses. Returns `false` if the frame is not an IPv4 packet.
+fn null_to_ethernet(frame: &[u8], buffer: &mut Vec<u8>) -> bool {
+    if frame.len() < NULL_HEADER_SIZE {
+        return false;
+    }
+    // The address family is in the host byte order
+    let mut family = [0u8; NULL_HEADER_SIZE];
+    family.copy_from_slice(&frame[..NULL_HEADER_SIZE]);
+    if u32::from_ne_bytes(family) != NULL_AF_INET {
+        return false;
+    }
+
+    buffer.clear();
+    buffer.extend_from_slice(&[0u8; ETHERNET_HEADER_SIZE - 2]);
+    buffer.extend_from_slice(&EtherTypes::Ipv4.0.to_be_bytes());
+    buffer.extend_from_slice(&frame[NULL_HEADER_SIZE..]);
+
+    true
+}
+
+/// Converts an Ethernet frame into a frame in the BSD loopback encapsulation.
+fn ethernet_to_null(frame: &[u8], buffer: &mut Vec<u8>) {
+    buffer.clear();
+    buffer.extend_from_slice(&NULL_AF_INET.to_ne_bytes());
+    buffer.extend_from_slice(&frame[min(frame.len(), ETHERNET_HEADER_SIZE)..]);
+}
+
+/// Represents the receive half of a loopback interface in the BSD loopback encapsulation, which
+/// presents frames in Ethernet. Frames other than IPv4 packets are skipped.
+struct LoopbackReceiver {
+    rx: Receiver,
+    offset: usize,
+    buffer: Vec<u8>,
+}
+
+impl LoopbackReceiver {
+    /// Creates a new `LoopbackReceiver`. The header of the BSD loopback encapsulation starts at
+    /// the offset of captured frames.
+    fn new(rx: Receiver, offset: usize) -> LoopbackReceiver {
+        LoopbackReceiver {
+            rx,
+            offset,
+            buffer: Vec::new(),
+        }
+    }
+}
+
+impl DataLinkReceiver for LoopbackReceiver {
+    fn next(&mut self) -> io::Result<&[u8]> {
+        loop {
+            let frame = self.rx.next()?;
+            if frame.len() >= self.offset
+                && null_to_ethernet(&frame[self.offset..], &mut self.buffer)
+            {
+                return Ok(&self.buffer);
+            }
+        }
+    }
+}
+
+/// Represents the send half of a loopback interface in the BSD loopback encapsulation, which
+/// replaces the Ethernet header of frames with the header of the encapsulation.
+struct LoopbackSender {
+    tx: Sender,
+    buffer: Vec<u8>,
+}
+
+impl LoopbackSender {
+    /// Creates a new `LoopbackSender`.
+    fn new(tx: Sender) -> LoopbackSender {
+        LoopbackSender {
+            tx,
+            buffer: Vec::new(),
+        }
+    }
+}
+
+impl DataLinkSender for LoopbackSender {
+    fn build_and_send(
+        &mut self,
+        num_packets: usize,
+        packet_size: usize,
+        func: &mut dyn FnMut(&mut [u8]),
+    ) -> Option<io::Result<()>> {
+        let mut frame = vec![0u8; packet_size];
+        for _ in 0..num_packets {
+            func(&mut frame);
+            ethernet_to_null(&frame, &mut self.buffer);
+            match self.tx.send_to(&self.buffer, None) {
+                Some(Ok(())) => {}
+                result => return result,
+            }
+        }
+
+        Some(Ok(()))
+    }
+
+    fn send_to(
+        &mut self,
+        packet: &[u8],
+        dst: Option<datalink::NetworkInterface>,
+    ) -> Option<io::Result<()>> {
+        ethernet_to_null(packet, &mut self.buffer);
+
+        self.tx.send_to(&self.buffer, dst)
+    }
+}
+
 /// Represents a virtual send half which will discard all incoming traffic.
 #[derive(Debug, Default)]
 pub struct BlackHole {}
@@ -255,3 +724,154 @@ impl DataLinkSender for BlackHole {
         Some(Ok(()))
     }
 }
+
+#[test]
+fn batch_sender_fallback() {
+    /// Represents a sender recording frames, which cannot send frames together if it is
+    /// unbatched.
+    struct Recorder {
+        frames: Vec<Vec<u8>>,
+        calls: usize,
+        is_unbatched: bool,
+    }
+
+    impl DataLinkSender for Recorder {
+        fn build_and_send(
+            &mut self,
+            num_packets: usize,
+            packet_size: usize,
+            func: &mut dyn FnMut(&mut [u8]),
+        ) -> Option<io::Result<()>> {
+            if self.is_unbatched && num_packets > 1 {
+                return None;
+            }
+            for _ in 0..num_packets {
+                let mut frame = vec![0u8; packet_size];
+                func(&mut frame);
+                self.frames.push(frame);
+            }
+            self.calls += 1;
+
+            Some(Ok(()))
+        }
+
+        fn send_to(
+            &mut self,
+            _: &[u8],
+            _: Option<datalink::NetworkInterface>,
+        ) -> Option<io::Result<()>> {
+            Some(Ok(()))
+        }
+    }
+
+    let mut batch = Batch::new();
+    for frame in &[[1u8; 60], [2u8; 60], [3u8; 60]] {
+        batch.push(60, &mut |buffer| buffer.copy_from_slice(frame));
+    }
+    batch.push(64, &mut |buffer| buffer.copy_from_slice(&[4u8; 64]));
+    assert_eq!(batch.len(), 4);
+    let frames = batch
+        .frames()
+        .map(|frame| frame.to_vec())
+        .collect::<Vec<_>>();
+
+    for &is_unbatched in &[false, true] {
+        let mut tx = Recorder {
+            frames: Vec::new(),
+            calls: 0,
+            is_unbatched,
+        };
+        tx.send_batch(&batch).unwrap();
+        assert_eq!(tx.frames, frames);
+        assert_eq!(tx.calls, if is_unbatched { 4 } else { 2 });
+    }
+
+    batch.clear();
+    assert!(batch.is_empty());
+}
+
+#[test]
+fn find_interfaces_by_name() {
+    let inter = |name: &str, alias: Option<&str>, description: Option<&str>| {
+        let mut inter = Interface::new();
+        inter.name = String::from(name);
+        inter.alias = alias.map(String::from);
+        inter.description = description.map(String::from);
+
+        inter
+    };
+    let inters = vec![
+        inter(
+            "\\Device\\NPF_{4E273621-5161-46C8-895A-48D0E52A0B83}",
+            Some("Ethernet"),
+            Some("Intel(R) Ethernet Connection"),
+        ),
+        inter(
+            "\\Device\\NPF_{0F6B3B9E-1C3A-4E0B-9F34-2F4A0D9D8C11}",
+            Some("Wi-Fi"),
+            Some("Ethernet"),
+        ),
+    ];
+    let find = |name: &str| {
+        find_interfaces(inters.clone(), name)
+            .iter()
+            .map(|inter| inter.alias().clone().unwrap())
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(
+        find("\\Device\\NPF_{0F6B3B9E-1C3A-4E0B-9F34-2F4A0D9D8C11}"),
+        vec!["Wi-Fi"]
+    );
+    assert_eq!(
+        find("{4e273621-5161-46c8-895a-48d0e52a0b83}"),
+        vec!["Ethernet"]
+    );
+    assert_eq!(find("0F6B3B9E-1C3A-4E0B-9F34-2F4A0D9D8C11"), vec!["Wi-Fi"]);
+    assert_eq!(find("wi-fi"), vec!["Wi-Fi"]);
+    // The friendly name takes precedence over the description
+    assert_eq!(find("Ethernet"), vec!["Ethernet"]);
+    assert_eq!(find("Intel(R) Ethernet Connection"), vec!["Ethernet"]);
+    assert!(find("eth0").is_empty());
+}
+
+#[test]
+fn loopback_framing() {
+    use std::collections::VecDeque;
+
+    struct Frames(VecDeque<Vec<u8>>, Vec<u8>);
+
+    impl DataLinkReceiver for Frames {
+        fn next(&mut self) -> io::Result<&[u8]> {
+            self.1 = self
+                .0
+                .pop_front()
+                .ok_or_else(|| io::Error::from(io::ErrorKind::TimedOut))?;
+
+            Ok(&self.1)
+        }
+    }
+
+    let ipv4 = [0x45u8; 20];
+    let mut ethernet = vec![0u8; ETHERNET_HEADER_SIZE];
+    ethernet[12..14].copy_from_slice(&[0x08, 0x00]);
+    ethernet.extend_from_slice(&ipv4);
+
+    // Sending
+    let mut null = Vec::new();
+    ethernet_to_null(&ethernet, &mut null);
+    assert_eq!(&null[..NULL_HEADER_SIZE], &NULL_AF_INET.to_ne_bytes());
+    assert_eq!(&null[NULL_HEADER_SIZE..], &ipv4);
+
+    // Receiving, where frames other than IPv4 packets are skipped
+    let mut ipv6 = null.clone();
+    ipv6[..NULL_HEADER_SIZE].copy_from_slice(&30u32.to_ne_bytes());
+    let mut fake = vec![0u8; ETHERNET_HEADER_SIZE - NULL_HEADER_SIZE];
+    fake.extend_from_slice(&null);
+    let mut rx = LoopbackReceiver::new(
+        Box::new(Frames(VecDeque::from(vec![ipv6, fake]), Vec::new())),
+        ETHERNET_HEADER_SIZE - NULL_HEADER_SIZE,
+    );
+    assert_eq!(rx.next().unwrap(), &ethernet[..]);
+    assert_eq!(rx.next().err().unwrap().kind(), io::ErrorKind::TimedOut);
+}
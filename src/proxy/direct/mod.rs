@@ -0,0 +1,470 @@
+//! Support for connecting to destinations directly.
+
+use super::socks::{self, SocksOption, SocksRecvHalf, SocksSendHalf};
+use log::{debug, trace};
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::future::Future;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io;
+use tokio::net::{TcpSocket, TcpStream, UdpSocket};
+use tokio::sync::watch;
+use tokio::time::{self, Instant};
+
+/// Returns if the error in connecting to the proxy indicates the proxy is unreachable, which is
+/// the connection is refused or not established in the timeout. A timeout in handshaking means the
+/// proxy is reachable, and is not included.
+pub fn is_unreachable(e: &io::Error) -> bool {
+    match e.kind() {
+        io::ErrorKind::ConnectionRefused => true,
+        io::ErrorKind::TimedOut => ConnectTimedOut::from_io_error(e).is_some(),
+        _ => false,
+    }
+}
+
+/// Represents the TCP connection to a proxy is not established in the timeout. The error is
+/// carried in the `io::Error` of `TimedOut`, and can be told from a timeout in handshaking by
+/// `ConnectTimedOut::from_io_error`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ConnectTimedOut;
+
+impl ConnectTimedOut {
+    /// Returns the `ConnectTimedOut` carried in the error.
+    pub fn from_io_error(e: &io::Error) -> Option<ConnectTimedOut> {
+        e.get_ref()
+            .and_then(|e| e.downcast_ref::<ConnectTimedOut>())
+            .copied()
+    }
+}
+
+impl Display for ConnectTimedOut {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "connection timed out")
+    }
+}
+
+impl Error for ConnectTimedOut {}
+
+impl From<ConnectTimedOut> for io::Error {
+    fn from(e: ConnectTimedOut) -> Self {
+        io::Error::new(io::ErrorKind::TimedOut, e)
+    }
+}
+
+/// Waits for a TCP connection to a proxy until the deadline. Returns a `ConnectTimedOut` error if
+/// the connection is not established before the deadline.
+pub async fn connect_until<F: Future<Output = io::Result<TcpStream>>>(
+    deadline: Instant,
+    future: F,
+) -> io::Result<TcpStream> {
+    match time::timeout_at(deadline, future).await {
+        Ok(result) => result,
+        Err(_) => Err(ConnectTimedOut.into()),
+    }
+}
+
+/// Represents the options of TCP sockets to proxies and to destinations connected directly.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct SocketOption {
+    bind_addr: Option<Ipv4Addr>,
+    is_nodelay: bool,
+    send_buffer_size: Option<u32>,
+    recv_buffer_size: Option<u32>,
+}
+
+impl SocketOption {
+    /// Creates a new `SocketOption` with `TCP_NODELAY` set. Data from the source is already
+    /// coalesced by the source, so Nagle's algorithm in the socket only delays it further.
+    pub fn new() -> SocketOption {
+        SocketOption {
+            bind_addr: None,
+            is_nodelay: true,
+            send_buffer_size: None,
+            recv_buffer_size: None,
+        }
+    }
+
+    /// Sets the local address of the socket. `None` represents any address.
+    pub fn set_bind_addr(&mut self, bind_addr: Option<Ipv4Addr>) {
+        self.bind_addr = bind_addr;
+    }
+
+    /// Sets if `TCP_NODELAY` is set on the socket, which disables Nagle's algorithm.
+    pub fn set_nodelay(&mut self, is_nodelay: bool) {
+        self.is_nodelay = is_nodelay;
+    }
+
+    /// Sets the size of the send buffer of the socket, or `SO_SNDBUF`. `None` represents the
+    /// default size of the system.
+    pub fn set_send_buffer_size(&mut self, size: Option<u32>) {
+        self.send_buffer_size = size;
+    }
+
+    /// Sets the size of the receive buffer of the socket, or `SO_RCVBUF`. `None` represents the
+    /// default size of the system.
+    pub fn set_recv_buffer_size(&mut self, size: Option<u32>) {
+        self.recv_buffer_size = size;
+    }
+
+    /// Returns the local address of the socket.
+    pub fn bind_addr(&self) -> Option<Ipv4Addr> {
+        self.bind_addr
+    }
+
+    /// Returns if `TCP_NODELAY` is set on the socket.
+    pub fn is_nodelay(&self) -> bool {
+        self.is_nodelay
+    }
+
+    /// Returns the size of the send buffer of the socket.
+    pub fn send_buffer_size(&self) -> Option<u32> {
+        self.send_buffer_size
+    }
+
+    /// Returns the size of the receive buffer of the socket.
+    pub fn recv_buffer_size(&self) -> Option<u32> {
+        self.recv_buffer_size
+    }
+}
+
+impl Default for SocketOption {
+    fn default() -> Self {
+        SocketOption::new()
+    }
+}
+
+/// Connects to a target server directly in the socket options. The connection will be aborted if
+/// it does not complete in the timeout.
+pub async fn connect(
+    option: &SocketOption,
+    dst: SocketAddrV4,
+    duration: Duration,
+) -> io::Result<TcpStream> {
+    match time::timeout(duration, connect_tcp(option, dst)).await {
+        Ok(result) => result,
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "direct connection timed out",
+        )),
+    }
+}
+
+/// Connects a TCP stream to the remote in the socket options. The local address and the sizes of
+/// the buffers are set before connecting, so the window scale advertised in the SYN covers the
+/// receive buffer.
+pub async fn connect_tcp(option: &SocketOption, remote: SocketAddrV4) -> io::Result<TcpStream> {
+    let socket = TcpSocket::new_v4()?;
+    if let Some(size) = option.send_buffer_size {
+        socket.set_send_buffer_size(size)?;
+    }
+    if let Some(size) = option.recv_buffer_size {
+        socket.set_recv_buffer_size(size)?;
+    }
+    if let Some(local) = option.bind_addr {
+        socket.bind(SocketAddr::V4(SocketAddrV4::new(local, 0)))?;
+    }
+
+    let stream = socket.connect(SocketAddr::V4(remote)).await?;
+    stream.set_nodelay(option.is_nodelay)?;
+
+    Ok(stream)
+}
+
+/// Binds a UDP socket to an ephemeral port of the local address, or of any address if it is not
+/// given.
+pub async fn bind_udp(local: Option<Ipv4Addr>) -> io::Result<UdpSocket> {
+    let local = SocketAddrV4::new(local.unwrap_or(Ipv4Addr::UNSPECIFIED), 0);
+
+    UdpSocket::bind(local).await
+}
+
+/// Represents the send half of a UDP client. Datagrams are sent through the SOCKS proxy, or
+/// directly if the destination is bypassed or the proxy is unavailable. The direct socket is bound
+/// when the first datagram is sent directly, and is shared with the receive half.
+#[derive(Debug)]
+pub struct DatagramSendHalf {
+    socks: Option<SocksSendHalf>,
+    direct: Option<Arc<UdpSocket>>,
+    direct_tx: watch::Sender<Option<Arc<UdpSocket>>>,
+    options: SocksOption,
+}
+
+impl DatagramSendHalf {
+    /// Sends data on the socket to the given address.
+    pub async fn send_to(&mut self, payload: &[u8], dst: SocketAddrV4) -> io::Result<usize> {
+        if !self.is_direct(dst) {
+            if let Some(socks) = &mut self.socks {
+                return socks.send_to(payload, dst).await;
+            }
+        }
+
+        self.direct().await?.send_to(payload, dst).await
+    }
+
+    async fn direct(&mut self) -> io::Result<Arc<UdpSocket>> {
+        if let Some(direct) = &self.direct {
+            return Ok(Arc::clone(direct));
+        }
+
+        let socket = Arc::new(bind_udp(self.options.socket().bind_addr()).await?);
+        trace!("bind UDP directly on {}", socket.local_addr()?);
+        self.direct = Some(Arc::clone(&socket));
+        // The receive half may be dropped
+        let _ = self.direct_tx.send(Some(Arc::clone(&socket)));
+
+        Ok(socket)
+    }
+
+    /// Returns if datagrams to the destination are sent directly.
+    pub fn is_direct(&self, dst: SocketAddrV4) -> bool {
+        self.socks.is_none() || self.options.is_bypassed(*dst.ip())
+    }
+
+    /// Returns if datagrams are sent through the SOCKS proxy unless the destination is bypassed.
+    pub fn is_socks(&self) -> bool {
+        self.socks.is_some()
+    }
+}
+
+/// Represents the receive half of a UDP client. Datagrams are received from both the SOCKS proxy
+/// and the direct socket once it is bound. Datagrams received directly from peers which are not
+/// bypassed are dropped if the SOCKS proxy is available, as replies from them come through the
+/// proxy.
+#[derive(Debug)]
+pub struct DatagramRecvHalf {
+    socks: Option<SocksRecvHalf>,
+    direct_rx: watch::Receiver<Option<Arc<UdpSocket>>>,
+    buffer: Vec<u8>,
+    options: SocksOption,
+}
+
+impl DatagramRecvHalf {
+    /// Receives a single datagram message on the socket. Returns an error if the control
+    /// connection of the association with the SOCKS proxy is closed.
+    pub async fn recv_from(&mut self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddrV4)> {
+        loop {
+            let direct = self.direct_rx.borrow().clone();
+            match (&mut self.socks, direct) {
+                (Some(socks), Some(direct)) => {
+                    let (size, addr) = tokio::select! {
+                        r = socks.recv_from(buffer) => return r,
+                        r = recv_from(&direct, &mut self.buffer) => r?
+                    };
+                    if !self.options.is_bypassed(*addr.ip()) {
+                        trace!("drop UDP from {}: not bypassed", addr);
+                        continue;
+                    }
+                    buffer[..size].copy_from_slice(&self.buffer[..size]);
+
+                    return Ok((size, addr));
+                }
+                (Some(socks), None) => {
+                    tokio::select! {
+                        r = socks.recv_from(buffer) => return r,
+                        r = self.direct_rx.changed() => {
+                            // The send half is dropped, and the direct socket will never be bound
+                            if r.is_err() {
+                                return socks.recv_from(buffer).await;
+                            }
+                        }
+                    }
+                }
+                (None, Some(direct)) => return recv_from(&direct, buffer).await,
+                (None, None) => return Err(io::Error::from(io::ErrorKind::NotConnected)),
+            }
+        }
+    }
+}
+
+async fn recv_from(socket: &UdpSocket, buffer: &mut [u8]) -> io::Result<(usize, SocketAddrV4)> {
+    loop {
+        let (size, addr) = socket.recv_from(buffer).await?;
+        // IPv6 is not supported
+        if let SocketAddr::V4(addr) = addr {
+            return Ok((size, addr));
+        }
+    }
+}
+
+/// Binds a local address to target servers through a SOCKS5 proxy. A direct socket will also be
+/// bound if the proxy is unreachable and the fallback is enabled, or when a datagram is sent to a
+/// bypassed destination. Returns the local port of the SOCKS association if it succeeds, or the
+/// local port of the direct socket.
+pub async fn bind(
+    remote: SocketAddrV4,
+    options: &SocksOption,
+) -> io::Result<(DatagramRecvHalf, DatagramSendHalf, u16)> {
+    let socks = match socks::bind(remote, options).await {
+        Ok(halves) => Some(halves),
+        Err(ref e) if options.is_direct_fallback() && is_unreachable(e) => {
+            debug!("bind UDP directly: proxy is unreachable: {}", e);
+
+            None
+        }
+        Err(e) => return Err(e),
+    };
+
+    let direct = match socks {
+        Some(_) => None,
+        None => {
+            let socket = bind_udp(options.socket().bind_addr()).await?;
+
+            Some(Arc::new(socket))
+        }
+    };
+    let (direct_tx, direct_rx) = watch::channel(direct.clone());
+
+    let (socks_rx, socks_tx, local_port) = match socks {
+        Some((socks_rx, socks_tx, local_port)) => (Some(socks_rx), Some(socks_tx), local_port),
+        None => {
+            let local_port = direct.as_ref().unwrap().local_addr()?.port();
+
+            (None, None, local_port)
+        }
+    };
+
+    Ok((
+        DatagramRecvHalf {
+            socks: socks_rx,
+            direct_rx,
+            buffer: vec![0u8; u16::MAX as usize],
+            options: options.clone(),
+        },
+        DatagramSendHalf {
+            socks: socks_tx,
+            direct,
+            direct_tx,
+            options: options.clone(),
+        },
+        local_port,
+    ))
+}
+
+#[tokio::test]
+async fn direct_connect() {
+    use tokio::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let dst = match listener.local_addr().unwrap() {
+        SocketAddr::V4(addr) => addr,
+        SocketAddr::V6(_) => unreachable!(),
+    };
+
+    let socket = SocketOption::new();
+    let stream = connect(&socket, dst, Duration::from_secs(1)).await.unwrap();
+    let (accepted, _) = listener.accept().await.unwrap();
+    assert_eq!(stream.local_addr().unwrap(), accepted.peer_addr().unwrap());
+
+    // A closed port is unreachable
+    drop(listener);
+    let e = connect(&socket, dst, Duration::from_secs(1))
+        .await
+        .unwrap_err();
+    assert!(is_unreachable(&e));
+
+    // Only a timeout in connecting to the proxy is unreachable, not one in handshaking
+    let e = connect_until(Instant::now(), std::future::pending())
+        .await
+        .unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+    assert!(is_unreachable(&e));
+    assert!(!is_unreachable(&io::Error::from(io::ErrorKind::TimedOut)));
+}
+
+#[tokio::test]
+async fn direct_connect_socket_option() {
+    use tokio::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let dst = match listener.local_addr().unwrap() {
+        SocketAddr::V4(addr) => addr,
+        SocketAddr::V6(_) => unreachable!(),
+    };
+
+    // `TCP_NODELAY` is set by default
+    let mut option = SocketOption::new();
+    let stream = connect(&option, dst, Duration::from_secs(1)).await.unwrap();
+    assert!(stream.nodelay().unwrap());
+    option.set_nodelay(false);
+    option.set_send_buffer_size(Some(65536));
+    option.set_recv_buffer_size(Some(65536));
+    let stream = connect(&option, dst, Duration::from_secs(1)).await.unwrap();
+    assert!(!stream.nodelay().unwrap());
+
+    // Streams and sockets are bound to the local address
+    option.set_bind_addr(Some(Ipv4Addr::LOCALHOST));
+    let stream = connect(&option, dst, Duration::from_secs(1)).await.unwrap();
+    assert_eq!(stream.local_addr().unwrap().ip(), Ipv4Addr::LOCALHOST);
+    let socket = bind_udp(Some(Ipv4Addr::LOCALHOST)).await.unwrap();
+    assert_eq!(socket.local_addr().unwrap().ip(), Ipv4Addr::LOCALHOST);
+
+    // An address not on the host cannot be bound
+    let local = Some(Ipv4Addr::new(192, 0, 2, 1));
+    option.set_bind_addr(local);
+    let e = connect(&option, dst, Duration::from_secs(1))
+        .await
+        .unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::AddrNotAvailable);
+    assert!(!is_unreachable(&e));
+    assert!(bind_udp(local).await.is_err());
+}
+
+#[tokio::test]
+async fn direct_bind_bypass() {
+    use tokio::io::BufStream;
+    use tokio::net::TcpListener;
+
+    // An association with the SOCKS server, whose relay never replies
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let stream = TcpStream::connect(listener.local_addr().unwrap())
+        .await
+        .unwrap();
+    let (_control, _) = listener.accept().await.unwrap();
+    let relay = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+    socket.connect(relay.local_addr().unwrap()).await.unwrap();
+
+    let mut options = SocksOption::new(false, false, None);
+    options.set_bypass(ipnetwork::Ipv4Network::new(Ipv4Addr::LOCALHOST, 32).unwrap());
+    let (direct_tx, direct_rx) = watch::channel(None);
+    let mut rx = DatagramRecvHalf {
+        socks: Some(SocksRecvHalf::new(
+            BufStream::new(stream),
+            Arc::clone(&socket),
+        )),
+        direct_rx,
+        buffer: vec![0u8; u16::MAX as usize],
+        options: options.clone(),
+    };
+    let mut tx = DatagramSendHalf {
+        socks: Some(SocksSendHalf::new(socket)),
+        direct: None,
+        direct_tx,
+        options,
+    };
+
+    // The direct socket is bound by the first datagram to a bypassed destination
+    let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let dst = match peer.local_addr().unwrap() {
+        SocketAddr::V4(addr) => addr,
+        SocketAddr::V6(_) => unreachable!(),
+    };
+    assert!(tx.direct.is_none());
+    tx.send_to(b"hello", dst).await.unwrap();
+    let port = tx.direct.as_ref().unwrap().local_addr().unwrap().port();
+    let local = SocketAddrV4::new(Ipv4Addr::LOCALHOST, port);
+    let mut buffer = [0u8; 16];
+    let (size, addr) = peer.recv_from(&mut buffer).await.unwrap();
+    assert_eq!(&buffer[..size], b"hello");
+    assert_eq!(addr, SocketAddr::V4(local));
+
+    // Datagrams from peers which are not bypassed are dropped
+    let other = UdpSocket::bind("127.0.0.2:0").await.unwrap();
+    other.send_to(b"dropped", local).await.unwrap();
+    peer.send_to(b"world", local).await.unwrap();
+    let (size, addr) = rx.recv_from(&mut buffer).await.unwrap();
+    assert_eq!(&buffer[..size], b"world");
+    assert_eq!(addr, dst);
+}
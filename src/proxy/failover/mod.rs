@@ -0,0 +1,221 @@
+//! Support for failing over between upstreams.
+
+use super::direct;
+use super::upstream::{BoxFuture, UpstreamConnector, UpstreamDatagram, UpstreamStream};
+use log::{debug, info, trace, warn};
+use std::net::SocketAddrV4;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io;
+
+/// Represents the time an unreachable upstream will not be retried.
+const FAILOVER_COOL_DOWN: u64 = 30000;
+
+/// Represents the policy of selecting an upstream in failover.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FailoverPolicy {
+    /// Represents trying the upstreams in the given order, so the first reachable upstream is
+    /// always preferred.
+    Priority,
+    /// Represents trying the upstreams starting from the next one in turn, which spreads the
+    /// load over the reachable upstreams.
+    RoundRobin,
+}
+
+/// Represents the upstream failing over through a list of upstreams. An upstream which is
+/// unreachable will be skipped until its cool down expires, unless all the upstreams are
+/// unreachable.
+pub struct FailoverUpstream {
+    upstreams: Vec<Arc<dyn UpstreamConnector>>,
+    policy: FailoverPolicy,
+    cool_down: Duration,
+    next: AtomicUsize,
+    /// Represents the time each upstream was found unreachable.
+    failures: Mutex<Vec<Option<Instant>>>,
+}
+
+impl FailoverUpstream {
+    /// Creates a new `FailoverUpstream`.
+    pub fn new(
+        upstreams: Vec<Arc<dyn UpstreamConnector>>,
+        policy: FailoverPolicy,
+    ) -> FailoverUpstream {
+        let failures = vec![None; upstreams.len()];
+
+        FailoverUpstream {
+            upstreams,
+            policy,
+            cool_down: Duration::from_millis(FAILOVER_COOL_DOWN),
+            next: AtomicUsize::new(0),
+            failures: Mutex::new(failures),
+        }
+    }
+
+    /// Sets the time an unreachable upstream will not be retried.
+    pub fn set_cool_down(&mut self, cool_down: Duration) {
+        self.cool_down = cool_down;
+        trace!("set failover cool down to {:?}", cool_down);
+    }
+
+    /// Returns if the upstream at the index is considered unreachable.
+    pub fn is_unreachable(&self, i: usize) -> bool {
+        match self.failures.lock().unwrap().get(i) {
+            Some(Some(instant)) => instant.elapsed() < self.cool_down,
+            _ => false,
+        }
+    }
+
+    /// Returns the indexes of the upstreams to try in order. Unreachable upstreams are left out
+    /// unless all the upstreams are unreachable.
+    fn order(&self) -> Vec<usize> {
+        let n = self.upstreams.len();
+        let start = match self.policy {
+            FailoverPolicy::Priority => 0,
+            FailoverPolicy::RoundRobin => self.next.fetch_add(1, Ordering::Relaxed),
+        };
+
+        let (reachable, unreachable): (Vec<_>, Vec<_>) = (0..n)
+            .map(|i| (start % n + i) % n)
+            .partition(|&i| !self.is_unreachable(i));
+        if reachable.is_empty() {
+            unreachable
+        } else {
+            reachable
+        }
+    }
+
+    fn set_unreachable(&self, i: usize, e: &io::Error) {
+        let mut failures = self.failures.lock().unwrap();
+        if failures[i].is_none() {
+            warn!("upstream {} is unreachable, fail over: {}", i, e);
+        }
+        failures[i] = Some(Instant::now());
+    }
+
+    fn set_reachable(&self, i: usize) {
+        if self.failures.lock().unwrap()[i].take().is_some() {
+            info!("Upstream {} is reachable again", i);
+        }
+    }
+}
+
+impl UpstreamConnector for FailoverUpstream {
+    fn connect(
+        &self,
+        dst: SocketAddrV4,
+        name: Option<String>,
+    ) -> BoxFuture<'_, io::Result<UpstreamStream>> {
+        Box::pin(async move {
+            let mut last_e = None;
+            for i in self.order() {
+                match self.upstreams[i].connect(dst, name.clone()).await {
+                    Err(e) if direct::is_unreachable(&e) => {
+                        debug!("connect to {} through upstream {}: {}", dst, i, e);
+                        self.set_unreachable(i, &e);
+                        last_e = Some(e);
+                    }
+                    result => {
+                        if result.is_ok() {
+                            self.set_reachable(i);
+                        }
+
+                        return result;
+                    }
+                }
+            }
+
+            Err(last_e.unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no upstream")))
+        })
+    }
+
+    fn bind(&self, src: SocketAddrV4) -> BoxFuture<'_, io::Result<UpstreamDatagram>> {
+        Box::pin(async move {
+            let mut last_e = None;
+            for i in self.order() {
+                match self.upstreams[i].bind(src).await {
+                    Err(e) if direct::is_unreachable(&e) => {
+                        debug!("bind for {} through upstream {}: {}", src, i, e);
+                        self.set_unreachable(i, &e);
+                        last_e = Some(e);
+                    }
+                    result => {
+                        if result.is_ok() {
+                            self.set_reachable(i);
+                        }
+
+                        return result;
+                    }
+                }
+            }
+
+            Err(last_e.unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no upstream")))
+        })
+    }
+}
+
+#[tokio::test]
+async fn failover_upstream_connect() {
+    use crate::testing::CountingUpstream;
+    use std::net::Ipv4Addr;
+
+    let dst = SocketAddrV4::new(Ipv4Addr::new(1, 1, 1, 1), 80);
+    let upstreams = [
+        Arc::new(CountingUpstream::new(Some(
+            io::ErrorKind::ConnectionRefused,
+        ))),
+        Arc::new(CountingUpstream::new(None)),
+        Arc::new(CountingUpstream::new(None)),
+    ];
+    let counts = || {
+        upstreams
+            .iter()
+            .map(|upstream| upstream.count())
+            .collect::<Vec<_>>()
+    };
+    let failover = |skip, policy| {
+        FailoverUpstream::new(
+            upstreams
+                .iter()
+                .skip(skip)
+                .map(|upstream| Arc::clone(upstream) as Arc<dyn UpstreamConnector>)
+                .collect(),
+            policy,
+        )
+    };
+
+    // The unreachable upstream is skipped once it failed
+    let upstream = failover(0, FailoverPolicy::Priority);
+    assert!(upstream.connect(dst, None).await.is_ok());
+    assert!(upstream.connect(dst, None).await.is_ok());
+    assert!(upstream.is_unreachable(0));
+    assert_eq!(counts(), vec![1, 2, 0]);
+
+    // Upstreams are tried in turn
+    let upstream = failover(1, FailoverPolicy::RoundRobin);
+    for _ in 0..4 {
+        assert!(upstream.connect(dst, None).await.is_ok());
+    }
+    assert_eq!(counts(), vec![1, 4, 2]);
+
+    // Unreachable upstreams are retried if all the upstreams are unreachable, and the last error
+    // is returned
+    let mut upstream = failover(0, FailoverPolicy::Priority);
+    upstream.set_cool_down(Duration::from_secs(60));
+    let e = upstream.bind(dst).await.err().unwrap();
+    assert_eq!(e.kind(), io::ErrorKind::ConnectionRefused);
+    assert!((0..3).all(|i| upstream.is_unreachable(i)));
+    assert!(upstream.bind(dst).await.is_err());
+
+    // Errors other than unreachable are not failed over
+    let upstream = FailoverUpstream::new(
+        vec![
+            Arc::new(CountingUpstream::new(Some(io::ErrorKind::PermissionDenied))),
+            Arc::clone(&upstreams[1]) as Arc<dyn UpstreamConnector>,
+        ],
+        FailoverPolicy::Priority,
+    );
+    let e = upstream.connect(dst, None).await.err().unwrap();
+    assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+    assert!(!upstream.is_unreachable(0));
+}
@@ -0,0 +1,309 @@
+//! Support for handling HTTP CONNECT proxies.
+
+use super::direct::{self, SocketOption};
+use super::upstream::{BoxFuture, UpstreamConnector, UpstreamDatagram, UpstreamStream};
+use log::trace;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::time::Duration;
+use tokio::io::{
+    self, AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+};
+use tokio::net::TcpStream;
+use tokio::time::{self, Instant};
+
+/// Represents the timeout of connecting to an HTTP proxy and handshaking.
+const CONNECT_TIMEOUT: u64 = 10000;
+
+/// Represents the max size of the response header of a CONNECT request.
+const MAX_HEADER_SIZE: usize = 8192;
+
+/// Represents the username and the password of the Basic authentication connecting to an HTTP
+/// proxy.
+#[derive(Clone, Debug)]
+pub struct HttpAuth {
+    username: String,
+    password: String,
+}
+
+impl HttpAuth {
+    /// Creates a `HttpAuth`.
+    pub fn new(username: String, password: String) -> HttpAuth {
+        HttpAuth { username, password }
+    }
+
+    fn to_header(&self) -> String {
+        let credentials = format!("{}:{}", self.username, self.password);
+
+        format!("Basic {}", encode_base64(credentials.as_bytes()))
+    }
+}
+
+/// Represents the upstream through an HTTP proxy. TCP connections are tunneled through the proxy
+/// by CONNECT requests, while UDP is not supported.
+#[derive(Clone, Debug)]
+pub struct HttpUpstream {
+    remote: SocketAddrV4,
+    auth: Option<HttpAuth>,
+    connect_timeout: Duration,
+    socket: SocketOption,
+}
+
+impl HttpUpstream {
+    /// Creates a new `HttpUpstream`.
+    pub fn new(remote: SocketAddrV4, auth: Option<(String, String)>) -> HttpUpstream {
+        HttpUpstream {
+            remote,
+            auth: auth.map(|(username, password)| HttpAuth::new(username, password)),
+            connect_timeout: Duration::from_millis(CONNECT_TIMEOUT),
+            socket: SocketOption::new(),
+        }
+    }
+
+    /// Sets the timeout of connecting to the proxy and handshaking.
+    pub fn set_connect_timeout(&mut self, timeout: Duration) {
+        self.connect_timeout = timeout;
+        trace!("set connect timeout to {:?}", timeout);
+    }
+
+    /// Sets the local address of connections to the proxy.
+    pub fn set_bind_addr(&mut self, bind_addr: Option<Ipv4Addr>) {
+        self.socket.set_bind_addr(bind_addr);
+        trace!("set bind address to {:?}", bind_addr);
+    }
+
+    /// Sets the options of sockets connecting to the proxy, including the local address.
+    pub fn set_socket(&mut self, socket: SocketOption) {
+        self.socket = socket;
+        trace!("set socket option to {:?}", socket);
+    }
+}
+
+impl UpstreamConnector for HttpUpstream {
+    fn connect(
+        &self,
+        dst: SocketAddrV4,
+        name: Option<String>,
+    ) -> BoxFuture<'_, io::Result<UpstreamStream>> {
+        Box::pin(async move {
+            let stream = connect(
+                &self.socket,
+                self.remote,
+                dst,
+                name,
+                self.auth.as_ref(),
+                self.connect_timeout,
+            )
+            .await?;
+
+            Ok(UpstreamStream::new(Box::new(stream), false))
+        })
+    }
+
+    fn bind(&self, _: SocketAddrV4) -> BoxFuture<'_, io::Result<UpstreamDatagram>> {
+        Box::pin(async {
+            Err(io::Error::new(
+                io::ErrorKind::Other,
+                "UDP is not supported through HTTP proxies",
+            ))
+        })
+    }
+}
+
+/// Connects to a target server through an HTTP proxy in the socket options. The connection will be
+/// aborted if the handshake does not complete in the timeout, and a `ConnectTimedOut` error is
+/// returned if the connection to the HTTP proxy is not established in the timeout. The stream is
+/// buffered, where data tunneled right after the response header is kept.
+pub async fn connect(
+    socket: &SocketOption,
+    remote: SocketAddrV4,
+    dst: SocketAddrV4,
+    name: Option<String>,
+    auth: Option<&HttpAuth>,
+    duration: Duration,
+) -> io::Result<BufReader<TcpStream>> {
+    let deadline = Instant::now() + duration;
+    let stream = direct::connect_until(deadline, direct::connect_tcp(socket, remote)).await?;
+    let mut stream = BufReader::new(stream);
+    let future = async {
+        handshake(&mut stream, dst, name, auth).await?;
+
+        Ok(stream)
+    };
+
+    match time::timeout_at(deadline, future).await {
+        Ok(result) => result,
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "HTTP handshake timed out",
+        )),
+    }
+}
+
+async fn handshake<S: AsyncBufRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    dst: SocketAddrV4,
+    name: Option<String>,
+    auth: Option<&HttpAuth>,
+) -> io::Result<()> {
+    let authority = match name {
+        Some(name) => format!("{}:{}", name, dst.port()),
+        None => dst.to_string(),
+    };
+
+    // Request
+    let mut request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n", authority, authority);
+    if let Some(auth) = auth {
+        request.push_str(&format!("Proxy-Authorization: {}\r\n", auth.to_header()));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    // Response, read line by line from the buffer, which keeps the tunneled data
+    let mut header = Vec::new();
+    let mut limited = (&mut *stream).take(MAX_HEADER_SIZE as u64);
+    while !header.ends_with(b"\r\n\r\n") {
+        if limited.read_until(b'\n', &mut header).await? == 0 {
+            return Err(match header.len() >= MAX_HEADER_SIZE {
+                true => {
+                    io::Error::new(io::ErrorKind::InvalidData, "HTTP response header too large")
+                }
+                false => io::Error::from(io::ErrorKind::UnexpectedEof),
+            });
+        }
+    }
+
+    let status = String::from_utf8_lossy(&header)
+        .lines()
+        .next()
+        .and_then(|line| {
+            let mut parts = line.split_whitespace();
+            match parts.next() {
+                Some(version) if version.starts_with("HTTP/1.") => {
+                    parts.next().and_then(|code| code.parse::<u16>().ok())
+                }
+                _ => None,
+            }
+        })
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid HTTP response"))?;
+    match status {
+        200..=299 => Ok(()),
+        407 => Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "HTTP proxy authentication required",
+        )),
+        _ => Err(io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            format!("HTTP proxy responded with status {}", status),
+        )),
+    }
+}
+
+fn encode_base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut s = String::new();
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                s.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                s.push('=');
+            }
+        }
+    }
+
+    s
+}
+
+#[test]
+fn encode_base64_padding() {
+    assert_eq!(encode_base64(b""), "");
+    assert_eq!(encode_base64(b"f"), "Zg==");
+    assert_eq!(encode_base64(b"fo"), "Zm8=");
+    assert_eq!(encode_base64(b"foo"), "Zm9v");
+    assert_eq!(encode_base64(b"user:pass"), "dXNlcjpwYXNz");
+}
+
+#[cfg(test)]
+async fn serve_http_proxy(listener: tokio::net::TcpListener, auth: &'static str) {
+    loop {
+        let (mut stream, _) = listener.accept().await.unwrap();
+
+        let mut header = Vec::new();
+        while !header.ends_with(b"\r\n\r\n") {
+            header.push(stream.read_u8().await.unwrap());
+        }
+        let header = String::from_utf8(header).unwrap();
+        assert!(header.starts_with("CONNECT example.com:80 HTTP/1.1\r\n"));
+
+        let authorization = format!("Proxy-Authorization: {}\r\n", auth);
+        if !header.contains(&authorization) {
+            stream
+                .write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\nProxy-Authenticate: Basic realm=\"proxy\"\r\n\r\n")
+                .await
+                .unwrap();
+            continue;
+        }
+        stream
+            .write_all(b"HTTP/1.1 200 Connection Established\r\n\r\npong")
+            .await
+            .unwrap();
+    }
+}
+
+#[tokio::test]
+async fn http_connect_auth() {
+    use std::net::{Ipv4Addr, SocketAddr};
+    use tokio::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let remote = match listener.local_addr().unwrap() {
+        SocketAddr::V4(addr) => addr,
+        SocketAddr::V6(_) => unreachable!(),
+    };
+    tokio::spawn(serve_http_proxy(listener, "Basic dXNlcjpwYXNz"));
+    let dst = SocketAddrV4::new(Ipv4Addr::new(1, 2, 3, 4), 80);
+
+    // The challenge without credentials
+    let upstream = HttpUpstream::new(remote, None);
+    let e = upstream
+        .connect(dst, Some("example.com".to_string()))
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+
+    // The tunnel with credentials, which leaves the data after the header in the stream
+    let upstream = HttpUpstream::new(remote, Some(("user".to_string(), "pass".to_string())));
+    let (mut rx, _) = upstream
+        .connect(dst, Some("example.com".to_string()))
+        .await
+        .unwrap()
+        .into_split();
+    let mut buffer = [0u8; 4];
+    rx.read_exact(&mut buffer).await.unwrap();
+    assert_eq!(&buffer, b"pong");
+
+    // UDP is not supported
+    assert!(upstream.bind(dst).await.is_err());
+}
+
+#[tokio::test]
+async fn http_handshake_header_too_large() {
+    use std::net::Ipv4Addr;
+
+    let dst = SocketAddrV4::new(Ipv4Addr::new(1, 2, 3, 4), 80);
+    let (stream, mut remote) = io::duplex(2 * MAX_HEADER_SIZE);
+    let mut stream = BufReader::new(stream);
+
+    remote
+        .write_all(&vec![b'a'; MAX_HEADER_SIZE + 1])
+        .await
+        .unwrap();
+    let e = handshake(&mut stream, dst, None, None).await.unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+}
@@ -0,0 +1,199 @@
+//! Support for handling traffic locally without any proxy.
+
+use super::upstream::{
+    BoxFuture, DatagramRecv, DatagramSend, UpstreamConnector, UpstreamDatagram, UpstreamStream,
+};
+use log::{debug, trace};
+use std::net::SocketAddrV4;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::Arc;
+use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+/// Represents the size of the in-memory stream of a loopback connection.
+const LOOPBACK_BUFFER_SIZE: usize = 65536;
+
+/// Represents the handler of a loopback upstream, which returns the response to the data sent to
+/// the destination. An empty response sends nothing back.
+pub type LoopbackHandler = dyn Fn(SocketAddrV4, &[u8]) -> Vec<u8> + Send + Sync;
+
+/// Represents the upstream handling traffic locally instead of connecting to any proxy, which
+/// echoes the data by default. It is used to benchmark and test the packet path in isolation.
+pub struct LoopbackUpstream {
+    handler: Arc<LoopbackHandler>,
+    next_port: AtomicU16,
+}
+
+impl LoopbackUpstream {
+    /// Creates a new `LoopbackUpstream` echoing the data.
+    pub fn new() -> LoopbackUpstream {
+        LoopbackUpstream::new_with_handler(|_, data| data.to_vec())
+    }
+
+    /// Creates a new `LoopbackUpstream` responding to the data by the handler. The handler is
+    /// called for each read from a stream, or for each datagram.
+    pub fn new_with_handler<F>(handler: F) -> LoopbackUpstream
+    where
+        F: Fn(SocketAddrV4, &[u8]) -> Vec<u8> + Send + Sync + 'static,
+    {
+        LoopbackUpstream {
+            handler: Arc::new(handler),
+            next_port: AtomicU16::new(1),
+        }
+    }
+
+    /// Returns the next local port of a datagram socket, which skips port 0 in wrapping.
+    fn next_port(&self) -> u16 {
+        loop {
+            let port = self.next_port.fetch_add(1, Ordering::Relaxed);
+            if port != 0 {
+                return port;
+            }
+        }
+    }
+}
+
+impl Default for LoopbackUpstream {
+    fn default() -> Self {
+        LoopbackUpstream::new()
+    }
+}
+
+impl UpstreamConnector for LoopbackUpstream {
+    fn connect(
+        &self,
+        dst: SocketAddrV4,
+        _: Option<String>,
+    ) -> BoxFuture<'_, io::Result<UpstreamStream>> {
+        let handler = Arc::clone(&self.handler);
+
+        Box::pin(async move {
+            let (stream, mut remote) = io::duplex(LOOPBACK_BUFFER_SIZE);
+
+            tokio::spawn(async move {
+                let mut buffer = vec![0u8; LOOPBACK_BUFFER_SIZE];
+                loop {
+                    let size = match remote.read(&mut buffer).await {
+                        Ok(0) => break,
+                        Ok(size) => size,
+                        Err(ref e) => {
+                            debug!("loopback receive: {}: {}", dst, e);
+                            break;
+                        }
+                    };
+
+                    let response = handler(dst, &buffer[..size]);
+                    if !response.is_empty() {
+                        if let Err(ref e) = remote.write_all(&response).await {
+                            debug!("loopback send: {}: {}", dst, e);
+                            break;
+                        }
+                    }
+                }
+
+                let _ = remote.shutdown().await;
+                trace!("close loopback stream {}", dst);
+            });
+
+            Ok(UpstreamStream::new(Box::new(stream), false))
+        })
+    }
+
+    fn bind(&self, _: SocketAddrV4) -> BoxFuture<'_, io::Result<UpstreamDatagram>> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let datagram = UpstreamDatagram::new(
+            Box::new(LoopbackRecvHalf(rx)),
+            Box::new(LoopbackSendHalf {
+                handler: Arc::clone(&self.handler),
+                tx,
+            }),
+            self.next_port(),
+            false,
+        );
+
+        Box::pin(async move { Ok(datagram) })
+    }
+}
+
+/// Represents the send half of a loopback datagram socket, which responds to each datagram.
+struct LoopbackSendHalf {
+    handler: Arc<LoopbackHandler>,
+    tx: UnboundedSender<(Vec<u8>, SocketAddrV4)>,
+}
+
+impl DatagramSend for LoopbackSendHalf {
+    fn send_to<'a>(
+        &'a mut self,
+        payload: &'a [u8],
+        dst: SocketAddrV4,
+    ) -> BoxFuture<'a, io::Result<usize>> {
+        let response = (self.handler)(dst, payload);
+        let result = match response.is_empty() || self.tx.send((response, dst)).is_ok() {
+            true => Ok(payload.len()),
+            false => Err(io::Error::from(io::ErrorKind::BrokenPipe)),
+        };
+
+        Box::pin(async move { result })
+    }
+}
+
+/// Represents the receive half of a loopback datagram socket.
+struct LoopbackRecvHalf(UnboundedReceiver<(Vec<u8>, SocketAddrV4)>);
+
+impl DatagramRecv for LoopbackRecvHalf {
+    fn recv_from<'a>(
+        &'a mut self,
+        buffer: &'a mut [u8],
+    ) -> BoxFuture<'a, io::Result<(usize, SocketAddrV4)>> {
+        Box::pin(async move {
+            let (response, src) = self
+                .0
+                .recv()
+                .await
+                .ok_or_else(|| io::Error::from(io::ErrorKind::BrokenPipe))?;
+            let size = response.len().min(buffer.len());
+            buffer[..size].copy_from_slice(&response[..size]);
+
+            Ok((size, src))
+        })
+    }
+}
+
+#[tokio::test]
+async fn loopback_upstream_handler() {
+    use std::net::Ipv4Addr;
+
+    let dst = SocketAddrV4::new(Ipv4Addr::new(1, 1, 1, 1), 80);
+    let upstream = LoopbackUpstream::new_with_handler(|_, data| data.to_ascii_uppercase());
+
+    // Streams respond to each read
+    let (mut rx, mut tx) = upstream.connect(dst, None).await.unwrap().into_split();
+    tx.write_all(b"ping").await.unwrap();
+    let mut buffer = [0u8; 4];
+    rx.read_exact(&mut buffer).await.unwrap();
+    assert_eq!(&buffer, b"PING");
+
+    // Datagrams are responded from the destination, and each binding has its own port
+    let datagram = upstream.bind(dst).await.unwrap();
+    assert_ne!(
+        datagram.local_port(),
+        upstream.bind(dst).await.unwrap().local_port()
+    );
+    let (mut rx, mut tx) = datagram.into_split();
+    assert_eq!(tx.send_to(b"pong", dst).await.unwrap(), 4);
+    let mut buffer = [0u8; 8];
+    assert_eq!(rx.recv_from(&mut buffer).await.unwrap(), (4, dst));
+    assert_eq!(&buffer[..4], b"PONG");
+
+    // Ports wrap without reaching 0
+    upstream.next_port.store(u16::MAX, Ordering::Relaxed);
+    assert_eq!(upstream.next_port(), u16::MAX);
+    assert_eq!(upstream.next_port(), 1);
+
+    // Empty responses send nothing back
+    let upstream = LoopbackUpstream::new_with_handler(|_, _| Vec::new());
+    let (mut rx, mut tx) = upstream.connect(dst, None).await.unwrap().into_split();
+    tx.write_all(b"ping").await.unwrap();
+    tx.shutdown().await.unwrap();
+    assert_eq!(rx.read(&mut buffer).await.unwrap(), 0);
+}
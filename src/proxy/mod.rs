@@ -1,20 +1,44 @@
 //! Support for handling proxies.
 
 use log::{debug, trace, warn};
-use std::net::{Ipv4Addr, Shutdown, SocketAddrV4};
-use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use async_socks5::AddrKind;
+use crate::cidr::Ipv4NetworkSet;
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::{Ipv4Addr, Shutdown, SocketAddr, SocketAddrV4};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::tcp::OwnedWriteHalf;
+use tokio::sync::mpsc::error::TrySendError;
 use tokio::sync::mpsc::{self, Sender, UnboundedReceiver, UnboundedSender};
-use tokio::{self, io, time};
-
+use tokio::time::{self, Instant};
+use tokio::{self, io};
+use tracing::Instrument;
+
+mod direct;
+pub use direct::SocketOption;
+mod failover;
+pub use failover::{FailoverPolicy, FailoverUpstream};
+mod loopback;
+pub use loopback::{LoopbackHandler, LoopbackUpstream};
+mod pool;
+pub use pool::ConnectionPool;
 mod socks;
 use socks::SocksSendHalf;
-use socks::{SocksAuth, SocksOption};
+pub use socks::{GssapiAuth, GssapiProtection, ProxyStream, SocksReply, SocksVersion};
+use socks::{SocksAuth, SocksOption, SocksStream};
+mod http;
+pub use http::HttpUpstream;
+mod upstream;
+pub use upstream::{
+    BoxFuture, DatagramRecv, DatagramSend, SocksUpstream, UpstreamConnector, UpstreamDatagram,
+    UpstreamStream,
+};
 
 /// Represents the configuration of the proxy.
+#[derive(Clone, Debug)]
 pub enum ProxyConfig {
     /// Represents the SOCKS proxy configuration.
     Socks(SocketAddrV4, SocksOption),
@@ -27,16 +51,108 @@ impl ProxyConfig {
         force_associate_remote: bool,
         force_associate_bind_addr: bool,
         auth: Option<(String, String)>,
+    ) -> ProxyConfig {
+        ProxyConfig::new_socks_with_version(
+            remote,
+            SocksVersion::Socks5,
+            force_associate_remote,
+            force_associate_bind_addr,
+            auth,
+        )
+    }
+
+    /// Creates a new SOCKS `ProxyConfig` with the given version of the SOCKS protocol.
+    pub fn new_socks_with_version(
+        remote: SocketAddrV4,
+        version: SocksVersion,
+        force_associate_remote: bool,
+        force_associate_bind_addr: bool,
+        auth: Option<(String, String)>,
     ) -> ProxyConfig {
         ProxyConfig::Socks(
             remote,
-            SocksOption::new(
+            SocksOption::new_with_version(
+                version,
                 force_associate_remote,
                 force_associate_bind_addr,
                 auth.map(|(username, password)| SocksAuth::new(username, password)),
             ),
         )
     }
+
+    /// Sets the address of the proxy.
+    pub fn set_remote(&mut self, remote: SocketAddrV4) {
+        match self {
+            ProxyConfig::Socks(prev_remote, _) => *prev_remote = remote,
+        }
+    }
+
+    /// Sets the timeout of connecting to the proxy and handshaking.
+    pub fn set_connect_timeout(&mut self, timeout: Duration) {
+        match self {
+            ProxyConfig::Socks(_, options) => options.set_connect_timeout(timeout),
+        }
+    }
+
+    /// Sets the GSSAPI authentication connecting to the proxy. The GSSAPI authentication requires
+    /// the `gssapi` feature, and is not supported in UDP.
+    pub fn set_gssapi(&mut self, gssapi: Option<GssapiAuth>) {
+        match self {
+            ProxyConfig::Socks(_, options) => options.set_gssapi(gssapi),
+        }
+    }
+
+    /// Sets the connector of TCP connections. If it is set, TCP connections will be connected
+    /// through the connector, like streams multiplexed over a single tunnel, instead of a proxy
+    /// connection for each. UDP is not affected, and the pool of warm connections is not used.
+    pub fn set_connector(&mut self, connector: Option<Arc<dyn UpstreamConnector>>) {
+        match self {
+            ProxyConfig::Socks(_, options) => options.set_connector(connector),
+        }
+    }
+
+    /// Sets the networks of destinations which will be connected directly from the host instead
+    /// of through the proxy. The networks are consulted before connecting to the proxy, and can be
+    /// parsed from CIDR strings into an `Ipv4NetworkSet`.
+    pub fn set_bypass<T: Into<Ipv4NetworkSet>>(&mut self, bypass: T) {
+        match self {
+            ProxyConfig::Socks(_, options) => options.set_bypass(bypass),
+        }
+    }
+
+    /// Sets if destinations will be connected directly from the host when the proxy is
+    /// unreachable.
+    pub fn set_direct_fallback(&mut self, is_direct_fallback: bool) {
+        match self {
+            ProxyConfig::Socks(_, options) => options.set_direct_fallback(is_direct_fallback),
+        }
+    }
+
+    /// Sets the local address of connections and sockets to the proxy and to destinations
+    /// connected directly, so the traffic egresses from the interface of the address on a
+    /// multi-homed host. `None` represents any address, where the interface follows the routing
+    /// table.
+    pub fn set_bind_addr(&mut self, bind_addr: Option<Ipv4Addr>) {
+        match self {
+            ProxyConfig::Socks(_, options) => options.set_bind_addr(bind_addr),
+        }
+    }
+
+    /// Sets the options of TCP sockets to the proxy and to destinations connected directly, like
+    /// `TCP_NODELAY` and the sizes of the buffers, which are applied as the worker connects. The
+    /// local address in the options replaces the one set by `set_bind_addr`.
+    pub fn set_socket(&mut self, socket: SocketOption) {
+        match self {
+            ProxyConfig::Socks(_, options) => options.set_socket(socket),
+        }
+    }
+
+    /// Returns the options of TCP sockets to the proxy and to destinations connected directly.
+    pub fn socket(&self) -> &SocketOption {
+        match self {
+            ProxyConfig::Socks(_, options) => options.socket(),
+        }
+    }
 }
 
 /// Trait for forwarding a stream.
@@ -53,6 +169,12 @@ pub trait ForwardStream: Send {
     /// Closes a stream connection.
     fn close(&mut self, dst: SocketAddrV4, src: SocketAddrV4) -> io::Result<()>;
 
+    /// Indicates data was written to the proxy. Used in reopening the window held by data
+    /// pending to be written.
+    fn drain(&mut self, _dst: SocketAddrV4, _src: SocketAddrV4, _size: usize) -> io::Result<()> {
+        Ok(())
+    }
+
     /// Checks the stream.
     fn check(&self, dst: SocketAddrV4, src: SocketAddrV4) -> io::Result<usize>;
 }
@@ -70,14 +192,26 @@ const MAX_RECV_ZERO: usize = 3;
 /// Represents the interval of a tick.
 const TICK_INTERVAL: u64 = 500;
 
+/// Represents the maximum count of payloads queued to be mirrored to a tee. A tee falling further
+/// behind is abandoned.
+const TEE_QUEUE_SIZE: usize = 256;
+
 /// Represents a worker of a proxied TCP stream.
 pub struct StreamWorker {
+    src: SocketAddrV4,
     dst: SocketAddrV4,
+    name: Option<String>,
+    is_direct: bool,
     tx_tx: UnboundedSender<Vec<u8>>,
+    tx_size: Arc<AtomicUsize>,
+    rx_size: Arc<AtomicUsize>,
     is_tx_closed: Arc<AtomicBool>,
     is_rx_closed: Arc<AtomicBool>,
     tx_close_tx: Sender<()>,
     rx_close_tx: Sender<()>,
+    id: usize,
+    teardown_tx: Option<UnboundedSender<(usize, SocketAddrV4, SocketAddrV4)>>,
+    tee_tx: Option<Sender<Vec<u8>>>,
 }
 
 impl StreamWorker {
@@ -87,15 +221,66 @@ impl StreamWorker {
         src: SocketAddrV4,
         dst: SocketAddrV4,
         proxy: &ProxyConfig,
+    ) -> io::Result<StreamWorker> {
+        StreamWorker::connect_with_name(tx, src, dst, None, proxy, None).await
+    }
+
+    /// Opens a new `StreamWorker`. The destination will be sent to the proxy in the name if the
+    /// name is given, and the connection to the proxy will be taken from the pool if the pool is
+    /// given.
+    pub async fn connect_with_name(
+        tx: Arc<Mutex<dyn ForwardStream>>,
+        src: SocketAddrV4,
+        dst: SocketAddrV4,
+        name: Option<String>,
+        proxy: &ProxyConfig,
+        pool: Option<&Arc<ConnectionPool>>,
+    ) -> io::Result<StreamWorker> {
+        StreamWorker::connect_with_target(tx, src, dst, dst, name, proxy, pool).await
+    }
+
+    /// Opens a new `StreamWorker` to the target instead of the destination. The destination is
+    /// still used in forwarding, so the sources are not aware of the target.
+    pub async fn connect_with_target(
+        tx: Arc<Mutex<dyn ForwardStream>>,
+        src: SocketAddrV4,
+        dst: SocketAddrV4,
+        target: SocketAddrV4,
+        name: Option<String>,
+        proxy: &ProxyConfig,
+        pool: Option<&Arc<ConnectionPool>>,
+    ) -> io::Result<StreamWorker> {
+        let stream = upstream::connect(proxy, pool, target, name.clone()).await?;
+
+        StreamWorker::start(tx, src, dst, name, stream)
+    }
+
+    /// Opens a new `StreamWorker` to the target through the upstream. The destination is still
+    /// used in forwarding, so the sources are not aware of the target.
+    pub async fn connect_with_upstream(
+        tx: Arc<Mutex<dyn ForwardStream>>,
+        src: SocketAddrV4,
+        dst: SocketAddrV4,
+        target: SocketAddrV4,
+        name: Option<String>,
+        upstream: &dyn UpstreamConnector,
+    ) -> io::Result<StreamWorker> {
+        let stream = upstream.connect(target, name.clone()).await?;
+
+        StreamWorker::start(tx, src, dst, name, stream)
+    }
+
+    fn start(
+        tx: Arc<Mutex<dyn ForwardStream>>,
+        src: SocketAddrV4,
+        dst: SocketAddrV4,
+        name: Option<String>,
+        stream: UpstreamStream,
     ) -> io::Result<StreamWorker> {
         let tx_cloned = Arc::clone(&tx);
+        let tx_drain = Arc::clone(&tx);
 
-        let stream = match proxy {
-            ProxyConfig::Socks(remote, options) => {
-                socks::connect(*remote, dst, options).await?
-            }
-        };
-        let stream = stream.into_inner();
+        let is_direct = stream.is_direct();
         let (mut stream_rx, mut stream_tx) = stream.into_split();
 
         // Open
@@ -103,6 +288,10 @@ impl StreamWorker {
 
         let (tx_tx, mut tx_rx): (UnboundedSender<Vec<u8>>, UnboundedReceiver<Vec<u8>>) =
             mpsc::unbounded_channel();
+        let tx_size = Arc::new(AtomicUsize::new(0));
+        let tx_size_cloned = Arc::clone(&tx_size);
+        let rx_size = Arc::new(AtomicUsize::new(0));
+        let rx_size_cloned = Arc::clone(&rx_size);
         let is_tx_closed = Arc::new(AtomicBool::new(false));
         let is_tx_closed_cloned = Arc::clone(&is_tx_closed);
         let is_rx_closed = Arc::new(AtomicBool::new(false));
@@ -111,7 +300,7 @@ impl StreamWorker {
         let (rx_close_tx, mut rx_close_rx) = mpsc::channel(1);
 
         // Send
-        tokio::spawn(async move {
+        spawn(async move {
             loop {
                 let is_close;
 
@@ -131,6 +320,17 @@ impl StreamWorker {
                                             "send to proxy: {}: {} -> {} ({} Bytes)",
                                             "TCP", 0, dst, payload.len()
                                         );
+                                        tx_size_cloned.fetch_add(payload.len(), Ordering::Relaxed);
+
+                                        // Reopen the window held by the payload. The write
+                                        // half is a boxed `AsyncWrite` with no readiness to
+                                        // poll, so the payload is counted as pending until the
+                                        // write completes instead, which holds the window for
+                                        // as long as the stream is not ready
+                                        let _ = tx_drain
+                                            .lock()
+                                            .unwrap()
+                                            .drain(dst, src, payload.len());
 
                                         is_close = false
                                     },
@@ -148,7 +348,9 @@ impl StreamWorker {
                 }
 
                 if is_close {
-                    // Close
+                    // Close, the write half is shut down explicitly since a half of a split
+                    // stream does not shut down on drop
+                    let _ = stream_tx.shutdown().await;
                     is_tx_closed_cloned.store(true, Ordering::Relaxed);
                     trace!("close stream TX {} -> {}", 0, dst);
                     break;
@@ -157,7 +359,7 @@ impl StreamWorker {
         });
 
         // Receive
-        tokio::spawn(async move {
+        spawn(async move {
             let mut buffer = vec![0u8; u16::MAX as usize];
             let mut recv_zero: usize = 0;
             loop {
@@ -204,6 +406,8 @@ impl StreamWorker {
                 }
 
                 if size > 0 {
+                    rx_size_cloned.fetch_add(size, Ordering::Relaxed);
+
                     // Loop until the data was transferred to the forwarder
                     let mut is_sent = false;
                     loop {
@@ -253,7 +457,7 @@ impl StreamWorker {
         });
 
         // Timeout
-        tokio::spawn(async move {
+        spawn(async move {
             loop {
                 time::sleep(Duration::from_millis(TICK_INTERVAL)).await;
                 // Send
@@ -269,17 +473,116 @@ impl StreamWorker {
         trace!("open stream {} -> {}", 0, dst);
 
         Ok(StreamWorker {
+            src,
             dst,
+            name,
+            is_direct,
             tx_tx,
+            tx_size,
+            rx_size,
             is_tx_closed,
             is_rx_closed,
             tx_close_tx,
             rx_close_tx,
+            id: 0,
+            teardown_tx: None,
+            tee_tx: None,
         })
     }
 
+    /// Sets the tee of the worker, which mirrors data sent to the destination to the target
+    /// through the upstream, like a shadow proxy validating a migration. The tee is best-effort:
+    /// it is connected in the background, its responses are ignored, and it is abandoned on any
+    /// failure or if it falls behind, which never affects the stream.
+    pub fn set_tee(
+        &mut self,
+        upstream: Arc<dyn UpstreamConnector>,
+        target: SocketAddrV4,
+        name: Option<String>,
+    ) {
+        let dst = self.dst;
+        let (tee_tx, mut tee_rx) = mpsc::channel::<Vec<u8>>(TEE_QUEUE_SIZE);
+
+        spawn(async move {
+            let stream = match upstream.connect(target, name).await {
+                Ok(stream) => stream,
+                Err(ref e) => {
+                    warn!("connect to tee: {}: {} -> {}: {}", "TCP", 0, dst, e);
+                    return;
+                }
+            };
+            let (mut stream_rx, mut stream_tx) = stream.into_split();
+
+            let mut buffer = vec![0u8; u16::MAX as usize];
+            loop {
+                tokio::select! {
+                    r = tee_rx.recv() => match r {
+                        Some(payload) => {
+                            if let Err(ref e) = stream_tx.write_all(payload.as_slice()).await {
+                                warn!("send to tee: {}: {} -> {}: {}", "TCP", 0, dst, e);
+                                break;
+                            }
+                        }
+                        None => {
+                            let _ = stream_tx.shutdown().await;
+                            break;
+                        }
+                    },
+                    // Responses are ignored
+                    r = stream_rx.read(&mut buffer) => match r {
+                        Ok(0) => {
+                            debug!("tee is closed: {}: {} -> {}", "TCP", 0, dst);
+                            break;
+                        }
+                        Ok(_) => {}
+                        Err(ref e) => {
+                            warn!("receive from tee: {}: {} -> {}: {}", "TCP", 0, dst, e);
+                            break;
+                        }
+                    }
+                }
+            }
+            trace!("close tee {} -> {}", 0, dst);
+        });
+
+        self.tee_tx = Some(tee_tx);
+        trace!("set tee of stream {} -> {} to {}", 0, dst, target);
+    }
+
+    /// Sets the channel which the identifier, the source and the destination of the stream will be
+    /// sent to if the worker is dropped before it is released, so the owner can reset the
+    /// connection to the source instead of leaving it half-open. The identifier tells the
+    /// connection apart from a later one of the same source and destination.
+    pub fn set_teardown(
+        &mut self,
+        teardown_tx: UnboundedSender<(usize, SocketAddrV4, SocketAddrV4)>,
+        id: usize,
+    ) {
+        self.id = id;
+        self.teardown_tx = Some(teardown_tx);
+    }
+
+    /// Releases the worker after the connection is torn down orderly, so nothing will be sent to
+    /// the teardown channel when it is dropped.
+    pub fn release(&mut self) {
+        self.teardown_tx = None;
+    }
+
     /// Sends data on the proxied stream in TCP to the destination.
     pub fn send(&mut self, payload: Vec<u8>) -> io::Result<()> {
+        // Mirror to the tee
+        if let Some(ref tee_tx) = self.tee_tx {
+            if let Err(e) = tee_tx.try_send(payload.clone()) {
+                if let TrySendError::Full(_) = e {
+                    warn!(
+                        "abandon tee of stream {} -> {}: tee falls behind",
+                        0, self.dst
+                    );
+                }
+                self.tee_tx = None;
+            }
+        }
+
         // Send
         if self.tx_tx.send(payload).is_err() {
             return Err(io::Error::from(io::ErrorKind::NotConnected));
@@ -292,6 +595,8 @@ impl StreamWorker {
     pub fn shutdown(&mut self, how: Shutdown) {
         match how {
             Shutdown::Write => {
+                // The tee is closed after the queued data
+                self.tee_tx = None;
                 if !self.is_tx_closed.load(Ordering::Relaxed) {
                     let _ = self.tx_close_tx.try_send(());
                 }
@@ -313,6 +618,26 @@ impl StreamWorker {
         self.shutdown(Shutdown::Both);
     }
 
+    /// Returns the name of the destination sent to the proxy.
+    pub fn name(&self) -> Option<&String> {
+        self.name.as_ref()
+    }
+
+    /// Returns if the stream is connected directly instead of through the proxy.
+    pub fn is_direct(&self) -> bool {
+        self.is_direct
+    }
+
+    /// Returns the size of data sent to the proxy.
+    pub fn tx_size(&self) -> usize {
+        self.tx_size.load(Ordering::Relaxed)
+    }
+
+    /// Returns the size of data received from the proxy.
+    pub fn rx_size(&self) -> usize {
+        self.rx_size.load(Ordering::Relaxed)
+    }
+
     /// Returns if the worker is closed for writing.
     pub fn is_tx_closed(&self) -> bool {
         self.is_tx_closed.load(Ordering::Relaxed)
@@ -327,6 +652,9 @@ impl StreamWorker {
 impl Drop for StreamWorker {
     fn drop(&mut self) {
         self.close();
+        if let Some(teardown_tx) = self.teardown_tx.take() {
+            let _ = teardown_tx.send((self.id, self.src, self.dst));
+        }
         trace!("drop stream {} -> {}", 0, self.dst);
     }
 }
@@ -353,10 +681,19 @@ impl StreamWorker2 {
 
         let stream = match proxy {
             ProxyConfig::Socks(remote, options) => {
-                socks::connect(*remote, dst, options).await?
+                socks::connect(*remote, AddrKind::Ip(SocketAddr::V4(dst)), options).await?
+            }
+        };
+        let stream = match stream {
+            SocksStream::Tcp(stream) => stream.into_inner(),
+            #[cfg(feature = "gssapi")]
+            SocksStream::Encapsulated(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "GSSAPI encapsulation is not supported",
+                ))
             }
         };
-        let stream = stream.into_inner();
         let (mut stream_rx, stream_tx) = stream.into_split();
 
         // Open
@@ -368,7 +705,7 @@ impl StreamWorker2 {
         let (rx_close_tx, mut rx_close_rx) = mpsc::channel(1);
 
         // Receive
-        tokio::spawn(async move {
+        spawn(async move {
             let mut buffer = vec![0u8; u16::MAX as usize];
             let mut recv_zero: usize = 0;
             loop {
@@ -433,7 +770,7 @@ impl StreamWorker2 {
         });
 
         // Timeout
-        tokio::spawn(async move {
+        spawn(async move {
             loop {
                 time::sleep(Duration::from_millis(TICK_INTERVAL)).await;
                 // Send
@@ -530,8 +867,18 @@ pub trait ForwardDatagram: Send {
 pub struct DatagramWorker {
     src: Arc<AtomicU64>,
     local_port: u16,
+    is_direct: bool,
     tx_tx: UnboundedSender<(Vec<u8>, SocketAddrV4)>,
+    /// Represents the map mapping a target to its rewritten destination.
+    targets: Arc<Mutex<HashMap<SocketAddrV4, SocketAddrV4>>>,
+    /// Represents the map mapping a target to the epoch of the binding which sent to it.
+    peers: Arc<Mutex<HashMap<SocketAddrV4, usize>>>,
+    epoch: Arc<AtomicUsize>,
+    is_strict: Arc<AtomicBool>,
+    tx_size: Arc<AtomicUsize>,
+    rx_size: Arc<AtomicUsize>,
     is_closed: Arc<AtomicBool>,
+    activity: Arc<Mutex<Instant>>,
     close_tx: Sender<()>,
     close_tx2: Sender<()>,
 }
@@ -543,9 +890,30 @@ impl DatagramWorker {
         src: SocketAddrV4,
         proxy: &ProxyConfig,
     ) -> io::Result<(DatagramWorker, u16)> {
-        let (mut socks_rx, mut socks_tx, local_port) = match proxy {
-            ProxyConfig::Socks(remote, options) => socks::bind(*remote, options).await?,
-        };
+        let datagram = upstream::bind(proxy).await?;
+
+        DatagramWorker::start(tx, src, datagram)
+    }
+
+    /// Creates a new `DatagramWorker` through the upstream.
+    pub async fn bind_with_upstream(
+        tx: Arc<Mutex<dyn ForwardDatagram>>,
+        src: SocketAddrV4,
+        upstream: &dyn UpstreamConnector,
+    ) -> io::Result<(DatagramWorker, u16)> {
+        let datagram = upstream.bind(src).await?;
+
+        DatagramWorker::start(tx, src, datagram)
+    }
+
+    fn start(
+        tx: Arc<Mutex<dyn ForwardDatagram>>,
+        src: SocketAddrV4,
+        datagram: UpstreamDatagram,
+    ) -> io::Result<(DatagramWorker, u16)> {
+        let local_port = datagram.local_port();
+        let is_direct = datagram.is_direct();
+        let (mut socks_rx, mut socks_tx) = datagram.into_split();
 
         #[allow(clippy::type_complexity)]
         let (tx_tx, mut tx_rx): (
@@ -554,13 +922,27 @@ impl DatagramWorker {
         ) = mpsc::unbounded_channel();
         let a_src = Arc::new(AtomicU64::from(socket_addr_v4_to_u64(&src)));
         let a_src_cloned = Arc::clone(&a_src);
+        let targets = Arc::new(Mutex::new(HashMap::new()));
+        let targets_cloned = Arc::clone(&targets);
+        let peers = Arc::new(Mutex::new(HashMap::new()));
+        let peers_cloned = Arc::clone(&peers);
+        let epoch = Arc::new(AtomicUsize::new(0));
+        let epoch_cloned = Arc::clone(&epoch);
+        let is_strict = Arc::new(AtomicBool::new(true));
+        let is_strict_cloned = Arc::clone(&is_strict);
+        let tx_size = Arc::new(AtomicUsize::new(0));
+        let tx_size_cloned = Arc::clone(&tx_size);
+        let rx_size = Arc::new(AtomicUsize::new(0));
+        let rx_size_cloned = Arc::clone(&rx_size);
         let is_closed = Arc::new(AtomicBool::new(false));
         let is_closed_cloned = Arc::clone(&is_closed);
+        let activity = Arc::new(Mutex::new(Instant::now()));
+        let activity_cloned = Arc::clone(&activity);
         let (close_tx, mut close_rx) = mpsc::channel(1);
         let (close_tx2, mut close_rx2) = mpsc::channel(1);
 
         // Send
-        tokio::spawn(async move {
+        spawn(async move {
             loop {
                 let is_close;
 
@@ -574,12 +956,17 @@ impl DatagramWorker {
                     tokio::select! {
                         r = tx_rx_fut => match r {
                             Some((payload, dst)) => {
+                                let path = match socks_tx.is_direct(dst) {
+                                    true => "directly",
+                                    false => "to proxy",
+                                };
                                 match socks_tx.send_to(payload.as_slice(), dst).await {
                                     Ok(size) => {
                                         debug!(
-                                            "send to proxy: {}: {} -> {} ({} Bytes)",
-                                            "UDP", local_port, dst, size
+                                            "send {}: {}: {} -> {} ({} Bytes)",
+                                            path, "UDP", local_port, dst, size
                                         );
+                                        tx_size_cloned.fetch_add(size, Ordering::Relaxed);
                                     },
                                     Err(ref e) => {
                                         warn!("handle send: {}: {} -> {}: {}", "UDP", local_port, dst, e);
@@ -601,7 +988,7 @@ impl DatagramWorker {
         });
 
         // Receive
-        tokio::spawn(async move {
+        spawn(async move {
             let mut buffer = vec![0u8; u16::MAX as usize];
             loop {
                 let size;
@@ -649,12 +1036,47 @@ impl DatagramWorker {
                 }
 
                 if size > 0 {
+                    // Drop datagrams from targets not sent to in the current binding, which are
+                    // either unsolicited or late replies to a prior binding of a reused port.
+                    // The source is read in the same lock as the epochs so they are of the same
+                    // binding
+                    let src = {
+                        let peers = peers_cloned.lock().unwrap();
+                        let src = u64_to_socket_addr_v4(a_src_cloned.load(Ordering::Relaxed));
+                        let is_current =
+                            peers.get(&addr) == Some(&epoch_cloned.load(Ordering::Relaxed));
+
+                        match is_current || !is_strict_cloned.load(Ordering::Relaxed) {
+                            true => Ok(src),
+                            false => Err(peers.contains_key(&addr)),
+                        }
+                    };
+                    let src = match src {
+                        Ok(src) => src,
+                        Err(is_late) => {
+                            trace!(
+                                "drop UDP {} -> {}: {}",
+                                addr,
+                                local_port,
+                                match is_late {
+                                    true => "reply to a prior binding",
+                                    false => "unsolicited",
+                                }
+                            );
+                            continue;
+                        }
+                    };
+
+                    *activity_cloned.lock().unwrap() = Instant::now();
+                    rx_size_cloned.fetch_add(size, Ordering::Relaxed);
+
+                    // Restore the rewritten destination
+                    if let Some(&dst) = targets_cloned.lock().unwrap().get(&addr) {
+                        addr = dst;
+                    }
+
                     // Send
-                    if let Err(ref e) = tx.lock().unwrap().forward(
-                        addr,
-                        u64_to_socket_addr_v4(a_src_cloned.load(Ordering::Relaxed)),
-                        &buffer[..size],
-                    ) {
+                    if let Err(ref e) = tx.lock().unwrap().forward(addr, src, &buffer[..size]) {
                         warn!(
                             "handle receive: {}: {} -> {}: {}",
                             "UDP", addr, local_port, e
@@ -679,8 +1101,16 @@ impl DatagramWorker {
             DatagramWorker {
                 src: a_src,
                 local_port,
+                is_direct,
                 tx_tx,
+                targets,
+                peers,
+                epoch,
+                is_strict,
+                tx_size,
+                rx_size,
                 is_closed,
+                activity,
                 close_tx,
                 close_tx2,
             },
@@ -690,19 +1120,83 @@ impl DatagramWorker {
 
     /// Sends data on the proxied datagram in UDP to the destination.
     pub fn send_to(&mut self, payload: Vec<u8>, dst: SocketAddrV4) -> io::Result<()> {
+        self.send_to_target(payload, dst, dst)
+    }
+
+    /// Sends data on the proxied datagram in UDP to the target instead of the destination.
+    /// Datagrams received from the target will be forwarded as from the destination of the latest
+    /// datagram sent to the target.
+    pub fn send_to_target(
+        &mut self,
+        payload: Vec<u8>,
+        dst: SocketAddrV4,
+        target: SocketAddrV4,
+    ) -> io::Result<()> {
+        {
+            let mut targets = self.targets.lock().unwrap();
+            if target != dst {
+                targets.insert(target, dst);
+            } else {
+                targets.remove(&target);
+            }
+        }
+        {
+            let mut peers = self.peers.lock().unwrap();
+            peers.insert(target, self.epoch.load(Ordering::Relaxed));
+        }
+
         // Send
-        if self.tx_tx.send((payload, dst)).is_err() {
+        if self.tx_tx.send((payload, target)).is_err() {
             return Err(io::Error::from(io::ErrorKind::NotConnected));
         }
+        *self.activity.lock().unwrap() = Instant::now();
 
         Ok(())
     }
 
-    /// Sets the source of the worker.
+    /// Sets the source of the worker, which reuses the worker in a new binding of the next epoch.
+    /// Targets sent to in prior bindings are forgotten, so late replies to them are not
+    /// misdelivered to the new source.
     pub fn set_src(&mut self, src: &SocketAddrV4) {
+        let mut peers = self.peers.lock().unwrap();
         self.src
             .store(socket_addr_v4_to_u64(src), Ordering::Relaxed);
-        trace!("set datagram {} = {}", src, self.local_port);
+        let epoch = self.epoch.fetch_add(1, Ordering::Relaxed) + 1;
+        peers.retain(|_, peer_epoch| *peer_epoch == epoch);
+        trace!(
+            "set datagram {} = {} in epoch {}",
+            src,
+            self.local_port,
+            epoch
+        );
+    }
+
+    /// Sets if datagrams are only delivered from targets sent to in the current binding, which
+    /// applies to fresh and reused workers alike. Defaults to `true`.
+    pub fn set_strict(&mut self, is_strict: bool) {
+        self.is_strict.store(is_strict, Ordering::Relaxed);
+        trace!("set datagram {} strict to {}", self.local_port, is_strict);
+    }
+
+    /// Returns the epoch of the current binding of the worker, which is increased each time the
+    /// worker is reused.
+    pub fn epoch(&self) -> usize {
+        self.epoch.load(Ordering::Relaxed)
+    }
+
+    /// Returns the size of data sent to the proxy.
+    pub fn tx_size(&self) -> usize {
+        self.tx_size.load(Ordering::Relaxed)
+    }
+
+    /// Returns the size of data received from the proxy.
+    pub fn rx_size(&self) -> usize {
+        self.rx_size.load(Ordering::Relaxed)
+    }
+
+    /// Returns the last time the worker sent or received data.
+    pub fn activity(&self) -> Instant {
+        *self.activity.lock().unwrap()
     }
 
     /// Returns the source of the worker.
@@ -710,6 +1204,11 @@ impl DatagramWorker {
         u64_to_socket_addr_v4(self.src.load(Ordering::Relaxed))
     }
 
+    /// Returns if the worker sends all datagrams directly because the proxy is unreachable.
+    pub fn is_direct(&self) -> bool {
+        self.is_direct
+    }
+
     /// Returns if the worker is closed.
     pub fn is_closed(&self) -> bool {
         self.is_closed.load(Ordering::Relaxed)
@@ -753,7 +1252,7 @@ impl DatagramWorker2 {
         let (close_tx, mut close_rx) = mpsc::channel(1);
 
         // Receive
-        tokio::spawn(async move {
+        spawn(async move {
             let mut buffer = vec![0u8; u16::MAX as usize];
             loop {
                 let size;
@@ -889,3 +1388,202 @@ fn u64_to_socket_addr_v4(v: u64) -> SocketAddrV4 {
 
     SocketAddrV4::new(ip, port)
 }
+
+/// Spawns a task in the current span, so the logs of the task can be correlated with the
+/// connection.
+fn spawn<F>(future: F)
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    tokio::spawn(future.in_current_span());
+}
+
+#[cfg(test)]
+struct NullForwarder;
+
+#[cfg(test)]
+impl ForwardStream for NullForwarder {
+    fn open(&mut self, _: SocketAddrV4, _: SocketAddrV4) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn forward(&mut self, _: SocketAddrV4, _: SocketAddrV4, _: &[u8]) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn tick(&mut self, _: SocketAddrV4, _: SocketAddrV4) -> io::Result<()> {
+        Err(io::Error::from(io::ErrorKind::NotFound))
+    }
+
+    fn close(&mut self, _: SocketAddrV4, _: SocketAddrV4) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn check(&self, _: SocketAddrV4, _: SocketAddrV4) -> io::Result<usize> {
+        Ok(usize::MAX)
+    }
+}
+
+#[tokio::test]
+async fn stream_worker_connect_connector() {
+    let src = SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 2), 10000);
+    let dst = SocketAddrV4::new(Ipv4Addr::new(1, 1, 1, 1), 80);
+    let (client, mut server) = io::duplex(64);
+
+    // The SOCKS server is never connected
+    let mut proxy = ProxyConfig::new_socks(
+        SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1),
+        false,
+        false,
+        None,
+    );
+    proxy.set_connector(Some(Arc::new(crate::testing::DuplexUpstream(Mutex::new(
+        Some(client),
+    )))));
+    let mut worker = StreamWorker::connect(Arc::new(Mutex::new(NullForwarder)), src, dst, &proxy)
+        .await
+        .unwrap();
+
+    worker.send(b"hello".to_vec()).unwrap();
+    let mut buf = [0u8; 5];
+    server.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"hello");
+
+    // The write half is shut down
+    worker.shutdown(Shutdown::Write);
+    assert_eq!(server.read(&mut buf).await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn stream_worker_connect_connector_timeout() {
+    let src = SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 2), 10000);
+    let dst = SocketAddrV4::new(Ipv4Addr::new(1, 1, 1, 1), 80);
+
+    // The connection through the connector is bounded by the connect timeout
+    let mut proxy = ProxyConfig::new_socks(
+        SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1),
+        false,
+        false,
+        None,
+    );
+    proxy.set_connect_timeout(Duration::from_millis(10));
+    proxy.set_connector(Some(Arc::new(crate::testing::PendingUpstream)));
+    let e = StreamWorker::connect(Arc::new(Mutex::new(NullForwarder)), src, dst, &proxy)
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+}
+
+#[tokio::test]
+async fn stream_worker_tee() {
+    let src = SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 2), 10000);
+    let dst = SocketAddrV4::new(Ipv4Addr::new(1, 1, 1, 1), 80);
+    let connect = |stream| async move {
+        let upstream = crate::testing::DuplexUpstream(Mutex::new(Some(stream)));
+        StreamWorker::connect_with_upstream(
+            Arc::new(Mutex::new(NullForwarder)),
+            src,
+            dst,
+            dst,
+            None,
+            &upstream,
+        )
+        .await
+        .unwrap()
+    };
+
+    // Data is mirrored to the tee, whose responses are ignored
+    let (client, mut server) = io::duplex(64);
+    let (tee_client, mut tee_server) = io::duplex(64);
+    let mut worker = connect(client).await;
+    worker.set_tee(
+        Arc::new(crate::testing::DuplexUpstream(Mutex::new(Some(tee_client)))),
+        dst,
+        None,
+    );
+    tee_server.write_all(b"ignored").await.unwrap();
+    worker.send(b"hello".to_vec()).unwrap();
+    let mut buf = [0u8; 5];
+    server.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"hello");
+    tee_server.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"hello");
+
+    // The tee is closed with the write half
+    worker.shutdown(Shutdown::Write);
+    assert_eq!(server.read(&mut buf).await.unwrap(), 0);
+    assert_eq!(tee_server.read(&mut buf).await.unwrap(), 0);
+
+    // A tee which cannot be connected never affects the stream
+    let (client, mut server) = io::duplex(64);
+    let mut worker = connect(client).await;
+    worker.set_tee(
+        Arc::new(crate::testing::DuplexUpstream(Mutex::new(None))),
+        dst,
+        None,
+    );
+    worker.send(b"hello".to_vec()).unwrap();
+    time::sleep(Duration::from_millis(10)).await;
+    worker.send(b"world".to_vec()).unwrap();
+    let mut buf = [0u8; 10];
+    server.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"helloworld");
+}
+
+#[tokio::test]
+async fn datagram_worker_reuse() {
+    use crate::testing::{DatagramRecorder, InjectedDatagram};
+
+    let src = SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 2), 10000);
+    let reused_src = SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 2), 10001);
+    let dst = SocketAddrV4::new(Ipv4Addr::new(1, 1, 1, 1), 53);
+    let reused_dst = SocketAddrV4::new(Ipv4Addr::new(8, 8, 8, 8), 53);
+
+    let (inject_tx, inject_rx) = mpsc::unbounded_channel();
+    let (_, never_rx) = mpsc::unbounded_channel();
+    let datagram = UpstreamDatagram::new(
+        Box::new(InjectedDatagram(inject_rx)),
+        Box::new(InjectedDatagram(never_rx)),
+        1,
+        false,
+    );
+    let forwarded = Arc::new(Mutex::new(Vec::new()));
+    let tx = Arc::new(Mutex::new(DatagramRecorder(Arc::clone(&forwarded))));
+    let (mut worker, _) = DatagramWorker::start(tx, src, datagram).unwrap();
+    let wait = |n| {
+        let forwarded = Arc::clone(&forwarded);
+        async move {
+            while forwarded.lock().unwrap().len() < n {
+                time::sleep(Duration::from_millis(1)).await;
+            }
+        }
+    };
+
+    // An unsolicited datagram is dropped even though the port is fresh
+    worker.send_to(vec![0], dst).unwrap();
+    assert_eq!(worker.epoch(), 0);
+    inject_tx.send(reused_dst).unwrap();
+    inject_tx.send(dst).unwrap();
+    wait(1).await;
+
+    // A late reply to the prior binding is dropped once the port is reused, like an unsolicited
+    // datagram
+    worker.set_src(&reused_src);
+    assert_eq!(worker.epoch(), 1);
+    worker.send_to(vec![0], reused_dst).unwrap();
+    inject_tx.send(dst).unwrap();
+    inject_tx.send(reused_dst).unwrap();
+    wait(2).await;
+    assert_eq!(
+        *forwarded.lock().unwrap(),
+        vec![(dst, src), (reused_dst, reused_src)]
+    );
+
+    // The late reply is delivered if the check is disabled
+    worker.set_strict(false);
+    inject_tx.send(dst).unwrap();
+    wait(3).await;
+    assert_eq!(forwarded.lock().unwrap()[2], (dst, reused_src));
+}
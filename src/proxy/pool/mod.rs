@@ -0,0 +1,218 @@
+//! Support for pooling connections to proxies.
+
+use super::direct::{self, SocketOption};
+use log::{trace, warn};
+use std::collections::VecDeque;
+use std::net::SocketAddrV4;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io;
+use tokio::net::TcpStream;
+
+/// Represents the maximum idle time of a connection in the pool. Proxies may close connections
+/// which do not start handshaking in time, so connections idle beyond the time will be dropped.
+const POOL_IDLE_TIMEOUT: u64 = 10000;
+
+/// Represents a pool of warm TCP connections to a proxy. Connections in the pool are established
+/// in advance but not handshaked, and each connection will be used only once.
+#[derive(Debug)]
+pub struct ConnectionPool {
+    remote: SocketAddrV4,
+    socket: SocketOption,
+    size: usize,
+    idle_timeout: Mutex<Duration>,
+    streams: Mutex<VecDeque<(TcpStream, Instant)>>,
+    pending: AtomicUsize,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+}
+
+impl ConnectionPool {
+    /// Creates a new `ConnectionPool` holding at most `size` connections to the remote.
+    pub fn new(remote: SocketAddrV4, size: usize) -> ConnectionPool {
+        ConnectionPool {
+            remote,
+            socket: SocketOption::new(),
+            size,
+            idle_timeout: Mutex::new(Duration::from_millis(POOL_IDLE_TIMEOUT)),
+            streams: Mutex::new(VecDeque::new()),
+            pending: AtomicUsize::new(0),
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+        }
+    }
+
+    /// Sets the maximum idle time of a connection in the pool. It may be set on a pool shared with
+    /// upstreams, and takes effect on the connections in the pool.
+    pub fn set_idle_timeout(&self, idle_timeout: Duration) {
+        *self.idle_timeout.lock().unwrap() = idle_timeout;
+        trace!("set pool idle timeout to {:?}", idle_timeout);
+    }
+
+    /// Sets the options of sockets connecting to the remote.
+    pub fn set_socket(&mut self, socket: SocketOption) {
+        self.socket = socket;
+    }
+
+    /// Takes a connection from the pool, or connects to the remote if no connection is
+    /// available. The pool will be refilled in the background.
+    pub async fn get(self: &Arc<Self>) -> io::Result<TcpStream> {
+        let stream = self.take();
+        self.fill();
+
+        match stream {
+            Some(stream) => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                trace!("take pooled connection to {}", self.remote);
+
+                Ok(stream)
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+
+                direct::connect_tcp(&self.socket, self.remote).await
+            }
+        }
+    }
+
+    fn take(&self) -> Option<TcpStream> {
+        let idle_timeout = *self.idle_timeout.lock().unwrap();
+        let mut streams = self.streams.lock().unwrap();
+        while let Some((stream, instant)) = streams.pop_front() {
+            if instant.elapsed() < idle_timeout && is_alive(&stream) {
+                return Some(stream);
+            }
+        }
+
+        None
+    }
+
+    /// Fills the pool in the background.
+    pub fn fill(self: &Arc<Self>) {
+        loop {
+            let size = self.streams.lock().unwrap().len();
+            let pending = self.pending.load(Ordering::Relaxed);
+            if size + pending >= self.size {
+                return;
+            }
+            if self
+                .pending
+                .compare_exchange(pending, pending + 1, Ordering::Relaxed, Ordering::Relaxed)
+                .is_err()
+            {
+                continue;
+            }
+
+            let pool = Arc::clone(self);
+            tokio::spawn(async move {
+                match direct::connect_tcp(&pool.socket, pool.remote).await {
+                    Ok(stream) => {
+                        pool.streams
+                            .lock()
+                            .unwrap()
+                            .push_back((stream, Instant::now()));
+                    }
+                    Err(ref e) => warn!("fill pool to {}: {}", pool.remote, e),
+                }
+                pool.pending.fetch_sub(1, Ordering::Relaxed);
+            });
+        }
+    }
+
+    /// Returns the number of idle connections in the pool.
+    pub fn len(&self) -> usize {
+        self.streams.lock().unwrap().len()
+    }
+
+    /// Returns if the pool has no idle connection.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the number of connections taken from the pool.
+    pub fn hits(&self) -> usize {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of connections established on demand because the pool is empty.
+    pub fn misses(&self) -> usize {
+        self.misses.load(Ordering::Relaxed)
+    }
+}
+
+/// Returns if the connection is still open. A connection which is closed by the remote, or
+/// receives unexpected data before handshaking, is not reusable.
+fn is_alive(stream: &TcpStream) -> bool {
+    let mut buffer = [0u8; 1];
+    match stream.try_read(&mut buffer) {
+        Err(ref e) => e.kind() == io::ErrorKind::WouldBlock,
+        Ok(_) => false,
+    }
+}
+
+#[tokio::test]
+async fn connection_pool_fill() {
+    use tokio::net::TcpListener;
+    use tokio::time;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let remote = match listener.local_addr().unwrap() {
+        std::net::SocketAddr::V4(addr) => addr,
+        std::net::SocketAddr::V6(_) => unreachable!(),
+    };
+    let accepted = Arc::new(AtomicUsize::new(0));
+    let accepted_cloned = Arc::clone(&accepted);
+    let server = tokio::spawn(async move {
+        let mut streams = Vec::new();
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            accepted_cloned.fetch_add(1, Ordering::Relaxed);
+            streams.push(stream);
+        }
+    });
+
+    let pool = Arc::new(ConnectionPool::new(remote, 2));
+    let _stream = pool.get().await.unwrap();
+    assert_eq!(pool.misses(), 1);
+
+    time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(pool.len(), 2);
+
+    let _stream = pool.get().await.unwrap();
+    assert_eq!(pool.hits(), 1);
+
+    time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(pool.len(), 2);
+    assert_eq!(accepted.load(Ordering::Relaxed), 4);
+    server.abort();
+}
+
+#[tokio::test]
+async fn connection_pool_idle_timeout() {
+    use tokio::net::TcpListener;
+    use tokio::time;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let remote = match listener.local_addr().unwrap() {
+        std::net::SocketAddr::V4(addr) => addr,
+        std::net::SocketAddr::V6(_) => unreachable!(),
+    };
+    let server = tokio::spawn(async move {
+        let mut streams = Vec::new();
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            streams.push(stream);
+        }
+    });
+
+    // The timeout is set on the shared pool, and idle connections beyond it are not taken
+    let pool = Arc::new(ConnectionPool::new(remote, 1));
+    pool.fill();
+    time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(pool.len(), 1);
+    pool.set_idle_timeout(Duration::ZERO);
+    let _stream = pool.get().await.unwrap();
+    assert_eq!((pool.hits(), pool.misses()), (0, 1));
+    server.abort();
+}
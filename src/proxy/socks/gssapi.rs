@@ -0,0 +1,533 @@
+//! Support for the GSSAPI authentication of SOCKS5 servers.
+
+use super::{GssapiProtection, ProxyStream};
+use libgssapi::context::{ClientCtx, CtxFlags, SecurityContext as _};
+use libgssapi::credential::{Cred, CredUsage};
+use libgssapi::name::Name;
+use libgssapi::oid::{OidSet, GSS_MECH_KRB5, GSS_NT_HOSTBASED_SERVICE};
+use log::trace;
+use std::cmp::min;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+impl GssapiProtection {
+    fn from_u8(level: u8) -> Option<GssapiProtection> {
+        match level {
+            0 => Some(GssapiProtection::Clear),
+            1 => Some(GssapiProtection::Integrity),
+            2 => Some(GssapiProtection::Confidentiality),
+            _ => None,
+        }
+    }
+
+    fn to_u8(self) -> u8 {
+        match self {
+            GssapiProtection::Clear => 0,
+            GssapiProtection::Integrity => 1,
+            GssapiProtection::Confidentiality => 2,
+        }
+    }
+}
+
+/// Trait for a GSSAPI security context initiated by the client.
+pub trait SecurityContext: Send {
+    /// Processes the token from the server and returns the token to send to the server, if any.
+    fn step(&mut self, token: Option<&[u8]>) -> io::Result<Option<Vec<u8>>>;
+
+    /// Returns if the security context is established.
+    fn is_complete(&self) -> bool;
+
+    /// Wraps a message. The message will also be encrypted if `encrypt` is `true`.
+    fn wrap(&mut self, encrypt: bool, message: &[u8]) -> io::Result<Vec<u8>>;
+
+    /// Unwraps a message.
+    fn unwrap(&mut self, token: &[u8]) -> io::Result<Vec<u8>>;
+}
+
+/// Represents a GSSAPI security context of the Kerberos V5 mechanism.
+pub struct KerberosContext {
+    ctx: ClientCtx,
+}
+
+impl KerberosContext {
+    /// Creates a new `KerberosContext` to the service, like `rcmd@proxy.example.com`, with the
+    /// default credentials of the user.
+    pub fn new(service: &str) -> io::Result<KerberosContext> {
+        let name = Name::new(service.as_bytes(), Some(&GSS_NT_HOSTBASED_SERVICE))
+            .and_then(|name| name.canonicalize(Some(&GSS_MECH_KRB5)))
+            .map_err(gss_error)?;
+        let mut mechs = OidSet::new().map_err(gss_error)?;
+        mechs.add(&GSS_MECH_KRB5).map_err(gss_error)?;
+        let cred =
+            Cred::acquire(None, None, CredUsage::Initiate, Some(&mechs)).map_err(gss_error)?;
+        let flags =
+            CtxFlags::GSS_C_MUTUAL_FLAG | CtxFlags::GSS_C_INTEG_FLAG | CtxFlags::GSS_C_CONF_FLAG;
+
+        Ok(KerberosContext {
+            ctx: ClientCtx::new(cred, name, flags, Some(&GSS_MECH_KRB5)),
+        })
+    }
+}
+
+impl SecurityContext for KerberosContext {
+    fn step(&mut self, token: Option<&[u8]>) -> io::Result<Option<Vec<u8>>> {
+        let token = self.ctx.step(token).map_err(gss_error)?;
+
+        Ok(token.map(|token| token.to_vec()))
+    }
+
+    fn is_complete(&self) -> bool {
+        self.ctx.is_complete()
+    }
+
+    fn wrap(&mut self, encrypt: bool, message: &[u8]) -> io::Result<Vec<u8>> {
+        let token = self.ctx.wrap(encrypt, message).map_err(gss_error)?;
+
+        Ok(token.to_vec())
+    }
+
+    fn unwrap(&mut self, token: &[u8]) -> io::Result<Vec<u8>> {
+        let message = self.ctx.unwrap(token).map_err(gss_error)?;
+
+        Ok(message.to_vec())
+    }
+}
+
+fn gss_error(e: libgssapi::error::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, format!("GSSAPI: {}", e))
+}
+
+const GSSAPI_VERSION: u8 = 1;
+const GSSAPI_MTYP_AUTH: u8 = 1;
+const GSSAPI_MTYP_PROTECTION: u8 = 2;
+const GSSAPI_MTYP_DATA: u8 = 3;
+const GSSAPI_MTYP_ABORT: u8 = 0xFF;
+
+/// Represents the max size of data encapsulated in a message.
+const MAX_ENCAPSULATED_SIZE: usize = 16384;
+
+async fn write_message<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    mtyp: u8,
+    token: &[u8],
+) -> io::Result<()> {
+    if token.len() > u16::MAX as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "GSSAPI token too long",
+        ));
+    }
+
+    let mut buf = vec![GSSAPI_VERSION, mtyp];
+    buf.extend_from_slice(&(token.len() as u16).to_be_bytes());
+    buf.extend_from_slice(token);
+    stream.write_all(buf.as_slice()).await?;
+    stream.flush().await
+}
+
+fn check_header(header: &[u8], mtyp: u8) -> io::Result<()> {
+    if header[0] != GSSAPI_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid GSSAPI message version {}", header[0]),
+        ));
+    }
+    if header[1] == GSSAPI_MTYP_ABORT {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "GSSAPI authentication aborted by the SOCKS server",
+        ));
+    }
+    if header[1] != mtyp {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unexpected GSSAPI message type {}", header[1]),
+        ));
+    }
+
+    Ok(())
+}
+
+async fn read_message<S: AsyncRead + Unpin>(stream: &mut S, mtyp: u8) -> io::Result<Vec<u8>> {
+    let mut header = [0u8; 2];
+    stream.read_exact(&mut header).await?;
+    check_header(&header, mtyp)?;
+
+    let mut len = [0u8; 2];
+    stream.read_exact(&mut len).await?;
+    let mut token = vec![0u8; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut token).await?;
+
+    Ok(token)
+}
+
+/// Establishes the security context and negotiates the protection level in the stream. Returns
+/// the protection level selected by the SOCKS server.
+pub async fn authenticate<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    ctx: &mut dyn SecurityContext,
+    protection: GssapiProtection,
+) -> io::Result<GssapiProtection> {
+    // Security context
+    let mut server_token = None;
+    loop {
+        let token = match ctx.step(server_token.as_deref()) {
+            Ok(token) => token,
+            Err(e) => {
+                // Notify the server, the error of the notification is ignored
+                let _ = stream.write_all(&[GSSAPI_VERSION, GSSAPI_MTYP_ABORT]).await;
+                let _ = stream.flush().await;
+
+                return Err(e);
+            }
+        };
+        if let Some(token) = token {
+            write_message(stream, GSSAPI_MTYP_AUTH, token.as_slice()).await?;
+        }
+        if ctx.is_complete() {
+            break;
+        }
+        server_token = Some(read_message(stream, GSSAPI_MTYP_AUTH).await?);
+    }
+    trace!("establish GSSAPI security context");
+
+    // Protection level
+    let token = ctx.wrap(false, &[protection.to_u8()])?;
+    write_message(stream, GSSAPI_MTYP_PROTECTION, token.as_slice()).await?;
+    let token = read_message(stream, GSSAPI_MTYP_PROTECTION).await?;
+    let level = ctx.unwrap(token.as_slice())?;
+    let protection = match level.as_slice() {
+        [level] => GssapiProtection::from_u8(*level).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported GSSAPI protection level {}", level),
+            )
+        })?,
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid GSSAPI protection level",
+            ))
+        }
+    };
+    trace!("select GSSAPI protection level {:?}", protection);
+
+    Ok(protection)
+}
+
+/// Represents the size of the header of a GSSAPI message.
+const HEADER_SIZE: usize = 4;
+
+/// Returns the value of a ready poll, or returns `Poll::Pending` from the function.
+macro_rules! ready {
+    ($e:expr) => {
+        match $e {
+            Poll::Ready(t) => t,
+            Poll::Pending => return Poll::Pending,
+        }
+    };
+}
+
+/// Represents a stream encapsulated in GSSAPI messages. Data written is wrapped in a message
+/// before written to the inner stream, and messages read from the inner stream are unwrapped
+/// before read.
+struct GssapiStream<S> {
+    stream: S,
+    ctx: Box<dyn SecurityContext>,
+    is_encrypted: bool,
+    /// Represents the message being read, and the size of it read so far.
+    read_message: Vec<u8>,
+    read_size: usize,
+    /// Represents the unwrapped data, and the size of it read so far.
+    read_buffer: Vec<u8>,
+    read_pos: usize,
+    /// Represents the wrapped message, and the size of it written so far.
+    write_message: Vec<u8>,
+    write_pos: usize,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> GssapiStream<S> {
+    fn new(stream: S, ctx: Box<dyn SecurityContext>, is_encrypted: bool) -> GssapiStream<S> {
+        GssapiStream {
+            stream,
+            ctx,
+            is_encrypted,
+            read_message: vec![0u8; HEADER_SIZE],
+            read_size: 0,
+            read_buffer: Vec::new(),
+            read_pos: 0,
+            write_message: Vec::new(),
+            write_pos: 0,
+        }
+    }
+
+    /// Reads a message from the inner stream and unwraps it into the read buffer. Returns `false`
+    /// if the inner stream is closed between messages.
+    fn poll_read_message(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<bool>> {
+        loop {
+            while self.read_size < self.read_message.len() {
+                let mut buf = ReadBuf::new(&mut self.read_message[self.read_size..]);
+                ready!(Pin::new(&mut self.stream).poll_read(cx, &mut buf))?;
+                let size = buf.filled().len();
+                if size == 0 {
+                    return match self.read_size {
+                        0 => Poll::Ready(Ok(false)),
+                        _ => Poll::Ready(Err(io::Error::from(io::ErrorKind::UnexpectedEof))),
+                    };
+                }
+                self.read_size += size;
+            }
+
+            // Header
+            if self.read_message.len() == HEADER_SIZE && self.read_size == HEADER_SIZE {
+                check_header(&self.read_message, GSSAPI_MTYP_DATA)?;
+                let len = u16::from_be_bytes([self.read_message[2], self.read_message[3]]);
+                if len > 0 {
+                    self.read_message.resize(HEADER_SIZE + len as usize, 0);
+                    continue;
+                }
+            }
+
+            // Token
+            self.read_buffer = self.ctx.unwrap(&self.read_message[HEADER_SIZE..])?;
+            self.read_pos = 0;
+            self.read_message.truncate(HEADER_SIZE);
+            self.read_size = 0;
+
+            return Poll::Ready(Ok(true));
+        }
+    }
+
+    /// Writes the wrapped message to the inner stream.
+    fn poll_write_message(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.write_pos < self.write_message.len() {
+            let message = &self.write_message[self.write_pos..];
+            let size = ready!(Pin::new(&mut self.stream).poll_write(cx, message))?;
+            if size == 0 {
+                return Poll::Ready(Err(io::Error::from(io::ErrorKind::WriteZero)));
+            }
+            self.write_pos += size;
+        }
+        self.write_message.clear();
+        self.write_pos = 0;
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for GssapiStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        // Messages with no data are skipped
+        while this.read_pos == this.read_buffer.len() {
+            if !ready!(this.poll_read_message(cx))? {
+                return Poll::Ready(Ok(()));
+            }
+        }
+
+        let size = min(buf.remaining(), this.read_buffer.len() - this.read_pos);
+        buf.put_slice(&this.read_buffer[this.read_pos..this.read_pos + size]);
+        this.read_pos += size;
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for GssapiStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_message(cx))?;
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let size = min(buf.len(), MAX_ENCAPSULATED_SIZE);
+        let token = this.ctx.wrap(this.is_encrypted, &buf[..size])?;
+        if token.len() > u16::MAX as usize {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "GSSAPI token too long",
+            )));
+        }
+        this.write_message = vec![GSSAPI_VERSION, GSSAPI_MTYP_DATA];
+        this.write_message
+            .extend_from_slice(&(token.len() as u16).to_be_bytes());
+        this.write_message.extend_from_slice(token.as_slice());
+
+        // The message is written out eagerly, and the rest is written in the next write or flush
+        if let Poll::Ready(Err(e)) = this.poll_write_message(cx) {
+            return Poll::Ready(Err(e));
+        }
+
+        Poll::Ready(Ok(size))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_message(cx))?;
+
+        Pin::new(&mut this.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_message(cx))?;
+
+        Pin::new(&mut this.stream).poll_shutdown(cx)
+    }
+}
+
+/// Encapsulates the stream with the protection level. Data written to the returned stream is
+/// wrapped before sent to the SOCKS server, and data from the SOCKS server is unwrapped before
+/// read from the returned stream.
+pub fn encapsulate<S: AsyncRead + AsyncWrite + Send + Unpin + 'static>(
+    stream: S,
+    ctx: Box<dyn SecurityContext>,
+    protection: GssapiProtection,
+) -> Box<dyn ProxyStream> {
+    let is_encrypted = protection == GssapiProtection::Confidentiality;
+    trace!(
+        "encapsulate GSSAPI messages in protection level {:?}",
+        protection
+    );
+
+    Box::new(GssapiStream::new(stream, ctx, is_encrypted))
+}
+
+/// Represents a security context which completes in 2 steps and wraps messages by inverting bits.
+#[cfg(test)]
+pub struct MockContext {
+    steps: usize,
+}
+
+#[cfg(test)]
+impl MockContext {
+    pub fn new() -> MockContext {
+        MockContext { steps: 0 }
+    }
+}
+
+#[cfg(test)]
+impl SecurityContext for MockContext {
+    fn step(&mut self, token: Option<&[u8]>) -> io::Result<Option<Vec<u8>>> {
+        self.steps += 1;
+        match self.steps {
+            1 => Ok(Some(b"hello".to_vec())),
+            _ => {
+                assert_eq!(token, Some(&b"world"[..]));
+                Ok(None)
+            }
+        }
+    }
+
+    fn is_complete(&self) -> bool {
+        self.steps >= 2
+    }
+
+    fn wrap(&mut self, _: bool, message: &[u8]) -> io::Result<Vec<u8>> {
+        Ok(message.iter().map(|b| !b).collect())
+    }
+
+    fn unwrap(&mut self, token: &[u8]) -> io::Result<Vec<u8>> {
+        Ok(token.iter().map(|b| !b).collect())
+    }
+}
+
+#[tokio::test]
+async fn gssapi_authenticate() {
+    let (mut client, mut server) = io::duplex(64);
+
+    let server = tokio::spawn(async move {
+        let mut buf = [0u8; 9];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, [1, 1, 0, 5, b'h', b'e', b'l', b'l', b'o']);
+        server
+            .write_all(&[1, 1, 0, 5, b'w', b'o', b'r', b'l', b'd'])
+            .await
+            .unwrap();
+
+        // The server downgrades the protection level
+        let mut buf = [0u8; 5];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, [1, 2, 0, 1, !2]);
+        server.write_all(&[1, 2, 0, 1, !1]).await.unwrap();
+    });
+
+    let mut ctx = MockContext::new();
+    let protection = authenticate(&mut client, &mut ctx, GssapiProtection::Confidentiality)
+        .await
+        .unwrap();
+    assert_eq!(protection, GssapiProtection::Integrity);
+    server.await.unwrap();
+}
+
+#[tokio::test]
+async fn gssapi_authenticate_abort() {
+    let (mut client, mut server) = io::duplex(64);
+
+    let server = tokio::spawn(async move {
+        let mut buf = [0u8; 9];
+        server.read_exact(&mut buf).await.unwrap();
+        server.write_all(&[1, 0xFF]).await.unwrap();
+    });
+
+    let mut ctx = MockContext::new();
+    let e = authenticate(&mut client, &mut ctx, GssapiProtection::Integrity)
+        .await
+        .unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+    server.await.unwrap();
+}
+
+#[tokio::test]
+async fn gssapi_encapsulate() {
+    let (stream, mut server) = io::duplex(64);
+
+    let mut stream = encapsulate(
+        stream,
+        Box::new(MockContext::new()),
+        GssapiProtection::Integrity,
+    );
+
+    // Data in both directions is encapsulated
+    stream.write_all(b"ping").await.unwrap();
+    stream.flush().await.unwrap();
+    let mut buf = [0u8; 8];
+    server.read_exact(&mut buf).await.unwrap();
+    assert_eq!(buf, [1, 3, 0, 4, !b'p', !b'i', !b'n', !b'g']);
+
+    server
+        .write_all(&[1, 3, 0, 4, !b'p', !b'o', !b'n', !b'g'])
+        .await
+        .unwrap();
+    let mut buf = [0u8; 4];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"pong");
+
+    // Empty messages are skipped, and a message is read in pieces
+    server.write_all(&[1, 3, 0, 0]).await.unwrap();
+    server
+        .write_all(&[1, 3, 0, 5, !b'h', !b'e', !b'l', !b'l', !b'o'])
+        .await
+        .unwrap();
+    let mut buf = [0u8; 2];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"he");
+    let mut buf = [0u8; 3];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"llo");
+
+    // The stream is closed between messages
+    drop(server);
+    let mut buf = [0u8; 1];
+    assert_eq!(stream.read(&mut buf).await.unwrap(), 0);
+}
@@ -1,11 +1,24 @@
 //! Support for handling SOCKS proxies.
 
 use async_socks5::{self, AddrKind, Auth};
-use log::trace;
+use super::direct::{self, SocketOption};
+use super::pool::ConnectionPool;
+use super::upstream::UpstreamConnector;
+use crate::cidr::Ipv4NetworkSet;
+use log::{trace, warn};
+use std::fmt;
+use std::future::Future;
 use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
 use std::sync::Arc;
-use tokio::io::{self, BufStream};
+use std::time::Duration;
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufStream};
 use tokio::net::{TcpStream, UdpSocket};
+use tokio::time::{self, Instant};
+
+#[cfg(feature = "gssapi")]
+mod gssapi;
+#[cfg(feature = "gssapi")]
+use gssapi::SecurityContext;
 
 /// Represents the username and the password of the authentication connecting to a SOCKS5 server.
 #[derive(Clone, Debug)]
@@ -21,12 +34,91 @@ impl SocksAuth {
     }
 }
 
-/// Represents the options connecting to a SOCKS5 server.
+/// Represents the protection level of data after the GSSAPI authentication.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum GssapiProtection {
+    /// Represents no per-message protection. This is an extension of Dante which is not defined
+    /// in RFC 1961, data is sent as is after the authentication.
+    Clear,
+    /// Represents the per-message integrity.
+    Integrity,
+    /// Represents the per-message integrity and confidentiality.
+    Confidentiality,
+}
+
+/// Represents the service and the protection level of the GSSAPI authentication connecting to a
+/// SOCKS5 server.
+#[derive(Clone, Debug)]
+pub struct GssapiAuth {
+    service: String,
+    protection: GssapiProtection,
+}
+
+impl GssapiAuth {
+    /// Creates a `GssapiAuth` to the service, like `rcmd@proxy.example.com`. The per-message
+    /// integrity will be requested.
+    pub fn new(service: String) -> GssapiAuth {
+        GssapiAuth::new_with_protection(service, GssapiProtection::Integrity)
+    }
+
+    /// Creates a `GssapiAuth` to the service with the given protection level.
+    pub fn new_with_protection(service: String, protection: GssapiProtection) -> GssapiAuth {
+        GssapiAuth {
+            service,
+            protection,
+        }
+    }
+
+    /// Returns the service of the GSSAPI authentication.
+    pub fn service(&self) -> &str {
+        &self.service
+    }
+
+    /// Returns the requested protection level of the GSSAPI authentication.
+    pub fn protection(&self) -> GssapiProtection {
+        self.protection
+    }
+}
+
+/// Represents a stream connected through a SOCKS proxy.
+pub enum SocksStream {
+    /// Represents a stream to the SOCKS server.
+    Tcp(BufStream<TcpStream>),
+    /// Represents a stream to the SOCKS server encapsulated in the GSSAPI protection.
+    #[cfg(feature = "gssapi")]
+    Encapsulated(Box<dyn ProxyStream>),
+}
+
+/// Trait for a stream which can be split into the halves of an `UpstreamStream`.
+pub trait ProxyStream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> ProxyStream for T {}
+
+/// Represents the version of the SOCKS protocol.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum SocksVersion {
+    /// Represents the SOCKS4 protocol, the SOCKS4a extension will be used for domain names.
+    Socks4,
+    /// Represents the SOCKS5 protocol.
+    Socks5,
+}
+
+/// Represents the timeout of connecting to a SOCKS server and handshaking.
+const CONNECT_TIMEOUT: u64 = 10000;
+
+/// Represents the options connecting to a SOCKS server.
 #[derive(Clone, Debug)]
 pub struct SocksOption {
+    version: SocksVersion,
     force_associate_remote: bool,
     force_associate_bind_addr: bool,
     auth: Option<SocksAuth>,
+    gssapi: Option<GssapiAuth>,
+    connector: Option<Arc<dyn UpstreamConnector>>,
+    connect_timeout: Duration,
+    bypass: Ipv4NetworkSet,
+    is_direct_fallback: bool,
+    socket: SocketOption,
 }
 
 impl SocksOption {
@@ -35,14 +127,112 @@ impl SocksOption {
         force_associate_remote: bool,
         force_associate_bind_addr: bool,
         auth: Option<SocksAuth>,
+    ) -> SocksOption {
+        SocksOption::new_with_version(
+            SocksVersion::Socks5,
+            force_associate_remote,
+            force_associate_bind_addr,
+            auth,
+        )
+    }
+
+    /// Creates a `SocksOption` with the given version of the SOCKS protocol.
+    pub fn new_with_version(
+        version: SocksVersion,
+        force_associate_remote: bool,
+        force_associate_bind_addr: bool,
+        auth: Option<SocksAuth>,
     ) -> SocksOption {
         SocksOption {
+            version,
             force_associate_remote,
             force_associate_bind_addr,
             auth,
+            gssapi: None,
+            connector: None,
+            connect_timeout: Duration::from_millis(CONNECT_TIMEOUT),
+            bypass: Ipv4NetworkSet::new(),
+            is_direct_fallback: false,
+            socket: SocketOption::new(),
         }
     }
 
+    /// Sets the timeout of connecting to the SOCKS server and handshaking.
+    pub fn set_connect_timeout(&mut self, timeout: Duration) {
+        self.connect_timeout = timeout;
+    }
+
+    /// Sets the GSSAPI authentication. If it is set, the GSSAPI authentication will be offered to
+    /// the SOCKS server in addition to the other authentication methods.
+    pub fn set_gssapi(&mut self, gssapi: Option<GssapiAuth>) {
+        self.gssapi = gssapi;
+    }
+
+    /// Sets the connector of TCP connections. If it is set, TCP connections will be connected
+    /// through the connector instead of the SOCKS server.
+    pub fn set_connector(&mut self, connector: Option<Arc<dyn UpstreamConnector>>) {
+        self.connector = connector;
+    }
+
+    /// Sets the networks of destinations which will be connected directly instead of through the
+    /// SOCKS server.
+    pub fn set_bypass<T: Into<Ipv4NetworkSet>>(&mut self, bypass: T) {
+        self.bypass = bypass.into();
+    }
+
+    /// Sets if destinations will be connected directly when the SOCKS server is unreachable.
+    pub fn set_direct_fallback(&mut self, is_direct_fallback: bool) {
+        self.is_direct_fallback = is_direct_fallback;
+    }
+
+    /// Sets the local address of connections and sockets to the SOCKS server and to destinations
+    /// connected directly, so the traffic egresses from the interface of the address.
+    pub fn set_bind_addr(&mut self, bind_addr: Option<Ipv4Addr>) {
+        self.socket.set_bind_addr(bind_addr);
+    }
+
+    /// Sets the options of TCP sockets to the SOCKS server and to destinations connected
+    /// directly, including the local address.
+    pub fn set_socket(&mut self, socket: SocketOption) {
+        self.socket = socket;
+    }
+
+    /// Returns the version of the SOCKS protocol.
+    pub fn version(&self) -> SocksVersion {
+        self.version
+    }
+
+    /// Returns the connector of TCP connections.
+    pub fn connector(&self) -> Option<&Arc<dyn UpstreamConnector>> {
+        self.connector.as_ref()
+    }
+
+    /// Returns the timeout of connecting to the SOCKS server and handshaking.
+    pub fn connect_timeout(&self) -> Duration {
+        self.connect_timeout
+    }
+
+    /// Returns if the destination will be connected directly instead of through the SOCKS server.
+    pub fn is_bypassed(&self, ip_addr: Ipv4Addr) -> bool {
+        self.bypass.contains(ip_addr)
+    }
+
+    /// Returns if any destination will be connected directly instead of through the SOCKS server.
+    pub fn has_bypass(&self) -> bool {
+        !self.bypass.is_empty()
+    }
+
+    /// Returns if destinations will be connected directly when the SOCKS server is unreachable.
+    pub fn is_direct_fallback(&self) -> bool {
+        self.is_direct_fallback
+    }
+
+    /// Returns the options of TCP sockets to the SOCKS server and to destinations connected
+    /// directly.
+    pub fn socket(&self) -> &SocketOption {
+        &self.socket
+    }
+
     fn auth(&self) -> Option<Auth> {
         self.auth
             .as_ref()
@@ -52,22 +242,444 @@ impl SocksOption {
     }
 }
 
-/// Connects to a target server through a SOCKS5 proxy.
+/// Connects to a target server through a SOCKS proxy. The connection will be aborted if the
+/// handshake does not complete in the timeout, and a `ConnectTimedOut` error is returned if the
+/// connection to the SOCKS server is not established in the timeout.
 pub async fn connect(
     remote: SocketAddrV4,
-    dst: SocketAddrV4,
+    dst: AddrKind,
     options: &SocksOption,
-) -> io::Result<BufStream<TcpStream>> {
-    let stream = TcpStream::connect(remote).await?;
+) -> io::Result<SocksStream> {
+    let deadline = Instant::now() + options.connect_timeout;
+    let stream =
+        direct::connect_until(deadline, direct::connect_tcp(&options.socket, remote)).await?;
+
+    timeout_at(deadline, handshake(stream, dst, options)).await
+}
+
+/// Connects to a target server through a SOCKS proxy using a connection from the pool. The
+/// connection will be aborted if the handshake does not complete in the timeout.
+pub async fn connect_pooled(
+    pool: &Arc<ConnectionPool>,
+    dst: AddrKind,
+    options: &SocksOption,
+) -> io::Result<SocksStream> {
+    let deadline = Instant::now() + options.connect_timeout;
+    let stream = direct::connect_until(deadline, pool.get()).await?;
+
+    timeout_at(deadline, handshake(stream, dst, options)).await
+}
+
+async fn timeout_at<F: Future<Output = io::Result<SocksStream>>>(
+    deadline: Instant,
+    future: F,
+) -> io::Result<SocksStream> {
+    match time::timeout_at(deadline, future).await {
+        Ok(result) => result,
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "SOCKS handshake timed out",
+        )),
+    }
+}
+
+async fn handshake(
+    stream: TcpStream,
+    dst: AddrKind,
+    options: &SocksOption,
+) -> io::Result<SocksStream> {
+    if options.version == SocksVersion::Socks4
+        && (options.auth.is_some() || options.gssapi.is_some())
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "SOCKS4 does not support authentication",
+        ));
+    }
+    if let Some(gssapi) = &options.gssapi {
+        return handshake_gssapi(stream, dst, options.auth.as_ref(), gssapi).await;
+    }
+
     let mut stream = BufStream::new(stream);
-    if let Err(e) = async_socks5::connect(&mut stream, dst, options.auth()).await {
-        return match e {
-            async_socks5::Error::Io(e) => Err(e),
-            _ => Err(io::Error::new(io::ErrorKind::Other, e)),
+    if options.version == SocksVersion::Socks4 {
+        connect_socks4(&mut stream, dst).await?;
+
+        return Ok(SocksStream::Tcp(stream));
+    }
+    let methods = match options.auth {
+        Some(_) => vec![SOCKS5_METHOD_PASSWORD, SOCKS5_METHOD_NO_AUTH],
+        None => vec![SOCKS5_METHOD_NO_AUTH],
+    };
+    if select_method_socks5(&mut stream, methods.as_slice()).await? == SOCKS5_METHOD_PASSWORD {
+        authenticate_socks5(&mut stream, options.auth.as_ref().unwrap()).await?;
+    }
+    connect_socks5(&mut stream, dst).await?;
+
+    Ok(SocksStream::Tcp(stream))
+}
+
+#[cfg(feature = "gssapi")]
+async fn handshake_gssapi(
+    stream: TcpStream,
+    dst: AddrKind,
+    auth: Option<&SocksAuth>,
+    gssapi: &GssapiAuth,
+) -> io::Result<SocksStream> {
+    connect_socks5_gssapi(stream, dst, auth, gssapi, || {
+        Ok(Box::new(gssapi::KerberosContext::new(gssapi.service())?))
+    })
+    .await
+}
+
+#[cfg(not(feature = "gssapi"))]
+async fn handshake_gssapi(
+    _: TcpStream,
+    _: AddrKind,
+    _: Option<&SocksAuth>,
+    _: &GssapiAuth,
+) -> io::Result<SocksStream> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "the GSSAPI authentication is not built in",
+    ))
+}
+
+/// Represents a reply of a SOCKS5 server other than succeeded. The reply is carried in the
+/// `io::Error` returned in connecting, and can be taken out by `SocksReply::from_io_error`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SocksReply {
+    /// Represents a general SOCKS server failure.
+    GeneralFailure,
+    /// Represents the connection is not allowed by the ruleset.
+    NotAllowed,
+    /// Represents the network is unreachable.
+    NetworkUnreachable,
+    /// Represents the host is unreachable.
+    HostUnreachable,
+    /// Represents the connection is refused by the destination.
+    ConnectionRefused,
+    /// Represents the TTL expired.
+    TtlExpired,
+    /// Represents the command is not supported.
+    CommandNotSupported,
+    /// Represents the address type is not supported.
+    AddressTypeNotSupported,
+    /// Represents an unassigned reply code.
+    Unassigned(u8),
+}
+
+impl SocksReply {
+    /// Creates a `SocksReply` from the reply code. Returns `None` if the reply is succeeded.
+    pub fn from_code(code: u8) -> Option<SocksReply> {
+        match code {
+            SOCKS5_REPLY_SUCCEEDED => None,
+            1 => Some(SocksReply::GeneralFailure),
+            2 => Some(SocksReply::NotAllowed),
+            3 => Some(SocksReply::NetworkUnreachable),
+            4 => Some(SocksReply::HostUnreachable),
+            5 => Some(SocksReply::ConnectionRefused),
+            6 => Some(SocksReply::TtlExpired),
+            7 => Some(SocksReply::CommandNotSupported),
+            8 => Some(SocksReply::AddressTypeNotSupported),
+            _ => Some(SocksReply::Unassigned(code)),
         }
     }
 
-    Ok(stream)
+    /// Returns the SOCKS reply carried in the error.
+    pub fn from_io_error(e: &io::Error) -> Option<SocksReply> {
+        e.get_ref()
+            .and_then(|e| e.downcast_ref::<SocksReply>())
+            .copied()
+    }
+
+    /// Returns the reply code.
+    pub fn code(&self) -> u8 {
+        match self {
+            SocksReply::GeneralFailure => 1,
+            SocksReply::NotAllowed => 2,
+            SocksReply::NetworkUnreachable => 3,
+            SocksReply::HostUnreachable => 4,
+            SocksReply::ConnectionRefused => 5,
+            SocksReply::TtlExpired => 6,
+            SocksReply::CommandNotSupported => 7,
+            SocksReply::AddressTypeNotSupported => 8,
+            SocksReply::Unassigned(code) => *code,
+        }
+    }
+
+    /// Returns if the reply indicates the network or the host of the destination is unreachable.
+    pub fn is_unreachable(&self) -> bool {
+        matches!(
+            self,
+            SocksReply::NetworkUnreachable | SocksReply::HostUnreachable
+        )
+    }
+}
+
+impl fmt::Display for SocksReply {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let reason = match self {
+            SocksReply::GeneralFailure => "general SOCKS server failure",
+            SocksReply::NotAllowed => "connection not allowed by ruleset",
+            SocksReply::NetworkUnreachable => "network unreachable",
+            SocksReply::HostUnreachable => "host unreachable",
+            SocksReply::ConnectionRefused => "connection refused",
+            SocksReply::TtlExpired => "TTL expired",
+            SocksReply::CommandNotSupported => "command not supported",
+            SocksReply::AddressTypeNotSupported => "address type not supported",
+            SocksReply::Unassigned(_) => "unassigned",
+        };
+
+        write!(f, "SOCKS5 request rejected: {} ({})", reason, self.code())
+    }
+}
+
+impl std::error::Error for SocksReply {}
+
+impl From<SocksReply> for io::Error {
+    fn from(reply: SocksReply) -> io::Error {
+        // Always `Other`, so replies are not mistaken as the proxy is unreachable
+        io::Error::new(io::ErrorKind::Other, reply)
+    }
+}
+
+const SOCKS4_VERSION: u8 = 4;
+const SOCKS4_COMMAND_CONNECT: u8 = 1;
+const SOCKS4_REPLY_VERSION: u8 = 0;
+const SOCKS4_REPLY_GRANTED: u8 = 90;
+const SOCKS4_REPLY_SIZE: usize = 8;
+
+/// Performs a SOCKS4 CONNECT handshake in the stream. The SOCKS4a extension is used if the
+/// destination is a domain name.
+async fn connect_socks4<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    dst: AddrKind,
+) -> io::Result<()> {
+    let mut buf = vec![SOCKS4_VERSION, SOCKS4_COMMAND_CONNECT];
+    match dst {
+        AddrKind::Ip(SocketAddr::V4(dst)) => {
+            // DSTPORT
+            buf.extend_from_slice(&dst.port().to_be_bytes());
+            // DSTIP
+            buf.extend_from_slice(&dst.ip().octets());
+            // USERID
+            buf.push(0);
+        }
+        AddrKind::Ip(SocketAddr::V6(_)) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "SOCKS4 does not support IPv6",
+            ))
+        }
+        AddrKind::Domain(domain, port) => {
+            // DSTPORT
+            buf.extend_from_slice(&port.to_be_bytes());
+            // DSTIP, 0.0.0.x indicates SOCKS4a
+            buf.extend_from_slice(&[0, 0, 0, 1]);
+            // USERID
+            buf.push(0);
+            // DOMAIN
+            buf.extend_from_slice(domain.as_bytes());
+            buf.push(0);
+        }
+    }
+    stream.write_all(buf.as_slice()).await?;
+    stream.flush().await?;
+
+    let mut reply = [0u8; SOCKS4_REPLY_SIZE];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != SOCKS4_REPLY_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid SOCKS4 reply version {}", reply[0]),
+        ));
+    }
+    if reply[1] != SOCKS4_REPLY_GRANTED {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("SOCKS4 request rejected with code {}", reply[1]),
+        ));
+    }
+
+    Ok(())
+}
+
+const SOCKS5_VERSION: u8 = 5;
+const SOCKS5_METHOD_NO_AUTH: u8 = 0;
+#[cfg(feature = "gssapi")]
+const SOCKS5_METHOD_GSSAPI: u8 = 1;
+const SOCKS5_METHOD_PASSWORD: u8 = 2;
+const SOCKS5_METHOD_NO_ACCEPTABLE: u8 = 0xFF;
+const SOCKS5_PASSWORD_VERSION: u8 = 1;
+const SOCKS5_PASSWORD_SUCCEEDED: u8 = 0;
+const SOCKS5_COMMAND_CONNECT: u8 = 1;
+const SOCKS5_REPLY_SUCCEEDED: u8 = 0;
+
+/// Performs a SOCKS5 CONNECT handshake offering the GSSAPI authentication in the stream. The
+/// security context is only created if the SOCKS server selects the GSSAPI authentication. The
+/// returned stream is encapsulated if the selected protection level requires.
+#[cfg(feature = "gssapi")]
+async fn connect_socks5_gssapi<F: FnOnce() -> io::Result<Box<dyn SecurityContext>>>(
+    mut stream: TcpStream,
+    dst: AddrKind,
+    auth: Option<&SocksAuth>,
+    gssapi: &GssapiAuth,
+    new_ctx: F,
+) -> io::Result<SocksStream> {
+    let methods = match auth {
+        Some(_) => vec![
+            SOCKS5_METHOD_GSSAPI,
+            SOCKS5_METHOD_PASSWORD,
+            SOCKS5_METHOD_NO_AUTH,
+        ],
+        None => vec![SOCKS5_METHOD_GSSAPI, SOCKS5_METHOD_NO_AUTH],
+    };
+    match select_method_socks5(&mut stream, methods.as_slice()).await? {
+        SOCKS5_METHOD_GSSAPI => {
+            let mut ctx = new_ctx()?;
+            let protection =
+                gssapi::authenticate(&mut stream, ctx.as_mut(), gssapi.protection).await?;
+            if protection != GssapiProtection::Clear {
+                let mut stream = gssapi::encapsulate(stream, ctx, protection);
+                connect_socks5(&mut stream, dst).await?;
+
+                return Ok(SocksStream::Encapsulated(stream));
+            }
+        }
+        SOCKS5_METHOD_PASSWORD => authenticate_socks5(&mut stream, auth.unwrap()).await?,
+        _ => {}
+    }
+    connect_socks5(&mut stream, dst).await?;
+
+    Ok(SocksStream::Tcp(BufStream::new(stream)))
+}
+
+/// Negotiates the authentication method in the stream. Returns the method selected by the SOCKS
+/// server.
+async fn select_method_socks5<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    methods: &[u8],
+) -> io::Result<u8> {
+    let mut buf = vec![SOCKS5_VERSION, methods.len() as u8];
+    buf.extend_from_slice(methods);
+    stream.write_all(buf.as_slice()).await?;
+    stream.flush().await?;
+
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != SOCKS5_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid SOCKS5 reply version {}", reply[0]),
+        ));
+    }
+    if reply[1] == SOCKS5_METHOD_NO_ACCEPTABLE {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "SOCKS5 server accepts none of the offered authentication methods",
+        ));
+    }
+    if !methods.contains(&reply[1]) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("SOCKS5 server selects unoffered method {}", reply[1]),
+        ));
+    }
+
+    Ok(reply[1])
+}
+
+/// Performs the SOCKS5 username/password authentication in the stream.
+async fn authenticate_socks5<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    auth: &SocksAuth,
+) -> io::Result<()> {
+    if auth.username.len() > u8::MAX as usize || auth.password.len() > u8::MAX as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "username or password too long",
+        ));
+    }
+
+    let mut buf = vec![SOCKS5_PASSWORD_VERSION, auth.username.len() as u8];
+    buf.extend_from_slice(auth.username.as_bytes());
+    buf.push(auth.password.len() as u8);
+    buf.extend_from_slice(auth.password.as_bytes());
+    stream.write_all(buf.as_slice()).await?;
+    stream.flush().await?;
+
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply).await?;
+    if reply[1] != SOCKS5_PASSWORD_SUCCEEDED {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "SOCKS5 username/password authentication failed",
+        ));
+    }
+
+    Ok(())
+}
+
+/// Performs a SOCKS5 CONNECT request in the stream after the authentication.
+async fn connect_socks5<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    dst: AddrKind,
+) -> io::Result<()> {
+    let mut buf = vec![SOCKS5_VERSION, SOCKS5_COMMAND_CONNECT, 0];
+    match dst {
+        AddrKind::Ip(SocketAddr::V4(dst)) => {
+            buf.push(ATYP_IPV4);
+            buf.extend_from_slice(&dst.ip().octets());
+            buf.extend_from_slice(&dst.port().to_be_bytes());
+        }
+        AddrKind::Ip(SocketAddr::V6(dst)) => {
+            buf.push(ATYP_IPV6);
+            buf.extend_from_slice(&dst.ip().octets());
+            buf.extend_from_slice(&dst.port().to_be_bytes());
+        }
+        AddrKind::Domain(domain, port) => {
+            if domain.len() > u8::MAX as usize {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "domain name too long",
+                ));
+            }
+            buf.push(ATYP_DOMAIN);
+            buf.push(domain.len() as u8);
+            buf.extend_from_slice(domain.as_bytes());
+            buf.extend_from_slice(&port.to_be_bytes());
+        }
+    }
+    stream.write_all(buf.as_slice()).await?;
+    stream.flush().await?;
+
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != SOCKS5_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid SOCKS5 reply version {}", reply[0]),
+        ));
+    }
+    if let Some(reply) = SocksReply::from_code(reply[1]) {
+        return Err(reply.into());
+    }
+    // BND.ADDR and BND.PORT
+    let size = match reply[3] {
+        ATYP_IPV4 => DST_ADDR_SIZE,
+        ATYP_IPV6 => 16,
+        ATYP_DOMAIN => stream.read_u8().await? as usize,
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid SOCKS5 address type {}", reply[3]),
+            ))
+        }
+    };
+    let mut bind = vec![0u8; size + DST_PORT_SIZE];
+    stream.read_exact(&mut bind).await?;
+
+    Ok(())
 }
 
 const RSV_SIZE: usize = 2;
@@ -78,19 +690,19 @@ const DST_PORT_SIZE: usize = 2;
 const HEADER_SIZE: usize = RSV_SIZE + FRAG_SIZE + ATYP_SIZE + DST_ADDR_SIZE + DST_PORT_SIZE;
 
 const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
 
 /// Represents the send half of a SOCKS5 UDP client.
-#[allow(dead_code)]
 #[derive(Debug)]
 pub struct SocksSendHalf {
-    stream: Arc<BufStream<TcpStream>>,
     socket: Arc<UdpSocket>,
 }
 
 impl SocksSendHalf {
     /// Creates a new `SocksSendHalf`.
-    pub fn new(stream: Arc<BufStream<TcpStream>>, socket: Arc<UdpSocket>) -> SocksSendHalf {
-        SocksSendHalf { stream, socket }
+    pub fn new(socket: Arc<UdpSocket>) -> SocksSendHalf {
+        SocksSendHalf { socket }
     }
 
     /// Sends data on the socket to the given address.
@@ -112,18 +724,18 @@ impl SocksSendHalf {
     }
 }
 
-/// Represents the receive half of a SOCKS5 UDP client.
-#[allow(dead_code)]
+/// Represents the receive half of a SOCKS5 UDP client. The receive half holds the control
+/// connection of the association, the association terminates when the control connection closes.
 #[derive(Debug)]
 pub struct SocksRecvHalf {
-    stream: Arc<BufStream<TcpStream>>,
+    stream: BufStream<TcpStream>,
     socket: Arc<UdpSocket>,
     buffer: Vec<u8>,
 }
 
 impl SocksRecvHalf {
     /// Creates a new `SocksRecvHalf`.
-    pub fn new(stream: Arc<BufStream<TcpStream>>, socket: Arc<UdpSocket>) -> SocksRecvHalf {
+    pub fn new(stream: BufStream<TcpStream>, socket: Arc<UdpSocket>) -> SocksRecvHalf {
         SocksRecvHalf {
             stream,
             socket,
@@ -131,28 +743,47 @@ impl SocksRecvHalf {
         }
     }
 
-    /// Receives a single datagram message on the socket.
+    /// Receives a single datagram message on the socket. Returns an error if the control
+    /// connection of the association is closed.
     pub async fn recv_from(&mut self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddrV4)> {
-        let n = self.socket.recv(&mut self.buffer).await?;
-        // ATYP and address
-        match self.buffer[3] {
-            ATYP_IPV4 => {}
-            _ => unreachable!(),
-        }
-        let addr = SocketAddrV4::new(
-            Ipv4Addr::new(
-                self.buffer[4],
-                self.buffer[5],
-                self.buffer[6],
-                self.buffer[7],
-            ),
-            self.buffer[8] as u16 * 256 + self.buffer[9] as u16,
-        );
-        // Buffer
-        let size = n - HEADER_SIZE;
-        buffer[..size].copy_from_slice(&self.buffer[HEADER_SIZE..n]);
+        let mut control_buffer = [0u8; 1];
+        loop {
+            let n = loop {
+                tokio::select! {
+                    r = self.socket.recv(&mut self.buffer) => break r?,
+                    r = self.stream.read(&mut control_buffer) => match r {
+                        // The SOCKS server should not send any data in the control connection
+                        Ok(size) if size > 0 => continue,
+                        Ok(_) => {
+                            return Err(io::Error::new(
+                                io::ErrorKind::ConnectionAborted,
+                                "association terminated",
+                            ))
+                        }
+                        Err(e) => return Err(e),
+                    }
+                }
+            };
+            // ATYP and address, datagrams in other address types are never requested
+            if n < HEADER_SIZE || self.buffer[3] != ATYP_IPV4 {
+                warn!("drop SOCKS5 UDP datagram: unexpected header ({} Bytes)", n);
+                continue;
+            }
+            let addr = SocketAddrV4::new(
+                Ipv4Addr::new(
+                    self.buffer[4],
+                    self.buffer[5],
+                    self.buffer[6],
+                    self.buffer[7],
+                ),
+                self.buffer[8] as u16 * 256 + self.buffer[9] as u16,
+            );
+            // Buffer
+            let size = n - HEADER_SIZE;
+            buffer[..size].copy_from_slice(&self.buffer[HEADER_SIZE..n]);
 
-        Ok((size, addr))
+            return Ok((size, addr));
+        }
     }
 }
 
@@ -161,12 +792,24 @@ pub async fn bind(
     remote: SocketAddrV4,
     options: &SocksOption,
 ) -> io::Result<(SocksRecvHalf, SocksSendHalf, u16)> {
+    if options.version == SocksVersion::Socks4 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "SOCKS4 does not support UDP",
+        ));
+    }
+    if options.gssapi.is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "GSSAPI authentication does not support UDP",
+        ));
+    }
+
     // Connect
-    let stream = TcpStream::connect(remote).await?;
+    let stream = direct::connect_tcp(&options.socket, remote).await?;
     let stream = BufStream::new(stream);
 
-    let local = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0);
-    let socket = UdpSocket::bind(local).await?;
+    let socket = direct::bind_udp(options.socket.bind_addr()).await?;
     let local_port = socket.local_addr().unwrap().port();
     let datagram = match async_socks5::SocksDatagram::associate::<SocketAddrV4>(
         stream,
@@ -212,15 +855,185 @@ pub async fn bind(
         );
     }
 
-    let a_stream = Arc::new(stream);
-    let a_stream_cloned = Arc::clone(&a_stream);
-
     let a_socket = Arc::new(socket);
     let a_socket_cloned = Arc::clone(&a_socket);
 
     Ok((
-        SocksRecvHalf::new(a_stream, a_socket),
-        SocksSendHalf::new(a_stream_cloned, a_socket_cloned),
+        SocksRecvHalf::new(stream, a_socket),
+        SocksSendHalf::new(a_socket_cloned),
         local_port,
     ))
 }
+
+#[tokio::test]
+async fn socks4_connect() {
+    let (mut client, mut server) = io::duplex(64);
+    let dst = SocketAddrV4::new(Ipv4Addr::new(1, 2, 3, 4), 80);
+
+    let server = tokio::spawn(async move {
+        let mut buf = [0u8; 9];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, [4, 1, 0, 80, 1, 2, 3, 4, 0]);
+
+        server.write_all(&[0, 90, 0, 0, 0, 0, 0, 0]).await.unwrap();
+    });
+
+    connect_socks4(&mut client, AddrKind::Ip(SocketAddr::V4(dst)))
+        .await
+        .unwrap();
+    server.await.unwrap();
+}
+
+#[tokio::test]
+async fn socks4a_connect() {
+    let (mut client, mut server) = io::duplex(64);
+
+    let server = tokio::spawn(async move {
+        let mut buf = [0u8; 21];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf[..9], [4, 1, 0x01, 0xBB, 0, 0, 0, 1, 0]);
+        assert_eq!(&buf[9..], b"example.com\0");
+
+        server.write_all(&[0, 91, 0, 0, 0, 0, 0, 0]).await.unwrap();
+    });
+
+    let e = connect_socks4(
+        &mut client,
+        AddrKind::Domain(String::from("example.com"), 443),
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::Other);
+    server.await.unwrap();
+}
+
+#[tokio::test]
+async fn socks_connect_timeout() {
+    use tokio::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let remote = match listener.local_addr().unwrap() {
+        SocketAddr::V4(addr) => addr,
+        SocketAddr::V6(_) => unreachable!(),
+    };
+
+    // The server accepts the connection but never replies
+    let server = tokio::spawn(async move {
+        let (_stream, _) = listener.accept().await.unwrap();
+        time::sleep(Duration::from_millis(1000)).await;
+    });
+
+    let mut options = SocksOption::new(false, false, None);
+    options.set_connect_timeout(Duration::from_millis(100));
+    let dst = SocketAddrV4::new(Ipv4Addr::new(1, 2, 3, 4), 80);
+    let e = connect(remote, AddrKind::Ip(SocketAddr::V4(dst)), &options)
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+    // The SOCKS server is reachable
+    assert!(!direct::is_unreachable(&e));
+    server.abort();
+}
+
+#[cfg(feature = "gssapi")]
+#[tokio::test]
+async fn socks5_connect_gssapi() {
+    use tokio::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let stream = TcpStream::connect(listener.local_addr().unwrap())
+        .await
+        .unwrap();
+    let (mut server, _) = listener.accept().await.unwrap();
+    let dst = SocketAddrV4::new(Ipv4Addr::new(1, 2, 3, 4), 80);
+
+    let server = tokio::spawn(async move {
+        let mut buf = [0u8; 4];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, [5, 2, 1, 0]);
+        server.write_all(&[5, 1]).await.unwrap();
+
+        // Security context and protection level
+        let mut buf = [0u8; 9];
+        server.read_exact(&mut buf).await.unwrap();
+        server
+            .write_all(&[1, 1, 0, 5, b'w', b'o', b'r', b'l', b'd'])
+            .await
+            .unwrap();
+        let mut buf = [0u8; 5];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, [1, 2, 0, 1, !0]);
+        server.write_all(&[1, 2, 0, 1, !0]).await.unwrap();
+
+        // The request is not encapsulated in the clear protection level
+        let mut buf = [0u8; 10];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, [5, 1, 0, 1, 1, 2, 3, 4, 0, 80]);
+        server
+            .write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0])
+            .await
+            .unwrap();
+    });
+
+    let gssapi =
+        GssapiAuth::new_with_protection(String::from("rcmd@localhost"), GssapiProtection::Clear);
+    connect_socks5_gssapi(
+        stream,
+        AddrKind::Ip(SocketAddr::V4(dst)),
+        None,
+        &gssapi,
+        || Ok(Box::new(gssapi::MockContext::new())),
+    )
+    .await
+    .unwrap();
+    server.await.unwrap();
+}
+
+#[tokio::test]
+async fn socks5_connect_reply() {
+    use tokio::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let stream = TcpStream::connect(listener.local_addr().unwrap())
+        .await
+        .unwrap();
+    let (mut server, _) = listener.accept().await.unwrap();
+    let dst = SocketAddrV4::new(Ipv4Addr::new(1, 2, 3, 4), 80);
+
+    let server = tokio::spawn(async move {
+        let mut buf = [0u8; 4];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, [5, 2, 2, 0]);
+        server.write_all(&[5, 2]).await.unwrap();
+
+        // Username and password
+        let mut buf = [0u8; 11];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"\x01\x04user\x04pass");
+        server.write_all(&[1, 0]).await.unwrap();
+
+        let mut buf = [0u8; 10];
+        server.read_exact(&mut buf).await.unwrap();
+        server
+            .write_all(&[5, 4, 0, 1, 0, 0, 0, 0, 0, 0])
+            .await
+            .unwrap();
+    });
+
+    let options = SocksOption::new(
+        false,
+        false,
+        Some(SocksAuth::new(String::from("user"), String::from("pass"))),
+    );
+    let e = handshake(stream, AddrKind::Ip(SocketAddr::V4(dst)), &options)
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(e.kind(), io::ErrorKind::Other);
+    assert_eq!(
+        SocksReply::from_io_error(&e),
+        Some(SocksReply::HostUnreachable)
+    );
+    server.await.unwrap();
+}
@@ -0,0 +1,291 @@
+//! Support for connecting to destinations through pluggable upstreams.
+
+use super::direct::{self, DatagramRecvHalf, DatagramSendHalf};
+use super::pool::ConnectionPool;
+use super::socks::{self, ProxyStream, SocksStream};
+use super::ProxyConfig;
+use async_socks5::AddrKind;
+use log::debug;
+use std::fmt;
+use std::future::Future;
+use std::net::{SocketAddr, SocketAddrV4};
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio::io::{self, AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::time;
+
+/// Represents a boxed future returned by the upstream traits.
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Represents a stream to a destination connected through an upstream.
+pub struct UpstreamStream {
+    rx: Box<dyn AsyncRead + Send + Unpin>,
+    tx: Box<dyn AsyncWrite + Send + Unpin>,
+    is_direct: bool,
+}
+
+impl UpstreamStream {
+    /// Creates a new `UpstreamStream`. The stream will be split into halves.
+    pub fn new(stream: Box<dyn ProxyStream>, is_direct: bool) -> UpstreamStream {
+        let (rx, tx) = io::split(stream);
+
+        UpstreamStream {
+            rx: Box::new(rx),
+            tx: Box::new(tx),
+            is_direct,
+        }
+    }
+
+    /// Creates a new `UpstreamStream` from a `TcpStream`, which is split without locking.
+    pub fn new_tcp(stream: TcpStream, is_direct: bool) -> UpstreamStream {
+        let (rx, tx) = stream.into_split();
+
+        UpstreamStream {
+            rx: Box::new(rx),
+            tx: Box::new(tx),
+            is_direct,
+        }
+    }
+
+    /// Returns if the stream is connected directly instead of through the proxy.
+    pub fn is_direct(&self) -> bool {
+        self.is_direct
+    }
+
+    /// Splits the stream into the read half and the write half.
+    #[allow(clippy::type_complexity)]
+    pub fn into_split(
+        self,
+    ) -> (
+        Box<dyn AsyncRead + Send + Unpin>,
+        Box<dyn AsyncWrite + Send + Unpin>,
+    ) {
+        (self.rx, self.tx)
+    }
+}
+
+/// Trait for the send half of a datagram socket bound through an upstream.
+pub trait DatagramSend: Send {
+    /// Sends data on the socket to the given address.
+    fn send_to<'a>(
+        &'a mut self,
+        payload: &'a [u8],
+        dst: SocketAddrV4,
+    ) -> BoxFuture<'a, io::Result<usize>>;
+
+    /// Returns if datagrams to the destination are sent directly instead of through the proxy.
+    fn is_direct(&self, _dst: SocketAddrV4) -> bool {
+        false
+    }
+}
+
+/// Trait for the receive half of a datagram socket bound through an upstream.
+pub trait DatagramRecv: Send {
+    /// Receives a single datagram message on the socket. Returns an error if the binding is
+    /// terminated.
+    fn recv_from<'a>(
+        &'a mut self,
+        buffer: &'a mut [u8],
+    ) -> BoxFuture<'a, io::Result<(usize, SocketAddrV4)>>;
+}
+
+impl DatagramSend for DatagramSendHalf {
+    fn send_to<'a>(
+        &'a mut self,
+        payload: &'a [u8],
+        dst: SocketAddrV4,
+    ) -> BoxFuture<'a, io::Result<usize>> {
+        Box::pin(DatagramSendHalf::send_to(self, payload, dst))
+    }
+
+    fn is_direct(&self, dst: SocketAddrV4) -> bool {
+        DatagramSendHalf::is_direct(self, dst)
+    }
+}
+
+impl DatagramRecv for DatagramRecvHalf {
+    fn recv_from<'a>(
+        &'a mut self,
+        buffer: &'a mut [u8],
+    ) -> BoxFuture<'a, io::Result<(usize, SocketAddrV4)>> {
+        Box::pin(DatagramRecvHalf::recv_from(self, buffer))
+    }
+}
+
+/// Represents a datagram socket bound through an upstream.
+pub struct UpstreamDatagram {
+    rx: Box<dyn DatagramRecv>,
+    tx: Box<dyn DatagramSend>,
+    local_port: u16,
+    is_direct: bool,
+}
+
+impl UpstreamDatagram {
+    /// Creates a new `UpstreamDatagram`. The local port identifies the binding, and should be
+    /// unique in all the bindings of the upstream which are alive.
+    pub fn new(
+        rx: Box<dyn DatagramRecv>,
+        tx: Box<dyn DatagramSend>,
+        local_port: u16,
+        is_direct: bool,
+    ) -> UpstreamDatagram {
+        UpstreamDatagram {
+            rx,
+            tx,
+            local_port,
+            is_direct,
+        }
+    }
+
+    /// Returns the local port of the binding.
+    pub fn local_port(&self) -> u16 {
+        self.local_port
+    }
+
+    /// Returns if all the datagrams are sent directly instead of through the proxy.
+    pub fn is_direct(&self) -> bool {
+        self.is_direct
+    }
+
+    /// Splits the datagram socket into the receive half and the send half.
+    pub fn into_split(self) -> (Box<dyn DatagramRecv>, Box<dyn DatagramSend>) {
+        (self.rx, self.tx)
+    }
+}
+
+/// Trait for connecting to destinations and binding datagram sockets for sources, like through a
+/// SOCKS proxy, an HTTP CONNECT proxy, directly, or in memory in tests.
+pub trait UpstreamConnector: Send + Sync {
+    /// Connects to the destination. The domain name of the destination is given if it is known,
+    /// which should take precedence over the address.
+    fn connect(
+        &self,
+        dst: SocketAddrV4,
+        name: Option<String>,
+    ) -> BoxFuture<'_, io::Result<UpstreamStream>>;
+
+    /// Binds a datagram socket for the source.
+    fn bind(&self, src: SocketAddrV4) -> BoxFuture<'_, io::Result<UpstreamDatagram>>;
+}
+
+impl fmt::Debug for dyn UpstreamConnector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "UpstreamConnector")
+    }
+}
+
+/// Represents the upstream through a proxy, which is the default upstream.
+pub struct SocksUpstream {
+    proxy: ProxyConfig,
+    pool: Option<Arc<ConnectionPool>>,
+}
+
+impl SocksUpstream {
+    /// Creates a new `SocksUpstream`.
+    pub fn new(proxy: ProxyConfig) -> SocksUpstream {
+        SocksUpstream::new_with_pool(proxy, None)
+    }
+
+    /// Creates a new `SocksUpstream` taking connections to the proxy from the pool.
+    pub fn new_with_pool(proxy: ProxyConfig, pool: Option<Arc<ConnectionPool>>) -> SocksUpstream {
+        SocksUpstream { proxy, pool }
+    }
+}
+
+impl UpstreamConnector for SocksUpstream {
+    fn connect(
+        &self,
+        dst: SocketAddrV4,
+        name: Option<String>,
+    ) -> BoxFuture<'_, io::Result<UpstreamStream>> {
+        Box::pin(connect(&self.proxy, self.pool.as_ref(), dst, name))
+    }
+
+    fn bind(&self, _: SocketAddrV4) -> BoxFuture<'_, io::Result<UpstreamDatagram>> {
+        Box::pin(bind(&self.proxy))
+    }
+}
+
+/// Connects to the target through the proxy, or directly if the target is bypassed or the proxy
+/// is unreachable and the fallback is enabled. The connection to the proxy will be taken from the
+/// pool if the pool is given.
+pub async fn connect(
+    proxy: &ProxyConfig,
+    pool: Option<&Arc<ConnectionPool>>,
+    target: SocketAddrV4,
+    name: Option<String>,
+) -> io::Result<UpstreamStream> {
+    match proxy {
+        ProxyConfig::Socks(remote, options) => {
+            if options.is_bypassed(*target.ip()) {
+                debug!("connect to {} directly: destination is bypassed", target);
+
+                let stream = direct::connect(options.socket(), target, options.connect_timeout());
+
+                return Ok(UpstreamStream::new_tcp(stream.await?, true));
+            }
+
+            let stream = match (options.connector(), pool) {
+                (Some(connector), _) => {
+                    let stream = connector.connect(target, name);
+
+                    match time::timeout(options.connect_timeout(), stream).await {
+                        Ok(stream) => stream,
+                        Err(_) => Err(io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            "connection through the connector timed out",
+                        )),
+                    }
+                }
+                (None, pool) => {
+                    let remote_dst = match name {
+                        Some(name) => AddrKind::Domain(name, target.port()),
+                        None => AddrKind::Ip(SocketAddr::V4(target)),
+                    };
+                    let stream = match pool {
+                        Some(pool) => socks::connect_pooled(pool, remote_dst, options).await,
+                        None => socks::connect(*remote, remote_dst, options).await,
+                    };
+
+                    stream.map(|stream| match stream {
+                        SocksStream::Tcp(stream) => {
+                            UpstreamStream::new_tcp(stream.into_inner(), false)
+                        }
+                        #[cfg(feature = "gssapi")]
+                        SocksStream::Encapsulated(stream) => UpstreamStream::new(stream, false),
+                    })
+                }
+            };
+            match stream {
+                Err(ref e) if options.is_direct_fallback() && direct::is_unreachable(e) => {
+                    debug!(
+                        "connect to {} directly: proxy is unreachable: {}",
+                        target, e
+                    );
+
+                    let stream =
+                        direct::connect(options.socket(), target, options.connect_timeout());
+
+                    Ok(UpstreamStream::new_tcp(stream.await?, true))
+                }
+                stream => stream,
+            }
+        }
+    }
+}
+
+/// Binds a datagram socket through the proxy. See `direct::bind` for the semantics.
+pub async fn bind(proxy: &ProxyConfig) -> io::Result<UpstreamDatagram> {
+    let (rx, tx, local_port) = match proxy {
+        ProxyConfig::Socks(remote, options) => direct::bind(*remote, options).await?,
+    };
+    let is_direct = !tx.is_socks();
+
+    Ok(UpstreamDatagram::new(
+        Box::new(rx),
+        Box::new(tx),
+        local_port,
+        is_direct,
+    ))
+}
@@ -0,0 +1,99 @@
+//! Support for rewriting destinations.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddrV4;
+
+/// Represents a hook rewriting a destination. Returns `None` if the destination is not rewritten.
+pub type Hook = Box<dyn Fn(SocketAddrV4) -> Option<SocketAddrV4> + Send + Sync>;
+
+/// Represents a rewriter deciding which target a destination will be redirected to.
+///
+/// The static rules are consulted before the hook. The destination seen by the sources is not
+/// changed, only the target sent to the proxy is rewritten.
+#[derive(Default)]
+pub struct Rewriter {
+    rules: HashMap<SocketAddrV4, SocketAddrV4>,
+    hook: Option<Hook>,
+}
+
+impl Rewriter {
+    /// Creates a new `Rewriter`.
+    pub fn new() -> Rewriter {
+        Rewriter::default()
+    }
+
+    /// Adds a rule rewriting the destination to the target.
+    pub fn add_rule(&mut self, dst: SocketAddrV4, target: SocketAddrV4) {
+        self.rules.insert(dst, target);
+    }
+
+    /// Removes the rule of the destination.
+    pub fn remove_rule(&mut self, dst: SocketAddrV4) {
+        self.rules.remove(&dst);
+    }
+
+    /// Sets the hook rewriting destinations which do not match any rule.
+    pub fn set_hook(&mut self, hook: Option<Hook>) {
+        self.hook = hook;
+    }
+
+    /// Removes all the rules and the hook.
+    pub fn clear(&mut self) {
+        self.rules.clear();
+        self.hook = None;
+    }
+
+    /// Returns the rules of the rewriter.
+    pub fn rules(&self) -> &HashMap<SocketAddrV4, SocketAddrV4> {
+        &self.rules
+    }
+
+    /// Returns the target of the given destination.
+    pub fn rewrite(&self, dst: SocketAddrV4) -> SocketAddrV4 {
+        if let Some(&target) = self.rules.get(&dst) {
+            return target;
+        }
+
+        match &self.hook {
+            Some(hook) => hook(dst).unwrap_or(dst),
+            None => dst,
+        }
+    }
+}
+
+impl fmt::Debug for Rewriter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Rewriter")
+            .field("rules", &self.rules)
+            .field("hook", &self.hook.is_some())
+            .finish()
+    }
+}
+
+#[test]
+fn rewriter_rewrite() {
+    use std::net::Ipv4Addr;
+
+    let a = SocketAddrV4::new(Ipv4Addr::new(1, 1, 1, 1), 80);
+    let b = SocketAddrV4::new(Ipv4Addr::new(2, 2, 2, 2), 8080);
+    let c = SocketAddrV4::new(Ipv4Addr::new(3, 3, 3, 3), 443);
+
+    let mut rewriter = Rewriter::new();
+    assert_eq!(rewriter.rewrite(a), a);
+
+    rewriter.add_rule(a, b);
+    rewriter.set_hook(Some(Box::new(move |dst| match dst.port() {
+        80 => Some(c),
+        _ => None,
+    })));
+    assert_eq!(rewriter.rewrite(a), b);
+    assert_eq!(
+        rewriter.rewrite(SocketAddrV4::new(Ipv4Addr::new(4, 4, 4, 4), 80)),
+        c
+    );
+    assert_eq!(rewriter.rewrite(c), c);
+
+    rewriter.clear();
+    assert_eq!(rewriter.rewrite(a), a);
+}
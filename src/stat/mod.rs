@@ -1,6 +1,6 @@
 //! Support for statistics.
 
-use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 
 /// Represents the traffic statistics.
@@ -29,3 +29,192 @@ impl Traffic {
         self.count.clone()
     }
 }
+
+/// Represents the statistics of connections.
+#[derive(Clone, Debug, Default)]
+pub struct Stats {
+    rejected_streams: Arc<AtomicUsize>,
+    evicted_datagrams: Arc<AtomicUsize>,
+    black_holes: Arc<AtomicUsize>,
+    tx_buffered: Arc<AtomicUsize>,
+    rx_buffered: Arc<AtomicUsize>,
+    max_buffered: Arc<AtomicUsize>,
+    buffer_capacity: Arc<AtomicUsize>,
+    malformed_frames: Arc<AtomicUsize>,
+    foreign_frames: Arc<AtomicUsize>,
+    unsupported_frames: Arc<AtomicUsize>,
+    filtered_frames: Arc<AtomicUsize>,
+    dropped_frames: Arc<AtomicUsize>,
+    sent_segments: Arc<AtomicUsize>,
+    retrans_segments: Arc<AtomicUsize>,
+}
+
+impl Stats {
+    /// Creates a new `Stats`.
+    pub fn new() -> Stats {
+        Stats {
+            rejected_streams: Arc::new(AtomicUsize::new(0)),
+            evicted_datagrams: Arc::new(AtomicUsize::new(0)),
+            black_holes: Arc::new(AtomicUsize::new(0)),
+            tx_buffered: Arc::new(AtomicUsize::new(0)),
+            rx_buffered: Arc::new(AtomicUsize::new(0)),
+            max_buffered: Arc::new(AtomicUsize::new(0)),
+            buffer_capacity: Arc::new(AtomicUsize::new(0)),
+            malformed_frames: Arc::new(AtomicUsize::new(0)),
+            foreign_frames: Arc::new(AtomicUsize::new(0)),
+            unsupported_frames: Arc::new(AtomicUsize::new(0)),
+            filtered_frames: Arc::new(AtomicUsize::new(0)),
+            dropped_frames: Arc::new(AtomicUsize::new(0)),
+            sent_segments: Arc::new(AtomicUsize::new(0)),
+            retrans_segments: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    pub(crate) fn increase_rejected_streams(&self) {
+        self.rejected_streams.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn increase_evicted_datagrams(&self) {
+        self.evicted_datagrams.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn increase_black_holes(&self) {
+        self.black_holes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn increase_malformed_frames(&self) {
+        self.malformed_frames.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn increase_foreign_frames(&self) {
+        self.foreign_frames.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn increase_unsupported_frames(&self) {
+        self.unsupported_frames.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn increase_filtered_frames(&self) {
+        self.filtered_frames.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn increase_dropped_frames(&self) {
+        self.dropped_frames.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn increase_segments(&self, is_retrans: bool) {
+        self.sent_segments.fetch_add(1, Ordering::Relaxed);
+        if is_retrans {
+            self.retrans_segments.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn set_tx_buffered(&self, size: usize) {
+        self.tx_buffered.store(size, Ordering::Relaxed);
+        self.max_buffered
+            .fetch_max(self.buffered(), Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_rx_buffered(&self, size: usize) {
+        self.rx_buffered.store(size, Ordering::Relaxed);
+        self.max_buffered
+            .fetch_max(self.buffered(), Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_buffer_capacity(&self, capacity: usize) {
+        self.buffer_capacity.store(capacity, Ordering::Relaxed);
+    }
+
+    /// Returns the count of TCP streams rejected because of the limit of concurrent streams.
+    pub fn rejected_streams(&self) -> usize {
+        self.rejected_streams.load(Ordering::Relaxed)
+    }
+
+    /// Returns the count of UDP datagram workers evicted and reused because of the limit of
+    /// datagram workers.
+    pub fn evicted_datagrams(&self) -> usize {
+        self.evicted_datagrams.load(Ordering::Relaxed)
+    }
+
+    /// Returns the count of possible MTU black holes detected, which are TCP connections making no
+    /// progress after retransmissions of full-sized segments while smaller ones get through.
+    pub fn black_holes(&self) -> usize {
+        self.black_holes.load(Ordering::Relaxed)
+    }
+
+    /// Returns the size of data buffered in TCP connections when last measured. The size is
+    /// measured whenever data is buffered, in every sweep of idle connections, or through
+    /// `Redirector::buffered_size`.
+    pub fn buffered(&self) -> usize {
+        self.tx_buffered.load(Ordering::Relaxed) + self.rx_buffered.load(Ordering::Relaxed)
+    }
+
+    /// Returns the high-water mark of the size of data buffered in TCP connections.
+    pub fn max_buffered(&self) -> usize {
+        self.max_buffered.load(Ordering::Relaxed)
+    }
+
+    /// Returns the total capacity of the send and receive windows of TCP connections when last
+    /// measured. The capacity is measured in every sweep of idle connections, or through
+    /// `Redirector::buffer_capacity`.
+    pub fn buffer_capacity(&self) -> usize {
+        self.buffer_capacity.load(Ordering::Relaxed)
+    }
+
+    /// Returns the count of frames dropped because they cannot be parsed, like truncated frames,
+    /// packets with invalid checksums, or TCP segments without any valid flag.
+    pub fn malformed_frames(&self) -> usize {
+        self.malformed_frames.load(Ordering::Relaxed)
+    }
+
+    /// Returns the count of frames dropped because they are not from the sources, or not in the
+    /// VLAN.
+    pub fn foreign_frames(&self) -> usize {
+        self.foreign_frames.load(Ordering::Relaxed)
+    }
+
+    /// Returns the count of frames dropped because their network or transport protocols are not
+    /// supported, like IPv6 or IGMP.
+    pub fn unsupported_frames(&self) -> usize {
+        self.unsupported_frames.load(Ordering::Relaxed)
+    }
+
+    /// Returns the count of frames dropped because their destinations are denied by the filter.
+    pub fn filtered_frames(&self) -> usize {
+        self.filtered_frames.load(Ordering::Relaxed)
+    }
+
+    /// Returns the count of captured frames dropped before being handled because the redirection
+    /// falls behind the capture, which is only measured if `Redirector::set_capture_dropping` is
+    /// set. A growing count suggests a bigger buffer size of the interface.
+    pub fn dropped_frames(&self) -> usize {
+        self.dropped_frames.load(Ordering::Relaxed)
+    }
+
+    /// Returns the count of TCP data and FIN segments sent to the sources, including
+    /// retransmissions.
+    pub fn sent_segments(&self) -> usize {
+        self.sent_segments.load(Ordering::Relaxed)
+    }
+
+    /// Returns the count of TCP data and FIN segments retransmitted to the sources, either fast
+    /// or due to timeout.
+    pub fn retrans_segments(&self) -> usize {
+        self.retrans_segments.load(Ordering::Relaxed)
+    }
+
+    /// Returns the ratio of retransmitted TCP segments in all the segments sent to the sources,
+    /// which is a rough estimate of the packet loss toward the sources.
+    pub fn retrans_ratio(&self) -> Option<f64> {
+        retrans_ratio(self.sent_segments(), self.retrans_segments())
+    }
+}
+
+/// Returns the ratio of retransmitted segments in all the segments sent. Returns `None` if no
+/// segment is sent.
+pub fn retrans_ratio(sent: usize, retrans: usize) -> Option<f64> {
+    match sent {
+        0 => None,
+        _ => Some(retrans as f64 / sent as f64),
+    }
+}
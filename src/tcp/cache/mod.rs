@@ -8,10 +8,14 @@ use std::ops::Bound::Included;
 use std::time::Duration;
 
 use super::Timer;
+use crate::error::StateError;
 
 /// Represents the max distance of u32 values between packets in an u32 window.
 const MAX_U32_WINDOW_SIZE: usize = 16 * 1024 * 1024;
 
+/// Represents the size of the space of sequences, which is 2^32.
+const SEQUENCE_SPACE: u64 = 1 << 32;
+
 /// Represents if the buffer should be allocated in the initial constructor of caches.
 const ALLOC_IN_INITIAL: bool = false;
 
@@ -58,7 +62,7 @@ impl Queue {
     /// Appends some bytes to the end of the queue.
     pub fn append(&mut self, payload: &[u8], rto: u64) -> Result<()> {
         if payload.len() > self.remaining() {
-            return Err(Error::new(ErrorKind::Other, "queue is full"));
+            return Err(Error::from(StateError::QueueFull));
         }
         if payload.len() > self.buffer.len() - self.size {
             // Extend the buffer
@@ -91,10 +95,7 @@ impl Queue {
         }
 
         // Sequence and clock
-        let sequence = self
-            .sequence
-            .checked_add(self.size as u32)
-            .unwrap_or_else(|| self.size as u32 - (u32::MAX - self.sequence));
+        let sequence = self.sequence.wrapping_add(self.size as u32);
         self.clocks.push_back((sequence, Timer::new(rto)));
 
         // From the tail to the end of the buffer
@@ -115,9 +116,7 @@ impl Queue {
 
     /// Invalidates queue to the certain sequence and returns the RTT.
     pub fn invalidate_to(&mut self, sequence: u32) -> Option<Duration> {
-        let size = sequence
-            .checked_sub(self.sequence)
-            .unwrap_or_else(|| u32::MAX - self.sequence + sequence) as usize;
+        let size = sequence.wrapping_sub(self.sequence) as usize;
 
         if size <= MAX_U32_WINDOW_SIZE as usize {
             self.sequence = sequence;
@@ -132,18 +131,12 @@ impl Queue {
 
             // Pop clocks
             while !self.clocks.is_empty() {
-                let dist = sequence
-                    .checked_sub(self.clocks[0].0)
-                    .unwrap_or_else(|| sequence + (u32::MAX - self.clocks[0].0))
-                    as usize;
+                let dist = sequence.wrapping_sub(self.clocks[0].0) as usize;
                 let recv_next = match self.clocks.len() {
                     1 => self.recv_next(),
                     _ => self.clocks[1].0,
                 };
-                let dist_next = sequence
-                    .checked_sub(recv_next)
-                    .unwrap_or_else(|| sequence + (u32::MAX - recv_next))
-                    as usize;
+                let dist_next = sequence.wrapping_sub(recv_next) as usize;
 
                 if dist <= MAX_U32_WINDOW_SIZE as usize && dist_next <= MAX_U32_WINDOW_SIZE as usize
                 {
@@ -156,12 +149,7 @@ impl Queue {
 
                             // Rollback on retransmission
                             if let Some(retrans) = self.retrans {
-                                if retrans
-                                    .checked_sub(sequence)
-                                    .unwrap_or_else(|| retrans + (u32::MAX - sequence))
-                                    as usize
-                                    <= MAX_U32_WINDOW_SIZE
-                                {
+                                if retrans.wrapping_sub(sequence) as usize <= MAX_U32_WINDOW_SIZE {
                                     // Karn's algorithm
                                     rtt = None;
                                 }
@@ -180,13 +168,7 @@ impl Queue {
 
             // Retransmission
             if let Some(retrans) = self.retrans {
-                if self
-                    .sequence
-                    .checked_sub(retrans)
-                    .unwrap_or_else(|| self.sequence + (u32::MAX - retrans))
-                    as usize
-                    <= MAX_U32_WINDOW_SIZE
-                {
+                if self.sequence.wrapping_sub(retrans) as usize <= MAX_U32_WINDOW_SIZE {
                     self.retrans = None;
                 }
             }
@@ -202,10 +184,7 @@ impl Queue {
         if size == 0 {
             return Ok(Vec::new());
         }
-        let distance = sequence
-            .checked_sub(self.sequence)
-            .unwrap_or_else(|| sequence + (u32::MAX - self.sequence))
-            as usize;
+        let distance = sequence.wrapping_sub(self.sequence) as usize;
         if distance > self.size {
             return Err(Error::new(
                 ErrorKind::InvalidInput,
@@ -252,10 +231,7 @@ impl Queue {
 
         match recv_next {
             Some(recv_next) => {
-                let size = recv_next
-                    .checked_sub(self.sequence)
-                    .unwrap_or_else(|| recv_next + (u32::MAX - self.sequence))
-                    as usize;
+                let size = recv_next.wrapping_sub(self.sequence) as usize;
 
                 self.get(self.sequence, size).unwrap()
             }
@@ -277,20 +253,16 @@ impl Queue {
 
         match recv_next {
             Some(recv_next) => {
-                let size = recv_next
-                    .checked_sub(self.sequence)
-                    .unwrap_or_else(|| recv_next + (u32::MAX - self.sequence))
-                    as usize;
+                let size = recv_next.wrapping_sub(self.sequence) as usize;
+                if size == 0 {
+                    return Vec::new();
+                }
 
-                // Update clock
+                // Update clock, the clock of the bytes which are not timed out is kept
                 while !self.clocks.is_empty() {
                     let next_sequence = self.clocks.front().unwrap().0;
-                    if recv_next
-                        .checked_sub(next_sequence)
-                        .unwrap_or_else(|| recv_next + (u32::MAX - next_sequence))
-                        as usize
-                        <= MAX_U32_WINDOW_SIZE
-                    {
+                    let distance = recv_next.wrapping_sub(next_sequence) as usize;
+                    if distance > 0 && distance <= MAX_U32_WINDOW_SIZE {
                         self.clocks.pop_front();
                     } else {
                         self.clocks.push_front((self.sequence, Timer::new(rto)));
@@ -343,9 +315,7 @@ impl Queue {
 
     /// Returns the receive next of the queue.
     pub fn recv_next(&self) -> u32 {
-        self.sequence
-            .checked_add(self.size as u32)
-            .unwrap_or_else(|| self.size as u32 - (u32::MAX - self.sequence))
+        self.sequence.wrapping_add(self.size as u32)
     }
 
     /// Returns if the queue is empty.
@@ -445,6 +415,25 @@ fn queue_append_overflow_overlapped_2() {
     assert_eq!(q.to_string(), "[9, 10, 11, 12, 13, 14>, <6, 7, 8]");
 }
 
+#[test]
+fn queue_get_timed_out_and_update() {
+    let mut q = Queue::with_capacity(16, 0);
+
+    let v = (0..4).collect::<Vec<_>>();
+    q.append(v.as_slice(), 0).unwrap();
+    let v = (4..8).collect::<Vec<_>>();
+    q.append(v.as_slice(), 60000).unwrap();
+
+    // Only the timed out bytes are returned
+    assert_eq!(
+        q.get_timed_out_and_update(60000),
+        (0..4).collect::<Vec<_>>()
+    );
+    // Nothing is timed out, the clocks are kept
+    assert!(q.get_timed_out_and_update(60000).is_empty());
+    assert!(q.get_timed_out_and_update(60000).is_empty());
+}
+
 /// Represents a window cache. The `Window` can hold discontinuous bytes and pop out them when
 /// they are completed. The `Window` can be used as a receive window of a TCP connection.
 #[derive(Debug)]
@@ -457,6 +446,7 @@ pub struct Window {
     size: usize,
     /// Represents edges of filled values. Use an u64 instead of an u32 because the sequence is used as a ring.
     edges: BTreeMap<u64, usize>,
+    max_gaps: usize,
 }
 
 impl Window {
@@ -478,29 +468,26 @@ impl Window {
             head: 0,
             size: 0,
             edges: BTreeMap::new(),
+            max_gaps: usize::MAX,
         }
     }
 
+    /// Sets the max number of gaps tracked in the window. If the gaps exceed the limit, the most
+    /// out of order data will be dropped and left to be retransmitted by the source.
+    pub fn set_max_gaps(&mut self, max_gaps: usize) {
+        self.max_gaps = max_gaps;
+        self.trim_gaps();
+    }
+
     /// Appends some bytes to the window and returns continuous bytes from the beginning.
-    #[allow(clippy::unnecessary_lazy_evaluations)]
     pub fn append(&mut self, sequence: u32, payload: &[u8]) -> Result<Option<Vec<u8>>> {
-        let sub_sequence = sequence
-            .checked_sub(self.sequence)
-            .unwrap_or_else(|| sequence + (u32::MAX - self.sequence))
-            as usize;
+        let sub_sequence = sequence.wrapping_sub(self.sequence) as usize;
         let (sequence, payload, sub_sequence) = if sub_sequence > MAX_U32_WINDOW_SIZE {
-            let recv_next = sequence
-                .checked_add(payload.len() as u32)
-                .unwrap_or_else(|| payload.len() as u32 - (u32::MAX - sequence));
-            let sub_recv_next_to_sequence = recv_next
-                .checked_sub(self.sequence)
-                .unwrap_or_else(|| sequence + (u32::MAX - recv_next));
+            let recv_next = sequence.wrapping_add(payload.len() as u32);
+            let sub_recv_next_to_sequence = recv_next.wrapping_sub(self.sequence);
 
             if sub_recv_next_to_sequence as usize <= MAX_U32_WINDOW_SIZE {
-                let sub_sequence = self
-                    .sequence
-                    .checked_sub(sequence)
-                    .unwrap_or_else(|| self.sequence + (u32::MAX - sequence));
+                let sub_sequence = self.sequence.wrapping_sub(sequence);
                 (self.sequence, &payload[sub_sequence as usize..], 0)
             } else {
                 return Ok(None);
@@ -511,7 +498,7 @@ impl Window {
 
         let size = sub_sequence + payload.len();
         if size > self.capacity {
-            return Err(Error::new(ErrorKind::Other, "window is full"));
+            return Err(Error::from(StateError::WindowFull));
         }
         if size > self.buffer.len() {
             // Extend the buffer
@@ -533,10 +520,7 @@ impl Window {
                 .edges
                 .iter()
                 .map(|(sequence, &size)| {
-                    let sub_sequence = sequence
-                        .checked_sub(self.sequence as u64)
-                        .unwrap_or_else(|| sequence + (u32::MAX - self.sequence) as u64)
-                        as usize;
+                    let sub_sequence = (*sequence as u32).wrapping_sub(self.sequence) as usize;
                     let mut begin = self.get_tail(self.head, sub_sequence, prev_len);
                     let end = self.get_tail(begin, size, prev_len);
                     if end <= begin {
@@ -581,16 +565,9 @@ impl Window {
         }
 
         // Update size
-        let recv_next = sequence
-            .checked_add(payload.len() as u32)
-            .unwrap_or_else(|| payload.len() as u32 - (u32::MAX - sequence));
-        let record_recv_next = self
-            .sequence
-            .checked_add(self.size as u32)
-            .unwrap_or_else(|| self.size as u32 - (u32::MAX - self.sequence));
-        let sub_recv_next = recv_next
-            .checked_sub(record_recv_next)
-            .unwrap_or_else(|| recv_next + (u32::MAX - record_recv_next));
+        let recv_next = sequence.wrapping_add(payload.len() as u32);
+        let record_recv_next = self.sequence.wrapping_add(self.size as u32);
+        let sub_recv_next = recv_next.wrapping_sub(record_recv_next);
         if sub_recv_next as usize <= MAX_U32_WINDOW_SIZE {
             self.size += sub_recv_next as usize;
         }
@@ -599,7 +576,7 @@ impl Window {
         {
             let mut sequence = sequence as u64;
             if (sequence as u32) < self.sequence {
-                sequence += u32::MAX as u64;
+                sequence += SEQUENCE_SPACE;
             }
 
             // Select ranges which can be merged in a loop
@@ -657,7 +634,7 @@ impl Window {
 
                 for key in keys {
                     let value = self.edges.remove(&key).unwrap();
-                    self.edges.insert(key - u32::MAX as u64, value);
+                    self.edges.insert(key - SEQUENCE_SPACE, value);
                 }
             }
 
@@ -674,19 +651,38 @@ impl Window {
                 cont_payload[len_a..].copy_from_slice(&self.buffer[..len_b]);
             }
 
-            self.sequence = self
-                .sequence
-                .checked_add(size as u32)
-                .unwrap_or_else(|| size as u32 - (u32::MAX - self.sequence));
+            self.sequence = self.sequence.wrapping_add(size as u32);
             self.head = (self.head + (size % self.buffer.len())) % self.buffer.len();
             self.size -= cont_payload.len();
+            self.trim_gaps();
 
             return Ok(Some(cont_payload));
         }
+        self.trim_gaps();
 
         Ok(None)
     }
 
+    fn trim_gaps(&mut self) {
+        // Every remaining edge is preceded by a gap
+        if self.edges.len() <= self.max_gaps {
+            return;
+        }
+
+        while self.edges.len() > self.max_gaps {
+            let last_key = *self.edges.keys().next_back().unwrap();
+            self.edges.remove(&last_key);
+        }
+
+        // Update size
+        self.size = match self.edges.iter().next_back() {
+            Some((&sequence, &size)) => (sequence as u32)
+                .wrapping_add(size as u32)
+                .wrapping_sub(self.sequence) as usize,
+            None => 0,
+        };
+    }
+
     /// Returns the sequence of the window.
     pub fn sequence(&self) -> u32 {
         self.sequence
@@ -698,11 +694,13 @@ impl Window {
     }
 
     /// Returns the receive next of the window.
-    #[allow(clippy::unnecessary_lazy_evaluations)]
     pub fn recv_next(&self) -> u32 {
-        self.sequence
-            .checked_add(self.size as u32)
-            .unwrap_or_else(|| self.size as u32 - (u32::MAX - self.sequence))
+        self.sequence.wrapping_add(self.size as u32)
+    }
+
+    /// Returns the capacity of the window.
+    pub fn capacity(&self) -> usize {
+        self.capacity
     }
 
     /// Returns the remaining size of the window.
@@ -710,6 +708,11 @@ impl Window {
         self.capacity - self.size
     }
 
+    /// Returns the max number of gaps tracked in the window.
+    pub fn max_gaps(&self) -> usize {
+        self.max_gaps
+    }
+
     fn tail(&self) -> usize {
         self.get_tail(self.head, self.size, self.buffer.len())
     }
@@ -724,14 +727,11 @@ impl Window {
     }
 
     /// Returns the filled edges of the window.
-    #[allow(clippy::unnecessary_lazy_evaluations)]
     pub fn filled(&self) -> Vec<(u32, u32)> {
         let mut v = Vec::new();
         for (&sequence, &size) in &self.edges {
-            let begin = sequence.checked_sub(u32::MAX as u64).unwrap_or(sequence) as u32;
-            let end = begin
-                .checked_add(size as u32)
-                .unwrap_or_else(|| size as u32 - (u32::MAX - begin));
+            let begin = sequence as u32;
+            let end = begin.wrapping_add(size as u32);
             v.push((begin, end));
         }
 
@@ -756,10 +756,7 @@ impl Display for Window {
         let mut edge_begin_set = HashSet::new();
         let mut edge_end_set = HashSet::new();
         self.edges.iter().for_each(|(sequence, &size)| {
-            let sub_sequence = sequence
-                .checked_sub(self.sequence as u64)
-                .unwrap_or_else(|| sequence + (u32::MAX - self.sequence) as u64)
-                as usize;
+            let sub_sequence = (*sequence as u32).wrapping_sub(self.sequence) as usize;
             let begin = self.get_tail(head, sub_sequence, self.buffer.len());
             let end = self
                 .get_tail(begin, size, self.buffer.len())
@@ -897,3 +894,71 @@ fn window_append_prev_and_next() {
 
     assert_eq!(w.to_string(), "[0, 1, 2, <0, <4, 5>>]");
 }
+
+#[test]
+fn queue_wrap() {
+    for i in 0..32u32 {
+        let sequence = (u32::MAX - 16).wrapping_add(i);
+        let mut q = Queue::with_capacity(16, sequence);
+
+        let v = (0..8).collect::<Vec<_>>();
+        q.append(v.as_slice(), 0).unwrap();
+        assert_eq!(q.recv_next(), sequence.wrapping_add(8));
+        assert_eq!(
+            q.get(sequence.wrapping_add(2), 4).unwrap(),
+            vec![2, 3, 4, 5]
+        );
+
+        q.invalidate_to(sequence.wrapping_add(5));
+        assert_eq!(q.sequence(), sequence.wrapping_add(5));
+        assert_eq!(q.get_all(), vec![5, 6, 7]);
+    }
+}
+
+#[test]
+fn window_append_wrap() {
+    for i in 0..32u32 {
+        let sequence = (u32::MAX - 16).wrapping_add(i);
+        let mut w = Window::with_capacity(16, sequence);
+
+        let v = (4..8).collect::<Vec<_>>();
+        assert!(w
+            .append(sequence.wrapping_add(4), v.as_slice())
+            .unwrap()
+            .is_none());
+        assert_eq!(
+            w.filled(),
+            vec![(sequence.wrapping_add(4), sequence.wrapping_add(8))]
+        );
+
+        let v = (0..6).collect::<Vec<_>>();
+        let r = w.append(sequence, v.as_slice()).unwrap().unwrap();
+        assert_eq!(r, (0..8).collect::<Vec<_>>());
+        assert_eq!(w.sequence(), sequence.wrapping_add(8));
+        assert!(w.filled().is_empty());
+    }
+}
+
+#[test]
+fn window_append_max_gaps() {
+    let mut w = Window::with_capacity(4096, 0);
+    w.set_max_gaps(8);
+
+    // Flood with 1 byte segments leaving a gap before each
+    for i in 0..1000u32 {
+        assert!(w.append(2 * i + 2, &[1]).unwrap().is_none());
+        assert!(w.filled().len() <= 8);
+    }
+    assert_eq!(w.filled().len(), 8);
+    assert_eq!(w.len(), 17);
+
+    // The most out of order data is dropped
+    assert!(w.append(200, &[1]).unwrap().is_none());
+    assert_eq!(w.filled().len(), 8);
+    assert_eq!(w.recv_next(), 17);
+
+    // Filling the first gap releases the continuous bytes
+    let r = w.append(0, &[0, 0]).unwrap().unwrap();
+    assert_eq!(r, vec![0, 0, 1]);
+    assert_eq!(w.filled().len(), 7);
+}
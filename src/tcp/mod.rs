@@ -1,17 +1,23 @@
 //! Support for tracking TCP connections.
 
 use log::trace;
+use md5::{Digest, Md5};
+use rand::rngs::StdRng;
+use rand::{self, Rng, SeedableRng};
 use std::cmp::{max, min};
 use std::collections::VecDeque;
-use std::fmt::{self, Display};
+use std::fmt::{self, Debug, Display, Formatter};
 use std::net::SocketAddrV4;
-use std::time::{Duration, Instant};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tokio::io;
+use tokio::time::Instant;
 
 mod cache;
 use cache::{Queue, Window};
 
-/// Represents a timer.
+/// Represents a timer. The timer follows the clock of the runtime, so it can be advanced in
+/// tests with the paused time.
 #[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
 pub struct Timer {
     instant: Instant,
@@ -38,6 +44,94 @@ impl Timer {
     }
 }
 
+/// Represents the ticks of the clock of initial sequence numbers in a second.
+const ISN_CLOCK_RATE: u128 = 250_000;
+
+/// Represents the generator of initial sequence numbers, which returns the initial sequence
+/// number of the connection from the source to the destination.
+pub type IsnGenerator = dyn Fn(SocketAddrV4, SocketAddrV4) -> u32 + Send + Sync;
+
+/// Enumeration of strategies choosing initial sequence numbers.
+#[derive(Clone)]
+pub enum IsnStrategy {
+    /// Represents initial sequence numbers are chosen randomly.
+    Random,
+    /// Represents initial sequence numbers are generated as RFC 6528, which are a clock ticking
+    /// every 4 microseconds plus an offset hashed from the connection and the secret key.
+    Rfc6528 {
+        /// Represents the secret key.
+        key: Vec<u8>,
+    },
+    /// Represents initial sequence numbers are generated by the generator, which pins initial
+    /// sequence numbers in tests.
+    Custom(Arc<IsnGenerator>),
+}
+
+impl IsnStrategy {
+    /// Creates an `IsnStrategy` choosing initial sequence numbers from a random number generator
+    /// seeded with the seed, so initial sequence numbers are deterministic.
+    pub fn new_seeded(seed: u64) -> IsnStrategy {
+        let rng = Mutex::new(StdRng::seed_from_u64(seed));
+
+        IsnStrategy::Custom(Arc::new(move |_, _| rng.lock().unwrap().gen::<u32>()))
+    }
+
+    /// Returns the initial sequence number of the connection from the source to the destination.
+    /// `elapsed` is the time elapsed of the clock.
+    pub fn isn(&self, src: SocketAddrV4, dst: SocketAddrV4, elapsed: Duration) -> u32 {
+        match self {
+            IsnStrategy::Random => rand::thread_rng().gen::<u32>(),
+            IsnStrategy::Rfc6528 { key } => {
+                let ticks = (elapsed.as_micros() * ISN_CLOCK_RATE / 1_000_000) as u32;
+
+                // Local address, local port, remote address, remote port and the secret key
+                let mut hasher = Md5::new();
+                hasher.update(dst.ip().octets());
+                hasher.update(dst.port().to_be_bytes());
+                hasher.update(src.ip().octets());
+                hasher.update(src.port().to_be_bytes());
+                hasher.update(key);
+                let hash = hasher.finalize();
+                let offset = u32::from_be_bytes([hash[0], hash[1], hash[2], hash[3]]);
+
+                ticks.wrapping_add(offset)
+            }
+            IsnStrategy::Custom(generator) => generator(src, dst),
+        }
+    }
+}
+
+impl Debug for IsnStrategy {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            IsnStrategy::Random => write!(f, "Random"),
+            // The secret key is never printed
+            IsnStrategy::Rfc6528 { .. } => f
+                .debug_struct("Rfc6528")
+                .field("key", &"<redacted>")
+                .finish(),
+            IsnStrategy::Custom(_) => write!(f, "Custom"),
+        }
+    }
+}
+
+impl PartialEq for IsnStrategy {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (IsnStrategy::Random, IsnStrategy::Random) => true,
+            (IsnStrategy::Rfc6528 { key }, IsnStrategy::Rfc6528 { key: other_key }) => {
+                key == other_key
+            }
+            (IsnStrategy::Custom(generator), IsnStrategy::Custom(other_generator)) => {
+                Arc::ptr_eq(generator, other_generator)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Eq for IsnStrategy {}
+
 /// Represents the max distance of `u32` values between packets in an `u32` window.
 const MAX_U32_WINDOW_SIZE: usize = 16 * 1024 * 1024;
 
@@ -71,6 +165,9 @@ pub trait TcpCc: Send + Sync {
 
     /// Returns the congestion window of the TCP connection.
     fn cwnd(&self) -> usize;
+
+    /// Returns the slow start threshold of the TCP connection.
+    fn ssthresh(&self) -> usize;
 }
 
 /// Represents the TCP Tahoe congestion control state of a TCP connection.
@@ -180,6 +277,10 @@ impl TcpCc for TcpTahoeCcState {
     fn cwnd(&self) -> usize {
         self.cwnd
     }
+
+    fn ssthresh(&self) -> usize {
+        self.ssthresh
+    }
 }
 
 impl Display for TcpTahoeCcState {
@@ -301,6 +402,10 @@ impl TcpCc for TcpRenoCcState {
     fn cwnd(&self) -> usize {
         self.cwnd
     }
+
+    fn ssthresh(&self) -> usize {
+        self.ssthresh
+    }
 }
 
 impl Display for TcpRenoCcState {
@@ -514,6 +619,10 @@ impl TcpCc for TcpCubicCcState {
     fn cwnd(&self) -> usize {
         self.cwnd
     }
+
+    fn ssthresh(&self) -> usize {
+        self.ssthresh
+    }
 }
 
 impl Display for TcpCubicCcState {
@@ -532,6 +641,13 @@ const RECV_WINDOW: u16 = u16::MAX;
 /// Represents the maximum size of extra cache in a TCP connection.
 const MAX_QUEUE: usize = 16777216;
 
+/// Represents the minimum send MSS which a TCP connection lowers to as full-sized segments keep
+/// timing out.
+const MIN_SEND_MSS: usize = 536;
+/// Represents the count of acknowledgements making progress at a lowered send MSS before the send
+/// MSS is probed up.
+const SEND_MSS_PROBE_ACKS: usize = 16;
+
 /// Represents if the RTO computation is enabled.
 const ENABLE_RTO_COMPUTE: bool = true;
 /// Represents the initial timeout for a retransmission in a TCP connection.
@@ -550,6 +666,27 @@ const ENABLE_CC: bool = true;
 /// Represents the congestion control algorithm.
 const CC_ALGORITHM: TcpCcAlgorithms = TcpCcAlgorithms::Reno;
 
+/// Represents the Proportional Rate Reduction ([RFC 6937](https://tools.ietf.org/html/rfc6937))
+/// state of a TCP connection in loss recovery.
+#[derive(Clone, Debug)]
+struct TcpPrrState {
+    recover: u32,
+    recover_fs: usize,
+    ssthresh: usize,
+    mss: usize,
+    delivered: usize,
+    last_delivered: usize,
+    out: usize,
+    sacked: usize,
+}
+
+impl TcpPrrState {
+    fn deliver(&mut self, size: usize) {
+        self.delivered = self.delivered.saturating_add(size);
+        self.last_delivered = self.last_delivered.saturating_add(size);
+    }
+}
+
 /// Represents the TX state of a TCP connection.
 pub struct TcpTxState {
     src: SocketAddrV4,
@@ -560,6 +697,10 @@ pub struct TcpTxState {
     sequence: u32,
     acknowledgement: u32,
     window: u16,
+    wscale: u8,
+    recv_mss: Option<u16>,
+    pending: usize,
+    zero_window: Option<Instant>,
     sacks: Option<Vec<(u32, u32)>>,
     delayed_ack: bool,
     cache: Queue,
@@ -568,10 +709,18 @@ pub struct TcpTxState {
     cache_fin_retrans: bool,
     queue: VecDeque<u8>,
     queue_fin: bool,
+    fin_acked: bool,
+    timedout_retrans: usize,
+    is_black_hole_suspected: bool,
+    is_black_hole_confirmed: bool,
+    send_mss: Option<usize>,
+    send_mss_acks: usize,
     rto: u64,
     srtt: Option<f64>,
     rttvar: Option<f64>,
     cc: Option<Box<dyn TcpCc>>,
+    prr: Option<TcpPrrState>,
+    activity: Instant,
 }
 
 impl TcpTxState {
@@ -591,12 +740,17 @@ impl TcpTxState {
         TcpTxState {
             src,
             dst,
-            src_window: (src_window as usize) << src_wscale.unwrap_or(0),
+            // The window in the TCP SYN is never scaled
+            src_window: src_window as usize,
             src_wscale,
             sack_perm,
             sequence,
             acknowledgement,
             window: RECV_WINDOW,
+            wscale: wscale.unwrap_or(0),
+            recv_mss: None,
+            pending: 0,
+            zero_window: None,
             sacks: None,
             delayed_ack: false,
             cache: Queue::with_capacity(
@@ -608,6 +762,12 @@ impl TcpTxState {
             cache_fin_retrans: true,
             queue: VecDeque::new(),
             queue_fin: false,
+            fin_acked: false,
+            timedout_retrans: 0,
+            is_black_hole_suspected: false,
+            is_black_hole_confirmed: false,
+            send_mss: None,
+            send_mss_acks: 0,
             rto: INITIAL_RTO,
             srtt: None,
             rttvar: None,
@@ -619,6 +779,8 @@ impl TcpTxState {
                 },
                 false => None,
             },
+            prr: None,
+            activity: Instant::now(),
         }
     }
 
@@ -633,13 +795,32 @@ impl TcpTxState {
         );
     }
 
+    /// Adds data pending to be written to the proxy to the TCP connection. The pending data holds
+    /// the window until it is drained.
+    pub fn add_pending(&mut self, size: usize) {
+        self.pending = self.pending.saturating_add(size);
+        trace!(
+            "add TCP pending of {} -> {} to {}",
+            self.dst,
+            self.src,
+            self.pending
+        );
+    }
+
+    /// Drains data pending to be written to the proxy from the TCP connection.
+    pub fn drain_pending(&mut self, size: usize) {
+        self.pending = self.pending.saturating_sub(size);
+        trace!(
+            "drain TCP pending of {} -> {} to {}",
+            self.dst,
+            self.src,
+            self.pending
+        );
+    }
+
     /// Adds sequence to the TCP connection.
-    #[allow(clippy::unnecessary_lazy_evaluations)]
     pub fn add_sequence(&mut self, n: u32) {
-        self.sequence = self
-            .sequence
-            .checked_add(n)
-            .unwrap_or_else(|| n - (u32::MAX - self.sequence));
+        self.sequence = self.sequence.wrapping_add(n);
         trace!(
             "add TCP sequence of {} -> {} to {}",
             self.dst,
@@ -649,12 +830,8 @@ impl TcpTxState {
     }
 
     /// Adds acknowledgement to the TCP connection.
-    #[allow(clippy::unnecessary_lazy_evaluations)]
     pub fn add_acknowledgement(&mut self, n: u32) {
-        self.acknowledgement = self
-            .acknowledgement
-            .checked_add(n)
-            .unwrap_or_else(|| n - (u32::MAX - self.acknowledgement));
+        self.acknowledgement = self.acknowledgement.wrapping_add(n);
         trace!(
             "add TCP acknowledgement of {} -> {} to {}",
             self.dst,
@@ -666,6 +843,11 @@ impl TcpTxState {
     /// Sets the window of the TCP connection.
     pub fn set_window(&mut self, window: u16) {
         self.window = window;
+        if window == 0 {
+            self.zero_window.get_or_insert_with(Instant::now);
+        } else {
+            self.zero_window = None;
+        }
         trace!(
             "set TCP window of {} -> {} to {}",
             self.dst,
@@ -674,6 +856,17 @@ impl TcpTxState {
         );
     }
 
+    /// Sets the MSS advertised to the source of the TCP connection.
+    pub fn set_recv_mss(&mut self, mss: u16) {
+        self.recv_mss = Some(mss);
+        trace!(
+            "set TCP receive MSS of {} -> {} to {}",
+            self.dst,
+            self.src,
+            mss
+        );
+    }
+
     /// Sets the SACKs of the TCP connection.
     pub fn set_sacks(&mut self, sacks: &Vec<(u32, u32)>) {
         if sacks.is_empty() {
@@ -692,18 +885,16 @@ impl TcpTxState {
     }
 
     /// Acknowledges to the given sequence of the TCP connection.
-    #[allow(clippy::unnecessary_lazy_evaluations)]
     pub fn acknowledge(&mut self, sequence: u32) {
         let mut rtt = None;
+        if let Some(prr) = &mut self.prr {
+            prr.last_delivered = 0;
+        }
 
         // SYN
         if let Some(instant) = self.cache_syn {
             let send_next = self.sequence;
-            if sequence
-                .checked_sub(send_next)
-                .unwrap_or_else(|| sequence + (u32::MAX - send_next)) as usize
-                <= MAX_U32_WINDOW_SIZE
-            {
+            if sequence.wrapping_sub(send_next) as usize <= MAX_U32_WINDOW_SIZE {
                 rtt = Some(instant.elapsed());
 
                 self.cache_syn = None;
@@ -714,16 +905,25 @@ impl TcpTxState {
             }
         }
 
+        // The FIN occupies the sequence next to the cache
+        let fin_sequence = self.cache.recv_next();
+
         // ACK
-        let sub_sequence = sequence
-            .checked_sub(self.cache.sequence())
-            .unwrap_or_else(|| sequence + (u32::MAX - self.cache.sequence()));
+        let sub_sequence = sequence.wrapping_sub(self.cache.sequence());
         if sub_sequence > 0 && sub_sequence as usize <= MAX_U32_WINDOW_SIZE {
             // Invalidate cache
             let cache_rtt = self.cache.invalidate_to(sequence);
             if rtt.is_none() {
                 rtt = cache_rtt;
             }
+            self.timedout_retrans = 0;
+            if self.send_mss.is_some() {
+                self.send_mss_acks += 1;
+            }
+            if self.is_black_hole_suspected {
+                self.is_black_hole_suspected = false;
+                self.is_black_hole_confirmed = true;
+            }
             trace!(
                 "acknowledge TCP cache of {} -> {} to sequence {}",
                 self.dst,
@@ -731,29 +931,40 @@ impl TcpTxState {
                 sequence
             );
 
-            // Congestion control
-            if let Some(cc) = &mut self.cc {
-                match self.srtt {
-                    Some(srtt) => cc.ack_rtt(sub_sequence as usize, srtt),
-                    None => cc.ack(sub_sequence as usize),
+            // Congestion control, which does not grow the window in recovery
+            let is_recovered = match &mut self.prr {
+                Some(prr) => {
+                    prr.deliver(sub_sequence as usize);
+
+                    sequence.wrapping_sub(prr.recover) as usize <= MAX_U32_WINDOW_SIZE
+                }
+                None => {
+                    if let Some(cc) = &mut self.cc {
+                        match self.srtt {
+                            Some(srtt) => cc.ack_rtt(sub_sequence as usize, srtt),
+                            None => cc.ack(sub_sequence as usize),
+                        }
+                    }
+
+                    false
                 }
+            };
+            if is_recovered {
+                self.exit_recovery();
             }
         }
 
         // FIN
         if let Some(timer) = self.cache_fin {
-            if sequence
-                .checked_sub(self.cache.recv_next())
-                .unwrap_or_else(|| sequence + (u32::MAX - self.cache.recv_next()))
-                as usize
-                <= MAX_U32_WINDOW_SIZE
-            {
+            let sub_sequence = sequence.wrapping_sub(fin_sequence);
+            if sub_sequence > 0 && sub_sequence as usize <= MAX_U32_WINDOW_SIZE {
                 if rtt.is_none() && !self.cache_fin_retrans && !timer.is_timedout() {
                     rtt = Some(timer.elapsed());
                 }
 
                 self.cache_fin = None;
                 self.cache_fin_retrans = false;
+                self.fin_acked = true;
                 trace!("acknowledge TCP FIN of {} -> {}", self.dst, self.src);
 
                 // Update TCP sequence
@@ -767,6 +978,77 @@ impl TcpTxState {
         }
     }
 
+    /// Enters the loss recovery of the TCP connection after a fast retransmission, in which the
+    /// data sent is paced by the Proportional Rate Reduction until all the data in flight is
+    /// acknowledged. The size of data in flight SACKed by the source is given. It does nothing if
+    /// the congestion control is disabled or the connection is already in recovery.
+    pub fn enter_recovery(&mut self, sacked: usize, mss: usize) {
+        if self.prr.is_some() || self.cache.is_empty() {
+            return;
+        }
+        let ssthresh = match &self.cc {
+            Some(cc) => cc.ssthresh(),
+            None => return,
+        };
+
+        let recover = self.cache.recv_next();
+        self.prr = Some(TcpPrrState {
+            recover,
+            recover_fs: self.cache.len(),
+            ssthresh,
+            mss,
+            delivered: 0,
+            last_delivered: 0,
+            out: 0,
+            sacked,
+        });
+        trace!(
+            "enter TCP recovery of {} -> {} to sequence {}",
+            self.dst,
+            self.src,
+            recover
+        );
+    }
+
+    /// Exits the loss recovery of the TCP connection.
+    pub fn exit_recovery(&mut self) {
+        if self.prr.take().is_some() {
+            trace!("exit TCP recovery of {} -> {}", self.dst, self.src);
+        }
+    }
+
+    /// Sets the size of data in flight SACKed by the source of the TCP connection. The change is
+    /// counted as delivered in recovery.
+    pub fn set_sacked(&mut self, sacked: usize) {
+        if let Some(prr) = &mut self.prr {
+            prr.delivered = prr
+                .delivered
+                .saturating_add(sacked)
+                .saturating_sub(prr.sacked);
+            prr.last_delivered = prr
+                .last_delivered
+                .saturating_add(sacked)
+                .saturating_sub(prr.sacked);
+            prr.sacked = sacked;
+        }
+    }
+
+    /// Counts a duplicate acknowledgement without SACK as a segment delivered in recovery of the
+    /// TCP connection.
+    pub fn deliver_duplicate(&mut self) {
+        if let Some(prr) = &mut self.prr {
+            let mss = prr.mss;
+            prr.deliver(mss);
+        }
+    }
+
+    /// Adds data sent in recovery of the TCP connection, no matter it is new or retransmitted.
+    pub fn add_recovery_out(&mut self, size: usize) {
+        if let Some(prr) = &mut self.prr {
+            prr.out = prr.out.saturating_add(size);
+        }
+    }
+
     /// Updates the TCP SYN timer of the TCP connection.
     pub fn update_syn_timer(&mut self) {
         self.cache_syn = Some(Instant::now());
@@ -832,16 +1114,27 @@ impl TcpTxState {
         self.update_fin_timer();
     }
 
-    /// Appends the payload to the queue of the TCP connection.
-    pub fn append_queue(&mut self, payload: &[u8]) {
+    /// Appends the payload to the queue of the TCP connection. The payload is refused once the
+    /// TCP FIN is appended, so the FIN always follows all the data.
+    pub fn append_queue(&mut self, payload: &[u8]) -> io::Result<()> {
+        if self.is_fin_appended() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "TCP FIN is already appended",
+            ));
+        }
+
         // TODO: major performance degradation
         self.queue.extend(payload);
+        self.activity = Instant::now();
         trace!(
             "append {} Bytes to TCP queue of {} -> {}",
             payload.len(),
             self.dst,
             self.src
         );
+
+        Ok(())
     }
 
     /// Appends the TCP FIN to the queue of the TCP connection.
@@ -863,6 +1156,65 @@ impl TcpTxState {
         }
     }
 
+    /// Increases the count of retransmissions due to timeout since the last forward progress of
+    /// the TCP connection, and returns the count.
+    pub fn increase_timedout_retrans(&mut self) -> usize {
+        self.timedout_retrans += 1;
+
+        self.timedout_retrans
+    }
+
+    /// Marks the TCP connection as possibly behind an MTU black hole, which is confirmed once
+    /// smaller segments make progress.
+    pub fn suspect_black_hole(&mut self) {
+        self.is_black_hole_suspected = true;
+    }
+
+    /// Returns if a possible MTU black hole is confirmed since the last call, where smaller
+    /// segments make progress after full-sized segments keep timing out.
+    pub fn take_black_hole(&mut self) -> bool {
+        std::mem::replace(&mut self.is_black_hole_confirmed, false)
+    }
+
+    /// Lowers the send MSS of the TCP connection by half, but not below `MIN_SEND_MSS`, as
+    /// full-sized segments keep timing out, and returns the send MSS. `mss` is the MSS of the path.
+    pub fn lower_send_mss(&mut self, mss: usize) -> usize {
+        let send_mss = max(self.send_mss(mss) / 2, min(MIN_SEND_MSS, mss));
+        self.send_mss = Some(send_mss);
+        self.send_mss_acks = 0;
+        trace!(
+            "lower TCP send MSS of {} -> {} to {}",
+            self.dst,
+            self.src,
+            send_mss
+        );
+
+        send_mss
+    }
+
+    /// Probes the send MSS of the TCP connection halfway up to the MSS of the path once enough
+    /// acknowledgements make progress at the lowered send MSS, and returns the send MSS.
+    pub fn probe_send_mss(&mut self, mss: usize) -> usize {
+        if let Some(send_mss) = self.send_mss {
+            if self.send_mss_acks >= SEND_MSS_PROBE_ACKS {
+                let send_mss = send_mss + max(mss.saturating_sub(send_mss) / 2, 1);
+                self.send_mss = match send_mss >= mss {
+                    true => None,
+                    false => Some(send_mss),
+                };
+                self.send_mss_acks = 0;
+                trace!(
+                    "probe TCP send MSS of {} -> {} up to {}",
+                    self.dst,
+                    self.src,
+                    min(send_mss, mss)
+                );
+            }
+        }
+
+        self.send_mss(mss)
+    }
+
     /// Doubles the RTO of the TCP connection.
     pub fn double_rto(&mut self) {
         self.set_rto(self.rto.saturating_mul(2));
@@ -942,6 +1294,38 @@ impl TcpTxState {
         self.window
     }
 
+    /// Returns the window scale of the TCP connection.
+    pub fn wscale(&self) -> u8 {
+        self.wscale
+    }
+
+    /// Returns the MSS advertised to the source of the TCP connection.
+    pub fn recv_mss(&self) -> Option<u16> {
+        self.recv_mss
+    }
+
+    /// Returns the window of the TCP connection excluding data pending to be written to the proxy.
+    /// The pending data is scaled by the window scale advertised to the source, which the window
+    /// is in.
+    pub fn available_window(&self) -> u16 {
+        let pending = min(
+            self.pending >> self.src_wscale.unwrap_or(0),
+            u16::MAX as usize,
+        ) as u16;
+
+        self.window.saturating_sub(pending)
+    }
+
+    /// Returns the size of data of the TCP connection pending to be written to the proxy.
+    pub fn pending(&self) -> usize {
+        self.pending
+    }
+
+    /// Returns the time since when the window of the TCP connection has been zero.
+    pub fn zero_window(&self) -> Option<Instant> {
+        self.zero_window
+    }
+
     /// Returns the half of the max window of the TCP connection.
     pub fn half_max_window(&self) -> u16 {
         RECV_WINDOW / 2
@@ -987,6 +1371,29 @@ impl TcpTxState {
         self.queue_fin
     }
 
+    /// Returns if the TCP FIN of the TCP connection is acknowledged.
+    pub fn is_fin_acked(&self) -> bool {
+        self.fin_acked
+    }
+
+    /// Returns if the TCP FIN of the TCP connection is appended, no matter it is in the queue, in
+    /// the cache or acknowledged.
+    pub fn is_fin_appended(&self) -> bool {
+        self.queue_fin || self.cache_fin.is_some() || self.fin_acked
+    }
+
+    /// Returns the count of retransmissions due to timeout since the last forward progress of the
+    /// TCP connection.
+    pub fn timedout_retrans(&self) -> usize {
+        self.timedout_retrans
+    }
+
+    /// Returns the MSS segmenting data sent to the source of the TCP connection, which is the MSS
+    /// of the path unless it is lowered.
+    pub fn send_mss(&self, mss: usize) -> usize {
+        self.send_mss.map_or(mss, |send_mss| min(send_mss, mss))
+    }
+
     /// Returns the remaining size of the queue of the TCP connection.
     pub fn queue_remaining(&self) -> usize {
         MAX_QUEUE.saturating_sub(self.queue().len())
@@ -997,6 +1404,16 @@ impl TcpTxState {
         self.rto
     }
 
+    /// Returns the smoothed RTT of the TCP connection.
+    pub fn srtt(&self) -> Option<Duration> {
+        self.srtt.map(Duration::from_secs_f64)
+    }
+
+    /// Returns the RTT variation of the TCP connection.
+    pub fn rttvar(&self) -> Option<Duration> {
+        self.rttvar.map(Duration::from_secs_f64)
+    }
+
     /// Returns the next RTO of the TCP connection.
     pub fn next_rto(&self) -> u64 {
         max(MIN_RTO, self.rto.checked_mul(2).unwrap_or(MAX_RTO))
@@ -1012,6 +1429,36 @@ impl TcpTxState {
         &mut self.cc
     }
 
+    /// Returns if the TCP connection is in loss recovery.
+    pub fn is_in_recovery(&self) -> bool {
+        self.prr.is_some()
+    }
+
+    /// Returns the size of data which can be sent in recovery of the TCP connection by the
+    /// Proportional Rate Reduction, or `None` if the connection is not in recovery. The data in
+    /// flight is estimated as the cache excluding data SACKed by the source.
+    pub fn recovery_window(&self) -> Option<usize> {
+        self.prr.as_ref().map(|prr| {
+            let pipe = self.cache.len().saturating_sub(prr.sacked);
+            if pipe > prr.ssthresh {
+                // Proportional rate reduction
+                let target = prr
+                    .delivered
+                    .saturating_mul(prr.ssthresh)
+                    .saturating_add(prr.recover_fs - 1)
+                    / prr.recover_fs;
+
+                target.saturating_sub(prr.out)
+            } else {
+                // Slow start reduction bound
+                let limit = max(prr.delivered.saturating_sub(prr.out), prr.last_delivered)
+                    .saturating_add(prr.mss);
+
+                min(prr.ssthresh - pipe, limit)
+            }
+        })
+    }
+
     /// Returns the send window of the TCP connection. The send window is the minimum one between
     /// the congestion window and the source window.
     pub fn send_window(&self) -> usize {
@@ -1021,6 +1468,11 @@ impl TcpTxState {
             self.src_window
         }
     }
+
+    /// Returns the last time when data is appended to the TCP connection.
+    pub fn activity(&self) -> Instant {
+        self.activity
+    }
 }
 
 impl Display for TcpTxState {
@@ -1029,6 +1481,69 @@ impl Display for TcpTxState {
     }
 }
 
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+/// Enumeration of states of a TCP connection. The `TIME_WAIT` state is not waited, and the
+/// connection is closed immediately once it is in the `TimeWait` or `Closed` state.
+pub enum TcpState {
+    /// Represents the `SYN_RECEIVED` state, the TCP ACK/SYN is not acknowledged.
+    SynReceived,
+    /// Represents the `ESTABLISHED` state.
+    Established,
+    /// Represents the `FIN_WAIT_1` state, the local sent a TCP FIN which is not acknowledged.
+    FinWait1,
+    /// Represents the `FIN_WAIT_2` state, the TCP FIN of the local is acknowledged.
+    FinWait2,
+    /// Represents the `CLOSING` state, both sides sent a TCP FIN simultaneously, and the TCP FIN
+    /// of the local is not acknowledged.
+    Closing,
+    /// Represents the `TIME_WAIT` state.
+    TimeWait,
+    /// Represents the `CLOSE_WAIT` state, the source sent a TCP FIN.
+    CloseWait,
+    /// Represents the `LAST_ACK` state, the local sent a TCP FIN after the source, which is not
+    /// acknowledged.
+    LastAck,
+    /// Represents the `CLOSED` state.
+    Closed,
+}
+
+impl TcpState {
+    /// Returns if the source sent a TCP FIN, no more data will be received in the TCP connection.
+    pub fn is_recv_closed(&self) -> bool {
+        matches!(
+            self,
+            TcpState::Closing
+                | TcpState::TimeWait
+                | TcpState::CloseWait
+                | TcpState::LastAck
+                | TcpState::Closed
+        )
+    }
+
+    /// Returns if the TCP connection is closed and should be cleaned up.
+    pub fn is_closed(&self) -> bool {
+        matches!(self, TcpState::TimeWait | TcpState::Closed)
+    }
+}
+
+impl Display for TcpState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            TcpState::SynReceived => "SYN_RECEIVED",
+            TcpState::Established => "ESTABLISHED",
+            TcpState::FinWait1 => "FIN_WAIT_1",
+            TcpState::FinWait2 => "FIN_WAIT_2",
+            TcpState::Closing => "CLOSING",
+            TcpState::TimeWait => "TIME_WAIT",
+            TcpState::CloseWait => "CLOSE_WAIT",
+            TcpState::LastAck => "LAST_ACK",
+            TcpState::Closed => "CLOSED",
+        };
+
+        write!(f, "{}", s)
+    }
+}
+
 /// Represents the RX state of a TCP connection.
 #[derive(Debug)]
 pub struct TcpRxState {
@@ -1039,21 +1554,29 @@ pub struct TcpRxState {
     duplicate: usize,
     last_retrans: Option<Instant>,
     wscale: u8,
+    recv_wscale: u8,
+    /// Represents the window in the TCP SYN, which is kept until the window scale is checked.
+    syn_window: Option<u16>,
     sack_perm: bool,
     cache: Window,
     fin_sequence: Option<u32>,
+    state: TcpState,
+    activity: Instant,
 }
 
 impl TcpRxState {
-    /// Creates a new `TcpRxState`, the sequence is the sequence in the TCP SYN packet.
+    /// Creates a new `TcpRxState`, the sequence is the sequence in the TCP SYN packet. The window
+    /// scale is the one of the source, and the receive window scale is the one advertised to the
+    /// source, which decides the capacity of the cache.
     pub fn new(
         src: SocketAddrV4,
         dst: SocketAddrV4,
         sequence: u32,
         wscale: u8,
+        recv_wscale: u8,
         sack_perm: bool,
     ) -> TcpRxState {
-        let recv_next = sequence.checked_add(1).unwrap_or(0);
+        let recv_next = sequence.wrapping_add(1);
 
         trace!("admit TCP SYN of {} -> {}", src, dst);
 
@@ -1065,19 +1588,70 @@ impl TcpRxState {
             duplicate: 0,
             last_retrans: None,
             wscale,
+            recv_wscale,
+            syn_window: None,
             sack_perm,
-            cache: Window::with_capacity((RECV_WINDOW as usize) << wscale as usize, recv_next),
+            cache: Window::with_capacity((RECV_WINDOW as usize) << recv_wscale as usize, recv_next),
             fin_sequence: None,
+            state: TcpState::SynReceived,
+            activity: Instant::now(),
+        }
+    }
+
+    fn set_state(&mut self, state: TcpState) {
+        trace!(
+            "set TCP state of {} -> {} from {} to {}",
+            self.src,
+            self.dst,
+            self.state,
+            state
+        );
+        self.state = state;
+    }
+
+    /// Updates the state of the TCP connection with the TX state, which tracks the TCP ACK/SYN and
+    /// the TCP FIN sent by the local.
+    pub fn update_state(&mut self, tx_state: &TcpTxState) {
+        if tx_state.cache_syn().is_none() {
+            self.admit_syn_ack();
+        }
+        if tx_state.is_fin_appended() {
+            self.admit_local_fin();
+        }
+        if tx_state.is_fin_acked() {
+            self.admit_local_fin_ack();
+        }
+    }
+
+    /// Admits the acknowledgement of the TCP ACK/SYN of the TCP connection.
+    pub fn admit_syn_ack(&mut self) {
+        if self.state == TcpState::SynReceived {
+            self.set_state(TcpState::Established);
+        }
+    }
+
+    /// Admits the TCP FIN sent by the local of the TCP connection.
+    pub fn admit_local_fin(&mut self) {
+        match self.state {
+            TcpState::SynReceived | TcpState::Established => self.set_state(TcpState::FinWait1),
+            TcpState::CloseWait => self.set_state(TcpState::LastAck),
+            _ => {}
+        }
+    }
+
+    /// Admits the acknowledgement of the TCP FIN sent by the local of the TCP connection.
+    pub fn admit_local_fin_ack(&mut self) {
+        match self.state {
+            TcpState::FinWait1 => self.set_state(TcpState::FinWait2),
+            TcpState::Closing => self.set_state(TcpState::TimeWait),
+            TcpState::LastAck => self.set_state(TcpState::Closed),
+            _ => {}
         }
     }
 
     /// Adds receive next to the TCP connection.
-    #[allow(clippy::unnecessary_lazy_evaluations)]
     pub fn add_recv_next(&mut self, n: u32) {
-        self.recv_next = self
-            .recv_next
-            .checked_add(n)
-            .unwrap_or_else(|| n - (u32::MAX - self.recv_next));
+        self.recv_next = self.recv_next.wrapping_add(n);
         trace!(
             "add TCP receive next of {} -> {} to {}",
             self.src,
@@ -1146,6 +1720,11 @@ impl TcpRxState {
         self.cache.append(sequence, payload)
     }
 
+    /// Sets the max number of gaps tracked in the cache of the TCP connection.
+    pub fn set_max_gaps(&mut self, max_gaps: usize) {
+        self.cache.set_max_gaps(max_gaps);
+    }
+
     /// Sets the TCP FIN sequence of the TCP connection.
     pub fn set_fin_sequence(&mut self, sequence: u32) {
         self.fin_sequence = Some(sequence);
@@ -1161,6 +1740,25 @@ impl TcpRxState {
     pub fn admit_fin(&mut self) {
         self.fin_sequence = None;
         trace!("admit TCP FIN of {} -> {}", self.src, self.dst);
+
+        match self.state {
+            TcpState::SynReceived | TcpState::Established => self.set_state(TcpState::CloseWait),
+            TcpState::FinWait1 => self.set_state(TcpState::Closing),
+            TcpState::FinWait2 => self.set_state(TcpState::TimeWait),
+            _ => {}
+        }
+    }
+
+    /// Admits the TCP RST of the TCP connection, which closes the connection in any state.
+    pub fn admit_rst(&mut self) {
+        trace!("admit TCP RST of {} -> {}", self.src, self.dst);
+
+        self.set_state(TcpState::Closed);
+    }
+
+    /// Updates the last time when a packet is received in the TCP connection.
+    pub fn update_activity(&mut self) {
+        self.activity = Instant::now();
     }
 
     /// Returns the receive next of the TCP connection.
@@ -1183,6 +1781,20 @@ impl TcpRxState {
         self.wscale
     }
 
+    /// Returns the receive window scale of the TCP connection.
+    pub fn recv_wscale(&self) -> u8 {
+        self.recv_wscale
+    }
+
+    /// Returns the window of the TCP connection to advertise to the source. The window is scaled
+    /// by the receive window scale and never exceeds the remaining size of the cache.
+    pub fn window(&self) -> u16 {
+        min(
+            self.cache.remaining() >> self.recv_wscale as usize,
+            u16::MAX as usize,
+        ) as u16
+    }
+
     /// Returns if the SACK is permitted of the TCP connection.
     pub fn sack_perm(&self) -> bool {
         self.sack_perm
@@ -1197,6 +1809,51 @@ impl TcpRxState {
     pub fn fin_sequence(&self) -> Option<u32> {
         self.fin_sequence
     }
+
+    /// Returns the state of the TCP connection.
+    pub fn state(&self) -> TcpState {
+        self.state
+    }
+
+    /// Returns the last time when a packet is received in the TCP connection.
+    pub fn activity(&self) -> Instant {
+        self.activity
+    }
+
+    /// Sets the window in the TCP SYN of the TCP connection, which the window scale will be
+    /// checked against by the next window from the source.
+    pub fn set_syn_window(&mut self, window: u16) {
+        self.syn_window = Some(window);
+        trace!(
+            "set TCP SYN window of {} -> {} to {}",
+            self.src,
+            self.dst,
+            window
+        );
+    }
+
+    /// Checks the window scale of the TCP connection with the first window from the source after
+    /// the TCP SYN. The window in the TCP SYN is never scaled, so if the window is not scaled down
+    /// below it, the source is considered not to scale its windows, like the window scale option
+    /// is rewritten by a middlebox, and the window scale is disabled. Returns if the window scale
+    /// is disabled.
+    pub fn check_wscale(&mut self, window: u16) -> bool {
+        match self.syn_window.take() {
+            Some(syn_window) if self.wscale > 0 && syn_window > 0 && window >= syn_window => {
+                self.wscale = 0;
+                trace!(
+                    "disable TCP window scale of {} -> {}: window {} is not scaled from {}",
+                    self.src,
+                    self.dst,
+                    window,
+                    syn_window
+                );
+
+                true
+            }
+            _ => false,
+        }
+    }
 }
 
 impl Display for TcpRxState {
@@ -1204,3 +1861,320 @@ impl Display for TcpRxState {
         write!(f, "TCP RX State: {} -> {}", self.src, self.dst)
     }
 }
+
+#[test]
+fn tcp_rx_state_window() {
+    let src = SocketAddrV4::new(std::net::Ipv4Addr::new(10, 6, 0, 2), 10000);
+    let dst = SocketAddrV4::new(std::net::Ipv4Addr::new(10, 6, 0, 1), 80);
+
+    for wscale in 0..=14 {
+        let recv_wscale = min(wscale, 8);
+        let mut state = TcpRxState::new(src, dst, 0, wscale, recv_wscale, false);
+        let capacity = state.cache().capacity();
+        assert_eq!(capacity, (RECV_WINDOW as usize) << recv_wscale);
+        assert!((state.window() as usize) << recv_wscale <= capacity);
+
+        // Out of order data shrinks the window
+        let size = capacity / 2;
+        state
+            .append_cache(1 + size as u32, &vec![0u8; size])
+            .unwrap();
+        assert!((state.window() as usize) << recv_wscale <= state.cache().remaining());
+        assert!(state.window() < (capacity >> recv_wscale) as u16);
+    }
+}
+
+#[test]
+fn tcp_rx_state_check_wscale() {
+    let src = SocketAddrV4::new(std::net::Ipv4Addr::new(10, 6, 0, 2), 10000);
+    let dst = SocketAddrV4::new(std::net::Ipv4Addr::new(10, 6, 0, 1), 80);
+
+    // Scaled
+    let mut state = TcpRxState::new(src, dst, 0, 7, 7, false);
+    state.set_syn_window(64240);
+    assert!(!state.check_wscale(502));
+    assert_eq!(state.wscale(), 7);
+
+    // Not scaled, and only checked once
+    let mut state = TcpRxState::new(src, dst, 0, 7, 7, false);
+    state.set_syn_window(64240);
+    assert!(state.check_wscale(64240));
+    assert_eq!(state.wscale(), 0);
+    assert!(!state.check_wscale(64240));
+
+    // Not checked without a window in the TCP SYN
+    let mut state = TcpRxState::new(src, dst, 0, 7, 7, false);
+    assert!(!state.check_wscale(64240));
+    assert_eq!(state.wscale(), 7);
+}
+
+#[test]
+fn tcp_tx_state_rtt() {
+    let src = SocketAddrV4::new(std::net::Ipv4Addr::new(10, 6, 0, 2), 10000);
+    let dst = SocketAddrV4::new(std::net::Ipv4Addr::new(10, 6, 0, 1), 80);
+
+    let mut state = TcpTxState::new(src, dst, 0, 1, u16::MAX, None, false, None, 1460);
+    assert_eq!(state.srtt(), None);
+    assert_eq!(state.rttvar(), None);
+
+    state.update_rto(Duration::from_millis(100));
+    assert_eq!(state.srtt(), Some(Duration::from_millis(100)));
+    assert_eq!(state.rttvar(), Some(Duration::from_millis(50)));
+
+    // SRTT = 7/8 * 100 + 1/8 * 200, RTTVAR = 3/4 * 50 + 1/4 * 100
+    state.update_rto(Duration::from_millis(200));
+    assert_eq!(state.srtt().unwrap().as_micros(), 112500);
+    assert_eq!(state.rttvar().unwrap().as_micros(), 62500);
+}
+
+#[test]
+fn tcp_rx_state_state() {
+    let src = SocketAddrV4::new(std::net::Ipv4Addr::new(10, 6, 0, 2), 10000);
+    let dst = SocketAddrV4::new(std::net::Ipv4Addr::new(10, 6, 0, 1), 80);
+
+    // Simultaneous close
+    let mut state = TcpRxState::new(src, dst, 0, 0, 0, false);
+    assert_eq!(state.state(), TcpState::SynReceived);
+    state.admit_syn_ack();
+    assert_eq!(state.state(), TcpState::Established);
+    state.admit_local_fin();
+    assert_eq!(state.state(), TcpState::FinWait1);
+    state.admit_fin();
+    assert_eq!(state.state(), TcpState::Closing);
+    assert!(!state.state().is_closed());
+    state.admit_local_fin_ack();
+    assert_eq!(state.state(), TcpState::TimeWait);
+    assert!(state.state().is_closed());
+
+    // Close by local
+    let mut state = TcpRxState::new(src, dst, 0, 0, 0, false);
+    state.admit_syn_ack();
+    state.admit_local_fin();
+    state.admit_local_fin_ack();
+    assert_eq!(state.state(), TcpState::FinWait2);
+    state.admit_fin();
+    assert_eq!(state.state(), TcpState::TimeWait);
+
+    // Close by source
+    let mut state = TcpRxState::new(src, dst, 0, 0, 0, false);
+    state.admit_syn_ack();
+    state.admit_fin();
+    assert_eq!(state.state(), TcpState::CloseWait);
+    state.admit_local_fin();
+    assert_eq!(state.state(), TcpState::LastAck);
+    state.admit_local_fin_ack();
+    assert_eq!(state.state(), TcpState::Closed);
+
+    // Reset
+    let mut state = TcpRxState::new(src, dst, 0, 0, 0, false);
+    state.admit_syn_ack();
+    state.admit_rst();
+    assert_eq!(state.state(), TcpState::Closed);
+    assert!(state.state().is_closed());
+}
+
+#[test]
+fn tcp_rx_state_update_state() {
+    let src = SocketAddrV4::new(std::net::Ipv4Addr::new(10, 6, 0, 2), 10000);
+    let dst = SocketAddrV4::new(std::net::Ipv4Addr::new(10, 6, 0, 1), 80);
+
+    let mut rx_state = TcpRxState::new(src, dst, 0, 0, 0, false);
+    let mut tx_state = TcpTxState::new(src, dst, 0, 1, u16::MAX, None, false, None, 1460);
+    tx_state.update_syn_timer();
+    rx_state.update_state(&tx_state);
+    assert_eq!(rx_state.state(), TcpState::SynReceived);
+
+    tx_state.acknowledge(1);
+    rx_state.update_state(&tx_state);
+    assert_eq!(rx_state.state(), TcpState::Established);
+
+    tx_state.append_queue_fin();
+    rx_state.update_state(&tx_state);
+    assert_eq!(rx_state.state(), TcpState::FinWait1);
+
+    // The FIN is not acknowledged until the acknowledgement covers it
+    tx_state.append_cache_fin();
+    tx_state.acknowledge(1);
+    rx_state.update_state(&tx_state);
+    assert_eq!(rx_state.state(), TcpState::FinWait1);
+
+    tx_state.acknowledge(2);
+    rx_state.update_state(&tx_state);
+    assert_eq!(rx_state.state(), TcpState::FinWait2);
+}
+
+#[test]
+fn tcp_tx_state_timedout_retrans() {
+    let src = SocketAddrV4::new(std::net::Ipv4Addr::new(10, 6, 0, 2), 10000);
+    let dst = SocketAddrV4::new(std::net::Ipv4Addr::new(10, 6, 0, 1), 80);
+
+    let mut state = TcpTxState::new(src, dst, 0, 1, u16::MAX, None, false, None, 1460);
+    state.append_queue(&[0u8; 100]).unwrap();
+    state.append_cache(100).unwrap();
+    assert_eq!(state.increase_timedout_retrans(), 1);
+    assert_eq!(state.increase_timedout_retrans(), 2);
+
+    // Duplicate ACKs are not forward progress
+    state.acknowledge(0);
+    assert_eq!(state.timedout_retrans(), 2);
+
+    state.acknowledge(50);
+    assert_eq!(state.timedout_retrans(), 0);
+
+    // A black hole is confirmed only once smaller segments make progress
+    state.suspect_black_hole();
+    state.acknowledge(50);
+    assert!(!state.take_black_hole());
+    state.acknowledge(100);
+    assert!(state.take_black_hole());
+    assert!(!state.take_black_hole());
+}
+
+#[test]
+fn tcp_tx_state_prr() {
+    let src = SocketAddrV4::new(std::net::Ipv4Addr::new(10, 6, 0, 2), 10000);
+    let dst = SocketAddrV4::new(std::net::Ipv4Addr::new(10, 6, 0, 1), 80);
+
+    let mut state = TcpTxState::new(src, dst, 0, 1, u16::MAX, None, true, None, 1000);
+    state.append_queue(&[0u8; 10000]).unwrap();
+    state.append_cache(10000).unwrap();
+    let cc = state.cc_mut().as_mut().unwrap();
+    cc.ack(9000);
+    cc.fast_retransmission();
+    assert_eq!(cc.ssthresh(), 5000);
+    state.enter_recovery(0, 1000);
+    assert!(state.is_in_recovery());
+    assert_eq!(state.recovery_window(), Some(0));
+
+    // Data is sent in proportion to the data delivered
+    state.set_sacked(1000);
+    assert_eq!(state.recovery_window(), Some(500));
+    state.set_sacked(2000);
+    state.add_recovery_out(1000);
+    assert_eq!(state.recovery_window(), Some(0));
+
+    // Data is sent up to the slow start threshold once the data in flight falls below it
+    state.set_sacked(6000);
+    assert_eq!(state.recovery_window(), Some(1000));
+
+    // Recovery ends once all the data in flight is acknowledged
+    state.acknowledge(5000);
+    assert!(state.is_in_recovery());
+    state.acknowledge(10000);
+    assert!(!state.is_in_recovery());
+    assert_eq!(state.recovery_window(), None);
+}
+
+#[test]
+fn isn_strategy_rfc6528() {
+    let src = SocketAddrV4::new(std::net::Ipv4Addr::new(192, 168, 1, 2), 10000);
+    let dst = SocketAddrV4::new(std::net::Ipv4Addr::new(1, 1, 1, 1), 80);
+    let strategy = IsnStrategy::Rfc6528 {
+        key: b"secret".to_vec(),
+    };
+
+    // The same connection advances with the clock
+    let isn = strategy.isn(src, dst, Duration::from_secs(1));
+    assert_eq!(isn, strategy.isn(src, dst, Duration::from_secs(1)));
+    let mut last = isn;
+    for i in 1..100 {
+        let next = strategy.isn(
+            src,
+            dst,
+            Duration::from_secs(1) + Duration::from_micros(i * 4),
+        );
+        assert_eq!(next.wrapping_sub(last), 1);
+        last = next;
+    }
+    assert_eq!(
+        strategy
+            .isn(src, dst, Duration::from_secs(2))
+            .wrapping_sub(isn),
+        ISN_CLOCK_RATE as u32
+    );
+
+    // Different connections or keys are offset differently
+    let other = SocketAddrV4::new(*src.ip(), src.port() + 1);
+    assert_ne!(isn, strategy.isn(other, dst, Duration::from_secs(1)));
+    let strategy = IsnStrategy::Rfc6528 {
+        key: b"another".to_vec(),
+    };
+    assert_ne!(isn, strategy.isn(src, dst, Duration::from_secs(1)));
+
+    // The secret key is redacted
+    assert_eq!(format!("{:?}", strategy), "Rfc6528 { key: \"<redacted>\" }");
+}
+
+#[test]
+fn isn_strategy_seeded() {
+    let src = SocketAddrV4::new(std::net::Ipv4Addr::new(192, 168, 1, 2), 10000);
+    let dst = SocketAddrV4::new(std::net::Ipv4Addr::new(1, 1, 1, 1), 80);
+
+    // The same seed generates the same sequence
+    let isns = |strategy: IsnStrategy| {
+        (0..4)
+            .map(|_| strategy.isn(src, dst, Duration::ZERO))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        isns(IsnStrategy::new_seeded(1)),
+        isns(IsnStrategy::new_seeded(1))
+    );
+    assert_ne!(
+        isns(IsnStrategy::new_seeded(1)),
+        isns(IsnStrategy::new_seeded(2))
+    );
+
+    let strategy = IsnStrategy::Custom(Arc::new(|src, _| src.port() as u32));
+    assert_eq!(strategy.isn(src, dst, Duration::ZERO), 10000);
+    assert_eq!(strategy, strategy.clone());
+}
+
+#[test]
+fn tcp_tx_state_send_mss() {
+    let src = SocketAddrV4::new(std::net::Ipv4Addr::new(10, 6, 0, 2), 10000);
+    let dst = SocketAddrV4::new(std::net::Ipv4Addr::new(10, 6, 0, 1), 80);
+
+    let mut state = TcpTxState::new(src, dst, 0, 1, u16::MAX, None, false, None, 1460);
+    assert_eq!(state.send_mss(1460), 1460);
+
+    // The send MSS is lowered by half, but not below the minimum
+    assert_eq!(state.lower_send_mss(1460), 730);
+    assert_eq!(state.lower_send_mss(1460), 536);
+    assert_eq!(state.lower_send_mss(1460), 536);
+    assert_eq!(state.send_mss(500), 500);
+
+    // The send MSS is probed up after enough progress
+    state.append_queue(&[0u8; SEND_MSS_PROBE_ACKS]).unwrap();
+    state.append_cache(SEND_MSS_PROBE_ACKS).unwrap();
+    for i in 1..SEND_MSS_PROBE_ACKS {
+        state.acknowledge(i as u32);
+    }
+    assert_eq!(state.probe_send_mss(1460), 536);
+    state.acknowledge(SEND_MSS_PROBE_ACKS as u32);
+    assert_eq!(state.probe_send_mss(1460), 998);
+    assert_eq!(state.probe_send_mss(1460), 998);
+
+    // The send MSS is restored once it reaches the MSS of the path
+    assert_eq!(state.lower_send_mss(1000), 536);
+    state.send_mss_acks = SEND_MSS_PROBE_ACKS;
+    assert_eq!(state.probe_send_mss(537), 537);
+    assert_eq!(state.send_mss, None);
+}
+
+#[test]
+fn tcp_tx_state_available_window() {
+    let src = SocketAddrV4::new(std::net::Ipv4Addr::new(10, 6, 0, 2), 10000);
+    let dst = SocketAddrV4::new(std::net::Ipv4Addr::new(10, 6, 0, 1), 80);
+
+    // The pending data is scaled by the window scale advertised to the source, not by the one of
+    // the source
+    let mut state = TcpTxState::new(src, dst, 0, 1, u16::MAX, Some(2), false, Some(8), 1460);
+    state.set_window(1024);
+    state.add_pending(2048);
+    assert_eq!(state.available_window(), 512);
+    state.drain_pending(1024);
+    assert_eq!(state.available_window(), 768);
+    state.add_pending(4096);
+    assert_eq!(state.available_window(), 0);
+}
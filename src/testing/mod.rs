@@ -0,0 +1,389 @@
+//! Support for the fixtures shared by tests.
+
+use super::packet::layer::ethernet::Ethernet;
+use super::packet::layer::ipv4::Ipv4;
+use super::packet::layer::tcp::Tcp;
+use super::packet::layer::{LayerKinds, Layers};
+use super::packet::Indicator;
+use super::pcap::{self, HardwareAddr};
+use super::proxy::{
+    BoxFuture, DatagramRecv, DatagramSend, ForwardDatagram, SocksReply, UpstreamConnector,
+    UpstreamDatagram, UpstreamStream,
+};
+use super::tcp::TcpTxState;
+use super::{Forwarder, Redirector};
+use ipnetwork::Ipv4Network;
+use pnet::datalink::{DataLinkReceiver, DataLinkSender, NetworkInterface};
+use pnet::packet::tcp::{self as pnet_tcp, TcpOption, TcpOptionPacket};
+use std::collections::VecDeque;
+use std::future;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::io::{self, DuplexStream};
+use tokio::sync::mpsc::UnboundedReceiver;
+
+/// Represents the frames recorded by a `Recorder`.
+pub type Frames = Arc<Mutex<Vec<Vec<u8>>>>;
+
+/// Represents the local IP address of the forwarders and redirectors in tests.
+pub const LOCAL_IP_ADDR: Ipv4Addr = Ipv4Addr::new(192, 168, 1, 1);
+
+/// Returns the source in tests.
+pub fn src() -> SocketAddrV4 {
+    SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 2), 10000)
+}
+
+/// Returns the destination in tests.
+pub fn dst() -> SocketAddrV4 {
+    SocketAddrV4::new(Ipv4Addr::new(1, 1, 1, 1), 80)
+}
+
+/// Returns the hardware address of the source in tests.
+pub fn src_hardware_addr() -> HardwareAddr {
+    "11:11:11:11:11:11".parse().unwrap()
+}
+
+/// Represents a sender recording the sent frames in tests.
+pub struct Recorder(pub Frames);
+
+impl DataLinkSender for Recorder {
+    fn build_and_send(
+        &mut self,
+        num_packets: usize,
+        packet_size: usize,
+        func: &mut dyn FnMut(&mut [u8]),
+    ) -> Option<io::Result<()>> {
+        for _ in 0..num_packets {
+            let mut buffer = vec![0u8; packet_size];
+            func(&mut buffer);
+            self.0.lock().unwrap().push(buffer);
+        }
+
+        Some(Ok(()))
+    }
+
+    fn send_to(&mut self, packet: &[u8], _: Option<NetworkInterface>) -> Option<io::Result<()>> {
+        self.0.lock().unwrap().push(packet.to_vec());
+
+        Some(Ok(()))
+    }
+}
+
+/// Represents a receiver replaying the frames in tests, which fails in `UnexpectedEof` in the end.
+pub struct Replayer(VecDeque<Vec<u8>>, Vec<u8>);
+
+impl Replayer {
+    /// Creates a `Replayer` replaying the given frames.
+    pub fn new(frames: Vec<Vec<u8>>) -> Replayer {
+        Replayer(frames.into(), Vec::new())
+    }
+}
+
+impl DataLinkReceiver for Replayer {
+    fn next(&mut self) -> io::Result<&[u8]> {
+        self.1 = self
+            .0
+            .pop_front()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+
+        Ok(&self.1)
+    }
+}
+
+/// Represents an upstream connecting to an in-memory stream once in tests.
+pub struct DuplexUpstream(pub Mutex<Option<DuplexStream>>);
+
+impl DuplexUpstream {
+    /// Creates a `DuplexUpstream`, and returns it with the remote end of the stream.
+    pub fn new() -> (DuplexUpstream, DuplexStream) {
+        let (stream, remote) = io::duplex(1024);
+
+        (DuplexUpstream(Mutex::new(Some(stream))), remote)
+    }
+}
+
+impl UpstreamConnector for DuplexUpstream {
+    fn connect(
+        &self,
+        _: SocketAddrV4,
+        _: Option<String>,
+    ) -> BoxFuture<'_, io::Result<UpstreamStream>> {
+        let stream = self.0.lock().unwrap().take();
+
+        Box::pin(async move {
+            match stream {
+                Some(stream) => Ok(UpstreamStream::new(Box::new(stream), false)),
+                None => Err(io::Error::from(io::ErrorKind::ConnectionRefused)),
+            }
+        })
+    }
+
+    fn bind(&self, _: SocketAddrV4) -> BoxFuture<'_, io::Result<UpstreamDatagram>> {
+        Box::pin(async { Err(io::Error::from(io::ErrorKind::AddrNotAvailable)) })
+    }
+}
+
+/// Represents an upstream in tests whose connections never complete.
+pub struct PendingUpstream;
+
+impl UpstreamConnector for PendingUpstream {
+    fn connect(
+        &self,
+        _: SocketAddrV4,
+        _: Option<String>,
+    ) -> BoxFuture<'_, io::Result<UpstreamStream>> {
+        Box::pin(future::pending())
+    }
+
+    fn bind(&self, _: SocketAddrV4) -> BoxFuture<'_, io::Result<UpstreamDatagram>> {
+        Box::pin(future::pending())
+    }
+}
+
+/// Represents an upstream rejecting all the connections with the SOCKS reply in tests.
+pub struct RejectingUpstream(pub SocksReply);
+
+impl UpstreamConnector for RejectingUpstream {
+    fn connect(
+        &self,
+        _: SocketAddrV4,
+        _: Option<String>,
+    ) -> BoxFuture<'_, io::Result<UpstreamStream>> {
+        let reply = self.0;
+
+        Box::pin(async move { Err(reply.into()) })
+    }
+
+    fn bind(&self, _: SocketAddrV4) -> BoxFuture<'_, io::Result<UpstreamDatagram>> {
+        let reply = self.0;
+
+        Box::pin(async move { Err(reply.into()) })
+    }
+}
+
+/// Represents an upstream in tests which connects to an in-memory stream, or fails in the given
+/// error, counting the connections.
+pub struct CountingUpstream(Option<io::ErrorKind>, AtomicUsize);
+
+impl CountingUpstream {
+    /// Creates a `CountingUpstream`.
+    pub fn new(kind: Option<io::ErrorKind>) -> CountingUpstream {
+        CountingUpstream(kind, AtomicUsize::new(0))
+    }
+
+    /// Returns the number of the connections.
+    pub fn count(&self) -> usize {
+        self.1.load(Ordering::Relaxed)
+    }
+}
+
+impl UpstreamConnector for CountingUpstream {
+    fn connect(
+        &self,
+        _: SocketAddrV4,
+        _: Option<String>,
+    ) -> BoxFuture<'_, io::Result<UpstreamStream>> {
+        self.1.fetch_add(1, Ordering::Relaxed);
+        let result = match self.0 {
+            Some(kind) => Err(io::Error::from(kind)),
+            None => Ok(UpstreamStream::new(Box::new(io::duplex(64).0), false)),
+        };
+
+        Box::pin(async move { result })
+    }
+
+    fn bind(&self, _: SocketAddrV4) -> BoxFuture<'_, io::Result<UpstreamDatagram>> {
+        Box::pin(async { Err(io::Error::from(io::ErrorKind::ConnectionRefused)) })
+    }
+}
+
+/// Represents a forwarder in tests recording forwarded datagrams.
+pub struct DatagramRecorder(pub Arc<Mutex<Vec<(SocketAddrV4, SocketAddrV4)>>>);
+
+impl ForwardDatagram for DatagramRecorder {
+    fn forward(&mut self, dst: SocketAddrV4, src: SocketAddrV4, _: &[u8]) -> io::Result<()> {
+        self.0.lock().unwrap().push((dst, src));
+
+        Ok(())
+    }
+}
+
+/// Represents the halves of a datagram socket in tests, which discards the sent datagrams and
+/// receives the datagrams injected through the channel.
+pub struct InjectedDatagram(pub UnboundedReceiver<SocketAddrV4>);
+
+impl DatagramSend for InjectedDatagram {
+    fn send_to<'a>(
+        &'a mut self,
+        payload: &'a [u8],
+        _: SocketAddrV4,
+    ) -> BoxFuture<'a, io::Result<usize>> {
+        Box::pin(async move { Ok(payload.len()) })
+    }
+}
+
+impl DatagramRecv for InjectedDatagram {
+    fn recv_from<'a>(
+        &'a mut self,
+        buffer: &'a mut [u8],
+    ) -> BoxFuture<'a, io::Result<(usize, SocketAddrV4)>> {
+        Box::pin(async move {
+            let src = self
+                .0
+                .recv()
+                .await
+                .ok_or_else(|| io::Error::from(io::ErrorKind::BrokenPipe))?;
+            buffer[0] = 0;
+
+            Ok((1, src))
+        })
+    }
+}
+
+/// Returns a forwarder recording the sent frames.
+pub fn forwarder(frames: &Frames) -> Forwarder {
+    Forwarder::new(
+        Box::new(Recorder(Arc::clone(frames))),
+        1500,
+        pcap::HARDWARE_ADDR_UNSPECIFIED,
+        LOCAL_IP_ADDR,
+    )
+}
+
+/// Returns a forwarder recording the sent frames, with an established TCP connection from the
+/// source to the destination.
+pub fn tcp_forwarder(frames: &Frames, src: SocketAddrV4, dst: SocketAddrV4) -> Forwarder {
+    let mut tx = forwarder(frames);
+    tx.set_src_hardware_addr(*src.ip(), src_hardware_addr());
+    tx.set_state(
+        dst,
+        src,
+        TcpTxState::new(src, dst, 0, 1, u16::MAX, None, false, None, 1460),
+    );
+
+    tx
+}
+
+/// Returns a redirector of the source through the upstream, recording the sent frames.
+pub fn redirector(
+    frames: &Frames,
+    src: Ipv4Addr,
+    upstream: Arc<dyn UpstreamConnector>,
+) -> Redirector {
+    Redirector::new_with_upstream(
+        Arc::new(Mutex::new(forwarder(frames))),
+        Ipv4Network::new(src, 32).unwrap(),
+        LOCAL_IP_ADDR,
+        None,
+        upstream,
+        None,
+    )
+}
+
+/// Returns a TCP segment from the source to the destination.
+pub fn segment(
+    src: SocketAddrV4,
+    dst: SocketAddrV4,
+    flags: u8,
+    sequence: u32,
+    acknowledgement: u32,
+) -> Tcp {
+    segment_with_options(src, dst, flags, sequence, acknowledgement, u16::MAX, vec![])
+}
+
+/// Returns a TCP segment from the source to the destination in the window and carrying the
+/// options, which are padded to 4 bytes.
+pub fn segment_with_options(
+    src: SocketAddrV4,
+    dst: SocketAddrV4,
+    flags: u8,
+    sequence: u32,
+    acknowledgement: u32,
+    window: u16,
+    mut options: Vec<TcpOption>,
+) -> Tcp {
+    let options_len = |options: &Vec<TcpOption>| -> usize {
+        options.iter().map(TcpOptionPacket::packet_size).sum()
+    };
+    while options_len(&options) % 4 != 0 {
+        options.push(TcpOption::nop());
+    }
+
+    let mut tcp = Tcp::from(pnet_tcp::Tcp {
+        source: src.port(),
+        destination: dst.port(),
+        sequence,
+        acknowledgement,
+        data_offset: 5 + (options_len(&options) / 4) as u8,
+        reserved: 0,
+        flags,
+        window,
+        checksum: 0,
+        urgent_ptr: 0,
+        options,
+        payload: vec![],
+    });
+    tcp.set_ipv4_layer(&Ipv4::new(0, LayerKinds::Tcp, *src.ip(), *dst.ip()).unwrap());
+
+    tcp
+}
+
+/// Returns the frame of the TCP segment and the payload from the source.
+pub fn frame(tcp: &Tcp, payload: &[u8]) -> Vec<u8> {
+    let ethernet = Ethernet::new(
+        LayerKinds::Ipv4,
+        src_hardware_addr(),
+        pcap::HARDWARE_ADDR_UNSPECIFIED,
+    )
+    .unwrap();
+    let ipv4 = Ipv4::new(0, LayerKinds::Tcp, tcp.src_ip_addr(), tcp.dst_ip_addr()).unwrap();
+    let indicator = Indicator::new(
+        Layers::Ethernet(ethernet),
+        Some(Layers::Ipv4(ipv4)),
+        Some(Layers::Tcp(tcp.clone())),
+    );
+    let mut frame = vec![0u8; indicator.len() + payload.len()];
+    indicator
+        .serialize_with_payload(&mut frame, payload)
+        .unwrap();
+
+    frame
+}
+
+/// Receives the TCP segment and the payload from the source in a frame.
+pub async fn receive(redirector: &mut Redirector, tcp: &Tcp, payload: &[u8]) -> io::Result<()> {
+    let frame = frame(tcp, payload);
+    let indicator = Indicator::from(&frame).unwrap();
+
+    redirector.handle_ipv4(&indicator, &frame).await
+}
+
+/// Takes the sent frames, and returns the TCP segments and their payloads in them.
+pub fn sent(frames: &Frames) -> Vec<(Tcp, Vec<u8>)> {
+    frames
+        .lock()
+        .unwrap()
+        .drain(..)
+        .map(|frame| {
+            let indicator = Indicator::from(&frame).unwrap();
+            let payload = frame[indicator.len()..indicator.content_len()].to_vec();
+
+            (indicator.tcp().unwrap().clone(), payload)
+        })
+        .collect()
+}
+
+/// Takes the sent frames, and returns the total lengths of the IPv4 packets in them.
+pub fn sizes(frames: &Frames) -> Vec<usize> {
+    frames
+        .lock()
+        .unwrap()
+        .drain(..)
+        .map(|frame| {
+            let indicator = Indicator::from(&frame).unwrap();
+
+            indicator.ipv4().unwrap().total_length() as usize
+        })
+        .collect()
+}